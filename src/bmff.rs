@@ -0,0 +1,406 @@
+//!
+//! Building blocks for ISO BMFF (ISO/IEC 14496-12) boxes.
+//!
+//! All integers are big-endian. Box sizes are patched when a box is closed,
+//! so box functions only write their fields and children.
+//!
+
+/// 16.16 fixed-point 1.0
+pub const FIXED_ONE: u32 = 0x0001_0000;
+
+/// transformation matrix of `mvhd` and `tkhd`, `{a, b, u, c, d, v, x, y, w}`
+pub const UNITY_MATRIX: [u32; 9] = [FIXED_ONE, 0, 0, 0, FIXED_ONE, 0, 0, 0, 0x4000_0000];
+
+#[derive(Default)]
+pub struct BoxWriter {
+    buffer: Vec<u8>,
+}
+
+impl BoxWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes a box whose content is produced by `f`.
+    pub fn boxed<F: FnOnce(&mut Self)>(&mut self, box_type: &[u8; 4], f: F) -> &mut Self {
+        let start = self.buffer.len();
+        self.u32(0); // size, patched below
+        self.fourcc(box_type);
+        f(self);
+        let size = self.buffer.len() - start;
+        assert!(size <= u32::MAX as usize, "box {:?} is too large", std::str::from_utf8(box_type));
+        self.patch_u32(start, size as u32);
+        self
+    }
+
+    /// Writes a full box, a box starting with an 8-bit version and 24-bit flags.
+    pub fn full_box<F: FnOnce(&mut Self)>(&mut self, box_type: &[u8; 4], version: u8, flags: u32, f: F) -> &mut Self {
+        self.boxed(box_type, |w| {
+            w.u8(version).u24(flags);
+            f(w);
+        })
+    }
+
+    pub fn u8(&mut self, v: u8) -> &mut Self {
+        self.buffer.push(v);
+        self
+    }
+
+    pub fn u16(&mut self, v: u16) -> &mut Self {
+        self.bytes(&v.to_be_bytes())
+    }
+
+    pub fn i16(&mut self, v: i16) -> &mut Self {
+        self.bytes(&v.to_be_bytes())
+    }
+
+    pub fn u24(&mut self, v: u32) -> &mut Self {
+        self.bytes(&v.to_be_bytes()[1..])
+    }
+
+    pub fn u32(&mut self, v: u32) -> &mut Self {
+        self.bytes(&v.to_be_bytes())
+    }
+
+    pub fn i32(&mut self, v: i32) -> &mut Self {
+        self.bytes(&v.to_be_bytes())
+    }
+
+    pub fn u64(&mut self, v: u64) -> &mut Self {
+        self.bytes(&v.to_be_bytes())
+    }
+
+    pub fn i64(&mut self, v: i64) -> &mut Self {
+        self.bytes(&v.to_be_bytes())
+    }
+
+    pub fn fourcc(&mut self, v: &[u8; 4]) -> &mut Self {
+        self.bytes(v)
+    }
+
+    pub fn bytes(&mut self, v: &[u8]) -> &mut Self {
+        self.buffer.extend_from_slice(v);
+        self
+    }
+
+    pub fn zeros(&mut self, n: usize) -> &mut Self {
+        self.buffer.resize(self.buffer.len() + n, 0);
+        self
+    }
+
+    /// null-terminated UTF-8 string
+    pub fn cstring(&mut self, v: &str) -> &mut Self {
+        self.bytes(v.as_bytes()).u8(0)
+    }
+
+    /// 16.16 fixed-point
+    pub fn fixed16_16(&mut self, v: f64) -> &mut Self {
+        self.i32((v * 65536.0).round() as i32)
+    }
+
+    /// 8.8 fixed-point
+    pub fn fixed8_8(&mut self, v: f64) -> &mut Self {
+        self.i16((v * 256.0).round() as i16)
+    }
+
+    pub fn matrix(&mut self, m: &[u32; 9]) -> &mut Self {
+        m.iter().for_each(|v| {
+            self.u32(*v);
+        });
+        self
+    }
+
+    /// Current offset from the start of the buffer.
+    pub fn position(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Overwrites a field written earlier, e.g. an offset only known once later boxes are written.
+    pub fn patch_u32(&mut self, position: usize, v: u32) {
+        self.buffer[position..position + 4].copy_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+/// A box found by `BoxReader::next_box`.
+#[derive(Clone, Copy, Debug)]
+pub struct BoxRef<'a> {
+    pub box_type: [u8; 4],
+    /// absolute offset of the box header
+    pub offset: usize,
+    pub size: usize,
+    pub payload: &'a [u8],
+}
+
+impl<'a> BoxRef<'a> {
+    pub fn reader(&self) -> BoxReader<'a> {
+        BoxReader::with_offset(self.payload, self.offset + self.size - self.payload.len())
+    }
+
+    pub fn type_str(&self) -> String {
+        String::from_utf8_lossy(&self.box_type).into_owned()
+    }
+}
+
+/// Reads big-endian fields and nested boxes, failing on truncated data instead of panicking.
+#[derive(Clone)]
+pub struct BoxReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// absolute offset of `data[0]`
+    base: usize,
+}
+
+impl<'a> BoxReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self::with_offset(data, 0)
+    }
+
+    pub fn with_offset(data: &'a [u8], base: usize) -> Self {
+        Self { data, pos: 0, base }
+    }
+
+    /// Absolute offset of the next byte.
+    pub fn position(&self) -> usize {
+        self.base + self.pos
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    pub fn bytes(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        if n > self.remaining() {
+            anyhow::bail!("truncated data at offset {}, {} bytes wanted, {} left", self.position(), n, self.remaining());
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    pub fn skip(&mut self, n: usize) -> anyhow::Result<()> {
+        self.bytes(n).map(|_| ())
+    }
+
+    fn array<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        let mut v = [0u8; N];
+        v.copy_from_slice(self.bytes(N)?);
+        Ok(v)
+    }
+
+    pub fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> anyhow::Result<u16> {
+        self.array().map(u16::from_be_bytes)
+    }
+
+    pub fn u24(&mut self) -> anyhow::Result<u32> {
+        let b = self.bytes(3)?;
+        Ok(u32::from(b[0]) << 16 | u32::from(b[1]) << 8 | u32::from(b[2]))
+    }
+
+    pub fn u32(&mut self) -> anyhow::Result<u32> {
+        self.array().map(u32::from_be_bytes)
+    }
+
+    pub fn i32(&mut self) -> anyhow::Result<i32> {
+        self.array().map(i32::from_be_bytes)
+    }
+
+    pub fn u64(&mut self) -> anyhow::Result<u64> {
+        self.array().map(u64::from_be_bytes)
+    }
+
+    pub fn i64(&mut self) -> anyhow::Result<i64> {
+        self.array().map(i64::from_be_bytes)
+    }
+
+    pub fn fourcc(&mut self) -> anyhow::Result<[u8; 4]> {
+        self.array()
+    }
+
+    /// version and flags of a full box
+    pub fn full_box_header(&mut self) -> anyhow::Result<(u8, u32)> {
+        Ok((self.u8()?, self.u24()?))
+    }
+
+    /// Reads a 32-bit field in version 0 boxes and a 64-bit one in version 1 boxes.
+    pub fn u32_or_u64(&mut self, version: u8) -> anyhow::Result<u64> {
+        if version == 1 {
+            self.u64()
+        } else {
+            self.u32().map(u64::from)
+        }
+    }
+
+    /// Returns `None` when no data is left.
+    pub fn next_box(&mut self) -> anyhow::Result<Option<BoxRef<'a>>> {
+        if self.remaining() == 0 {
+            return Ok(None);
+        }
+        let offset = self.position();
+        let start = self.pos;
+        let size = self.u32()? as usize;
+        let box_type = self.fourcc()?;
+        let size = match size {
+            // box extends to the end of the enclosing data
+            0 => self.data.len() - start,
+            1 => self.u64()? as usize,
+            n => n,
+        };
+        let header_size = self.pos - start;
+        if size < header_size || size - header_size > self.remaining() {
+            anyhow::bail!("box '{}' at offset {} has invalid size {}",
+                          String::from_utf8_lossy(&box_type), offset, size);
+        }
+        let payload = self.bytes(size - header_size)?;
+        Ok(Some(BoxRef { box_type, offset, size, payload }))
+    }
+
+    /// All remaining data as a list of boxes.
+    pub fn boxes(&mut self) -> anyhow::Result<Vec<BoxRef<'a>>> {
+        let mut boxes = vec![];
+        while let Some(b) = self.next_box()? {
+            boxes.push(b);
+        }
+        Ok(boxes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `moov` with a full box and a nested box, between two empty boxes.
+    fn nested() -> Vec<u8> {
+        let mut w = BoxWriter::new();
+        w.boxed(b"free", |_| {});
+        w.boxed(b"moov", |w| {
+            w.full_box(b"mvhd", 1, 0x0A0B0C, |w| {
+                w.u8(1).u16(0x0203).u24(0x040506).u32(0x0708_090A).i32(-2).u64(u64::MAX - 1).i64(-3);
+                w.fourcc(b"abcd").cstring("hi").zeros(2).fixed16_16(1.5).fixed8_8(-0.5);
+            });
+            w.boxed(b"trak", |w| {
+                w.boxed(b"tkhd", |w| {
+                    w.matrix(&UNITY_MATRIX);
+                });
+            });
+        });
+        w.boxed(b"skip", |_| {});
+        w.into_bytes()
+    }
+
+    #[test]
+    fn boxes_round_trip() {
+        let data = nested();
+        let boxes = BoxReader::new(&data).boxes().unwrap();
+        let layout = boxes.iter().map(|b| (b.type_str(), b.offset, b.size)).collect::<Vec<_>>();
+        let moov_size = 8 + (12 + 1 + 2 + 3 + 4 + 4 + 8 + 8 + 4 + 3 + 2 + 4 + 2) + (8 + 8 + 36);
+        assert_eq!(layout, [("free".to_string(), 0, 8), ("moov".to_string(), 8, moov_size), ("skip".to_string(), 8 + moov_size, 8)]);
+
+        let children = boxes[1].reader().boxes().unwrap();
+        assert_eq!(children.iter().map(|b| (b.type_str(), b.offset)).collect::<Vec<_>>(),
+                   [("mvhd".to_string(), 16), ("trak".to_string(), 16 + children[0].size)]);
+        let mut r = children[0].reader();
+        assert_eq!(r.position(), 24);
+        assert_eq!(r.full_box_header().unwrap(), (1, 0x0A0B0C));
+        assert_eq!((r.u8().unwrap(), r.u16().unwrap(), r.u24().unwrap(), r.u32().unwrap()), (1, 0x0203, 0x040506, 0x0708_090A));
+        assert_eq!((r.i32().unwrap(), r.u64().unwrap(), r.i64().unwrap()), (-2, u64::MAX - 1, -3));
+        assert_eq!((&r.fourcc().unwrap(), r.bytes(3).unwrap(), r.bytes(2).unwrap()), (b"abcd", &b"hi\0"[..], &[0, 0][..]));
+        assert_eq!((r.u32().unwrap(), r.u16().unwrap()), (0x0001_8000, 0xFF80));
+        assert_eq!(r.remaining(), 0);
+        assert!(r.u8().is_err());
+
+        let mut r = children[1].reader().next_box().unwrap().unwrap().reader();
+        let matrix = (0..9).map(|_| r.u32().unwrap()).collect::<Vec<_>>();
+        assert_eq!(matrix, UNITY_MATRIX);
+        assert!(r.next_box().unwrap().is_none());
+    }
+
+    #[test]
+    fn u32_or_u64_follows_the_version() {
+        let data = [0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2];
+        let mut r = BoxReader::new(&data);
+        assert_eq!((r.u32_or_u64(0).unwrap(), r.u32_or_u64(1).unwrap()), (1, 2));
+    }
+
+    #[test]
+    fn patched_fields_replace_the_written_ones() {
+        let mut w = BoxWriter::new();
+        w.boxed(b"stco", |w| {
+            w.u32(0);
+        });
+        w.patch_u32(8, 0xDEAD_BEEF);
+        assert_eq!(w.into_bytes(), [&12u32.to_be_bytes()[..], b"stco", &[0xDE, 0xAD, 0xBE, 0xEF]].concat());
+    }
+
+    #[test]
+    fn reads_64_bit_sizes() {
+        let data = [&1u32.to_be_bytes()[..], b"mdat", &20u64.to_be_bytes(), b"abcd", &8u32.to_be_bytes(), b"free"].concat();
+        let boxes = BoxReader::new(&data).boxes().unwrap();
+        assert_eq!((&boxes[0].box_type, boxes[0].offset, boxes[0].size, boxes[0].payload), (b"mdat", 0, 20, &b"abcd"[..]));
+        assert_eq!(boxes[0].reader().position(), 16);
+        assert_eq!((&boxes[1].box_type, boxes[1].offset, boxes[1].size), (b"free", 20, 8));
+
+        // a 64-bit size beyond the end of the data, or smaller than the 16 bytes of header
+        for size in [29, 15, u64::MAX] {
+            let data = [&1u32.to_be_bytes()[..], b"mdat", &size.to_be_bytes(), b"abcd", &8u32.to_be_bytes(), b"free"].concat();
+            assert!(BoxReader::new(&data).boxes().is_err(), "size {}", size);
+        }
+    }
+
+    #[test]
+    fn size_zero_extends_to_the_end() {
+        let data = [&8u32.to_be_bytes()[..], b"free", &0u32.to_be_bytes(), b"mdat", b"abcdef"].concat();
+        let boxes = BoxReader::new(&data).boxes().unwrap();
+        assert_eq!((&boxes[1].box_type, boxes[1].offset, boxes[1].size, boxes[1].payload), (b"mdat", 8, 14, &b"abcdef"[..]));
+
+        // within a parent, the end is the end of the parent
+        let mut w = BoxWriter::new();
+        w.boxed(b"moov", |w| {
+            w.u32(0).fourcc(b"udta").bytes(b"ab");
+        });
+        w.boxed(b"free", |_| {});
+        let data = w.into_bytes();
+        let boxes = BoxReader::new(&data).boxes().unwrap();
+        assert_eq!(boxes.len(), 2);
+        let udta = boxes[0].reader().next_box().unwrap().unwrap();
+        assert_eq!((udta.offset, udta.size, udta.payload), (8, 10, &b"ab"[..]));
+    }
+
+    #[test]
+    fn truncated_boxes_are_errors() {
+        let data = nested();
+        for end in 1..data.len() {
+            // every cut but the ones between top level boxes lands within a header or a payload
+            let read = BoxReader::new(&data[..end]).boxes();
+            assert_eq!(read.is_ok(), end == 8 || end == data.len() - 8, "{} of {} bytes", end, data.len());
+        }
+        // a truncated 64-bit size
+        let data = [&1u32.to_be_bytes()[..], b"mdat", &[0; 4]].concat();
+        assert!(BoxReader::new(&data).boxes().is_err());
+    }
+
+    #[test]
+    fn oversized_boxes_are_errors() {
+        let data = nested();
+        let patched = |offset: usize, size: u32| {
+            let mut data = data.clone();
+            data[offset..offset + 4].copy_from_slice(&size.to_be_bytes());
+            data
+        };
+        // beyond the end of the data, and smaller than its own header
+        for size in [u32::MAX, data.len() as u32, 7, 4] {
+            assert!(BoxReader::new(&patched(8, size)).boxes().is_err(), "size {}", size);
+        }
+        // a child larger than its parent still fits in the data, but not in the parent
+        let data = patched(16, data.len() as u32 - 16);
+        let moov = BoxReader::new(&data).boxes().unwrap()[1];
+        assert!(moov.reader().boxes().is_err());
+    }
+}
@@ -0,0 +1,371 @@
+//!
+//! Common Encryption (ISO/IEC 23001-7) of VP9 samples, with the `cenc` (AES-CTR) and
+//! `cbcs` (AES-CBC with a 1:9 pattern) schemes.
+//!
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+
+use aes::cipher::generic_array::GenericArray;
+use aes::{Aes128, BlockCipher, NewBlockCipher};
+use anyhow::{bail, Context};
+
+use crate::vp9;
+
+/// W3C common PSSH box format, understood by Clear Key players
+pub const COMMON_SYSTEM_ID: [u8; 16] = [
+    0x10, 0x77, 0xef, 0xec, 0xc0, 0xb2, 0x4d, 0x02, 0xac, 0xe3, 0x3c, 0x1e, 0x52, 0xe2, 0xfb, 0x4b,
+];
+
+/// `cbcs` encrypts 1 block out of 10
+const CRYPT_BYTE_BLOCK: u8 = 1;
+const SKIP_BYTE_BLOCK: u8 = 9;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    /// AES-CTR, full subsample encryption with a per-sample IV
+    Cenc,
+    /// AES-CBC, pattern encryption with a constant IV
+    Cbcs,
+}
+
+impl Scheme {
+    pub fn fourcc(&self) -> [u8; 4] {
+        match self {
+            Scheme::Cenc => *b"cenc",
+            Scheme::Cbcs => *b"cbcs",
+        }
+    }
+
+    /// Size of the IV stored with every sample, 0 when a constant IV is used.
+    pub fn per_sample_iv_size(&self) -> u8 {
+        match self {
+            Scheme::Cenc => 8,
+            Scheme::Cbcs => 0,
+        }
+    }
+
+    /// `(crypt_byte_block, skip_byte_block)` of the `tenc` box
+    pub fn pattern(&self) -> (u8, u8) {
+        match self {
+            Scheme::Cenc => (0, 0),
+            Scheme::Cbcs => (CRYPT_BYTE_BLOCK, SKIP_BYTE_BLOCK),
+        }
+    }
+}
+
+impl std::str::FromStr for Scheme {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "cenc" => Ok(Scheme::Cenc),
+            "cbcs" => Ok(Scheme::Cbcs),
+            _ => bail!("unknown protection scheme {}, expected cenc or cbcs", s),
+        }
+    }
+}
+
+/// Everything a player needs to know about the protection, written into the init segment.
+#[derive(Clone, Debug)]
+pub struct Protection {
+    pub scheme: Scheme,
+    pub kid: [u8; 16],
+    /// `cbcs` only
+    pub constant_iv: Option<[u8; 16]>,
+    pub pssh: Vec<Pssh>,
+}
+
+/// Protection system specific header, data for a DRM system to find the key.
+#[derive(Clone, Debug)]
+pub struct Pssh {
+    pub system_id: [u8; 16],
+    pub kids: Vec<[u8; 16]>,
+    pub data: Vec<u8>,
+}
+
+/// Bytes at the start of a subsample left in the clear, followed by encrypted bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subsample {
+    pub clear: u16,
+    pub protected: u32,
+}
+
+/// Sample auxiliary information, stored in `senc`.
+#[derive(Clone, Debug)]
+pub struct SampleEncryption {
+    /// empty with a constant IV
+    pub iv: Vec<u8>,
+    pub subsamples: Vec<Subsample>,
+}
+
+impl SampleEncryption {
+    /// size of the entry in `senc`, as listed by `saiz`
+    pub fn size(&self) -> u8 {
+        (self.iv.len() + 2 + 6 * self.subsamples.len()) as u8
+    }
+}
+
+pub struct Encryptor {
+    protection: Protection,
+    cipher: Aes128,
+    /// `cenc` IV of the next sample
+    next_iv: u64,
+    headers: vp9::HeaderParser,
+}
+
+impl Encryptor {
+    /// `iv` is the constant IV for `cbcs` and the IV of the first sample for `cenc`
+    /// (its first 8 bytes), random if `None`.
+    pub fn new(scheme: Scheme, kid: [u8; 16], key: [u8; 16], iv: Option<[u8; 16]>) -> Self {
+        let iv = iv.unwrap_or_else(|| {
+            let mut iv = [0; 16];
+            iv[..8].copy_from_slice(&random_u64().to_be_bytes());
+            iv[8..].copy_from_slice(&random_u64().to_be_bytes());
+            iv
+        });
+        let mut next_iv = [0; 8];
+        next_iv.copy_from_slice(&iv[..8]);
+        Self {
+            protection: Protection {
+                scheme,
+                kid,
+                constant_iv: if scheme == Scheme::Cbcs { Some(iv) } else { None },
+                pssh: vec![Pssh { system_id: COMMON_SYSTEM_ID, kids: vec![kid], data: vec![] }],
+            },
+            cipher: Aes128::new(GenericArray::from_slice(&key)),
+            next_iv: u64::from_be_bytes(next_iv),
+            headers: vp9::HeaderParser::new(),
+        }
+    }
+
+    /// Reads the key from a file of `name=value` lines, `#` starting a comment:
+    ///
+    /// ```text
+    /// kid=00112233445566778899aabbccddeeff
+    /// key=00112233445566778899aabbccddeeff
+    /// # optional, see `new`
+    /// iv=00112233445566778899aabbccddeeff
+    /// # optional, any number of extra pssh boxes
+    /// pssh=<system id in hex>:<data in hex>
+    /// ```
+    pub fn from_key_file(path: impl AsRef<Path>, scheme: Scheme) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).with_context(|| format!("cannot read key file {}", path.display()))?;
+        let (mut kid, mut key, mut iv, mut pssh) = (None, None, None, vec![]);
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            let (name, value) = line.split_once('=').with_context(|| format!("invalid line in key file: {}", line))?;
+            match name.trim() {
+                "kid" => kid = Some(hex16(value)?),
+                "key" => key = Some(hex16(value)?),
+                "iv" => iv = Some(hex16(value)?),
+                "pssh" => {
+                    let (system_id, data) = value.split_once(':').context("pssh needs <system id>:<data>")?;
+                    pssh.push((hex16(system_id)?, hex(data)?));
+                }
+                _ => bail!("unknown key file entry {}", name),
+            }
+        }
+        let kid = kid.context("key file without kid")?;
+        let mut encryptor = Self::new(scheme, kid, key.context("key file without key")?, iv);
+        for (system_id, data) in pssh {
+            encryptor.protection.pssh.push(Pssh { system_id, kids: vec![kid], data });
+        }
+        Ok(encryptor)
+    }
+
+    pub fn protection(&self) -> &Protection {
+        &self.protection
+    }
+
+    /// Encrypts a VP9 frame or superframe in place, leaving the uncompressed headers and the
+    /// superframe index in the clear.
+    ///
+    /// Frames have to be passed in decode order, see `vp9::HeaderParser`.
+    pub fn encrypt_vp9(&mut self, data: &mut [u8]) -> anyhow::Result<SampleEncryption> {
+        let subsamples = self.vp9_subsamples(data)?;
+        let iv = match self.protection.constant_iv {
+            Some(iv) => {
+                cbcs(&self.cipher, iv, data, &subsamples);
+                vec![]
+            }
+            None => {
+                let iv = self.next_iv;
+                self.next_iv = self.next_iv.wrapping_add(1);
+                ctr(&self.cipher, iv, data, &subsamples);
+                iv.to_be_bytes().to_vec()
+            }
+        };
+        Ok(SampleEncryption { iv, subsamples })
+    }
+
+    fn vp9_subsamples(&mut self, data: &[u8]) -> anyhow::Result<Vec<Subsample>> {
+        let (frames, index_size) = vp9::superframe(data);
+        let mut subsamples = vec![];
+        let mut offset = 0;
+        for size in frames {
+            let header_size = self.headers.uncompressed_header_size(&data[offset..offset + size])?.min(size);
+            // protected ranges are whole blocks, the remainder stays in the clear
+            let protected = (size - header_size) / 16 * 16;
+            subsamples.push(Subsample { clear: (size - protected) as u16, protected: protected as u32 });
+            offset += size;
+        }
+        if index_size > 0 {
+            subsamples.push(Subsample { clear: index_size as u16, protected: 0 });
+        }
+        Ok(subsamples)
+    }
+}
+
+/// AES-CTR over the protected bytes of all subsamples as one stream.
+fn ctr(cipher: &Aes128, iv: u64, data: &mut [u8], subsamples: &[Subsample]) {
+    // 64-bit IV followed by a 64-bit block counter
+    let mut counter = u128::from(iv) << 64;
+    let mut keystream = [0u8; 16];
+    let mut used = 16;
+    for (offset, s) in subsample_ranges(subsamples) {
+        for byte in &mut data[offset + usize::from(s.clear)..offset + usize::from(s.clear) + s.protected as usize] {
+            if used == 16 {
+                let block = GenericArray::from_mut_slice(&mut keystream);
+                block.copy_from_slice(&counter.to_be_bytes());
+                cipher.encrypt_block(block);
+                counter = counter.wrapping_add(1);
+                used = 0;
+            }
+            *byte ^= keystream[used];
+            used += 1;
+        }
+    }
+}
+
+/// AES-CBC of 1 block out of every 10, restarting from the constant IV in every subsample.
+fn cbcs(cipher: &Aes128, iv: [u8; 16], data: &mut [u8], subsamples: &[Subsample]) {
+    let pattern = usize::from(CRYPT_BYTE_BLOCK + SKIP_BYTE_BLOCK);
+    for (offset, s) in subsample_ranges(subsamples) {
+        let start = offset + usize::from(s.clear);
+        let protected = &mut data[start..start + s.protected as usize];
+        let mut chain = iv;
+        for block in protected.chunks_exact_mut(16).step_by(pattern) {
+            block.iter_mut().zip(&chain).for_each(|(b, c)| *b ^= c);
+            cipher.encrypt_block(GenericArray::from_mut_slice(block));
+            chain.copy_from_slice(block);
+        }
+    }
+}
+
+/// subsamples with their offset in the sample
+fn subsample_ranges(subsamples: &[Subsample]) -> impl Iterator<Item = (usize, &Subsample)> {
+    subsamples.iter().scan(0, |offset, s| {
+        let start = *offset;
+        *offset += usize::from(s.clear) + s.protected as usize;
+        Some((start, s))
+    })
+}
+
+fn random_u64() -> u64 {
+    // std seeds every RandomState randomly, good enough for IVs which only need to be unique
+    RandomState::new().build_hasher().finish()
+}
+
+fn hex(s: &str) -> anyhow::Result<Vec<u8>> {
+    let s = s.trim().replace('-', "");
+    s.as_bytes().chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair).ok()
+                .filter(|pair| pair.len() == 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .with_context(|| format!("invalid hex {}", s))
+        })
+        .collect()
+}
+
+fn hex16(s: &str) -> anyhow::Result<[u8; 16]> {
+    let bytes = hex(s)?;
+    if bytes.len() != 16 {
+        bail!("expected 16 bytes, got {} in {}", bytes.len(), s.trim());
+    }
+    let mut array = [0; 16];
+    array.copy_from_slice(&bytes);
+    Ok(array)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+    use crate::bmff::BoxReader;
+    use crate::fmp4::tests::{encrypted_fmp4, find};
+    use crate::fmp4_reader::Fmp4Reader;
+    use crate::vp9::tests::frame;
+
+    #[test]
+    fn encrypted_samples_decrypt_to_the_clear_frames() {
+        let key = GenericArray::from([0x22; 16]);
+        let cipher = Aes128::new(&key);
+        for scheme in [Scheme::Cenc, Scheme::Cbcs] {
+            let mut fmp4 = encrypted_fmp4(scheme);
+            let mut file = fmp4.init_segment();
+            let init_size = file.len();
+            // payloads long enough for the cbcs pattern to skip blocks, with a partial block at the end
+            let frames = (0..3u8)
+                .map(|i| frame(i == 0, 64, 48, &(0..205).map(|b| (b as u8).wrapping_mul(7) ^ i).collect::<Vec<_>>()))
+                .collect::<Vec<_>>();
+            for (i, data) in frames.iter().enumerate() {
+                file.extend_from_slice(&fmp4.wrap_frame(data, i == 0).unwrap());
+            }
+            let samples = Fmp4Reader::new(&file).unwrap().samples().unwrap();
+            let moofs = BoxReader::new(&file[init_size..]).boxes().unwrap().into_iter().filter(|b| &b.box_type == b"moof");
+
+            for ((moof, sample), clear) in moofs.zip(&samples).zip(&frames) {
+                let mut data = sample.data.to_vec();
+                assert_ne!(&data, clear);
+                let mut r = find(moof.payload, &[b"traf", b"senc"]).reader();
+                assert_eq!(r.full_box_header().unwrap(), (0, 2));
+                assert_eq!(r.u32().unwrap(), 1);
+                let iv = r.bytes(usize::from(scheme.per_sample_iv_size())).unwrap().to_vec();
+                let mut offset = 0;
+                // one counter across the subsamples with cenc, the constant IV in each subsample with cbcs
+                let mut counter = iv.iter().fold(0u128, |c, &b| c << 8 | u128::from(b)) << 64;
+                let mut keystream = vec![];
+                for _ in 0..r.u16().unwrap() {
+                    let (clear_size, protected_size) = (usize::from(r.u16().unwrap()), r.u32().unwrap() as usize);
+                    let protected = &mut data[offset + clear_size..offset + clear_size + protected_size];
+                    offset += clear_size + protected_size;
+                    match scheme {
+                        Scheme::Cenc => {
+                            for byte in protected {
+                                if keystream.is_empty() {
+                                    let mut block = GenericArray::from(counter.to_be_bytes());
+                                    cipher.encrypt_block(&mut block);
+                                    keystream = block.iter().rev().copied().collect();
+                                    counter += 1;
+                                }
+                                *byte ^= keystream.pop().unwrap();
+                            }
+                        }
+                        Scheme::Cbcs => {
+                            let mut chain = [0x33; 16];
+                            for block in protected.chunks_exact_mut(16).step_by(10) {
+                                let encrypted = <[u8; 16]>::try_from(&*block).unwrap();
+                                cipher.decrypt_block(GenericArray::from_mut_slice(block));
+                                block.iter_mut().zip(&chain).for_each(|(b, c)| *b ^= c);
+                                chain = encrypted;
+                            }
+                        }
+                    }
+                }
+                assert_eq!(r.remaining(), 0);
+                assert_eq!(offset, data.len());
+                assert_eq!(&data, clear);
+            }
+        }
+    }
+
+    #[test]
+    fn unencryptable_frames_are_errors() {
+        let mut fmp4 = encrypted_fmp4(Scheme::Cenc);
+        // not a VP9 frame, there is no uncompressed header to keep in the clear
+        assert!(fmp4.wrap_frame(&[0; 40], true).is_err());
+        assert!(fmp4.push_frame(&[0; 40], true).is_err());
+    }
+}
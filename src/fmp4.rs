@@ -1,440 +1,1403 @@
-use std::{u32, vec};
+use std::time::Duration;
+
+use anyhow::Context;
+
+use crate::bmff::{BoxWriter, UNITY_MATRIX};
+use crate::cenc::{Encryptor, Protection, Pssh, SampleEncryption};
+use crate::metadata::{self, Metadata};
+use crate::opus::{self, OpusHead};
+use crate::video::{ColorInfo, SampleFormat, VideoProperties};
 
 pub struct Fmp4 {
     track: Track, 
     sn: u32,
+    frame_rate: FrameRate,
+    /// frames wrapped at the nominal frame rate, used to place them without drift
+    frames: u64,
+    last_timestamp: Option<u64>,
+    /// emit a sidx in front of every segment
+    segment_index: bool,
+    /// fragments of the segment `push_frame` is collecting, with `segment_index`
+    segment: Vec<Fragment>,
+    /// record fragments for `sidx` and `mfra`
+    random_access_index: bool,
+    fragments: Vec<FragmentInfo>,
+    /// bytes returned by `wrap_frame` and `push_frame` so far
+    fragments_size: u64,
+    /// CMAF brands, `styp` on segments and default-base-is-moof
+    cmaf: bool,
+    /// frames per chunk for `push_frame`
+    chunk_frames: usize,
+    pending: Vec<PendingFrame>,
+    /// audio and metadata tracks, written along with the video
+    side_tracks: Vec<SideTrack>,
+    /// `emsg` boxes for the next fragment, with their version
+    pending_events: Vec<(EventMessage, u8)>,
+    events: Vec<EventMessage>,
+    /// encrypts the video samples
+    encryptor: Option<Encryptor>,
+    /// creation time, title etc. in the init segment
+    metadata: Metadata,
+}
+
+/// A track whose samples are queued and written into the next video fragment.
+struct SideTrack {
+    track: Track,
+    pending: Vec<PendingFrame>,
+}
+
+/// A moof+mdat produced by `push_frame`, or a whole segment with `set_segment_index`.
+pub struct Chunk {
+    pub data: Vec<u8>,
+    /// first chunk of a segment, starting with a key frame
+    pub independent: bool,
+}
+
+/// `emsg` boxes, `moof` and `mdat` of a fragment, not yet returned.
+struct Fragment {
+    data: Vec<u8>,
+    /// offset of the moof in `data`
+    moof_offset: usize,
+    dts: u64,
+    duration: u32,
+    key: bool,
+}
+
+struct PendingFrame {
+    data: Vec<u8>,
+    key: bool,
+    duration: u32,
+}
+
+/// a sample waiting to be written into a fragment
+struct SampleData<'a> {
+    data: &'a [u8],
+    key: bool,
+    duration: u32,
+}
+
+/// A fragment returned by `wrap_frame` or `push_frame`, offsets are relative to the first fragment.
+#[derive(Clone, Debug)]
+pub struct FragmentInfo {
+    pub offset: u64,
+    pub moof_offset: u64,
+    pub size: u32,
+    pub dts: u64,
+    pub duration: u32,
+    pub key: bool,
 }
 
 impl Fmp4 {
-    pub fn new(fps: u32, width: u16, height: u16) -> Self {
-        let duration = Track::DEFAULT_TIMESCALE / fps;
+    pub fn new(frame_rate: impl Into<FrameRate>, width: u16, height: u16) -> Self {
+        Self::with_timescale(frame_rate, Track::DEFAULT_TIMESCALE, width, height)
+    }
+
+    /// `timescale` is the number of ticks per second, see `FrameRate::timescale` for a drift-free choice.
+    pub fn with_timescale(frame_rate: impl Into<FrameRate>, timescale: u32, width: u16, height: u16) -> Self {
+        let frame_rate = frame_rate.into();
         Self{
-            track: Track::new(duration, width, height),
-            sn: 0,
+            track: Track::new(timescale, frame_rate.ticks(1, timescale) as u32, width, height),
+            // sequence numbers start at 1
+            sn: 1,
+            frame_rate,
+            frames: 0,
+            last_timestamp: None,
+            segment_index: false,
+            segment: vec![],
+            random_access_index: false,
+            fragments: vec![],
+            fragments_size: 0,
+            cmaf: false,
+            chunk_frames: 1,
+            pending: vec![],
+            side_tracks: vec![],
+            pending_events: vec![],
+            events: vec![],
+            encryptor: None,
+            metadata: Metadata::now(),
+        }
+    }
+
+    /// Produces CMAF conformant output: `cmfc`/`cmf2` brands, `styp` in front of every segment
+    /// and data offsets relative to the `moof`.
+    pub fn set_cmaf(&mut self, enabled: bool) {
+        self.cmaf = enabled;
+    }
+
+    /// Number of frames `push_frame` collects into one chunk, for low-latency delivery.
+    pub fn set_chunk_frames(&mut self, frames: usize) {
+        self.chunk_frames = frames.max(1);
+    }
+
+    pub fn track(&self) -> &Track {
+        &self.track
+    }
+
+    /// Orientation, pixel aspect ratio, clean aperture and colour description of the video.
+    pub fn set_video_properties(&mut self, video: VideoProperties) {
+        self.track.video = video;
+    }
+
+    /// Encrypts the video track with Common Encryption, other tracks stay in the clear.
+    ///
+    /// Must be set before the init segment is written, which then carries the `encv`
+    /// sample entry and the `pssh` boxes.
+    pub fn set_encryption(&mut self, encryptor: Encryptor) {
+        self.track.protection = Some(encryptor.protection().clone());
+        self.encryptor = Some(encryptor);
+    }
+
+    /// Adds an Opus track next to the video, returns its track ID.
+    ///
+    /// Audio packets are interleaved with the video: each fragment carries the packets
+    /// queued by `push_audio` since the previous one in a second `traf`.
+    pub fn add_opus_track(&mut self, head: OpusHead) -> u32 {
+        self.add_side_track(Track::opus(head))
+    }
+
+    /// Queues an Opus packet lasting `duration` samples at 48 kHz, it goes out with the next fragment.
+    pub fn push_audio(&mut self, packet: &[u8], duration: u32) {
+        self.push_side_sample(|m| matches!(m, Media::Opus(_)), packet, true, duration);
+    }
+
+    /// Adds an auxiliary VP9 track carrying the alpha channel of the video, returns its track ID.
+    ///
+    /// The track refers to the video with `auxl`, and its frames are pushed along with the
+    /// video frames by `push_frame_with_alpha`.
+    pub fn add_alpha_track(&mut self) -> u32 {
+        let track = Track::alpha(&self.track);
+        self.add_side_track(track)
+    }
+
+    /// Adds a timed metadata track in the video timescale, returns its track ID.
+    ///
+    /// Metadata samples cover the timeline without gaps, so an empty sample can fill
+    /// the time between two pieces of metadata.
+    pub fn add_metadata_track(&mut self, format: MetadataFormat) -> u32 {
+        self.add_side_track(Track::metadata(format, self.track.timescale))
+    }
+
+    /// Queues a metadata sample lasting `duration` ticks of the video timescale.
+    pub fn push_metadata(&mut self, data: &[u8], duration: u32) {
+        self.push_side_sample(|m| matches!(m, Media::Metadata(_)), data, true, duration);
+    }
+
+    fn add_side_track(&mut self, mut track: Track) -> u32 {
+        track.id = self.track.id + 1 + self.side_tracks.len() as u32;
+        let id = track.id;
+        self.side_tracks.push(SideTrack { track, pending: vec![] });
+        id
+    }
+
+    fn push_side_sample(&mut self, media: impl Fn(&Media) -> bool, data: &[u8], key: bool, duration: u32) {
+        let side = self.side_tracks.iter_mut()
+            .find(|t| media(&t.track.media))
+            .expect("no track for this kind of sample");
+        side.pending.push(PendingFrame { data: data.to_vec(), key, duration });
+    }
+
+    /// Attaches an event message to the next fragment, as `emsg` of version 0 or 1.
+    ///
+    /// Times are in the video timescale. Version 0 carries the time relative to the start
+    /// of the fragment, so the event must not start before it; version 1 carries it as is.
+    pub fn push_event(&mut self, event: EventMessage, version: u8) {
+        assert!(version <= 1, "unsupported emsg version {}", version);
+        self.events.push(event.clone());
+        self.pending_events.push((event, version));
+    }
+
+    /// All events pushed so far, e.g. for the manifest.
+    pub fn events(&self) -> &[EventMessage] {
+        &self.events
+    }
+
+    /// Video track first, then audio and metadata tracks in the order they were added.
+    pub fn tracks(&self) -> Vec<Track> {
+        std::iter::once(&self.track).chain(self.side_tracks.iter().map(|t| &t.track)).cloned().collect()
+    }
+
+    pub fn frame_rate(&self) -> FrameRate {
+        self.frame_rate
+    }
+
+    /// Fragments recorded since `set_random_access_index` was enabled.
+    pub fn fragments(&self) -> &[FragmentInfo] {
+        &self.fragments
+    }
+
+    /// Prefixes every segment with a `sidx` box so players can address it by byte range.
+    ///
+    /// The sidx covers all chunks up to the next key frame, so `push_frame` holds them back
+    /// and returns the whole segment as one chunk. Fragments of `wrap_frame` have no sidx.
+    pub fn set_segment_index(&mut self, enabled: bool) {
+        self.segment_index = enabled;
+    }
+
+    /// Keeps track of all fragments, which is required by `sidx` and `mfra`.
+    ///
+    /// Memory grows with the number of fragments, so leave it off for endless live streams.
+    pub fn set_random_access_index(&mut self, enabled: bool) {
+        self.random_access_index = enabled;
+    }
+
+    /// A top-level `sidx` with one subsegment per key frame, to be placed `first_offset` bytes
+    /// in front of the first fragment.
+    pub fn sidx(&self, first_offset: u64) -> Vec<u8> {
+        let mut references: Vec<SidxReference> = vec![];
+        for f in &self.fragments {
+            match references.last_mut() {
+                Some(r) if !f.key => {
+                    r.size += f.size;
+                    r.duration += f.duration;
+                }
+                _ => references.push(SidxReference {
+                    // segments with their own sidx are referenced through it
+                    hierarchical: self.segment_index,
+                    size: f.size,
+                    duration: f.duration,
+                    starts_with_sap: f.key,
+                }),
+            }
         }
+        let earliest_presentation_time = self.fragments.first().map_or(0, |f| f.dts);
+
+        let mut w = BoxWriter::new();
+        sidx(&mut w, &self.track, earliest_presentation_time, first_offset, &references);
+        w.into_bytes()
+    }
+
+    /// Size of a top-level `sidx` with `subsegments` references.
+    pub fn sidx_size(subsegments: usize) -> usize {
+        // full box header, reference_ID to reference_count, 12 bytes per reference
+        12 + 28 + 12 * subsegments
+    }
+
+    /// The `mfra` trailer listing every fragment starting with a key frame.
+    ///
+    /// `first_fragment_offset` is the file offset of the first fragment,
+    /// i.e. the size of the init segment plus anything written between them.
+    pub fn mfra(&self, first_fragment_offset: u64) -> Vec<u8> {
+        let mut w = BoxWriter::new();
+        let start = w.position();
+        w.boxed(b"mfra", |w| {
+            tfra(w, &self.track, first_fragment_offset, &self.fragments);
+            w.full_box(b"mfro", 0, 0, |w| {
+                w.u32(0); // size of the mfra box, patched below
+            });
+        });
+        let size = w.position() - start;
+        w.patch_u32(w.position() - 4, size as u32);
+        w.into_bytes()
+    }
+
+    /// Converts a capture time into ticks of the track timescale.
+    pub fn ticks(&self, time: Duration) -> u64 {
+        (time.as_nanos() * u128::from(self.track.timescale) / 1_000_000_000) as u64
     }
 
     pub fn init_segment(&self) -> Vec<u8> {
-        let mut ftyp = ftyp();
-        let mut movie = moov(&vec![self.track.clone()], Track::DEFAULT_TIMESCALE, self.track.timescale);
-        let total_len = ftyp.len() + movie.len();
-    
-        let mut buffer = Vec::with_capacity(total_len);
-        buffer.append(&mut ftyp);
-        buffer.append(&mut movie);
-    
-        buffer
-    }
-
-    pub fn wrap_frame(&mut self, data: &[u8], key_frame: bool) -> Vec<u8> {
-        let sample = Sample::new(
-            data.len() as u32,
-            self.track.duration,
-            0,
-            key_frame,
-        );
-
-        let mut buffer = moof(self.sn, self.track.dts, &self.track, &vec![sample]);
-        buffer.append(&mut mdat(data));
-
-        self.track.dts += self.track.duration;
+        init_segment(&self.tracks(), self.track.timescale, self.cmaf, &self.metadata)
+    }
+
+    /// Replaces the metadata of the init segment, by default the current time and the encoder name.
+    pub fn set_metadata(&mut self, metadata: Metadata) {
+        self.metadata = metadata;
+    }
+
+    /// Replaces the edit list of a track, e.g. to start the presentation later than the media.
+    pub fn set_edit_list(&mut self, track_id: u32, edits: Vec<Edit>) {
+        let side_tracks = self.side_tracks.iter_mut().map(|t| &mut t.track);
+        if let Some(track) = std::iter::once(&mut self.track).chain(side_tracks).find(|t| t.id == track_id) {
+            track.edits = edits;
+        }
+    }
+
+    /// Wraps a frame lasting one frame interval.
+    ///
+    /// Durations are derived from the frame count, so fractional frame rates alternate
+    /// between neighbouring tick counts instead of drifting.
+    pub fn wrap_frame(&mut self, data: &[u8], key_frame: bool) -> anyhow::Result<Vec<u8>> {
+        let duration = self.next_frame_duration();
+        self.wrap_sample(data, key_frame, duration)
+    }
+
+    /// Collects frames into chunks of `set_chunk_frames` frames.
+    ///
+    /// A key frame always starts a new segment, so it flushes the pending chunk even if
+    /// it is not full. Returns the chunks completed by this frame, possibly none, or an error
+    /// if an encrypted frame does not start with a VP9 uncompressed header.
+    pub fn push_frame(&mut self, data: &[u8], key_frame: bool) -> anyhow::Result<Vec<Chunk>> {
+        self.push_frames(data, None, key_frame)
+    }
+
+    /// Like `push_frame`, with the frame of the alpha stream going to the `add_alpha_track` track.
+    ///
+    /// `alpha` is decoded along with the video frame, so it must be a key frame wherever the
+    /// video starts a segment.
+    pub fn push_frame_with_alpha(&mut self, data: &[u8], alpha: &[u8], key_frame: bool) -> anyhow::Result<Vec<Chunk>> {
+        self.push_frames(data, Some(alpha), key_frame)
+    }
+
+    fn push_frames(&mut self, data: &[u8], alpha: Option<&[u8]>, key_frame: bool) -> anyhow::Result<Vec<Chunk>> {
+        let mut chunks = vec![];
+        // queued audio and metadata wait for the video, rather than going out in a fragment of their own
+        if key_frame && !self.pending.is_empty() {
+            chunks.extend(self.wrap_pending()?);
+        }
+        if key_frame {
+            chunks.extend(self.end_segment());
+        }
+        let duration = self.next_frame_duration();
+        if let Some(alpha) = alpha {
+            self.push_side_sample(|m| matches!(m, Media::Alpha { .. }), alpha, key_frame, duration);
+        }
+        self.pending.push(PendingFrame { data: data.to_vec(), key: key_frame, duration });
+        if self.pending.len() >= self.chunk_frames {
+            chunks.extend(self.wrap_pending()?);
+        }
+        Ok(chunks)
+    }
+
+    /// Writes the frames collected by `push_frame` into a chunk, e.g. at the end of the stream.
+    ///
+    /// Samples of other tracks queued after the last video frame go into a fragment without video.
+    /// With `set_segment_index` this ends the segment held back so far.
+    pub fn flush(&mut self) -> anyhow::Result<Option<Chunk>> {
+        Ok(self.wrap_pending()?.or_else(|| self.end_segment()))
+    }
+
+    /// The chunk of the pending frames, held back in the segment with `set_segment_index`.
+    fn wrap_pending(&mut self) -> anyhow::Result<Option<Chunk>> {
+        let fragment = match self.pending_fragment()? {
+            Some(fragment) => fragment,
+            None => return Ok(None),
+        };
+        if self.segment_index {
+            self.segment.push(fragment);
+            return Ok(None);
+        }
+        Ok(Some(self.chunk(vec![fragment])))
+    }
+
+    /// The segment held back with `set_segment_index`, if any.
+    fn end_segment(&mut self) -> Option<Chunk> {
+        if self.segment.is_empty() {
+            return None;
+        }
+        let segment = std::mem::take(&mut self.segment);
+        Some(self.chunk(segment))
+    }
+
+    fn pending_fragment(&mut self) -> anyhow::Result<Option<Fragment>> {
+        if self.pending.is_empty() && self.side_tracks.iter().all(|t| t.pending.is_empty()) {
+            return Ok(None);
+        }
+        let pending = std::mem::take(&mut self.pending);
+        let samples = pending.iter()
+            .map(|f| SampleData { data: &f.data, key: f.key, duration: f.duration })
+            .collect::<Vec<_>>();
+        self.wrap_samples(&samples).map(Some)
+    }
+
+    fn chunk(&mut self, fragments: Vec<Fragment>) -> Chunk {
+        let independent = fragments.first().is_some_and(|f| f.key);
+        Chunk { data: self.write_fragments(fragments, self.segment_index), independent }
+    }
+
+    fn next_frame_duration(&mut self) -> u32 {
+        let timescale = self.track.timescale;
+        let duration = self.frame_rate.ticks(self.frames + 1, timescale) - self.frame_rate.ticks(self.frames, timescale);
+        self.frames += 1;
+        duration as u32
+    }
+
+    /// Sets the decode time of the next frame, in ticks of the timescale, e.g. to keep the
+    /// timestamps of a stream being re-muxed.
+    pub fn set_decode_time(&mut self, dts: u64) {
+        self.track.dts = dts;
+    }
+
+    /// Wraps a frame with an explicit duration (in timescale units).
+    pub fn wrap_frame_with_duration(&mut self, data: &[u8], key_frame: bool, duration: u32) -> anyhow::Result<Vec<u8>> {
+        self.wrap_sample(data, key_frame, duration)
+    }
+
+    /// Wraps a frame at its capture time, relative to the first frame of the stream.
+    ///
+    /// The decode time comes from `timestamp`, the duration is the interval since the previous
+    /// frame because the next one is not known yet.
+    pub fn wrap_frame_at(&mut self, data: &[u8], key_frame: bool, timestamp: Duration) -> anyhow::Result<Vec<u8>> {
+        let dts = self.ticks(timestamp);
+        let duration = match self.last_timestamp {
+            Some(last) if dts > last => (dts - last) as u32,
+            _ => self.track.duration,
+        };
+        self.last_timestamp = Some(dts);
+        self.track.dts = dts;
+        self.wrap_sample(data, key_frame, duration)
+    }
+
+    fn wrap_sample(&mut self, data: &[u8], key_frame: bool, duration: u32) -> anyhow::Result<Vec<u8>> {
+        let fragment = self.wrap_samples(&[SampleData { data, key: key_frame, duration }])?;
+        Ok(self.write_fragments(vec![fragment], false))
+    }
+
+    fn wrap_samples(&mut self, samples: &[SampleData]) -> anyhow::Result<Fragment> {
+        // encrypt first, so a frame that cannot be encrypted leaves the queued side samples alone
+        let mut encrypted = vec![];
+        let mut encryption = vec![];
+        if let Some(encryptor) = &mut self.encryptor {
+            for s in samples {
+                let mut data = s.data.to_vec();
+                encryption.push(encryptor.encrypt_vp9(&mut data).context("cannot encrypt VP9 frame")?);
+                encrypted.push(data);
+            }
+        }
+
+        let key_frame = samples.first().is_some_and(|s| s.key);
+        let duration = samples.iter().map(|s| s.duration).sum::<u32>();
+        let side_samples = self.side_tracks.iter_mut().map(|t| std::mem::take(&mut t.pending)).collect::<Vec<_>>();
+        let side_data = || side_samples.iter().flatten().map(|f| &f.data[..]);
+        let size = samples.iter().map(|s| s.data.len()).chain(side_data().map(|d| d.len())).sum::<usize>();
+        let video_data = || samples.iter().enumerate().map(|(i, s)| encrypted.get(i).map_or(s.data, |d| &d[..]));
+
+        let mut trafs = vec![];
+        if !samples.is_empty() {
+            trafs.push(TrackFragment {
+                track: &self.track,
+                samples: samples.iter().map(|s| Sample::new(s.data.len() as u32, s.duration, 0, s.key)).collect(),
+                encryption,
+            });
+        }
+        for (side, pending) in self.side_tracks.iter().zip(&side_samples).filter(|(_, p)| !p.is_empty()) {
+            trafs.push(TrackFragment {
+                track: &side.track,
+                samples: pending.iter().map(|f| Sample::new(f.data.len() as u32, f.duration, 0, f.key)).collect(),
+                encryption: vec![],
+            });
+        }
+
+        let mut fragment = BoxWriter::new();
+        // emsg boxes go in front of the moof, within the bytes referenced by the sidx
+        for (event, version) in std::mem::take(&mut self.pending_events) {
+            emsg(&mut fragment, &event, version, self.track.timescale, self.track.dts);
+        }
+        let emsg_size = fragment.position();
+        // implicit base offsets of later trafs would depend on the previous traf, be explicit
+        moof(&mut fragment, self.sn, &trafs, self.cmaf || trafs.len() > 1);
+        mdat(&mut fragment, video_data().chain(side_data()));
+        let fragment = Fragment {
+            data: fragment.into_bytes(),
+            moof_offset: emsg_size,
+            dts: self.track.dts,
+            duration,
+            key: key_frame,
+        };
+
+        self.track.dts += u64::from(duration);
+        for (side, pending) in self.side_tracks.iter_mut().zip(&side_samples) {
+            side.track.dts += pending.iter().map(|f| u64::from(f.duration)).sum::<u64>();
+        }
         self.sn += 1;
 
-        println!("[wrap_frame] {} => {}", data.len(), buffer.len());
+        println!("[wrap_frame] {} => {}", size, fragment.data.len());
 
-        buffer
+        Ok(fragment)
+    }
+
+    /// `fragments` back to back, a segment starting with a key frame prefixed with `styp` in
+    /// CMAF mode and with a `sidx` covering all of them when `segment_index` is set.
+    fn write_fragments(&mut self, fragments: Vec<Fragment>, segment_index: bool) -> Vec<u8> {
+        let mut w = BoxWriter::new();
+        let segment_start = fragments.first().is_some_and(|f| f.key);
+        if self.cmaf && segment_start {
+            styp(&mut w);
+        }
+        if segment_index && segment_start {
+            let reference = SidxReference {
+                hierarchical: false,
+                size: fragments.iter().map(|f| f.data.len() as u32).sum(),
+                duration: fragments.iter().map(|f| f.duration).sum(),
+                starts_with_sap: true,
+            };
+            sidx(&mut w, &self.track, fragments[0].dts, 0, &[reference]);
+        }
+        for (i, f) in fragments.into_iter().enumerate() {
+            // the styp and sidx count as part of the first fragment, so fragments tile the output
+            let start = if i == 0 { 0 } else { w.position() };
+            w.bytes(&f.data);
+            let end = w.position();
+            if self.random_access_index {
+                self.fragments.push(FragmentInfo {
+                    offset: self.fragments_size + start as u64,
+                    moof_offset: self.fragments_size + (end - f.data.len() + f.moof_offset) as u64,
+                    size: (end - start) as u32,
+                    dts: f.dts,
+                    duration: f.duration,
+                    key: f.key,
+                });
+            }
+        }
+        self.fragments_size += w.position() as u64;
+        w.into_bytes()
     }
 }
 
-fn moof(sn:u32, base_media_decode_time: u32, track: &Track, samples: &[Sample]) -> Vec<u8> {
-    mp4_box(b"moof", vec![&mfhd(sn), &traf(track, base_media_decode_time, samples)])
+/// `ftyp` and `moov` for `tracks`, `timescale` is the movie timescale used by edit lists.
+pub fn init_segment(tracks: &[Track], timescale: u32, cmaf: bool, metadata: &Metadata) -> Vec<u8> {
+    let mut w = BoxWriter::new();
+    ftyp(&mut w, cmaf);
+    // the duration of a fragmented movie is unknown up front, fragments carry their own
+    moov(&mut w, tracks, 0, timescale, metadata);
+    w.into_bytes()
 }
 
-/// movie data
-fn mdat(data: &[u8]) -> Vec<u8> {
-    mp4_box(b"mdat", vec![data])
+/// A moof+mdat with one traf per entry of `trafs`, `data` holds their samples in the same order.
+pub fn fragment<'a>(sn: u32, trafs: &[TrackFragment], data: impl Iterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut w = BoxWriter::new();
+    moof(&mut w, sn, trafs, true);
+    mdat(&mut w, data);
+    w.into_bytes()
 }
 
-fn mp4_box(box_type: &[u8; 4], payloads: Vec<&[u8]> ) -> Vec<u8> {
-    let size: u32 = 8 + payloads.iter().map(|x| x.len() as u32).sum::<u32>();
-    let mut buffer = Vec::with_capacity(size as usize);
-    buffer.extend_from_slice(&size.to_be_bytes());
-    buffer.extend_from_slice(box_type);
+/// samples of one track within a fragment, starting at the track's current `dts`
+pub struct TrackFragment<'a> {
+    pub track: &'a Track,
+    pub samples: Vec<Sample>,
+    /// one entry per sample for encrypted tracks, empty otherwise
+    pub encryption: Vec<SampleEncryption>,
+}
 
-    for p in payloads {
-        buffer.extend_from_slice(p);
+/// The data of the trafs follows in one mdat, in the same order.
+fn moof(w: &mut BoxWriter, sn: u32, trafs: &[TrackFragment], base_is_moof: bool) {
+    let start = w.position();
+    let mut positions = vec![];
+    w.boxed(b"moof", |w| {
+        mfhd(w, sn);
+        for t in trafs {
+            positions.push(traf(w, t, base_is_moof));
+        }
+    });
+    // samples start right after the header of the following mdat
+    let mut offset = (w.position() - start) as u32 + 8;
+    for (position, t) in positions.into_iter().zip(trafs) {
+        w.patch_u32(position.data_offset, offset);
+        offset += t.samples.iter().map(|s| s.size).sum::<u32>();
+        // the base offset of the first traf is the moof, with or without default-base-is-moof
+        if let Some((saio, senc_data)) = position.aux_info_offset {
+            w.patch_u32(saio, (senc_data - start) as u32);
+        }
     }
+}
 
-    buffer
+struct SidxReference {
+    /// the referenced item is another sidx
+    hierarchical: bool,
+    size: u32,
+    duration: u32,
+    starts_with_sap: bool,
 }
 
-fn mfhd(sn:u32)-> Vec<u8>{
-    let bytes: [u8; 8] = [
-        0x00,
-        0x00, 0x00, 0x00, // flags
-        (sn >> 24) as u8,
-        (sn >> 16)  as u8,
-        (sn >> 8)  as u8,
-        sn  as u8, // sequence_number
-    ];
-    mp4_box(b"mfhd", vec![&bytes])
-}
-
-fn traf(track: &Track, base_media_decode_time: u32, samples: &[Sample]) -> Vec<u8>{
-    let sample_dependency_table = sdtp(samples);
-    let id = track.id;
-
-    let tfhd =  {
-        let bytes: [u8; 8] = [
-            0x00, // version 0
-            0x00, 0x00, 0x00, // flags
-            (id >> 24) as u8,
-            (id >> 16) as u8,
-            (id >> 8) as u8,
-            (id  as u8), // track_ID
-        ];
-        mp4_box(b"tfhd", vec![&bytes])
-    };
+/// segment index, `references` follow the sidx back to back
+fn sidx(w: &mut BoxWriter, track: &Track, earliest_presentation_time: u64, first_offset: u64, references: &[SidxReference]) {
+    w.full_box(b"sidx", 1, 0, |w| {
+        w.u32(track.id) // reference_ID
+            .u32(track.timescale)
+            .u64(earliest_presentation_time)
+            .u64(first_offset)
+            .u16(0) // reserved
+            .u16(references.len() as u16);
+        for r in references {
+            w.u32(u32::from(r.hierarchical) << 31 | r.size) // reference_type, referenced_size
+                .u32(r.duration) // subsegment_duration
+                // starts_with_SAP, SAP_type 1 (closed GOP), SAP_delta_time
+                .u32(if r.starts_with_sap { 0x9000_0000 } else { 0 });
+        }
+    });
+}
 
-    let tfdt =  {
-        let bytes: [u8; 8] = [
-            0x00, // version 0
-            0x00, 0x00, 0x00, // flags
-            (base_media_decode_time >> 24) as u8,
-            (base_media_decode_time >> 16) as u8,
-            (base_media_decode_time >> 8) as u8,
-            (base_media_decode_time as u8), // baseMediaDecodeTime
-        ];
-        mp4_box(b"tfdt", vec![&bytes])
-    };
+/// track fragment random access, one entry per fragment starting with a key frame
+fn tfra(w: &mut BoxWriter, track: &Track, first_fragment_offset: u64, fragments: &[FragmentInfo]) {
+    let entries = fragments.iter().filter(|f| f.key).collect::<Vec<_>>();
+    w.full_box(b"tfra", 1, 0, |w| {
+        w.u32(track.id)
+            .u32(0) // reserved, 1-byte traf, trun and sample numbers
+            .u32(entries.len() as u32);
+        for f in entries {
+            w.u64(f.dts) // time
+                .u64(first_fragment_offset + f.moof_offset)
+                .u8(1) // traf_number
+                .u8(1) // trun_number
+                .u8(1); // sample_number
+        }
+    });
+}
+
+/// movie data
+fn mdat<'a>(w: &mut BoxWriter, samples: impl Iterator<Item = &'a [u8]>) {
+    w.boxed(b"mdat", |w| {
+        samples.for_each(|data| {
+            w.bytes(data);
+        });
+    });
+}
+
+/// segment type, CMAF media segment brands
+fn styp(w: &mut BoxWriter) {
+    w.boxed(b"styp", |w| {
+        w.fourcc(b"cmfs") // major_brand
+            .u32(0) // minor_version
+            .fourcc(b"cmfs")
+            .fourcc(b"msdh");
+    });
+}
 
-    let trun = trun(track, sample_dependency_table.len() as u32 +
-        16 + // tfhd
-        16 + // tfdt
-        8 +  // traf header
-        16 + // mfhd
-        8 +  // moof header
-        8, samples);
+/// DASH event message box, `fragment_time` is the decode time of the fragment it precedes
+fn emsg(w: &mut BoxWriter, event: &EventMessage, version: u8, timescale: u32, fragment_time: u64) {
+    w.full_box(b"emsg", version, 0, |w| {
+        if version == 0 {
+            w.cstring(&event.scheme_id_uri)
+                .cstring(&event.value)
+                .u32(timescale)
+                .u32(event.presentation_time.saturating_sub(fragment_time) as u32) // presentation_time_delta
+                .u32(event.duration)
+                .u32(event.id);
+        } else {
+            w.u32(timescale)
+                .u64(event.presentation_time)
+                .u32(event.duration)
+                .u32(event.id)
+                .cstring(&event.scheme_id_uri)
+                .cstring(&event.value);
+        }
+        w.bytes(&event.message_data);
+    });
+}
 
-    mp4_box(b"traf", vec![&tfhd, &tfdt, &trun, &sample_dependency_table])
+fn mfhd(w: &mut BoxWriter, sn: u32) {
+    w.full_box(b"mfhd", 0, 0, |w| {
+        w.u32(sn); // sequence_number
+    });
 }
 
-fn trun(_track: &Track, offset: u32, samples: &[Sample]) -> Vec<u8>{
-    let sample_count = samples.len() as u32;
-    let data_offset = offset + 8 + 12 + 16 * sample_count;
+/// offsets within a traf to be patched once the moof size is known
+struct TrafPositions {
+    /// trun data_offset
+    data_offset: usize,
+    /// saio offset and the start of the sample data in senc
+    aux_info_offset: Option<(usize, usize)>,
+}
 
-    let mut buffer = vec![];
-    buffer.push(0x00); // version 0
-    buffer.extend_from_slice(&[0x00, 0x0F, 0x01]); // flags
-    buffer.extend_from_slice(&sample_count.to_be_bytes());
-    buffer.extend_from_slice(&data_offset.to_be_bytes());
+fn traf(w: &mut BoxWriter, t: &TrackFragment, base_is_moof: bool) -> TrafPositions {
+    let mut positions = TrafPositions { data_offset: 0, aux_info_offset: None };
+    // default-base-is-moof
+    let flags = if base_is_moof { 0x020000 } else { 0 };
+    w.boxed(b"traf", |w| {
+        w.full_box(b"tfhd", 0, flags, |w| {
+            w.u32(t.track.id); // track_ID
+        });
+        // version 1, 64-bit baseMediaDecodeTime
+        w.full_box(b"tfdt", 1, 0, |w| {
+            w.u64(t.track.dts);
+        });
+        positions.data_offset = trun(w, &t.samples);
+        sdtp(w, &t.samples);
+        if !t.encryption.is_empty() {
+            let senc_data = senc(w, &t.encryption);
+            saiz(w, &t.encryption);
+            positions.aux_info_offset = Some((saio(w), senc_data));
+        }
+    });
+    positions
+}
 
-    for s in samples {
-        buffer.extend_from_slice(&s.duration.to_be_bytes());
-        buffer.extend_from_slice(&s.size.to_be_bytes());
-        buffer.extend_from_slice(&s.flags.as_four_byte());
-        buffer.extend_from_slice(&s.cts.to_be_bytes());
-    }
+/// sample encryption, returns the position of the first entry
+fn senc(w: &mut BoxWriter, samples: &[SampleEncryption]) -> usize {
+    let mut position = 0;
+    // use_subsample_encryption
+    w.full_box(b"senc", 0, 0x000002, |w| {
+        w.u32(samples.len() as u32); // sample_count
+        position = w.position();
+        for s in samples {
+            w.bytes(&s.iv)
+                .u16(s.subsamples.len() as u16);
+            for subsample in &s.subsamples {
+                w.u16(subsample.clear) // BytesOfClearData
+                    .u32(subsample.protected); // BytesOfProtectedData
+            }
+        }
+    });
+    position
+}
 
-    mp4_box(b"trun", vec![&buffer])
+/// sample auxiliary information sizes
+fn saiz(w: &mut BoxWriter, samples: &[SampleEncryption]) {
+    w.full_box(b"saiz", 0, 0, |w| {
+        w.u8(0) // default_sample_info_size
+            .u32(samples.len() as u32); // sample_count
+        for s in samples {
+            w.u8(s.size());
+        }
+    });
 }
 
-fn sdtp(samples: &[Sample]) -> Vec<u8> {
-    let mut buffer = Vec::with_capacity(samples.len() + 4);
-    // leave the full box header (4 bytes) all zero
-    buffer.extend_from_slice(&[0x00, 0x00, 0x00, 0x00,]);
+/// sample auxiliary information offsets, returns the position of the offset to be patched
+fn saio(w: &mut BoxWriter) -> usize {
+    let mut position = 0;
+    w.full_box(b"saio", 0, 0, |w| {
+        w.u32(1); // entry_count, the senc entries are contiguous
+        position = w.position();
+        w.u32(0); // offset
+    });
+    position
+}
 
-    for s in samples{
-        buffer.push(s.flags.as_byte());
-    }
-    mp4_box(b"sdtp", vec![&buffer])
+fn trun(w: &mut BoxWriter, samples: &[Sample]) -> usize {
+    let mut data_offset = 0;
+    // data-offset, sample-duration, sample-size, sample-flags, sample-composition-time-offset
+    w.full_box(b"trun", 0, 0x000F01, |w| {
+        w.u32(samples.len() as u32); // sample_count
+        data_offset = w.position();
+        w.u32(0); // data_offset
+        for s in samples {
+            w.u32(s.duration)
+                .u32(s.size)
+                .bytes(&s.flags.as_four_byte())
+                .u32(s.cts);
+        }
+    });
+    data_offset
+}
+
+fn sdtp(w: &mut BoxWriter, samples: &[Sample]) {
+    w.full_box(b"sdtp", 0, 0, |w| {
+        for s in samples {
+            w.u8(s.flags.as_byte());
+        }
+    });
 }
 
 /// file type
-fn ftyp() -> Vec<u8>{
-    const MAJOR_BRAND: [u8; 4] = *b"isom"; 
-    const MINOR_VERSION: [u8; 4] = [0, 0, 0, 1]; 
-    const VP9_BRAND: [u8; 4] = *b"vp09"; 
-
-    mp4_box(b"ftyp", vec![&MAJOR_BRAND, &MINOR_VERSION, &MAJOR_BRAND, &VP9_BRAND])
-}
-
-fn mvhd(timescale: u32, duration: u32) -> Vec<u8> {
-    let bytes = vec![
-        0x00, // version 0
-        0x00, 0x00, 0x00, // flags
-        0x00, 0x00, 0x00, 0x01, // creation_time
-        0x00, 0x00, 0x00, 0x02, // modification_time
-        (timescale >> 24) as u8,
-        (timescale >> 16) as u8,
-        (timescale >> 8) as u8,
-        timescale as u8, // timescale
-        (duration >> 24) as u8,
-        (duration >> 16) as u8,
-        (duration >> 8) as u8,
-        duration as u8, // duration
-        0x00, 0x01, 0x00, 0x00, // 1.0 rate
-        0x01, 0x00, // 1.0 volume
-        0x00, 0x00, // reserved
-        0x00, 0x00, 0x00, 0x00, // reserved
-        0x00, 0x00, 0x00, 0x00, // reserved
-        0x00, 0x01, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x01, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x40, 0x00, 0x00, 0x00, // transformation: unity matrix
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00, // pre_defined
-        0xff, 0xff, 0xff, 0xff, // next_track_ID
-    ];
+fn ftyp(w: &mut BoxWriter, cmaf: bool) {
+    w.boxed(b"ftyp", |w| {
+        if cmaf {
+            w.fourcc(b"cmf2") // major_brand
+                .u32(0) // minor_version
+                .fourcc(b"cmf2")
+                .fourcc(b"cmfc")
+                .fourcc(b"iso6");
+        } else {
+            w.fourcc(b"isom") // major_brand
+                .u32(1) // minor_version
+                .fourcc(b"isom")
+                // movie fragments with tfdt, sidx and emsg
+                .fourcc(b"iso5")
+                .fourcc(b"iso6");
+        }
+        w.fourcc(b"vp09");
+    });
+}
 
-    mp4_box(b"mvhd", vec![&bytes])
-}
-
-fn trak(track: &Track) -> Vec<u8> {
-    mp4_box(b"trak", vec![&tkhd(&track), &mdia(&track)])
-}
-
-fn tkhd(track: &Track) -> Vec<u8> {
-    let bytes = vec![
-        0x00, // version 0
-        0x00, 0x00, 0x07, // flags
-        0x00, 0x00, 0x00, 0x00, // creation_time
-        0x00, 0x00, 0x00, 0x00, // modification_time
-        (track.id >> 24) as u8,
-        (track.id >> 16) as u8,
-        (track.id >> 8) as u8,
-        track.id as u8, // track_ID
-        0x00, 0x00, 0x00, 0x00, // reserved
-        (track.duration >> 24) as u8,
-        (track.duration >> 16) as u8,
-        (track.duration >> 8) as u8,
-        track.duration as u8, // duration
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00, // reserved
-        0x00, 0x00, // layer
-        0x00, 0x00, // alternate_group
-        (track.volume >> 0) as u8, (((track.volume % 1) * 10) >> 0) as u8, // track volume // FIXME
-        0x00, 0x00, // reserved
-        0x00, 0x01, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x01, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00,
-        0x40, 0x00, 0x00, 0x00, // transformation: unity matrix
-        (track.width >> 8)  as u8,
-        track.width  as u8,
-        0x00, 0x00, // width
-        (track.height >> 8)  as u8,
-        track.height as u8,
-        0x00, 0x00, // height
-    ];
-    mp4_box(b"tkhd", vec![&bytes])
+/// `time` is the creation and modification time in seconds since 1904-01-01
+fn mvhd(w: &mut BoxWriter, time: u64, timescale: u32, duration: u64, next_track_id: u32) {
+    w.full_box(b"mvhd", 1, 0, |w| {
+        w.u64(time) // creation_time
+            .u64(time) // modification_time
+            .u32(timescale)
+            .u64(duration)
+            .fixed16_16(1.0) // rate
+            .fixed8_8(1.0) // volume
+            .zeros(2 + 8) // reserved
+            .matrix(&UNITY_MATRIX)
+            .zeros(24) // pre_defined
+            .u32(next_track_id);
+    });
 }
 
-fn mdia(track: &Track) -> Vec<u8>{
-    mp4_box(b"mdia", vec![&mdhd(track.timescale, track.duration), &hdlr(), &minf(track)])
+fn trak(w: &mut BoxWriter, track: &Track, time: u64) {
+    w.boxed(b"trak", |w| {
+        tkhd(w, track, time);
+        if let Media::Alpha { video_track } = track.media {
+            // auxiliary for the video track
+            w.boxed(b"tref", |w| {
+                w.boxed(b"auxl", |w| {
+                    w.u32(video_track);
+                });
+            });
+        }
+        if !track.edits.is_empty() {
+            edts(w, &track.edits);
+        }
+        mdia(w, track, time);
+    });
 }
 
-fn minf(track: &Track) -> Vec<u8> {
-    const VMHD: [u8; 12] = [
-        0x00, // version
-        0x00, 0x00, 0x01, // flags
-        0x00, 0x00, // graphicsmode
-        0x00, 0x00,
-        0x00, 0x00,
-        0x00, 0x00, // opcolor
-    ];
-    const DREF: [u8; 20] = [
-        0x00, // version 0
-        0x00, 0x00, 0x00, // flags
-        0x00, 0x00, 0x00, 0x01, // entry_count
-        0x00, 0x00, 0x00, 0x0c, // entry_size
-        0x75, 0x72, 0x6c, 0x20, // 'url' type
-        0x00, // version 0
-        0x00, 0x00, 0x01, // entry_flags
-    ];
-    let dinf = mp4_box(b"dinf", vec![&mp4_box(b"dref", vec![&DREF])]);
-    mp4_box(b"minf", vec![&mp4_box(b"vmhd", vec![&VMHD]), &dinf, &stbl(&track)])
-}
-
-fn mdhd(timescale:u32, duration: u32)-> Vec<u8>{
-    let bytes = vec![
-        0x00, // version 0
-        0x00, 0x00, 0x00, // flags
-        0x00, 0x00, 0x00, 0x02, // creation_time
-        0x00, 0x00, 0x00, 0x03, // modification_time
-        (timescale >> 24) as u8,
-        (timescale >> 16) as u8,
-        (timescale >> 8) as u8,
-        timescale as u8, // timescale
-        (duration >> 24) as u8,
-        (duration >> 16) as u8,
-        (duration >> 8) as u8,
-        duration as u8, // duration
-        0x55, 0xc4, // 'und' language (undetermined)
-        0x00, 0x00,
-    ];
-    mp4_box(b"mdhd", vec![&bytes])
-}
-
-fn hdlr() -> Vec<u8> {
-    const VIDEO_HDLR: [u8; 37] = [
-        0x00, // version 0
-        0x00, 0x00, 0x00, // flags
-        0x00, 0x00, 0x00, 0x00, // pre_defined
-        0x76, 0x69, 0x64, 0x65, // handler_type: 'vide'
-        0x00, 0x00, 0x00, 0x00, // reserved
-        0x00, 0x00, 0x00, 0x00, // reserved
-        0x00, 0x00, 0x00, 0x00, // reserved
-        0x56, 0x69, 0x64, 0x65,
-        0x6f, 0x48, 0x61, 0x6e,
-        0x64, 0x6c, 0x65, 0x72, 0x00, // name: 'VideoHandler'
-    ];
-    mp4_box(b"hdlr", vec![&VIDEO_HDLR])
+/// edit list
+fn edts(w: &mut BoxWriter, edits: &[Edit]) {
+    w.boxed(b"edts", |w| {
+        // version 1, 64-bit segment_duration and media_time
+        w.full_box(b"elst", 1, 0, |w| {
+            w.u32(edits.len() as u32); // entry_count
+            for e in edits {
+                w.u64(e.segment_duration)
+                    .i64(e.media_time)
+                    .i16(1) // media_rate_integer
+                    .i16(0); // media_rate_fraction
+            }
+        });
+    });
 }
 
-fn stbl(track: &Track) -> Vec<u8>{
-    const STCO: [u8; 8] = [
-        0x00, // version
-        0x00, 0x00, 0x00, // flags
-        0x00, 0x00, 0x00, 0x00, // entry_count
-    ];
-    const STTS: [u8; 8] = STCO;
-    const STSC: [u8; 8] = STCO;
-    const STSZ: [u8; 12] = [
-        0x00, // version
-        0x00, 0x00, 0x00, // flags
-        0x00, 0x00, 0x00, 0x00, // sample_size
-        0x00, 0x00, 0x00, 0x00, // sample_count
-    ];
+fn tkhd(w: &mut BoxWriter, track: &Track, time: u64) {
+    let (width, height) = track.video.display_size(track.width, track.height);
+    // track_enabled, track_in_movie, track_in_preview
+    w.full_box(b"tkhd", 1, 0x000007, |w| {
+        w.u64(time) // creation_time
+            .u64(time) // modification_time
+            .u32(track.id)
+            .u32(0) // reserved
+            .u64(0) // duration, unknown for fragmented tracks
+            .zeros(8) // reserved
+            .i16(0) // layer
+            .i16(0) // alternate_group
+            .u16(track.volume)
+            .zeros(2) // reserved
+            .matrix(&track.video.matrix(width, height))
+            .u32(width << 16) // 16.16 width
+            .u32(height << 16); // 16.16 height
+    });
+}
 
-    mp4_box(b"stbl", vec![
-        &stsd(track), 
-        &mp4_box(b"stts", vec![&STTS]),
-         &mp4_box(b"stsc", vec![&STSC]), 
-         &mp4_box(b"stsz", vec![&STSZ]), 
-         &mp4_box(b"stco",vec![& STCO])
-    ])
+fn mdia(w: &mut BoxWriter, track: &Track, time: u64) {
+    w.boxed(b"mdia", |w| {
+        mdhd(w, time, track.timescale, 0);
+        hdlr(w, track);
+        minf(w, track);
+    });
 }
 
-fn stsd(track: &Track) -> Vec<u8> {
-    const STSD: [u8; 8] = [
-        0x00, // version 0
-        0x00, 0x00, 0x00, // flags
-        0x00, 0x00, 0x00, 0x01
-    ];
-    mp4_box(b"stsd", vec![&STSD, &vp09(track.width, track.height)])
-}
-
-fn vp09(width: u16, height: u16) -> Vec<u8> {
-    let vp09: [u8; 78] = [
-        0x01, // version 0
-        0x00, 0x00, 0x00, // flags
-        0x00, 0x00, // start_ code
-        0x00, 0x01, // data_reference_index
-        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // reserved
-        (width >> 8) as u8, width as u8,
-        (height >> 8) as u8, height as u8,
-        0x00, 0x48, 0x00, 0x00, // horizresolution
-        0x00, 0x48, 0x00, 0x00, // vertresolution
-        0x00, 0x00, 0x00, 0x00, // reserved
-        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // compressorname
-        0x00, 0x01, // frame count
-        0x00, 0x18, // depth
-        0xFF, 0xFF,
-    ];
-    mp4_box(b"vp09", vec![&vp09, &vpcc()])
-}
-
-fn vpcc() -> Vec<u8> {
-    const VPCC: [u8; 12] = [
-        0x01, // version 1
-        0x00, 0x00, 0x00, // flags
-        0x00, // profile
-        0x1F, // level 3.1
-        0x80, // bitDepth, chromaSubsampling, videoFullRangeFlag
-        0x02, // colourPrimaries
-        0x02, // transferCharacteristics
-        0x02, // matrixCoefficients
-        0x00, 0x00 // codecIntializationDataSize
+fn minf(w: &mut BoxWriter, track: &Track) {
+    w.boxed(b"minf", |w| {
+        match &track.handler() {
+            b"vide" | b"auxv" => w.full_box(b"vmhd", 0, 1, |w| {
+                w.u16(0) // graphicsmode
+                    .zeros(6); // opcolor
+            }),
+            b"soun" => w.full_box(b"smhd", 0, 0, |w| {
+                w.i16(0) // balance
+                    .u16(0); // reserved
+            }),
+            _ => w.full_box(b"nmhd", 0, 0, |_| {}),
+        };
+        w.boxed(b"dinf", |w| {
+            w.full_box(b"dref", 0, 0, |w| {
+                w.u32(1); // entry_count
+                // media data is in the same file
+                w.full_box(b"url ", 0, 1, |_| {});
+            });
+        });
+        stbl(w, track);
+    });
+}
+
+fn mdhd(w: &mut BoxWriter, time: u64, timescale: u32, duration: u64) {
+    w.full_box(b"mdhd", 1, 0, |w| {
+        w.u64(time) // creation_time
+            .u64(time) // modification_time
+            .u32(timescale)
+            .u64(duration)
+            .u16(0x55c4) // 'und' language (undetermined)
+            .u16(0); // pre_defined
+    });
+}
+
+fn hdlr(w: &mut BoxWriter, track: &Track) {
+    let handler_type = track.handler();
+    let name = match &handler_type {
+        b"vide" => "VideoHandler",
+        b"auxv" => "AuxiliaryVideoHandler",
+        b"soun" => "SoundHandler",
+        b"meta" => "MetadataHandler",
+        _ => "",
+    };
+    w.full_box(b"hdlr", 0, 0, |w| {
+        w.u32(0) // pre_defined
+            .fourcc(&handler_type)
+            .zeros(12) // reserved
+            .cstring(name);
+    });
+}
+
+/// empty sample tables, samples are described by the fragments
+fn stbl(w: &mut BoxWriter, track: &Track) {
+    w.boxed(b"stbl", |w| {
+        stsd(w, track);
+        for box_type in &[b"stts", b"stsc"] {
+            w.full_box(box_type, 0, 0, |w| {
+                w.u32(0); // entry_count
+            });
+        }
+        w.full_box(b"stsz", 0, 0, |w| {
+            w.u32(0) // sample_size
+                .u32(0); // sample_count
+        });
+        w.full_box(b"stco", 0, 0, |w| {
+            w.u32(0); // entry_count
+        });
+    });
+}
+
+fn stsd(w: &mut BoxWriter, track: &Track) {
+    w.full_box(b"stsd", 0, 0, |w| {
+        w.u32(1); // entry_count
+        match &track.media {
+            Media::Video | Media::Alpha { .. } => vp09(w, track),
+            Media::Opus(head) => opus_entry(w, head),
+            Media::Metadata(format) => metadata_entry(w, format),
+            Media::SampleEntry { entry, .. } => {
+                w.bytes(entry);
+            }
+        }
+    });
+}
+
+/// `encv` instead of `vp09` when the track is encrypted
+fn vp09(w: &mut BoxWriter, track: &Track) {
+    let box_type = if track.protection.is_some() { b"encv" } else { b"vp09" };
+    w.boxed(box_type, |w| {
+        w.zeros(6) // reserved
+            .u16(1) // data_reference_index
+            .zeros(16) // pre_defined, reserved
+            .u16(track.width)
+            .u16(track.height)
+            .fixed16_16(72.0) // horizresolution
+            .fixed16_16(72.0) // vertresolution
+            .u32(0) // reserved
+            .u16(1) // frame_count
+            .zeros(32) // compressorname
+            .u16(0x0018) // depth
+            .i16(-1); // pre_defined
+        vpcc(w, vp9_level(track), track.video.color.unwrap_or_default(), track.video.sample_format);
+        if let Some(color) = &track.video.color {
+            colr(w, color);
+        }
+        if !track.video.pixel_aspect_ratio.is_square() {
+            w.boxed(b"pasp", |w| {
+                w.u32(track.video.pixel_aspect_ratio.h_spacing)
+                    .u32(track.video.pixel_aspect_ratio.v_spacing);
+            });
+        }
+        if let Some(aperture) = &track.video.clean_aperture {
+            let (horiz_off, vert_off) = aperture.center_offset(track.width, track.height);
+            // fractions of numerator and denominator
+            w.boxed(b"clap", |w| {
+                w.u32(aperture.width).u32(1)
+                    .u32(aperture.height).u32(1)
+                    .i32(horiz_off).u32(2)
+                    .i32(vert_off).u32(2);
+            });
+        }
+        if let Media::Alpha { .. } = track.media {
+            // auxiliary type info, as in HEIF
+            w.full_box(b"auxi", 0, 0, |w| {
+                w.cstring("urn:mpeg:mpegB:cicp:systems:auxiliary:alpha");
+            });
+        }
+        if let Some(protection) = &track.protection {
+            sinf(w, b"vp09", protection);
+        }
+    });
+}
+
+/// protection scheme information
+fn sinf(w: &mut BoxWriter, original_format: &[u8; 4], protection: &Protection) {
+    w.boxed(b"sinf", |w| {
+        w.boxed(b"frma", |w| {
+            w.fourcc(original_format);
+        });
+        w.full_box(b"schm", 0, 0, |w| {
+            w.fourcc(&protection.scheme.fourcc())
+                .u32(0x0001_0000); // scheme_version 1.0
+        });
+        w.boxed(b"schi", |w| tenc(w, protection));
+    });
+}
+
+/// track encryption defaults
+fn tenc(w: &mut BoxWriter, protection: &Protection) {
+    let (crypt_byte_block, skip_byte_block) = protection.scheme.pattern();
+    // version 1 carries the pattern
+    let version = if crypt_byte_block > 0 { 1 } else { 0 };
+    w.full_box(b"tenc", version, 0, |w| {
+        w.u8(0) // reserved
+            .u8(crypt_byte_block << 4 | skip_byte_block)
+            .u8(1) // default_isProtected
+            .u8(protection.scheme.per_sample_iv_size())
+            .bytes(&protection.kid);
+        if let Some(iv) = &protection.constant_iv {
+            w.u8(iv.len() as u8)
+                .bytes(iv);
+        }
+    });
+}
+
+/// protection system specific header, version 1 with key IDs
+fn pssh(w: &mut BoxWriter, pssh: &Pssh) {
+    w.full_box(b"pssh", 1, 0, |w| {
+        w.bytes(&pssh.system_id)
+            .u32(pssh.kids.len() as u32);
+        for kid in &pssh.kids {
+            w.bytes(kid);
+        }
+        w.u32(pssh.data.len() as u32)
+            .bytes(&pssh.data);
+    });
+}
+
+/// AudioSampleEntry of an Opus track, see "Encapsulation of Opus in ISO Base Media File Format"
+fn opus_entry(w: &mut BoxWriter, head: &OpusHead) {
+    w.boxed(b"Opus", |w| {
+        w.zeros(6) // reserved
+            .u16(1) // data_reference_index
+            .zeros(8) // reserved
+            .u16(u16::from(head.channels)) // channelcount
+            .u16(16) // samplesize
+            .u16(0) // pre_defined
+            .u16(0) // reserved
+            .u32(opus::SAMPLE_RATE << 16); // 16.16 samplerate
+        dops(w, head);
+    });
+}
+
+/// Opus specific box, the OpusHead without magic signature and in big-endian
+fn dops(w: &mut BoxWriter, head: &OpusHead) {
+    w.boxed(b"dOps", |w| {
+        w.u8(0) // version
+            .u8(head.channels) // OutputChannelCount
+            .u16(head.pre_skip)
+            .u32(head.input_sample_rate)
+            .i16(head.output_gain)
+            .u8(head.channel_mapping_family);
+        if head.channel_mapping_family != 0 {
+            w.u8(head.stream_count)
+                .u8(head.coupled_count)
+                .bytes(&head.channel_mapping);
+        }
+    });
+}
+
+/// `mett` or `urim` sample entry of a timed metadata track
+fn metadata_entry(w: &mut BoxWriter, format: &MetadataFormat) {
+    match format {
+        MetadataFormat::Text { mime_type } => w.boxed(b"mett", |w| {
+            w.zeros(6) // reserved
+                .u16(1) // data_reference_index
+                .cstring("") // content_encoding
+                .cstring(mime_type);
+        }),
+        MetadataFormat::Uri { uri } => w.boxed(b"urim", |w| {
+            w.zeros(6) // reserved
+                .u16(1); // data_reference_index
+            w.full_box(b"uri ", 0, 0, |w| {
+                w.cstring(uri);
+            });
+        }),
+    };
+}
+
+/// smallest VP9 level whose picture size and luma sample rate limits fit the track
+fn vp9_level(track: &Track) -> u8 {
+    const LEVELS: [(u8, u64, u64); 14] = [
+        (10, 36864, 829440),
+        (11, 73728, 2764800),
+        (20, 122880, 4608000),
+        (21, 245760, 9216000),
+        (30, 552960, 20736000),
+        (31, 983040, 36864000),
+        (40, 2228224, 83558400),
+        (41, 2228224, 160432128),
+        (50, 8912896, 311951360),
+        (51, 8912896, 588251136),
+        (52, 8912896, 1176502272),
+        (60, 35651584, 1176502272),
+        (61, 35651584, 2353004544),
+        (62, 35651584, 4706009088),
     ];
-    mp4_box(b"vpcC", vec![&VPCC])
+    let picture_size = u64::from(track.width) * u64::from(track.height);
+    let sample_rate = picture_size * u64::from(track.timescale) / u64::from(track.duration.max(1));
+    LEVELS.iter()
+        .find(|(_, max_size, max_rate)| picture_size <= *max_size && sample_rate <= *max_rate)
+        .map_or(62, |(level, _, _)| *level)
+}
+
+fn vpcc(w: &mut BoxWriter, level: u8, color: ColorInfo, format: SampleFormat) {
+    w.full_box(b"vpcC", 1, 0, |w| {
+        w.u8(format.profile())
+            .u8(level)
+            // bitDepth, chromaSubsampling, videoFullRangeFlag
+            .u8(format.bit_depth << 4 | format.chroma.vpcc_value() << 1 | u8::from(color.full_range))
+            .u8(color.primaries) // colourPrimaries
+            .u8(color.transfer) // transferCharacteristics
+            .u8(color.matrix) // matrixCoefficients
+            .u16(0); // codecIntializationDataSize
+    });
+}
+
+/// colour description, as in `vpcC`
+fn colr(w: &mut BoxWriter, color: &ColorInfo) {
+    w.boxed(b"colr", |w| {
+        w.fourcc(b"nclx")
+            .u16(u16::from(color.primaries))
+            .u16(u16::from(color.transfer))
+            .u16(u16::from(color.matrix))
+            .u8(u8::from(color.full_range) << 7);
+    });
 }
 
 /// movie extend
-fn mvex(tracks: &[Track]) -> Vec<u8> {
-    let boxes = tracks.into_iter().map(|t| trex(t)).collect::<Vec<Vec<u8>>>();
-    mp4_box(b"mvex", boxes.iter().map(AsRef::as_ref).collect())
-}
-
-fn trex(track: &Track) -> Vec<u8> {
-    let bytes = [
-        0x00, // version 0
-        0x00, 0x00, 0x00, // flags
-        (track.id >> 24) as u8,
-        (track.id >> 16) as u8,
-        (track.id >> 8) as u8,
-        track.id as u8, // track_ID
-        0x00, 0x00, 0x00, 0x01, // default_sample_description_index
-        0x00, 0x00, 0x00, 0x00, // default_sample_duration
-        0x00, 0x00, 0x00, 0x00, // default_sample_size
-        0x00, 0x01, 0x00, 0x01, // default_sample_flags
-    ];
-    mp4_box(b"trex", vec![&bytes])
+fn mvex(w: &mut BoxWriter, tracks: &[Track]) {
+    w.boxed(b"mvex", |w| {
+        tracks.iter().for_each(|t| trex(w, t));
+    });
+}
+
+fn trex(w: &mut BoxWriter, track: &Track) {
+    w.full_box(b"trex", 0, 0, |w| {
+        w.u32(track.id)
+            .u32(1) // default_sample_description_index
+            .u32(0) // default_sample_duration
+            .u32(0) // default_sample_size
+            .u32(0x0001_0000); // default_sample_flags: non-sync
+    });
 }
 
 /// movie box
-fn moov(tracks: &[Track], duration: u32, timescale: u32) -> Vec<u8> {
-    let boxes = tracks.iter().map(|t| trak(t)).collect::<Vec<Vec<u8>>>();
-    let mvhd = mvhd(timescale, duration);
-    let mvex = mvex(&tracks);
+fn moov(w: &mut BoxWriter, tracks: &[Track], duration: u64, timescale: u32, metadata: &Metadata) {
+    let next_track_id = tracks.iter().map(|t| t.id).max().unwrap_or(0) + 1;
+    let time = metadata.mp4_time();
+    w.boxed(b"moov", |w| {
+        mvhd(w, time, timescale, duration, next_track_id);
+        tracks.iter().for_each(|t| trak(w, t, time));
+        mvex(w, tracks);
+        metadata::write(w, metadata);
+        tracks.iter()
+            .filter_map(|t| t.protection.as_ref())
+            .flat_map(|p| &p.pssh)
+            .for_each(|p| pssh(w, p));
+    });
+}
 
-    let mut payloads: Vec<&[u8]> = vec![];
-    payloads.push(&mvhd);
-    boxes.iter().for_each(|x|payloads.push(x));
-    payloads.push(&mvex);
+/// Frame rate as a fraction of frames per second, e.g. `30000/1001` for 29.97 fps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameRate {
+    pub num: u32,
+    pub den: u32,
+}
+
+impl FrameRate {
+    pub fn new(num: u32, den: u32) -> Self {
+        assert!(num > 0 && den > 0, "invalid frame rate {}/{}", num, den);
+        Self { num, den }
+    }
+
+    /// A timescale in which every frame lasts exactly `den` ticks.
+    pub fn timescale(&self) -> u32 {
+        self.num
+    }
+
+    /// Start time of frame `n` in ticks of `timescale`, rounded down.
+    pub fn ticks(&self, n: u64, timescale: u32) -> u64 {
+        (u128::from(n) * u128::from(timescale) * u128::from(self.den) / u128::from(self.num)) as u64
+    }
+
+    /// Number of frames in `seconds`, rounded up, e.g. for the interval of forced key frames.
+    pub fn frames(&self, seconds: u64) -> u64 {
+        (seconds * u64::from(self.num)).div_ceil(u64::from(self.den))
+    }
+}
+
+impl Default for FrameRate {
+    fn default() -> Self {
+        Self::new(30, 1)
+    }
+}
+
+/// `<num>[/<den>]`, e.g. `25` or `30000/1001`
+impl std::str::FromStr for FrameRate {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (num, den) = s.split_once('/').unwrap_or((s, "1"));
+        let (num, den) = (num.parse()?, den.parse()?);
+        if num == 0 || den == 0 {
+            anyhow::bail!("invalid frame rate {}", s);
+        }
+        Ok(Self::new(num, den))
+    }
+}
+
+impl From<u32> for FrameRate {
+    fn from(fps: u32) -> Self {
+        Self::new(fps, 1)
+    }
+}
 
-    mp4_box(b"moov", payloads)
+#[derive(Clone, Debug)]
+pub enum Media {
+    Video,
+    /// the alpha channel of the track `video_track`, as an 8-bit VP9 stream of its own
+    Alpha { video_track: u32 },
+    Opus(OpusHead),
+    Metadata(MetadataFormat),
+    /// a whole sample entry box copied from another file, e.g. by `trim`
+    SampleEntry { handler: [u8; 4], entry: Vec<u8> },
+}
+
+#[derive(Clone, Debug)]
+pub enum MetadataFormat {
+    /// `mett`, text samples of the given MIME type, e.g. `text/plain` or `application/json`
+    Text { mime_type: String },
+    /// `urim`, samples whose format is defined by the scheme `uri`
+    Uri { uri: String },
+}
+
+/// A DASH event message, see ISO/IEC 23009-1 5.10.3.3.
+#[derive(Clone, Debug, Default)]
+pub struct EventMessage {
+    pub scheme_id_uri: String,
+    pub value: String,
+    /// in the video timescale
+    pub presentation_time: u64,
+    /// in the video timescale, 0xFFFFFFFF if unknown
+    pub duration: u32,
+    pub id: u32,
+    pub message_data: Vec<u8>,
+}
+
+/// An edit list entry, mapping a span of the presentation to the media timeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Edit {
+    /// in movie timescale, 0 for the rest of a fragmented track
+    pub segment_duration: u64,
+    /// in track timescale, -1 for an empty edit
+    pub media_time: i64,
+}
+
+impl Edit {
+    /// Presents the track from `media_time` on, hiding what comes before it.
+    pub fn skip(media_time: i64) -> Self {
+        Self { segment_duration: 0, media_time }
+    }
+
+    /// Delays the presentation of the following edit by `duration` (in movie timescale).
+    pub fn empty(duration: u64) -> Self {
+        Self { segment_duration: duration, media_time: -1 }
+    }
 }
 
 #[derive(Clone)]
 pub struct Track {
     pub id: u32,
+    pub media: Media,
+    /// default sample duration
     pub duration: u32,
     pub timescale: u32,
     pub width: u16,
     pub height: u16,
+    /// 8.8 fixed-point, 0 for visual tracks
     pub volume: u16,
-    pub dts: u32,
+    /// decode time of the next sample, 64-bit so long running streams never wrap
+    pub dts: u64,
+    /// written as `edts` when not empty
+    pub edits: Vec<Edit>,
+    /// Common Encryption of the samples, the sample entry becomes `encv`
+    pub protection: Option<Protection>,
+    /// presentation of video tracks
+    pub video: VideoProperties,
 }
 
 impl Track {
-    pub const DEFAULT_TIMESCALE: u32 = 1000;
-    pub fn new(duration:u32, width: u16, height: u16) -> Self {
+    /// RFC 6381 codecs parameter, e.g. `vp09.00.40.08` for profile 0, level 4, 8 bits
+    pub fn codecs(&self) -> String {
+        match self.media {
+            Media::Video | Media::Alpha { .. } => {
+                let format = self.video.sample_format;
+                format!("vp09.{:02}.{:02}.{:02}", format.profile(), vp9_level(self), format.bit_depth)
+            }
+            Media::Opus(_) => "opus".to_string(),
+            Media::Metadata(MetadataFormat::Text { .. }) => "mett".to_string(),
+            Media::Metadata(MetadataFormat::Uri { .. }) => "urim".to_string(),
+            Media::SampleEntry { ref entry, .. } => String::from_utf8_lossy(&entry[4..8]).into_owned(),
+        }
+    }
+
+    /// `hdlr` handler type
+    pub fn handler(&self) -> [u8; 4] {
+        match self.media {
+            Media::Video => *b"vide",
+            Media::Alpha { .. } => *b"auxv",
+            Media::Opus(_) => *b"soun",
+            Media::Metadata(_) => *b"meta",
+            Media::SampleEntry { handler, .. } => handler,
+        }
+    }
+
+    /// 90 kHz, as used by MPEG transport, gives whole tick durations for common frame rates
+    pub const DEFAULT_TIMESCALE: u32 = 90000;
+    pub fn new(timescale: u32, duration:u32, width: u16, height: u16) -> Self {
         Self{
-            id: 0,
+            // track_ID 0 is reserved
+            id: 1,
+            media: Media::Video,
             duration,
-            timescale: Track::DEFAULT_TIMESCALE,
+            timescale,
             width,
             height,
             volume: 0,
             dts: 0,
+            edits: vec![],
+            protection: None,
+            video: VideoProperties::default(),
+        }
+    }
+
+    /// 48 kHz timescale, so durations are in samples; 20 ms packets by default.
+    ///
+    /// The pre-skip samples are hidden by an edit list.
+    pub fn opus(head: OpusHead) -> Self {
+        Self {
+            id: 1,
+            edits: vec![Edit::skip(i64::from(head.pre_skip))],
+            media: Media::Opus(head),
+            duration: 960,
+            timescale: opus::SAMPLE_RATE,
+            width: 0,
+            height: 0,
+            // full volume
+            volume: 0x0100,
+            dts: 0,
+            protection: None,
+            video: VideoProperties::default(),
+        }
+    }
+
+    /// Same size and timing as `video`, alpha being coded as full-range 8-bit 4:2:0 luma.
+    pub fn alpha(video: &Track) -> Self {
+        Self {
+            media: Media::Alpha { video_track: video.id },
+            protection: None,
+            video: VideoProperties {
+                color: Some(ColorInfo { full_range: true, ..ColorInfo::default() }),
+                sample_format: SampleFormat::default(),
+                ..video.video.clone()
+            },
+            ..video.clone()
+        }
+    }
+
+    pub fn metadata(format: MetadataFormat, timescale: u32) -> Self {
+        Self {
+            media: Media::Metadata(format),
+            ..Self::new(timescale, 0, 0, 0)
         }
     }
 }
@@ -478,17 +1441,299 @@ pub struct Flags{
 }
 
 impl Flags {
+    /// in sdtp box
     pub fn as_byte(&self) -> u8 {
-        self.depands_on << 4 | self.is_depended_on << 2 | self.has_redundancy as u8
+        self.is_leading << 6 | self.depands_on << 4 | self.is_depended_on << 2 | self.has_redundancy
     }
 
     /// in trun box
     pub fn as_four_byte(&self) -> [u8; 4] {
         [
             self.is_leading << 2 | self.depands_on,
-            self.is_depended_on  << 6 | self.has_redundancy  << 6 | self.padding_value << 1 | self.is_non_sync,
+            self.is_depended_on  << 6 | self.has_redundancy  << 4 | self.padding_value << 1 | self.is_non_sync,
             (self.degrad_prio >> 8) as u8,
             self.degrad_prio as u8,
         ]
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::bmff::{BoxReader, BoxRef};
+    use crate::cenc::{Scheme, COMMON_SYSTEM_ID};
+    use crate::fmp4_reader::Fmp4Reader;
+    use crate::vp9::tests::frame;
+
+    pub const KID: [u8; 16] = [0x11; 16];
+
+    /// The first box at the end of `path`, each type looked up within the previous box.
+    pub fn find<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> BoxRef<'a> {
+        let mut reader = BoxReader::new(data);
+        let mut found = None;
+        for box_type in path {
+            let b = reader.boxes().unwrap().into_iter().find(|b| &b.box_type == *box_type)
+                .unwrap_or_else(|| panic!("no {}", String::from_utf8_lossy(*box_type)));
+            reader = b.reader();
+            found = Some(b);
+        }
+        found.unwrap()
+    }
+
+    pub fn types(data: &[u8]) -> Vec<String> {
+        BoxReader::new(data).boxes().unwrap().iter().map(|b| b.type_str()).collect()
+    }
+
+    pub fn encrypted_fmp4(scheme: Scheme) -> Fmp4 {
+        let mut fmp4 = Fmp4::new(30, 64, 48);
+        fmp4.set_encryption(Encryptor::new(scheme, KID, [0x22; 16], Some([0x33; 16])));
+        fmp4
+    }
+
+
+    /// The init segment followed by the fragments, as one file.
+    fn file(fmp4: &Fmp4, fragments: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = fmp4.init_segment();
+        fragments.iter().for_each(|f| file.extend_from_slice(f));
+        file
+    }
+
+    #[test]
+    fn counts_frames_in_seconds() {
+        assert_eq!(FrameRate::new(30, 1).frames(2), 60);
+        assert_eq!(FrameRate::new(30000, 1001).frames(2), 60);
+        assert_eq!(FrameRate::new(24000, 1001).frames(2), 48);
+        assert_eq!(FrameRate::new(1, 4).frames(2), 1);
+    }
+
+    #[test]
+    fn parses_frame_rates() {
+        assert_eq!("25".parse::<FrameRate>().unwrap(), FrameRate::new(25, 1));
+        assert_eq!("30000/1001".parse::<FrameRate>().unwrap(), FrameRate::new(30000, 1001));
+        assert!("0".parse::<FrameRate>().is_err());
+        assert!("30/0".parse::<FrameRate>().is_err());
+        assert!("fast".parse::<FrameRate>().is_err());
+    }
+
+    #[test]
+    fn fractional_frame_rate_durations_do_not_drift() {
+        let frame_rate = FrameRate::new(30000, 1001);
+        let mut fmp4 = Fmp4::with_timescale(frame_rate, frame_rate.timescale(), 64, 48);
+        let fragments = (0..3).map(|i| fmp4.wrap_frame(&[0x82, 0, 0], i == 0).unwrap()).collect::<Vec<_>>();
+        let file = file(&fmp4, &fragments);
+        let samples = Fmp4Reader::new(&file).unwrap().samples().unwrap();
+        let times = samples.iter().map(|s| (s.dts, s.duration)).collect::<Vec<_>>();
+        assert_eq!(times, [(0, 1001), (1001, 1001), (2002, 1001)]);
+    }
+
+    #[test]
+    fn capture_times_become_decode_times() {
+        let mut fmp4 = Fmp4::new(30, 64, 48);
+        let fragments = [0, 40, 70].iter()
+            .map(|&ms| fmp4.wrap_frame_at(&[0x86, 0, 0], ms == 0, Duration::from_millis(ms)).unwrap())
+            .collect::<Vec<_>>();
+        let file = file(&fmp4, &fragments);
+        let samples = Fmp4Reader::new(&file).unwrap().samples().unwrap();
+        let times = samples.iter().map(|s| (s.dts, s.duration)).collect::<Vec<_>>();
+        // the first frame lasts one frame interval, later ones the time since the previous frame
+        assert_eq!(times, [(0, 3000), (3600, 3600), (6300, 2700)]);
+    }
+
+    #[test]
+    fn segment_index_covers_whole_segments() {
+        for cmaf in [false, true] {
+            let mut fmp4 = Fmp4::new(30, 64, 48);
+            fmp4.set_cmaf(cmaf);
+            fmp4.set_chunk_frames(2);
+            fmp4.set_segment_index(true);
+            fmp4.set_random_access_index(true);
+            let mut segments = vec![];
+            for i in 0..7 {
+                let key = i % 4 == 0;
+                for chunk in fmp4.push_frame(&[if key { 0x82 } else { 0x86 }, 0, 0], key).unwrap() {
+                    segments.push(chunk);
+                }
+                // nothing comes out before the key frame ending the segment
+                assert_eq!(segments.len(), usize::from(i >= 4), "frame {}", i);
+            }
+            segments.extend(fmp4.flush().unwrap());
+            assert!(segments.iter().all(|s| s.independent));
+
+            let prefix = if cmaf { vec!["styp", "sidx"] } else { vec!["sidx"] };
+            let mut offset = 0;
+            for (segment, chunks) in segments.iter().zip([2, 2]) {
+                let boxes = BoxReader::new(&segment.data).boxes().unwrap();
+                let types = boxes.iter().map(|b| b.type_str()).collect::<Vec<_>>();
+                let expected = prefix.iter().copied().chain((0..chunks).flat_map(|_| ["moof", "mdat"])).collect::<Vec<_>>();
+                assert_eq!(types, expected);
+                let mut r = boxes[prefix.len() - 1].reader();
+                r.skip(4 + 4 + 4 + 8 + 8 + 2).unwrap();
+                assert_eq!(r.u16().unwrap(), 1);
+                let referenced = boxes[prefix.len()..].iter().map(|b| b.size).sum::<usize>();
+                assert_eq!(r.u32().unwrap() as usize, referenced);
+                let frames = if offset == 0 { 4 } else { 3 };
+                assert_eq!((r.u32().unwrap(), r.u32().unwrap()), (frames * 3000, 0x9000_0000));
+                offset += segment.data.len();
+            }
+
+            // the recorded fragments tile the output, styp and sidx going with the first one
+            let fragments = fmp4.fragments();
+            assert_eq!(fragments.len(), 4);
+            assert_eq!(fragments.iter().map(|f| f.offset).collect::<Vec<_>>(),
+                       fragments.iter().scan(0, |end, f| Some(std::mem::replace(end, *end + u64::from(f.size)))).collect::<Vec<_>>());
+            assert_eq!(fragments.last().map(|f| f.offset + u64::from(f.size)), Some(offset as u64));
+            assert_eq!(fragments.iter().map(|f| f.key).collect::<Vec<_>>(), [true, false, true, false]);
+        }
+    }
+
+    #[test]
+    fn init_segment_round_trips() {
+        let mut fmp4 = encrypted_fmp4(Scheme::Cenc);
+        let edits = vec![Edit::empty(4500), Edit::skip(0)];
+        fmp4.set_edit_list(1, edits.clone());
+        let init = fmp4.init_segment();
+        assert_eq!(types(&init), ["ftyp", "moov"]);
+
+        let ftyp = find(&init, &[b"ftyp"]).payload;
+        assert_eq!(ftyp, [&b"isom"[..], &1u32.to_be_bytes(), b"isom", b"iso5", b"iso6", b"vp09"].concat());
+
+        let mut r = find(&init, &[b"moov", b"trak", b"tkhd"]).reader();
+        assert_eq!(r.full_box_header().unwrap(), (1, 7));
+        r.skip(16).unwrap();
+        assert_eq!(r.u32().unwrap(), 1); // track_ID
+        r.skip(4 + 8 + 8 + 8 + 36).unwrap();
+        assert_eq!((r.u32().unwrap(), r.u32().unwrap()), (64 << 16, 48 << 16));
+        assert_eq!(r.remaining(), 0);
+
+        let mut r = find(&init, &[b"moov", b"mvex", b"trex"]).reader();
+        r.full_box_header().unwrap();
+        assert_eq!(r.u32().unwrap(), 1);
+        assert_eq!(r.u32().unwrap(), 1); // default_sample_description_index
+
+        let stsd = find(&init, &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stsd"]);
+        let mut r = stsd.reader();
+        r.skip(8).unwrap();
+        let encv = r.next_box().unwrap().unwrap();
+        assert_eq!(&encv.box_type, b"encv");
+        // the boxes of the visual sample entry follow its 78 bytes of fields
+        let mut r = BoxReader::with_offset(&encv.payload[78..], 0);
+        let sinf = r.boxes().unwrap().into_iter().find(|b| &b.box_type == b"sinf").unwrap();
+        let mut r = find(sinf.payload, &[b"frma"]).reader();
+        assert_eq!(&r.fourcc().unwrap(), b"vp09");
+        let mut r = find(sinf.payload, &[b"schm"]).reader();
+        r.full_box_header().unwrap();
+        assert_eq!(&r.fourcc().unwrap(), b"cenc");
+        let mut r = find(sinf.payload, &[b"schi", b"tenc"]).reader();
+        assert_eq!(r.full_box_header().unwrap(), (0, 0));
+        r.skip(2).unwrap();
+        assert_eq!((r.u8().unwrap(), r.u8().unwrap()), (1, 8)); // default_isProtected, Per_Sample_IV_Size
+        assert_eq!(r.bytes(16).unwrap(), KID);
+        assert_eq!(r.remaining(), 0);
+
+        let mut r = find(&init, &[b"moov", b"pssh"]).reader();
+        assert_eq!(r.full_box_header().unwrap(), (1, 0));
+        assert_eq!(r.bytes(16).unwrap(), COMMON_SYSTEM_ID);
+        assert_eq!(r.u32().unwrap(), 1);
+        assert_eq!(r.bytes(16).unwrap(), KID);
+        assert_eq!(r.u32().unwrap(), 0);
+
+        let reader = Fmp4Reader::new(&init).unwrap();
+        assert_eq!(&reader.major_brand, b"isom");
+        let track = &reader.tracks[0];
+        assert_eq!((track.id, track.timescale, &track.handler, &track.codec), (1, 90000, b"vide", b"encv"));
+        assert_eq!((track.width, track.height), (64, 48));
+        assert_eq!(track.edits, edits);
+    }
+
+    #[test]
+    fn fragments_round_trip() {
+        let mut fmp4 = encrypted_fmp4(Scheme::Cenc);
+        fmp4.set_segment_index(true);
+        fmp4.set_random_access_index(true);
+        let init = fmp4.init_segment();
+        fmp4.push_event(EventMessage {
+            scheme_id_uri: "urn:test".to_string(),
+            value: "1".to_string(),
+            presentation_time: 1500,
+            duration: 3000,
+            id: 7,
+            message_data: b"hello".to_vec(),
+        }, 1);
+
+        let frames = [frame(true, 64, 48, &[1; 40]), frame(false, 64, 48, &[2; 40]), frame(false, 64, 48, &[3; 40])];
+        let mut file = init.clone();
+        for (i, data) in frames.iter().enumerate() {
+            // held back until the segment is complete
+            assert!(fmp4.push_frame(data, i == 0).unwrap().is_empty());
+        }
+        file.extend_from_slice(&fmp4.flush().unwrap().unwrap().data);
+        let mfra = fmp4.mfra(init.len() as u64);
+        file.extend_from_slice(&mfra);
+        let fragments = &file[init.len()..file.len() - mfra.len()];
+        assert_eq!(types(fragments), ["sidx", "emsg", "moof", "mdat", "moof", "mdat", "moof", "mdat"]);
+
+        // the sidx covers the whole segment behind it
+        let boxes = BoxReader::new(fragments).boxes().unwrap();
+        let mut r = boxes[0].reader();
+        assert_eq!(r.full_box_header().unwrap(), (1, 0));
+        assert_eq!((r.u32().unwrap(), r.u32().unwrap(), r.u64().unwrap(), r.u64().unwrap()), (1, 90000, 0, 0));
+        r.skip(2).unwrap();
+        assert_eq!(r.u16().unwrap(), 1);
+        assert_eq!(r.u32().unwrap() as usize, boxes[1..].iter().map(|b| b.size).sum::<usize>());
+        assert_eq!((r.u32().unwrap(), r.u32().unwrap()), (9000, 0x9000_0000));
+
+        let mut r = boxes[1].reader();
+        assert_eq!(r.full_box_header().unwrap(), (1, 0));
+        assert_eq!((r.u32().unwrap(), r.u64().unwrap(), r.u32().unwrap(), r.u32().unwrap()), (90000, 1500, 3000, 7));
+        assert_eq!(r.bytes(r.remaining()).unwrap(), b"urn:test\x001\x00hello");
+
+        let moof = boxes[4];
+        let mut r = find(moof.payload, &[b"mfhd"]).reader();
+        r.full_box_header().unwrap();
+        assert_eq!(r.u32().unwrap(), 2); // sequence_number
+        let mut r = find(moof.payload, &[b"traf", b"tfhd"]).reader();
+        assert_eq!(r.full_box_header().unwrap(), (0, 0));
+        assert_eq!(r.u32().unwrap(), 1);
+        let mut r = find(moof.payload, &[b"traf", b"tfdt"]).reader();
+        assert_eq!(r.full_box_header().unwrap(), (1, 0));
+        assert_eq!(r.u64().unwrap(), 3000);
+        let mut r = find(moof.payload, &[b"traf", b"trun"]).reader();
+        assert_eq!(r.full_box_header().unwrap(), (0, 0xF01));
+        assert_eq!(r.u32().unwrap(), 1);
+        // data_offset from the moof to the samples in the mdat
+        assert_eq!(r.i32().unwrap() as usize, moof.size + 8);
+        assert_eq!((r.u32().unwrap(), r.u32().unwrap() as usize), (3000, frames[1].len()));
+
+        // one entry with the second IV, the header in the clear and the rest in whole blocks
+        let mut r = find(moof.payload, &[b"traf", b"senc"]).reader();
+        assert_eq!(r.full_box_header().unwrap(), (0, 2));
+        assert_eq!(r.u32().unwrap(), 1);
+        let senc_data = r.position();
+        assert_eq!(r.bytes(8).unwrap(), [0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x34]);
+        assert_eq!((r.u16().unwrap(), r.u16().unwrap(), r.u32().unwrap()), (1, 18, 32));
+        let mut r = find(moof.payload, &[b"traf", b"saiz"]).reader();
+        assert_eq!(r.full_box_header().unwrap(), (0, 0));
+        // no default_sample_info_size, one sample of 16 bytes
+        assert_eq!((r.u8().unwrap(), r.u32().unwrap(), r.u8().unwrap()), (0, 1, 16));
+        let mut r = find(moof.payload, &[b"traf", b"saio"]).reader();
+        assert_eq!(r.full_box_header().unwrap(), (0, 0));
+        assert_eq!(r.u32().unwrap(), 1);
+        // relative to the moof, the payload of the moof starting 8 bytes in
+        assert_eq!(r.u32().unwrap() as usize, senc_data + 8);
+
+        let mut r = find(&mfra, &[b"mfra", b"tfra"]).reader();
+        assert_eq!(r.full_box_header().unwrap(), (1, 0));
+        assert_eq!((r.u32().unwrap(), r.u32().unwrap(), r.u32().unwrap()), (1, 0, 1));
+        assert_eq!((r.u64().unwrap(), r.u64().unwrap() as usize), (0, boxes[2].offset + init.len()));
+        assert_eq!(&file[boxes[2].offset + init.len() + 4..][..4], b"moof");
+        let mut r = find(&mfra, &[b"mfra", b"mfro"]).reader();
+        r.full_box_header().unwrap();
+        assert_eq!(r.u32().unwrap() as usize, mfra.len());
+
+        let samples = Fmp4Reader::new(&file).unwrap().samples().unwrap();
+        let times = samples.iter().map(|s| (s.dts, s.duration, s.key, s.data.len())).collect::<Vec<_>>();
+        assert_eq!(times, [(0, 3000, true, 54), (3000, 3000, false, 50), (6000, 3000, false, 50)]);
+        // the uncompressed headers stay in the clear, the payload doesn't
+        assert_eq!(samples[1].data[..18], frames[1][..18]);
+        assert_ne!(samples[1].data[18..], frames[1][18..]);
+    }
+}
@@ -0,0 +1,437 @@
+//!
+//! Reads fragmented MP4 files back, e.g. to verify `Fmp4` output or to re-package
+//! recordings without re-encoding.
+//!
+use std::collections::HashMap;
+use std::ops::Range;
+
+use anyhow::{bail, Context};
+
+use crate::bmff::{BoxReader, BoxRef};
+use crate::fmp4::Edit;
+use crate::metadata::{self, Metadata};
+use crate::video::PixelAspectRatio;
+
+#[derive(Clone, Debug, Default)]
+pub struct TrackInfo {
+    pub id: u32,
+    pub timescale: u32,
+    /// `vide`, `soun`...
+    pub handler: [u8; 4],
+    /// fourcc of the first sample entry, e.g. `vp09`
+    pub codec: [u8; 4],
+    pub width: u16,
+    pub height: u16,
+    /// the whole sample entry box, so it can be copied into a new init segment
+    pub sample_entry: Vec<u8>,
+    pub edits: Vec<Edit>,
+    /// `tkhd` transformation matrix
+    pub matrix: [u32; 9],
+    /// `pasp` of a video sample entry
+    pub pixel_aspect_ratio: PixelAspectRatio,
+    default_sample_duration: u32,
+    default_sample_size: u32,
+    default_sample_flags: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Mp4Sample<'a> {
+    pub track_id: u32,
+    /// decode time in track timescale
+    pub dts: u64,
+    /// presentation time in track timescale
+    pub pts: i64,
+    pub duration: u32,
+    pub key: bool,
+    pub data: &'a [u8],
+}
+
+pub struct Fmp4Reader<'a> {
+    data: &'a [u8],
+    pub major_brand: [u8; 4],
+    pub compatible_brands: Vec<[u8; 4]>,
+    /// seconds since 1904-01-01, 0 if unknown
+    pub creation_time: u64,
+    pub movie_timescale: u32,
+    pub tracks: Vec<TrackInfo>,
+    /// title, comment etc. of the `moov`
+    pub metadata: Metadata,
+}
+
+impl<'a> Fmp4Reader<'a> {
+    /// Parses `ftyp` and `moov`. Fragments are parsed lazily by `samples`.
+    pub fn new(data: &'a [u8]) -> anyhow::Result<Self> {
+        let mut reader = Self {
+            data,
+            major_brand: [0; 4],
+            compatible_brands: vec![],
+            creation_time: 0,
+            movie_timescale: 0,
+            tracks: vec![],
+            metadata: Metadata::default(),
+        };
+
+        let mut has_moov = false;
+        for b in BoxReader::new(data).boxes()? {
+            match &b.box_type {
+                b"ftyp" => reader.parse_ftyp(&b)?,
+                b"moov" => {
+                    reader.parse_moov(&b)?;
+                    has_moov = true;
+                }
+                _ => {}
+            }
+        }
+        if !has_moov {
+            bail!("no moov box found");
+        }
+        Ok(reader)
+    }
+
+    pub fn track(&self, id: u32) -> Option<&TrackInfo> {
+        self.tracks.iter().find(|t| t.id == id)
+    }
+
+    /// All samples of all fragments in file order.
+    pub fn samples(&self) -> anyhow::Result<Vec<Mp4Sample<'a>>> {
+        let mut samples = vec![];
+        // running decode time per track, for fragments without tfdt
+        let mut next_dts: HashMap<u32, u64> = HashMap::new();
+
+        let boxes = BoxReader::new(self.data).boxes()?;
+        // samples have to lie within the payload of an mdat, not just within the file
+        let mdats = boxes.iter()
+            .filter(|b| &b.box_type == b"mdat")
+            .map(|b| b.offset + b.size - b.payload.len()..b.offset + b.size)
+            .collect::<Vec<_>>();
+        for b in boxes.iter().filter(|b| &b.box_type == b"moof") {
+            self.parse_moof(b, &mdats, &mut next_dts, &mut samples)
+                .with_context(|| format!("invalid moof at offset {}", b.offset))?;
+        }
+        Ok(samples)
+    }
+
+    fn parse_ftyp(&mut self, b: &BoxRef) -> anyhow::Result<()> {
+        let mut r = b.reader();
+        self.major_brand = r.fourcc()?;
+        r.u32()?; // minor_version
+        while r.remaining() >= 4 {
+            self.compatible_brands.push(r.fourcc()?);
+        }
+        Ok(())
+    }
+
+    fn parse_moov(&mut self, moov: &BoxRef) -> anyhow::Result<()> {
+        self.metadata = metadata::read(moov).context("invalid metadata")?;
+        for b in moov.reader().boxes()? {
+            match &b.box_type {
+                b"mvhd" => {
+                    let mut r = b.reader();
+                    let (version, _) = r.full_box_header()?;
+                    self.creation_time = r.u32_or_u64(version)?;
+                    r.u32_or_u64(version)?; // modification_time
+                    self.movie_timescale = r.u32()?;
+                }
+                b"trak" => {
+                    let track = parse_trak(&b, self.data).with_context(|| format!("invalid trak at offset {}", b.offset))?;
+                    self.tracks.push(track);
+                }
+                b"mvex" => {
+                    for trex in find_all(&b, b"trex")? {
+                        let mut r = trex.reader();
+                        r.full_box_header()?;
+                        let id = r.u32()?;
+                        r.u32()?; // default_sample_description_index
+                        let (duration, size, flags) = (r.u32()?, r.u32()?, r.u32()?);
+                        if let Some(t) = self.tracks.iter_mut().find(|t| t.id == id) {
+                            t.default_sample_duration = duration;
+                            t.default_sample_size = size;
+                            t.default_sample_flags = flags;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_moof(&self, moof: &BoxRef, mdats: &[Range<usize>], next_dts: &mut HashMap<u32, u64>, samples: &mut Vec<Mp4Sample<'a>>) -> anyhow::Result<()> {
+        // end of the data of the previous traf, the implicit base offset of the next one
+        let mut previous_end = moof.offset;
+        for traf in find_all(moof, b"traf")? {
+            let boxes = traf.reader().boxes()?;
+            let tfhd = boxes.iter().find(|b| &b.box_type == b"tfhd").context("traf without tfhd")?;
+
+            let mut r = tfhd.reader();
+            let (_, flags) = r.full_box_header()?;
+            let track_id = r.u32()?;
+            let track = self.track(track_id).with_context(|| format!("unknown track {}", track_id))?;
+            let base_data_offset = if flags & 0x01 != 0 {
+                r.u64()? as usize
+            } else if flags & 0x020000 != 0 {
+                // default-base-is-moof
+                moof.offset
+            } else {
+                previous_end
+            };
+            if flags & 0x02 != 0 {
+                r.u32()?; // sample_description_index
+            }
+            let default_duration = if flags & 0x08 != 0 { r.u32()? } else { track.default_sample_duration };
+            let default_size = if flags & 0x10 != 0 { r.u32()? } else { track.default_sample_size };
+            let default_flags = if flags & 0x20 != 0 { r.u32()? } else { track.default_sample_flags };
+
+            let mut dts = next_dts.get(&track_id).copied().unwrap_or(0);
+            if let Some(tfdt) = boxes.iter().find(|b| &b.box_type == b"tfdt") {
+                let mut r = tfdt.reader();
+                let (version, _) = r.full_box_header()?;
+                dts = r.u32_or_u64(version)?;
+            }
+
+            let mut data_offset = base_data_offset;
+            for trun in boxes.iter().filter(|b| &b.box_type == b"trun") {
+                let mut r = trun.reader();
+                let (version, flags) = r.full_box_header()?;
+                let count = r.u32()?;
+                if flags & 0x01 != 0 {
+                    let offset = r.i32()?;
+                    data_offset = base_data_offset.checked_add_signed(offset as isize)
+                        .with_context(|| format!("invalid data offset {} of track {}", offset, track_id))?;
+                }
+                let first_sample_flags = if flags & 0x04 != 0 { Some(r.u32()?) } else { None };
+
+                for i in 0..count {
+                    let duration = if flags & 0x100 != 0 { r.u32()? } else { default_duration };
+                    let size = if flags & 0x200 != 0 { r.u32()? } else { default_size } as usize;
+                    let sample_flags = match (i, first_sample_flags) {
+                        (0, Some(f)) => f,
+                        _ if flags & 0x400 != 0 => r.u32()?,
+                        _ => default_flags,
+                    };
+                    let cts = match flags & 0x800 {
+                        0 => 0,
+                        _ if version == 0 => i64::from(r.u32()?),
+                        _ => i64::from(r.i32()?),
+                    };
+
+                    let end = match data_offset.checked_add(size) {
+                        Some(end) if mdats.iter().any(|m| m.start <= data_offset && end <= m.end) => end,
+                        _ => bail!("sample {} of track {} at offset {} is outside of the mdat payloads", i, track_id, data_offset),
+                    };
+                    samples.push(Mp4Sample {
+                        track_id,
+                        dts,
+                        pts: dts as i64 + cts,
+                        duration,
+                        // sample_is_non_sync_sample
+                        key: sample_flags & 0x0001_0000 == 0,
+                        data: &self.data[data_offset..end],
+                    });
+                    data_offset = end;
+                    dts += u64::from(duration);
+                }
+            }
+            next_dts.insert(track_id, dts);
+            previous_end = data_offset;
+        }
+        Ok(())
+    }
+}
+
+/// `data` is the whole file, which the offsets of the boxes refer to.
+fn parse_trak(trak: &BoxRef, data: &[u8]) -> anyhow::Result<TrackInfo> {
+    let mut track = TrackInfo::default();
+
+    let tkhd = find(trak, b"tkhd")?;
+    let mut r = tkhd.reader();
+    let (version, _) = r.full_box_header()?;
+    r.u32_or_u64(version)?; // creation_time
+    r.u32_or_u64(version)?; // modification_time
+    track.id = r.u32()?;
+    r.u32()?; // reserved
+    r.u32_or_u64(version)?; // duration
+    r.skip(8 + 2 + 2 + 2 + 2)?; // reserved, layer, alternate_group, volume, reserved
+    for v in track.matrix.iter_mut() {
+        *v = r.u32()?;
+    }
+
+    if let Some(edts) = find_all(trak, b"edts")?.first() {
+        let mut r = find(edts, b"elst")?.reader();
+        let (version, _) = r.full_box_header()?;
+        for _ in 0..r.u32()? {
+            let segment_duration = r.u32_or_u64(version)?;
+            let media_time = if version == 1 { r.i64()? } else { i64::from(r.i32()?) };
+            r.u32()?; // media_rate
+            track.edits.push(Edit { segment_duration, media_time });
+        }
+    }
+
+    let mdia = find(trak, b"mdia")?;
+    let mut r = find(&mdia, b"mdhd")?.reader();
+    let (version, _) = r.full_box_header()?;
+    r.u32_or_u64(version)?; // creation_time
+    r.u32_or_u64(version)?; // modification_time
+    track.timescale = r.u32()?;
+
+    let mut r = find(&mdia, b"hdlr")?.reader();
+    r.full_box_header()?;
+    r.u32()?; // pre_defined
+    track.handler = r.fourcc()?;
+
+    let stbl = find(&find(&mdia, b"minf")?, b"stbl")?;
+    let mut r = find(&stbl, b"stsd")?.reader();
+    r.full_box_header()?;
+    if r.u32()? > 0 {
+        let entry = r.next_box()?.context("stsd without sample entry")?;
+        track.codec = entry.box_type;
+        track.sample_entry = box_bytes(&entry, data).to_vec();
+        if matches!(&track.handler, b"vide" | b"auxv") {
+            let mut r = entry.reader();
+            r.skip(6 + 2 + 16)?; // reserved, data_reference_index, pre_defined
+            track.width = r.u16()?;
+            track.height = r.u16()?;
+            // resolutions, data_size, frame_count, compressorname, depth, pre_defined
+            r.skip(4 + 4 + 4 + 2 + 32 + 2 + 2)?;
+            if let Some(pasp) = r.boxes()?.into_iter().find(|b| &b.box_type == b"pasp") {
+                let mut r = pasp.reader();
+                let (h_spacing, v_spacing) = (r.u32()?, r.u32()?);
+                if h_spacing > 0 && v_spacing > 0 {
+                    track.pixel_aspect_ratio = PixelAspectRatio { h_spacing, v_spacing };
+                }
+            }
+        }
+    }
+    Ok(track)
+}
+
+/// The box as found in `data`, header included, whatever form its size takes.
+fn box_bytes<'a>(b: &BoxRef, data: &'a [u8]) -> &'a [u8] {
+    &data[b.offset..b.offset + b.size]
+}
+
+fn find<'a>(parent: &BoxRef<'a>, box_type: &[u8; 4]) -> anyhow::Result<BoxRef<'a>> {
+    find_all(parent, box_type)?
+        .into_iter()
+        .next()
+        .with_context(|| format!("no '{}' in '{}'", String::from_utf8_lossy(box_type), parent.type_str()))
+}
+
+fn find_all<'a>(parent: &BoxRef<'a>, box_type: &[u8; 4]) -> anyhow::Result<Vec<BoxRef<'a>>> {
+    Ok(parent.reader().boxes()?.into_iter().filter(|b| &b.box_type == box_type).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmff::BoxWriter;
+    use crate::cenc::Scheme;
+    use crate::fmp4::tests::{encrypted_fmp4, find};
+    use crate::fmp4::Fmp4;
+    use crate::vp9::tests::frame;
+
+    /// A moof with a traf of one sample per track, with the tfhd flags and trun data offsets
+    /// given for each, and the mdat of the samples.
+    fn fragment(trafs: &[(u32, u32, Option<i32>, &[u8])]) -> Vec<u8> {
+        let mut w = BoxWriter::new();
+        w.boxed(b"moof", |w| {
+            w.full_box(b"mfhd", 0, 0, |w| {
+                w.u32(1);
+            });
+            for (track_id, tfhd_flags, data_offset, data) in trafs {
+                w.boxed(b"traf", |w| {
+                    w.full_box(b"tfhd", 0, *tfhd_flags, |w| {
+                        w.u32(*track_id);
+                    });
+                    // sample-duration, sample-size and maybe data-offset
+                    w.full_box(b"trun", 0, 0x300 | u32::from(data_offset.is_some()), |w| {
+                        w.u32(1);
+                        if let Some(offset) = data_offset {
+                            w.i32(*offset);
+                        }
+                        w.u32(3000).u32(data.len() as u32);
+                    });
+                });
+            }
+        });
+        w.boxed(b"mdat", |w| {
+            trafs.iter().for_each(|t| {
+                w.bytes(t.3);
+            });
+        });
+        w.into_bytes()
+    }
+
+    fn two_track_init() -> Vec<u8> {
+        let mut fmp4 = Fmp4::new(30, 64, 48);
+        fmp4.add_alpha_track();
+        fmp4.init_segment()
+    }
+
+    #[test]
+    fn later_trafs_start_after_the_previous_one() {
+        let init = two_track_init();
+        // implicit, the end of the data of the first traf, or relative to the moof
+        for (flags, explicit) in [(0, false), (0x020000, true)] {
+            let trafs = |moof_size: i32| {
+                // the data starts after the header of the mdat
+                let second = explicit.then(|| moof_size + 8 + 5);
+                fragment(&[(1, 0, Some(moof_size + 8), b"video"), (2, flags, second, b"alpha")])
+            };
+            let moof_size = trafs(0).len() - 8 - 10;
+            let file = [&init[..], &trafs(moof_size as i32)].concat();
+            let samples = Fmp4Reader::new(&file).unwrap().samples().unwrap();
+            let data = samples.iter().map(|s| (s.track_id, s.data)).collect::<Vec<_>>();
+            assert_eq!(data, [(1, &b"video"[..]), (2, &b"alpha"[..])]);
+        }
+    }
+
+    #[test]
+    fn data_offsets_outside_the_file_are_errors() {
+        let init = two_track_init();
+        for offset in [i32::MIN, -(init.len() as i32) - 1, i32::MAX] {
+            let file = [&init[..], &fragment(&[(1, 0, Some(offset), b"video")])].concat();
+            assert!(Fmp4Reader::new(&file).unwrap().samples().is_err(), "data offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn samples_outside_the_mdat_are_errors() {
+        let init = two_track_init();
+        let moof_size = fragment(&[(1, 0, Some(0), b"video")]).len() - 8 - 5;
+        let file = |offset: usize| [&init[..], &fragment(&[(1, 0, Some(offset as i32), b"video")]).repeat(2)].concat();
+        assert_eq!(Fmp4Reader::new(&file(moof_size + 8)).unwrap().samples().unwrap().len(), 2);
+        // within the moof, over the mdat header, and running into the next fragment
+        for offset in [0, moof_size, moof_size + 9] {
+            assert!(Fmp4Reader::new(&file(offset)).unwrap().samples().is_err(), "data offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn box_bytes_keep_the_header() {
+        // a 64-bit size, then a box extending to the end of the data
+        let data = [&1u32.to_be_bytes()[..], b"free", &20u64.to_be_bytes(), b"abcd", &0u32.to_be_bytes(), b"skip", b"ef"].concat();
+        let boxes = BoxReader::new(&data).boxes().unwrap();
+        assert_eq!(box_bytes(&boxes[0], &data), &data[..20]);
+        assert_eq!(box_bytes(&boxes[1], &data), &data[20..]);
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        let mut fmp4 = encrypted_fmp4(Scheme::Cbcs);
+        let mut file = fmp4.init_segment();
+        file.extend_from_slice(&fmp4.wrap_frame(&frame(true, 64, 48, &[1; 40]), true).unwrap());
+        let init_size = fmp4.init_segment().len();
+        for end in (0..file.len()).filter(|&end| end != init_size) {
+            // cut between the moof and the mdat, the boxes are fine but the samples are missing
+            let read = Fmp4Reader::new(&file[..end]).and_then(|reader| reader.samples());
+            assert!(read.is_err(), "{} of {} bytes read as a file", end, file.len());
+        }
+
+        // a sample larger than the mdat
+        let trun = find(&file[init_size..], &[b"moof", b"traf", b"trun"]);
+        let size = init_size + trun.offset + 12 + 4 + 4 + 4;
+        file[size..size + 4].copy_from_slice(&1000u32.to_be_bytes());
+        assert!(Fmp4Reader::new(&file).unwrap().samples().is_err());
+    }
+}
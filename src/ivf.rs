@@ -0,0 +1,294 @@
+//!
+//! IVF container, the simplest framing for raw VP8/VP9 bitstreams.
+//!
+//! The file starts with a 32-byte header, followed by frames prefixed with a
+//! 12-byte frame header (4 bytes size, 8 bytes pts). All fields are little-endian.
+//!
+use std::convert::TryFrom;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use anyhow::{bail, Context};
+
+use crate::fmp4::{Fmp4, FrameRate};
+use crate::vp9;
+
+const SIGNATURE: &[u8; 4] = b"DKIF";
+const HEADER_SIZE: u16 = 32;
+const FRAME_HEADER_SIZE: usize = 12;
+/// offset of the frame count in the file header
+const FRAME_COUNT_OFFSET: u64 = 24;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IvfHeader {
+    pub fourcc: [u8; 4],
+    pub width: u16,
+    pub height: u16,
+    /// numerator and denominator (in seconds), same as `vpx_encode::Config::timebase`
+    pub timebase: [u32; 2],
+    pub frame_count: u32,
+}
+
+impl IvfHeader {
+    pub const VP9_FOURCC: [u8; 4] = *b"VP90";
+
+    pub fn new(width: u16, height: u16, timebase: [u32; 2]) -> Self {
+        Self {
+            fourcc: Self::VP9_FOURCC,
+            width,
+            height,
+            timebase,
+            frame_count: 0,
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; HEADER_SIZE as usize] {
+        let mut bytes = [0u8; HEADER_SIZE as usize];
+        bytes[0..4].copy_from_slice(SIGNATURE);
+        bytes[4..6].copy_from_slice(&0u16.to_le_bytes()); // version
+        bytes[6..8].copy_from_slice(&HEADER_SIZE.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.fourcc);
+        bytes[12..14].copy_from_slice(&self.width.to_le_bytes());
+        bytes[14..16].copy_from_slice(&self.height.to_le_bytes());
+        // IVF stores the rate (denominator) before the scale (numerator)
+        bytes[16..20].copy_from_slice(&self.timebase[1].to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.timebase[0].to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.frame_count.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; HEADER_SIZE as usize]) -> anyhow::Result<Self> {
+        if &bytes[0..4] != SIGNATURE {
+            bail!("not an IVF file");
+        }
+        let header_size = u16::from_le_bytes([bytes[6], bytes[7]]);
+        if header_size != HEADER_SIZE {
+            bail!("unsupported IVF header size {}", header_size);
+        }
+        let u32_at = |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+
+        let mut fourcc = [0u8; 4];
+        fourcc.copy_from_slice(&bytes[8..12]);
+        Ok(Self {
+            fourcc,
+            width: u16::from_le_bytes([bytes[12], bytes[13]]),
+            height: u16::from_le_bytes([bytes[14], bytes[15]]),
+            timebase: [u32_at(20), u32_at(16)],
+            frame_count: u32_at(24),
+        })
+    }
+}
+
+/// Writes encoded frames into an IVF stream.
+pub struct IvfWriter<W: Write> {
+    inner: W,
+    header: IvfHeader,
+}
+
+impl<W: Write> IvfWriter<W> {
+    pub fn new(mut inner: W, header: IvfHeader) -> anyhow::Result<Self> {
+        inner.write_all(&header.to_bytes())?;
+        Ok(Self { inner, header })
+    }
+
+    pub fn write_frame(&mut self, frame: &vpx_encode::Frame) -> anyhow::Result<()> {
+        self.write(frame.data, frame.pts)
+    }
+
+    pub fn write(&mut self, data: &[u8], pts: i64) -> anyhow::Result<()> {
+        self.inner.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&pts.to_le_bytes())?;
+        self.inner.write_all(data)?;
+        self.header.frame_count += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> IvfWriter<W> {
+    /// Patches the frame count in the file header and returns the inner writer.
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        let end = self.inner.stream_position()?;
+        self.inner.seek(SeekFrom::Start(FRAME_COUNT_OFFSET))?;
+        self.inner.write_all(&self.header.frame_count.to_le_bytes())?;
+        self.inner.seek(SeekFrom::Start(end))?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IvfFrame {
+    pub pts: i64,
+    pub data: Vec<u8>,
+}
+
+impl IvfFrame {
+    /// IVF does not flag key frames, so look at the VP9 uncompressed header.
+    pub fn is_key(&self) -> bool {
+        vp9::is_key_frame(&self.data)
+    }
+}
+
+/// Reads frames back from an IVF stream.
+pub struct IvfReader<R: Read> {
+    inner: R,
+    header: IvfHeader,
+}
+
+impl<R: Read> IvfReader<R> {
+    pub fn new(mut inner: R) -> anyhow::Result<Self> {
+        let mut bytes = [0u8; HEADER_SIZE as usize];
+        inner.read_exact(&mut bytes).context("truncated IVF header")?;
+        let header = IvfHeader::from_bytes(&bytes)?;
+        Ok(Self { inner, header })
+    }
+
+    pub fn header(&self) -> &IvfHeader {
+        &self.header
+    }
+
+    /// Returns `None` at the end of the stream.
+    pub fn next_frame(&mut self) -> anyhow::Result<Option<IvfFrame>> {
+        let mut bytes = [0u8; FRAME_HEADER_SIZE];
+        let mut read = 0;
+        while read < FRAME_HEADER_SIZE {
+            match self.inner.read(&mut bytes[read..])? {
+                0 if read == 0 => return Ok(None),
+                0 => bail!("truncated IVF frame header"),
+                n => read += n,
+            }
+        }
+
+        let size = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        let mut pts = [0u8; 8];
+        pts.copy_from_slice(&bytes[4..12]);
+
+        // the size is untrusted, so the buffer only grows with the data actually there
+        let mut data = vec![];
+        (&mut self.inner).take(size as u64).read_to_end(&mut data)?;
+        if data.len() != size {
+            bail!("truncated IVF frame");
+        }
+        Ok(Some(IvfFrame {
+            pts: i64::from_le_bytes(pts),
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for IvfReader<R> {
+    type Item = anyhow::Result<IvfFrame>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+/// Re-muxes a VP9 IVF stream into a fragmented MP4 without re-encoding.
+///
+/// The timescale is the one of the IVF header and the frame timestamps become the decode times,
+/// so fractional and variable frame rates come through as they are. The init segment and one
+/// fragment per frame are written back to back to `output`. Returns the number of frames.
+pub fn ivf_to_fmp4<R: Read, W: Write>(input: R, output: &mut W) -> anyhow::Result<u32> {
+    let mut reader = IvfReader::new(input)?;
+    let header = reader.header().clone();
+    if header.fourcc != IvfHeader::VP9_FOURCC {
+        bail!("only VP9 IVF streams can be muxed into fMP4");
+    }
+    let [num, den] = header.timebase;
+    if num == 0 || den == 0 {
+        bail!("invalid IVF timebase {}/{}", num, den);
+    }
+    // timestamps in ticks of 1/den seconds
+    let ticks = |frame: &IvfFrame| match u64::try_from(frame.pts) {
+        Ok(pts) => pts.checked_mul(u64::from(num)).with_context(|| format!("IVF timestamp {} out of range", frame.pts)),
+        Err(_) => Err(anyhow::anyhow!("negative IVF timestamp {}", frame.pts)),
+    };
+
+    // a frame is wrapped once the next one tells its duration
+    let mut current = reader.next_frame()?;
+    let mut next = reader.next_frame()?;
+    // the nominal frame rate only sets the default duration and the VP9 level
+    let interval = match (&current, &next) {
+        (Some(current), Some(next)) => ticks(next)?.saturating_sub(ticks(current)?),
+        _ => 0,
+    };
+    let frame_rate = match u32::try_from(interval) {
+        Ok(interval) if interval > 0 => FrameRate::new(den, interval),
+        _ => FrameRate::new(den, num),
+    };
+    let mut fmp4 = Fmp4::with_timescale(frame_rate, den, header.width, header.height);
+    output.write_all(&fmp4.init_segment())?;
+
+    let mut count = 0;
+    while let Some(frame) = current {
+        let dts = ticks(&frame)?;
+        fmp4.set_decode_time(dts);
+        let fragment = match &next {
+            Some(next) => {
+                let interval = ticks(next)?.checked_sub(dts)
+                    .with_context(|| format!("IVF timestamps go backwards after frame {}", count))?;
+                let duration = u32::try_from(interval).with_context(|| format!("frame {} lasts too long", count))?;
+                fmp4.wrap_frame_with_duration(&frame.data, frame.is_key(), duration)?
+            }
+            // the last frame lasts one nominal frame interval
+            None => fmp4.wrap_frame(&frame.data, frame.is_key())?,
+        };
+        output.write_all(&fragment)?;
+        count += 1;
+        current = next;
+        next = reader.next_frame()?;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fmp4_reader::Fmp4Reader;
+
+    #[test]
+    fn remux_keeps_timebase_and_timestamps() {
+        let mut ivf = vec![];
+        let mut writer = IvfWriter::new(&mut ivf, IvfHeader::new(64, 48, [1001, 30000])).unwrap();
+        // a key frame marker byte, then inter frames, with a dropped frame before the last one
+        for (pts, first) in [(0, 0x82), (1, 0x86), (2, 0x86), (4, 0x86)] {
+            writer.write(&[first, 0, 0, 0], pts).unwrap();
+        }
+
+        let mut mp4 = vec![];
+        assert_eq!(ivf_to_fmp4(&ivf[..], &mut mp4).unwrap(), 4);
+        let reader = Fmp4Reader::new(&mp4).unwrap();
+        assert_eq!(reader.tracks[0].timescale, 30000);
+        let samples = reader.samples().unwrap();
+        let times = samples.iter().map(|s| (s.dts, s.duration, s.key)).collect::<Vec<_>>();
+        assert_eq!(times, [(0, 1001, true), (1001, 1001, false), (2002, 2002, false), (4004, 1001, false)]);
+    }
+
+    #[test]
+    fn remux_rejects_backward_timestamps() {
+        let mut ivf = vec![];
+        let mut writer = IvfWriter::new(&mut ivf, IvfHeader::new(64, 48, [1, 30])).unwrap();
+        writer.write(&[0x82], 5).unwrap();
+        writer.write(&[0x86], 3).unwrap();
+        assert!(ivf_to_fmp4(&ivf[..], &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn frame_sizes_are_bounded_by_the_input() {
+        let mut ivf = vec![];
+        IvfWriter::new(&mut ivf, IvfHeader::new(64, 48, [1, 30])).unwrap().write(&[0x82; 8], 0).unwrap();
+        // claims 4 GiB, with 8 bytes there
+        let size = HEADER_SIZE as usize;
+        ivf[size..size + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = IvfReader::new(&ivf[..]).unwrap();
+        assert!(reader.next_frame().is_err());
+    }
+
+    #[test]
+    fn remux_rejects_overflowing_timestamps() {
+        let mut ivf = vec![];
+        let mut writer = IvfWriter::new(&mut ivf, IvfHeader::new(64, 48, [1001, 30000])).unwrap();
+        writer.write(&[0x82], 0).unwrap();
+        writer.write(&[0x86], i64::MAX).unwrap();
+        assert!(ivf_to_fmp4(&ivf[..], &mut vec![]).is_err());
+    }
+}
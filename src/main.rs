@@ -1,53 +1,542 @@
 //!
 //! Don't forget to install `libvpx`.
 //!
+mod bmff;
+mod cenc;
 mod fmp4;
+mod fmp4_reader;
+mod ivf;
+mod manifest;
+mod metadata;
+mod opus;
+mod output;
+mod trim;
+mod video;
+mod vp9;
+mod webm;
+mod yuv_ops;
+mod yuv_simd;
 mod yuv_util;
 
-use fmp4::{Fmp4};
-use image::{imageops::FilterType, GenericImageView, ImageFormat};
-use std::time::Instant;
+use cenc::Encryptor;
+use fmp4::{Edit, EventMessage, Fmp4, FrameRate, MetadataFormat, Track};
+use fmp4_reader::Fmp4Reader;
+use ivf::{ivf_to_fmp4, IvfHeader, IvfWriter};
+use metadata::Metadata;
+use opus::OggOpusReader;
+use image::{codecs::{png::{PngDecoder, PngEncoder}, tiff::TiffDecoder}, ColorType, DynamicImage, EncodableLayout, ImageDecoder};
+use output::{SegmentFiles, SegmentSink, SingleFile};
+use std::path::Path;
+use std::time::{Duration, Instant};
 use std::{fs::File, io::Cursor};
-use std::{fs::OpenOptions, io::Write, u32};
 use std::{io::Read, vec};
-use yuv_util::convert_rgb_to_yuv420p;
+use video::{ChromaSubsampling, ColorInfo, VideoProperties};
+use webm::{WebmHeader, WebmWriter};
+use yuv_ops::{Rect, ResizeOptions};
+use yuv_util::{convert_alpha_to_yuv420p_into, convert_color, convert_rgb_to_yuv420p_into, convert_rgb_to_yuv_high_bit_depth_into,
+               convert_yuv_to_rgb, parse_color, ColorMatrix, ConvertOptions, FramePool, PixelLayout, Range, Transfer, YuvFormat, YuvFrame};
 
 const OUTPUT_DIR: &str = "m4s";
-const HEADER_NAME: &str = "m4s/header.m4s";
-const SEGMENT_PREFIX: &str = "m4s/body";
+const IVF_NAME: &str = "m4s/stream.ivf";
+
+const WIDTH: u32 = 1920;
+const HEIGHT: u32 = 1080;
+const FRAMES: u32 = 1200;
 
 fn main() -> anyhow::Result<()> {
-    let width = 1920;
-    let height = 1080;
-    let fps = 30u32;
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() >= 4 && args[1] == "remux" {
+        return remux(&args[2], &args[3]);
+    }
+    if args.len() >= 3 && args[1] == "probe" {
+        return probe(&args[2]);
+    }
+    if args.len() >= 5 && args[1] == "trim" {
+        let end = args.get(5).map(|x| x.parse()).transpose()?;
+        return trim(&args[2], &args[3], args[4].parse()?, end);
+    }
+
+    let options = Options::parse(&args[1..])?;
+    let frame_rate = options.frame_rate;
+    // 90 kHz unless its frame durations would have to be rounded
+    let mut fmp4 = match u64::from(Track::DEFAULT_TIMESCALE) * u64::from(frame_rate.den) % u64::from(frame_rate.num) {
+        0 => Fmp4::new(frame_rate, WIDTH as _, HEIGHT as _),
+        _ => Fmp4::with_timescale(frame_rate, frame_rate.timescale(), WIDTH as _, HEIGHT as _),
+    };
+    if let Some(frames) = options.cmaf_chunk_frames {
+        fmp4.set_cmaf(true);
+        fmp4.set_chunk_frames(frames);
+    }
+    fmp4.set_segment_index(options.segment_index);
+    if let Some((scheme, key_file)) = &options.encryption {
+        fmp4.set_encryption(Encryptor::from_key_file(key_file, *scheme)?);
+    }
+    let mut video = options.video.clone();
+    // the colour description follows the conversion unless given explicitly
+    video.color = video.color.or(Some(ColorInfo::from_yuv(options.conversion.matrix, options.conversion.range, options.conversion.transfer)));
+    fmp4.set_video_properties(video);
+    if options.keep_alpha {
+        fmp4.add_alpha_track();
+    }
+    fmp4.set_metadata(Metadata {
+        title: options.title.clone(),
+        comment: options.comment.clone(),
+        custom: options.custom_metadata.clone(),
+        ..Metadata::now()
+    });
+    let events = options.events.as_ref().map(|path| read_events(path, fmp4.track().timescale)).transpose()?.unwrap_or_default();
+    let audio = options.opus.as_ref().map(|path| OggOpusReader::new(File::open(path)?)).transpose()?;
+    if let Some(audio) = &audio {
+        fmp4.add_opus_track(audio.head().clone());
+    }
+    if let Some(format) = &options.events_track {
+        fmp4.add_metadata_track(format.clone());
+    }
+    if let Some(delay) = options.delay {
+        // in the movie timescale, which is the one of the video
+        let delay = (delay.as_secs_f64() * f64::from(fmp4.track().timescale)).round() as u64;
+        for track in fmp4.tracks() {
+            let edits = match track.edits.is_empty() {
+                true => vec![Edit::skip(0)],
+                false => track.edits,
+            };
+            fmp4.set_edit_list(track.id, std::iter::once(Edit::empty(delay)).chain(edits).collect());
+        }
+    }
+
+    match &options.single_file {
+        Some(output) => record_single_file(fmp4, output, &options, audio, events),
+        None => {
+            let mut sink = SegmentFiles::create(OUTPUT_DIR)?;
+            sink.set_group_chunks(options.cmaf_chunk_frames.is_some());
+            record(&mut fmp4, &mut sink, IVF_NAME, &options, audio, events)
+        }
+    }
+}
+
+/// Options of the recording mode.
+#[derive(Default)]
+struct Options {
+    /// `--single-file <output.mp4>`
+    single_file: Option<String>,
+    /// `--cmaf <frames per chunk>`
+    cmaf_chunk_frames: Option<usize>,
+    /// `--segment-index`, a `sidx` in front of every segment
+    segment_index: bool,
+    /// `--opus <input.opus>`, an Ogg Opus file muxed as audio track
+    opus: Option<String>,
+    /// `--events <events.txt>`, see `read_events`
+    events: Option<String>,
+    /// `--events-track <mime type|uri>`, carries the events as samples of a `mett` (e.g.
+    /// `text/plain`) or `urim` (a URI with a scheme, e.g. `urn:...`) metadata track instead of `emsg`
+    events_track: Option<MetadataFormat>,
+    /// `--encrypt <cenc|cbcs> <key file>`, see `Encryptor::from_key_file`
+    encryption: Option<(cenc::Scheme, String)>,
+    /// `--title <title>`
+    title: Option<String>,
+    /// `--comment <comment>`
+    comment: Option<String>,
+    /// `--meta <key>=<value>`, repeatable
+    custom_metadata: Vec<(String, String)>,
+    /// `--rotate <0|90|180|270>`, `--flip`, `--par <h>:<v>`, `--clap <w>x<h>+<x>+<y>`,
+    /// `--color <primaries>,<transfer>,<matrix>[,full]`, `--bit-depth <8|10|12>`, `--chroma <420|422|444>`
+    video: VideoProperties,
+    /// `--matrix <bt601|bt709|bt2020>`, `--range <full|limited>`, `--chroma-siting <left|center>`,
+    /// `--alpha <drop|rrggbb>`, `--transfer <sdr|pq|hlg>`, `--linear`, `--threads <n>`, also used
+    /// by the encoder
+    conversion: ConvertOptions,
+    /// `--keep-alpha`, encodes the alpha channel as a second VP9 stream, muxed into a WebM next
+    /// to the IVF and into an auxiliary fMP4 track
+    keep_alpha: bool,
+    /// `--resize <stretch|fit|letterbox|fill>`, `--filter <bilinear|bicubic|lanczos>`, `--pad <rrggbb>`,
+    /// for frames of another size than the video
+    resize: ResizeOptions,
+    /// `--crop <w>x<h>+<x>+<y>`, the part of the input pictures kept, before resizing
+    crop: Option<Rect>,
+    /// `--overlay <image.png> <x>,<y>[,<opacity>]`, a picture put over every frame
+    overlay: Option<Overlay>,
+    /// `--overlay-size <w>x<h>`, the overlay scaled with `--filter` rather than at its own size
+    overlay_size: Option<(u32, u32)>,
+    /// `--fps <num>[/<den>]`, 30 by default
+    frame_rate: FrameRate,
+    /// `--capture-time`, times frames by when they were captured rather than by the frame rate
+    capture_time: bool,
+    /// `--delay <seconds>`, an empty edit in front of every track
+    delay: Option<Duration>,
+    /// `--poster <poster.png>`, the first frame as it goes into the encoder, back in RGB
+    poster: Option<String>,
+}
+
+/// A picture blended over every frame, see `yuv_ops::blend`.
+struct Overlay {
+    path: String,
+    x: u32,
+    y: u32,
+    /// out of 255
+    opacity: u8,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> anyhow::Result<Self> {
+        let mut options = Self::default();
+        options.conversion.threads = num_cpus::get();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow::anyhow!("missing value for {}", arg));
+            match arg.as_str() {
+                "--single-file" => options.single_file = Some(value()?.clone()),
+                "--cmaf" => options.cmaf_chunk_frames = Some(value()?.parse()?),
+                "--segment-index" => options.segment_index = true,
+                "--opus" => options.opus = Some(value()?.clone()),
+                "--events" => options.events = Some(value()?.clone()),
+                "--events-track" => {
+                    let format = value()?;
+                    options.events_track = Some(match format.contains(':') {
+                        true => MetadataFormat::Uri { uri: format.clone() },
+                        false => MetadataFormat::Text { mime_type: format.clone() },
+                    });
+                }
+                "--encrypt" => options.encryption = Some((value()?.parse()?, value()?.clone())),
+                "--title" => options.title = Some(value()?.clone()),
+                "--comment" => options.comment = Some(value()?.clone()),
+                "--meta" => {
+                    let entry = value()?;
+                    let (key, value) = entry.split_once('=').ok_or_else(|| anyhow::anyhow!("expected --meta <key>=<value>, got {}", entry))?;
+                    options.custom_metadata.push((key.to_string(), value.to_string()));
+                }
+                "--rotate" => options.video.rotation = value()?.parse()?,
+                "--flip" => options.video.flip = true,
+                "--par" => options.video.pixel_aspect_ratio = value()?.parse()?,
+                "--clap" => options.video.clean_aperture = Some(value()?.parse()?),
+                "--color" => options.video.color = Some(value()?.parse()?),
+                "--bit-depth" => options.video.sample_format.bit_depth = value()?.parse()?,
+                "--chroma" => options.video.sample_format.chroma = value()?.parse()?,
+                "--matrix" => options.conversion.matrix = value()?.parse()?,
+                "--range" => options.conversion.range = value()?.parse()?,
+                "--chroma-siting" => options.conversion.siting = value()?.parse()?,
+                "--alpha" => options.conversion.alpha = value()?.parse()?,
+                "--transfer" => options.conversion.transfer = value()?.parse()?,
+                "--linear" => options.conversion.linear_input = true,
+                "--keep-alpha" => options.keep_alpha = true,
+                "--resize" => options.resize.mode = value()?.parse()?,
+                "--filter" => options.resize.filter = value()?.parse()?,
+                "--pad" => options.resize.padding = parse_color(value()?)?,
+                "--crop" => options.crop = Some(value()?.parse()?),
+                "--overlay" => {
+                    let path = value()?.clone();
+                    let position = value()?;
+                    let invalid = || anyhow::anyhow!("expected --overlay <image.png> <x>,<y>[,<opacity 0-255>], got {}", position);
+                    let (x, y, opacity) = match position.split(',').collect::<Vec<_>>()[..] {
+                        [x, y] => (x, y, "255"),
+                        [x, y, opacity] => (x, y, opacity),
+                        _ => return Err(invalid()),
+                    };
+                    options.overlay = Some(Overlay {
+                        path,
+                        x: x.parse().map_err(|_| invalid())?,
+                        y: y.parse().map_err(|_| invalid())?,
+                        opacity: opacity.parse().map_err(|_| invalid())?,
+                    });
+                }
+                "--overlay-size" => {
+                    let size = value()?;
+                    let invalid = || anyhow::anyhow!("expected --overlay-size <w>x<h>, got {}", size);
+                    let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+                    let (width, height) = (width.parse::<u32>().map_err(|_| invalid())?, height.parse::<u32>().map_err(|_| invalid())?);
+                    if width == 0 || height == 0 {
+                        return Err(invalid());
+                    }
+                    options.overlay_size = Some((width, height));
+                }
+                "--fps" => options.frame_rate = value()?.parse()?,
+                "--capture-time" => options.capture_time = true,
+                "--delay" => options.delay = Some(Duration::try_from_secs_f64(value()?.parse()?)?),
+                "--threads" => options.conversion.threads = value()?.parse::<usize>()?.max(1),
+                "--poster" => options.poster = Some(value()?.clone()),
+                _ => anyhow::bail!("unknown option {}", arg),
+            }
+        }
+        let format = options.video.sample_format;
+        if !matches!(format.bit_depth, 8 | 10 | 12) {
+            anyhow::bail!("unsupported bit depth {}, expected 8, 10 or 12", format.bit_depth);
+        }
+        // the 8-bit path keeps to 4:2:0 and doesn't know about transfer functions
+        let plain = format.chroma == ChromaSubsampling::Yuv420 && options.conversion.transfer == Transfer::Sdr && !options.conversion.linear_input;
+        if format.bit_depth == 8 && !plain {
+            anyhow::bail!("--chroma, --transfer and --linear need --bit-depth 10 or 12");
+        }
+        // a CMAF track file carries exactly one track
+        if options.cmaf_chunk_frames.is_some() && (options.opus.is_some() || options.keep_alpha || options.events_track.is_some()) {
+            anyhow::bail!("--cmaf cannot be combined with --opus, --keep-alpha or --events-track, CMAF allows one track per file");
+        }
+        // the alpha track would go out in the clear
+        if options.encryption.is_some() && options.keep_alpha {
+            anyhow::bail!("--encrypt cannot be combined with --keep-alpha");
+        }
+        if options.events_track.is_some() && options.events.is_none() {
+            anyhow::bail!("--events-track needs --events");
+        }
+        if options.poster.is_some() && format.bit_depth != 8 {
+            anyhow::bail!("--poster needs --bit-depth 8");
+        }
+        if options.overlay_size.is_some() && options.overlay.is_none() {
+            anyhow::bail!("--overlay-size needs --overlay");
+        }
+        // the alpha, and the chroma of 4:2:0 and 4:2:2, come in samples of 2 pixels
+        let even = |x: u32, y: u32| x.is_multiple_of(2) && y.is_multiple_of(2);
+        if !options.crop.is_none_or(|crop| even(crop.x, crop.y)) || !options.overlay.as_ref().is_none_or(|o| even(o.x, o.y)) {
+            anyhow::bail!("--crop and --overlay need an even x and y");
+        }
+        // chunks, segments and the alpha track are timed by the frame rate
+        if options.capture_time && (options.cmaf_chunk_frames.is_some() || options.segment_index || options.keep_alpha) {
+            anyhow::bail!("--capture-time cannot be combined with --cmaf, --segment-index or --keep-alpha");
+        }
+        Ok(options)
+    }
+}
+
+/// `img2vp9 --single-file <output.mp4>`, also writes `<output>.m3u8` and `<output>.mpd`
+fn record_single_file(mut fmp4: Fmp4, output: &str, options: &Options, audio: Option<OggOpusReader<File>>,
+                      events: Vec<EventMessage>) -> anyhow::Result<()> {
+    let path = Path::new(output);
+    fmp4.set_random_access_index(true);
+
+    let mut sink = SingleFile::create(path, segment_count(options.frame_rate))?;
+    record(&mut fmp4, &mut sink, &path.with_extension("ivf").to_string_lossy(), options, audio, events)?;
+    let index = sink.finish(&fmp4)?;
+
+    let uri = path.file_name().map(|x| x.to_string_lossy()).unwrap_or_default();
+    std::fs::write(path.with_extension("m3u8"), manifest::hls_playlist(&index, &uri))?;
+    std::fs::write(path.with_extension("mpd"), manifest::dash_mpd(&index, &uri, &fmp4.tracks(), fmp4.frame_rate(), fmp4.events()))?;
+    Ok(())
+}
+
+/// `img2vp9 remux <input.ivf> <output.mp4>`
+fn remux(input: &str, output: &str) -> anyhow::Result<()> {
+    let mut file = File::create(output)?;
+    let count = ivf_to_fmp4(File::open(input)?, &mut file)?;
+    println!("remuxed {} frames into {}", count, output);
+    Ok(())
+}
+
+/// One event per line, `<start seconds> <duration seconds> <message>`, `#` starts a comment.
+///
+/// Events are sent as `emsg` with the `urn:img2vp9:marker` scheme, times in `timescale`.
+fn read_events(path: &str, timescale: u32) -> anyhow::Result<Vec<EventMessage>> {
+    let mut events = vec![];
+    for (i, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.splitn(3, char::is_whitespace);
+        let mut seconds = || -> anyhow::Result<f64> {
+            let field = fields.next().ok_or_else(|| anyhow::anyhow!("{}:{}: missing time", path, i + 1))?;
+            Ok(field.parse::<f64>()? * f64::from(timescale))
+        };
+        let (start, duration) = (seconds()?, seconds()?);
+        events.push(EventMessage {
+            scheme_id_uri: "urn:img2vp9:marker".to_string(),
+            value: String::new(),
+            presentation_time: start as u64,
+            duration: duration as u32,
+            id: events.len() as u32,
+            message_data: fields.next().unwrap_or_default().trim().as_bytes().to_vec(),
+        });
+    }
+    events.sort_by_key(|e| e.presentation_time);
+    Ok(events)
+}
+
+/// `img2vp9 trim <input.mp4> <output.mp4> <start seconds> [end seconds]`
+fn trim(input: &str, output: &str, start: f64, end: Option<f64>) -> anyhow::Result<()> {
+    let mut buffer = vec![];
+    File::open(input)?.read_to_end(&mut buffer)?;
+    let trimmed = trim::trim(&buffer, Duration::from_secs_f64(start), end.map(Duration::from_secs_f64))?;
+    std::fs::write(output, trimmed)?;
+    Ok(())
+}
+
+/// `img2vp9 probe <input.mp4>`
+fn probe(input: &str) -> anyhow::Result<()> {
+    let mut buffer = vec![];
+    File::open(input)?.read_to_end(&mut buffer)?;
+    let reader = Fmp4Reader::new(&buffer)?;
+    println!("brand={}", String::from_utf8_lossy(&reader.major_brand));
+    let samples = reader.samples()?;
+    for track in &reader.tracks {
+        let track_samples = samples.iter().filter(|s| s.track_id == track.id);
+        let (count, keys) = track_samples.clone().fold((0, 0), |(n, k), s| (n + 1, k + s.key as u32));
+        let end = track_samples.map(|s| s.dts + u64::from(s.duration)).max().unwrap_or(0);
+        println!("track #{}, handler={}, codec={}, {}x{}, samples={}, keys={}, duration={:.3}s",
+                 track.id,
+                 String::from_utf8_lossy(&track.handler),
+                 String::from_utf8_lossy(&track.codec),
+                 track.width, track.height, count, keys,
+                 end as f64 / f64::from(track.timescale.max(1)));
+    }
+    Ok(())
+}
+
+/// Frames from one forced key frame to the next, two seconds.
+fn key_frame_interval(frame_rate: FrameRate) -> u32 {
+    frame_rate.frames(2) as u32
+}
+
+/// Segments of a recording, one per forced key frame.
+fn segment_count(frame_rate: FrameRate) -> usize {
+    (FRAMES / key_frame_interval(frame_rate).max(1) + 1) as usize
+}
+
+/// Also writes `<ivf_name>.webm` with `--keep-alpha`.
+fn record(fmp4: &mut Fmp4, sink: &mut impl SegmentSink, ivf_name: &str, options: &Options,
+          mut audio: Option<OggOpusReader<File>>, events: Vec<EventMessage>) -> anyhow::Result<()> {
+    let (conversion, format) = (&options.conversion, options.video.sample_format);
+    let width = WIDTH;
+    let height = HEIGHT;
+    let frame_rate = options.frame_rate;
     let bitrate = 1920 * 2;
+    let (yuv_format, pixel_format) = match format.chroma {
+        ChromaSubsampling::Yuv420 => (YuvFormat::I420, vpx_encode::PixelFormat::I420),
+        ChromaSubsampling::Yuv422 => (YuvFormat::I422, vpx_encode::PixelFormat::I422),
+        ChromaSubsampling::Yuv444 => (YuvFormat::I444, vpx_encode::PixelFormat::I444),
+    };
 
-    let mut vpx = vpx_encode::Encoder::new(vpx_encode::Config {
+    let config = vpx_encode::Config {
         width: width,
         height: height,
         timebase: [1, 1000_000_000],
         bitrate: bitrate,
         codec: vpx_encode::VideoCodecId::VP9,
-        kf_max_dist: fps * 2,
+        kf_max_dist: key_frame_interval(frame_rate),
         quantizer: (32, 32),
-        threads: num_cpus::get() as _,
-    })?;
+        threads: conversion.threads as _,
+        color_space: match conversion.matrix {
+            ColorMatrix::Bt601 => vpx_encode::ColorSpace::Bt601,
+            ColorMatrix::Bt709 => vpx_encode::ColorSpace::Bt709,
+            ColorMatrix::Bt2020Ncl => vpx_encode::ColorSpace::Bt2020,
+        },
+        full_range: conversion.range == Range::Full,
+        pixel_format,
+        bit_depth: u32::from(format.bit_depth),
+    };
+    let mut vpx = vpx_encode::Encoder::new(config)?;
+    // the alpha stream is plain full-range 8-bit 4:2:0, its luma being the alpha
+    let mut alpha_vpx = options.keep_alpha.then(|| vpx_encode::Encoder::new(vpx_encode::Config {
+        color_space: vpx_encode::ColorSpace::Unknown,
+        full_range: true,
+        pixel_format: vpx_encode::PixelFormat::I420,
+        bit_depth: 8,
+        ..config
+    })).transpose()?;
     println!("created the encoder");
 
-    std::fs::remove_dir_all(OUTPUT_DIR).ok();
-    std::fs::create_dir(OUTPUT_DIR).ok();
-
-    let mut fmp4 = Fmp4::new(fps, width as _, height as _);
-    output_data(fmp4.init_segment(), false, HEADER_NAME);
+    sink.write_init(&fmp4.init_segment())?;
+    // keep the raw bitstream as well, for debugging and conformance tools
+    let mut ivf = IvfWriter::new(File::create(ivf_name)?, IvfHeader::new(width as _, height as _, [1, 1000_000_000]))?;
+    let mut webm = match options.keep_alpha {
+        true => {
+            let header = WebmHeader {
+                alpha: true,
+                color: fmp4.track().video.color,
+                ..WebmHeader::new(width as _, height as _, [1, 1000_000_000])
+            };
+            Some(WebmWriter::new(File::create(Path::new(ivf_name).with_extension("webm"))?, header)?)
+        }
+        false => None,
+    };
+    // audio samples (at 48 kHz) queued so far
+    let mut audio_time = 0u64;
+    let mut events = events.into_iter().peekable();
+    // end of the metadata samples queued so far, with `--events-track`
+    let mut metadata_time = 0u64;
+    // buffers reused for every frame, so long recordings don't keep the allocator busy
+    let (mut buffer, mut images) = (vec![], ImageBuffers::default());
+    let mut frames = FramePool::new(yuv_format, width, height, u32::from(format.bit_depth));
+    let mut alpha_frames = FramePool::new(YuvFormat::I420, width, height, 8);
+    let overlay = options.overlay.as_ref().map(|overlay| read_overlay(overlay, options, yuv_format, u32::from(format.bit_depth))).transpose()?;
+    let start = Instant::now();
     // Start recording.
-    for i in 0..1200 {
-        let buffer= read_image(i)?;
+    for i in 0..FRAMES {
+        read_image(i, &mut buffer)?;
 
         let now = Instant::now();
-        let yuv = convert_image(&buffer, width, height)?;
+        // in nanoseconds, the timebase of the encoder
+        let pts = match options.capture_time {
+            true => start.elapsed().as_nanos() as i64,
+            false => frame_rate.ticks(u64::from(i), 1_000_000_000) as i64,
+        };
+        // the frame is expected to last one frame interval
+        let end = pts as u64 + frame_rate.ticks(1, 1_000_000_000);
+        let end_in = |timescale: u32| (u128::from(end) * u128::from(timescale) / 1_000_000_000) as u64;
+        let mut yuv = frames.take();
+        let mut alpha = alpha_vpx.as_ref().map(|_| alpha_frames.take());
+        convert_image(&buffer, &mut images, options, options.crop, &mut yuv, alpha.as_mut())?;
+        if let (Some(picture), Some(overlay)) = (&overlay, &options.overlay) {
+            yuv_ops::blend(&mut yuv, picture, overlay.x, overlay.y, overlay.opacity);
+        }
+        if let (0, Some(poster)) = (i, &options.poster) {
+            write_poster(poster, &yuv, conversion)?;
+        }
+
+        // queue the audio up to the end of this frame, it goes out with the next fragment
+        if let Some(reader) = &mut audio {
+            while audio_time < end_in(opus::SAMPLE_RATE) {
+                match reader.next_packet()? {
+                    Some(packet) => {
+                        fmp4.push_audio(&packet.data, packet.duration);
+                        audio_time += u64::from(packet.duration);
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        // events starting before the end of this frame go out with the next fragment
+        let frame_end = end_in(fmp4.track().timescale);
+        match options.events_track {
+            Some(_) => queue_metadata(fmp4, &mut events, &mut metadata_time, frame_end),
+            None => {
+                while let Some(event) = events.next_if(|e| e.presentation_time < frame_end) {
+                    fmp4.push_event(event, 1);
+                }
+            }
+        }
 
-        for frame in vpx.encode(0i64, &yuv).unwrap() {
-            output_data(fmp4.wrap_frame(frame.data, frame.key), frame.key, &format!("{}_{}.m4s", SEGMENT_PREFIX, i));
+        for frame in vpx.encode_planes(pts, yuv.planes(), yuv.strides()).unwrap() {
+            ivf.write_frame(&frame)?;
+            // one packet per picture, so the alpha of the same picture goes along
+            let alpha_data = match (&mut alpha_vpx, &alpha) {
+                (Some(encoder), Some(alpha)) => {
+                    // decoders of the alpha start where the colour does
+                    if frame.key {
+                        encoder.force_key_frame();
+                    }
+                    let packet = encoder.encode_planes(pts, alpha.planes(), alpha.strides())?.next()
+                        .ok_or_else(|| anyhow::anyhow!("no alpha packet for frame {}", i))?;
+                    Some(packet.data.to_vec())
+                }
+                _ => None,
+            };
+            if let Some(webm) = &mut webm {
+                webm.write_frame(frame.data, alpha_data.as_deref(), frame.pts, frame.key)?;
+            }
+            if options.capture_time {
+                let data = fmp4.wrap_frame_at(frame.data, frame.key, Duration::from_nanos(frame.pts as u64))?;
+                sink.write_segment(&data, frame.key)?;
+                continue;
+            }
+            let chunks = match &alpha_data {
+                Some(alpha) => fmp4.push_frame_with_alpha(frame.data, alpha, frame.key),
+                None => fmp4.push_frame(frame.data, frame.key),
+            }?;
+            for chunk in chunks {
+                sink.write_segment(&chunk.data, chunk.independent)?;
+            }
+        }
+        frames.put(yuv);
+        if let Some(alpha) = alpha {
+            alpha_frames.put(alpha);
         }
         println!("#{}, cost={}", i, now.elapsed().as_millis());
     }
@@ -57,38 +546,303 @@ fn main() -> anyhow::Result<()> {
     while let Some(_frame) = frames.next().unwrap() {
         println!("WARNING, frame after finishing");
     }
+    if let Some(alpha_vpx) = alpha_vpx {
+        let mut frames = alpha_vpx.finish().unwrap();
+        while let Some(_frame) = frames.next().unwrap() {
+            println!("WARNING, alpha frame after finishing");
+        }
+    }
+    if let Some(chunk) = fmp4.flush()? {
+        sink.write_segment(&chunk.data, chunk.independent)?;
+    }
+    ivf.finish()?;
+    if let Some(webm) = webm {
+        webm.finish()?;
+    }
 
     Ok(())
 }
 
-fn read_image(i: u32) -> anyhow::Result<Vec<u8>> {
-    let mut buffer = vec![];
-    File::open(format!("./frames/frame{}.png", 1 + i))?.read_to_end(&mut buffer)?;
-    Ok(buffer)
+/// Queues metadata samples up to `end`: the message of each event while it lasts, until the
+/// next one starts, and empty samples in between.
+fn queue_metadata(fmp4: &mut Fmp4, events: &mut std::iter::Peekable<vec::IntoIter<EventMessage>>, time: &mut u64, end: u64) {
+    while *time < end {
+        let (data, sample_end) = match events.next_if(|e| e.presentation_time <= *time) {
+            Some(event) => {
+                let next_start = events.peek().map_or(u64::MAX, |e| e.presentation_time);
+                let sample_end = next_start.min(event.presentation_time + u64::from(event.duration));
+                if sample_end <= *time {
+                    // already over
+                    continue;
+                }
+                (event.message_data, sample_end)
+            }
+            None => (vec![], events.peek().map_or(end, |e| e.presentation_time.min(end))),
+        };
+        let duration = (sample_end - *time).min(u64::from(u32::MAX));
+        fmp4.push_metadata(&data, duration as u32);
+        *time += duration;
+    }
 }
 
-fn convert_image(image_bytes: &[u8], out_width: u32, out_height: u32) -> anyhow::Result<Vec<u8>> {
-    let reader = Cursor::new(image_bytes);
-    let mut raw_img = image::io::Reader::with_format(reader, ImageFormat::Png).decode()?;
-    // resize image, it costs a lot of time
-    if (out_width, out_height) != (raw_img.width(), raw_img.height()) {
-        // println!("wh={},{}", raw_img.width(), raw_img.height());
-        raw_img = raw_img.resize_exact(out_width as _, out_height as _, FilterType::Nearest);
+/// Reads frame `i` into `buffer`, replacing its content. 16-bit frames may be TIFF instead of PNG.
+fn read_image(i: u32, buffer: &mut Vec<u8>) -> anyhow::Result<()> {
+    buffer.clear();
+    let path = format!("./frames/frame{}.png", 1 + i);
+    let mut file = match File::open(&path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => File::open(path.replace(".png", ".tif"))?,
+        file => file?,
+    };
+    file.read_to_end(buffer)?;
+    Ok(())
+}
+
+/// Buffers of `convert_image`, kept from one frame to the next.
+#[derive(Default)]
+struct ImageBuffers {
+    /// the decoded pixels
+    rgb: Vec<u8>,
+    /// the picture and its alpha at their own size, when that isn't the size of the video
+    source: Option<YuvFrame>,
+    source_alpha: Option<YuvFrame>,
+}
+
+/// The frame in `slot`, made again when it isn't `width`x`height` in `format` at `bit_depth`.
+fn reusable_frame(slot: &mut Option<YuvFrame>, format: YuvFormat, width: u32, height: u32, bit_depth: u32) -> &mut YuvFrame {
+    let reusable = |f: &YuvFrame| (f.format(), f.width(), f.height(), f.bit_depth()) == (format, width, height, bit_depth);
+    if !slot.as_ref().is_some_and(reusable) {
+        *slot = Some(YuvFrame::with_bit_depth(format, width, height, bit_depth));
     }
-    let img = raw_img.to_rgb8();
+    slot.as_mut().unwrap()
+}
 
-    let yuv = convert_rgb_to_yuv420p(img.as_ref(), img.width(), img.height(), 3);
-    Ok(yuv)
+/// The `--overlay` picture in `format` at `bit_depth`, scaled to `--overlay-size`.
+fn read_overlay(overlay: &Overlay, options: &Options, format: YuvFormat, bit_depth: u32) -> anyhow::Result<YuvFrame> {
+    let image_bytes = std::fs::read(&overlay.path)?;
+    let (width, height) = image::io::Reader::new(Cursor::new(&image_bytes)).with_guessed_format()?.into_dimensions()?;
+    let mut picture = YuvFrame::with_bit_depth(format, width, height, bit_depth);
+    convert_image(&image_bytes, &mut ImageBuffers::default(), options, None, &mut picture, None)?;
+    Ok(match options.overlay_size {
+        Some((width, height)) => yuv_ops::scale(&picture, width, height, options.resize.filter),
+        None => picture,
+    })
 }
 
-#[allow(unused)]
-fn output_data(bytes: Vec<u8>, _key: bool, filename: &str) {
-    // println!("[output_data] len={}, key={}", bytes.len(), key);
+/// Decodes a PNG or TIFF and converts it into `frame`, at its bit depth, and its alpha channel into
+/// `alpha`. Pictures of another size, or cropped to `crop`, are converted at their own size,
+/// then resized with `options.resize`.
+fn convert_image(image_bytes: &[u8], buffers: &mut ImageBuffers, options: &Options, crop: Option<Rect>, frame: &mut YuvFrame,
+                 alpha: Option<&mut YuvFrame>) -> anyhow::Result<()> {
+    let conversion = &options.conversion;
+    let rgb = &mut buffers.rgb;
+    let high_bit_depth = frame.bit_depth() > 8;
+    let (width, height, layout) = match image_bytes {
+        [b'I', b'I', 42, 0, ..] | [b'M', b'M', 0, 42, ..] => decode(TiffDecoder::new(Cursor::new(image_bytes))?, high_bit_depth, rgb)?,
+        _ => decode(PngDecoder::new(Cursor::new(image_bytes))?, high_bit_depth, rgb)?,
+    };
+
+    if let Some(crop) = crop {
+        let fits = |start: u32, size: u32, limit: u32| start.checked_add(size).is_some_and(|end| end <= limit);
+        if !fits(crop.x, crop.width, width) || !fits(crop.y, crop.height, height) {
+            anyhow::bail!("--crop {}x{}+{}+{} is outside of the {}x{} picture", crop.width, crop.height, crop.x, crop.y, width, height);
+        }
+    }
+    if crop.is_none() && (width, height) == (frame.width(), frame.height()) {
+        convert_rgb(rgb, layout, conversion, frame);
+        if let Some(alpha) = alpha {
+            convert_alpha_to_yuv420p_into(rgb, layout, alpha);
+        }
+        return Ok(());
+    }
 
-    let mut file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(filename)
-        .unwrap();
-    file.write_all(&bytes).unwrap();
+    // scaling the planes is cheaper than scaling the RGB, and keeps the bit depth
+    let cropped = |source: &YuvFrame| crop.map(|c| yuv_ops::crop(source, c.x, c.y, c.width, c.height));
+    let source = reusable_frame(&mut buffers.source, frame.format(), width, height, frame.bit_depth());
+    convert_rgb(rgb, layout, conversion, source);
+    let padding = convert_color(options.resize.padding, conversion, frame.bit_depth());
+    yuv_ops::resize_into(cropped(source).as_ref().unwrap_or(source), frame, &options.resize, padding);
+    if let Some(alpha) = alpha {
+        let source = reusable_frame(&mut buffers.source_alpha, YuvFormat::I420, width, height, 8);
+        convert_alpha_to_yuv420p_into(rgb, layout, source);
+        // transparent bars
+        yuv_ops::resize_into(cropped(source).as_ref().unwrap_or(source), alpha, &options.resize, [0, 128, 128]);
+    }
+    Ok(())
+}
+
+/// Writes an 8-bit `frame` as an RGB PNG, to see what the encoder is given.
+fn write_poster(path: &str, frame: &YuvFrame, conversion: &ConvertOptions) -> anyhow::Result<()> {
+    let rgb = convert_yuv_to_rgb(frame, PixelLayout::Rgb, conversion);
+    PngEncoder::new(File::create(path)?).encode(&rgb, frame.width(), frame.height(), ColorType::Rgb8)?;
+    Ok(())
+}
+
+/// Decodes into `pixels` in the layout of the picture, or in RGBA, 16-bit when `high_bit_depth`
+/// as 8 bits would band. Returns the size and the layout.
+fn decode<'a>(decoder: impl ImageDecoder<'a>, high_bit_depth: bool, pixels: &mut Vec<u8>) -> anyhow::Result<(u32, u32, PixelLayout)> {
+    let (width, height) = decoder.dimensions();
+    let layout = match decoder.color_type() {
+        ColorType::Rgb8 => PixelLayout::Rgb,
+        ColorType::Rgba8 => PixelLayout::Rgba,
+        ColorType::Bgr8 => PixelLayout::Bgr,
+        ColorType::Bgra8 => PixelLayout::Bgra,
+        ColorType::Rgb16 => PixelLayout::Rgb48,
+        ColorType::Rgba16 => PixelLayout::Rgba64,
+        ColorType::L8 => PixelLayout::Gray8,
+        ColorType::L16 => PixelLayout::Gray16,
+        // other pixel types go through `image`
+        _ => {
+            let img = DynamicImage::from_decoder(decoder)?;
+            pixels.clear();
+            return Ok(match high_bit_depth {
+                true => {
+                    pixels.extend_from_slice(img.to_rgba16().as_bytes());
+                    (width, height, PixelLayout::Rgba64)
+                }
+                false => {
+                    pixels.extend_from_slice(img.to_rgba8().as_ref());
+                    (width, height, PixelLayout::Rgba)
+                }
+            });
+        }
+    };
+    pixels.resize(decoder.total_bytes() as usize, 0);
+    decoder.read_image(pixels)?;
+    Ok((width, height, layout))
+}
+
+fn convert_rgb(img: &[u8], layout: PixelLayout, conversion: &ConvertOptions, frame: &mut YuvFrame) {
+    match frame.bit_depth() {
+        8 => convert_rgb_to_yuv420p_into(img, layout, conversion, frame),
+        _ => convert_rgb_to_yuv_high_bit_depth_into(img, layout, conversion, frame),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::tiff::TiffEncoder;
+
+    fn parse(args: &str) -> anyhow::Result<Options> {
+        Options::parse(&args.split_whitespace().map(String::from).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn cmaf_takes_a_single_track() {
+        assert!(parse("--cmaf 5").is_ok());
+        assert!(parse("--cmaf 5 --opus a.opus").is_err());
+        assert!(parse("--keep-alpha --cmaf 5").is_err());
+        assert!(parse("--opus a.opus --keep-alpha").is_ok());
+    }
+
+    #[test]
+    fn alpha_is_not_encrypted() {
+        assert!(parse("--encrypt cenc key.txt").is_ok());
+        assert!(parse("--encrypt cbcs key.txt --keep-alpha").is_err());
+    }
+
+    #[test]
+    fn events_fill_the_metadata_track() {
+        let mut fmp4 = Fmp4::new(30, 64, 48);
+        let track_id = fmp4.add_metadata_track(MetadataFormat::Text { mime_type: "text/plain".to_string() });
+        let event = |presentation_time, duration, data: &[u8]| EventMessage { presentation_time, duration, message_data: data.to_vec(), ..EventMessage::default() };
+        let mut events = vec![event(0, 100, b"a"), event(150, 1000, b"b"), event(200, 10, b"c"), event(400, 10, b"d")].into_iter().peekable();
+        let mut time = 0;
+        for end in [120, 300] {
+            queue_metadata(&mut fmp4, &mut events, &mut time, end);
+        }
+        let mut file = fmp4.init_segment();
+        file.extend(fmp4.wrap_frame(&[0x82], true).unwrap());
+
+        let samples = Fmp4Reader::new(&file).unwrap().samples().unwrap();
+        let samples = samples.iter().filter(|s| s.track_id == track_id).map(|s| (s.dts, s.duration, s.data)).collect::<Vec<_>>();
+        // gaps end with the frame, the next frame continues them
+        assert_eq!(samples, [(0, 100, &b"a"[..]), (100, 20, b""), (120, 30, b""), (150, 50, b"b"), (200, 10, b"c"), (210, 90, b"")]);
+        assert_eq!(events.next().map(|e| e.presentation_time), Some(400));
+    }
+
+    #[test]
+    fn key_frames_are_two_seconds_apart() {
+        assert_eq!(key_frame_interval(FrameRate::new(30, 1)), 60);
+        assert_eq!(key_frame_interval(FrameRate::new(30000, 1001)), 60);
+        assert_eq!(key_frame_interval(FrameRate::new(50, 1)), 100);
+        // the single file reserves room for one sidx reference per segment
+        assert_eq!(segment_count(FrameRate::new(30, 1)), 21);
+        assert_eq!(segment_count(FrameRate::new(1, 4)), 1201);
+    }
+
+    #[test]
+    fn delay_is_a_non_negative_duration() {
+        assert_eq!(parse("--delay 1.5").unwrap().delay, Some(Duration::from_millis(1500)));
+        assert!(parse("--delay -1").is_err());
+    }
+
+    #[test]
+    fn poster_shows_the_encoded_frame() {
+        assert!(parse("--poster poster.png --bit-depth 10").is_err());
+        let conversion = parse("--poster poster.png").unwrap().conversion;
+        let mut frame = YuvFrame::new(YuvFormat::I420, 6, 4);
+        convert_rgb_to_yuv420p_into(&[200, 100, 50].repeat(6 * 4), PixelLayout::Rgb, &conversion, &mut frame);
+
+        let path = std::env::temp_dir().join(format!("img2vp9-poster-{}.png", std::process::id()));
+        write_poster(&path.to_string_lossy(), &frame, &conversion).unwrap();
+        let poster = image::open(&path).unwrap().to_rgb8();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(poster.dimensions(), (6, 4));
+        for pixel in poster.pixels() {
+            let error = pixel.0.iter().zip([200, 100, 50]).map(|(a, b)| (i32::from(*a) - b).abs()).max();
+            assert!(error <= Some(2), "{:?}", pixel);
+        }
+    }
+
+    #[test]
+    fn decodes_16_bit_pictures_with_alpha() {
+        // mid gray, opaque on the left and transparent on the right
+        let rgba = [[32768, 32768, 32768, 65535], [32768, 32768, 32768, 0]].repeat(2).concat();
+        let rgba = rgba.iter().flat_map(|v: &u16| v.to_ne_bytes()).collect::<Vec<_>>();
+        let mut tiff = Cursor::new(vec![]);
+        TiffEncoder::new(&mut tiff).encode(&rgba, 2, 2, ColorType::Rgba16).unwrap();
+        let tiff = tiff.into_inner();
+        let mut pixels = vec![];
+        let decoded = decode(TiffDecoder::new(Cursor::new(&tiff)).unwrap(), true, &mut pixels).unwrap();
+        assert_eq!((decoded, pixels), ((2, 2, PixelLayout::Rgba64), rgba));
+
+        // gray with alpha is RGBA, 16-bit above 8 bits
+        let la = [[32768u16, 65535], [32768, 0]].repeat(2).concat();
+        let mut png = vec![];
+        // the PNG encoder takes the samples as they are stored, big-endian
+        let la_bytes = la.iter().flat_map(|v| v.to_be_bytes()).collect::<Vec<_>>();
+        PngEncoder::new(&mut png).encode(&la_bytes, 2, 2, ColorType::La16).unwrap();
+        let mut pixels = vec![];
+        assert_eq!(decode(PngDecoder::new(Cursor::new(&png)).unwrap(), false, &mut pixels).unwrap().2, PixelLayout::Rgba);
+        assert_eq!(pixels, [128, 128, 128, 255, 128, 128, 128, 0].repeat(2));
+
+        let options = parse("--bit-depth 10").unwrap();
+        for picture in [&tiff, &png] {
+            let mut frame = YuvFrame::with_bit_depth(YuvFormat::I420, 2, 2, 10);
+            let mut alpha = YuvFrame::new(YuvFormat::I420, 2, 2);
+            convert_image(picture, &mut ImageBuffers::default(), &options, None, &mut frame, Some(&mut alpha)).unwrap();
+            // limited range 64 + 876 / 2, little-endian
+            for row in frame.planes()[0].chunks(frame.strides()[0]) {
+                assert_eq!(row[..4], [246, 1, 246, 1]);
+            }
+            for row in alpha.planes()[0].chunks(alpha.strides()[0]) {
+                assert_eq!(row[..2], [255, 0]);
+            }
+        }
+    }
+
+    #[test]
+    fn crops_and_overlays_start_on_even_pixels() {
+        assert_eq!(parse("--crop 101x51+10+4").unwrap().crop, Some(Rect { x: 10, y: 4, width: 101, height: 51 }));
+        assert!(parse("--crop 100x50+3+4").is_err());
+        assert!(parse("--crop 0x50+0+0").is_err());
+        let overlay = parse("--overlay logo.png 20,10,128").unwrap().overlay.unwrap();
+        assert_eq!((overlay.path.as_str(), overlay.x, overlay.y, overlay.opacity), ("logo.png", 20, 10, 128));
+        assert_eq!(parse("--overlay logo.png 20,10").unwrap().overlay.unwrap().opacity, 255);
+        assert!(parse("--overlay logo.png 20,11").is_err());
+        assert!(parse("--overlay logo.png 20,10,256").is_err());
+        assert!(parse("--overlay-size 64x32").is_err());
+        assert_eq!(parse("--overlay logo.png 0,0 --overlay-size 64x32").unwrap().overlay_size, Some((64, 32)));
+    }
 }
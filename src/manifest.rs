@@ -0,0 +1,134 @@
+//!
+//! HLS and DASH manifests for a single-file recording written by `output::SingleFile`.
+//!
+use std::fmt::Write;
+
+use crate::fmp4::{EventMessage, FrameRate, Track};
+use crate::output::{ByteRange, FileIndex, SegmentRange};
+
+/// Media playlist addressing segments with `#EXT-X-BYTERANGE`.
+///
+/// Consecutive fragments are grouped into one segment per key frame, so every segment
+/// can be decoded on its own.
+pub fn hls_playlist(index: &FileIndex, uri: &str) -> String {
+    let segments = group_by_key_frame(&index.segments);
+    let timescale = f64::from(index.timescale);
+    let target_duration = segments.iter()
+        .map(|(_, duration)| (*duration as f64 / timescale).ceil() as u64)
+        .max()
+        .unwrap_or(1);
+
+    let mut m3u8 = String::new();
+    writeln!(m3u8, "#EXTM3U").unwrap();
+    writeln!(m3u8, "#EXT-X-VERSION:7").unwrap();
+    writeln!(m3u8, "#EXT-X-TARGETDURATION:{}", target_duration).unwrap();
+    writeln!(m3u8, "#EXT-X-PLAYLIST-TYPE:VOD").unwrap();
+    writeln!(m3u8, "#EXT-X-INDEPENDENT-SEGMENTS").unwrap();
+    writeln!(m3u8, "#EXT-X-MAP:URI=\"{}\",BYTERANGE=\"{}@{}\"", uri, index.init.length, index.init.offset).unwrap();
+    for (range, duration) in segments {
+        writeln!(m3u8, "#EXTINF:{:.5},", duration as f64 / timescale).unwrap();
+        writeln!(m3u8, "#EXT-X-BYTERANGE:{}@{}", range.length, range.offset).unwrap();
+        writeln!(m3u8, "{}", uri).unwrap();
+    }
+    writeln!(m3u8, "#EXT-X-ENDLIST").unwrap();
+    m3u8
+}
+
+/// On-demand profile MPD with a `SegmentBase` pointing at the top-level `sidx`.
+///
+/// `tracks` are multiplexed in one representation, the first one being the video track.
+/// The schemes of `events` are announced as in-band event streams, carried by `emsg` boxes.
+pub fn dash_mpd(index: &FileIndex, uri: &str, tracks: &[Track], frame_rate: FrameRate, events: &[EventMessage]) -> String {
+    let track = &tracks[0];
+    let codecs = tracks.iter().map(|t| t.codecs()).collect::<Vec<_>>().join(",");
+    let duration = index.segments.iter().map(|s| u64::from(s.duration)).sum::<u64>() as f64 / f64::from(index.timescale);
+    let size = index.segments.iter().map(|s| s.range.length).sum::<u64>();
+    let bandwidth = if duration > 0.0 { (size as f64 * 8.0 / duration) as u64 } else { 0 };
+
+    let mut mpd = String::new();
+    writeln!(mpd, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+    writeln!(mpd, r#"<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" profiles="urn:mpeg:dash:profile:isoff-on-demand:2011" type="static" mediaPresentationDuration="PT{:.3}S" minBufferTime="PT2S">"#, duration).unwrap();
+    writeln!(mpd, r#"  <Period>"#).unwrap();
+    // the sidx has a subsegment per key frame, only the first one may start without
+    let start_with_sap = match index.segments.first() {
+        Some(s) if !s.key => "",
+        _ => r#" startWithSAP="1""#,
+    };
+    writeln!(mpd, r#"    <AdaptationSet mimeType="video/mp4" segmentAlignment="true"{}>"#, start_with_sap).unwrap();
+    let mut schemes = events.iter().map(|e| (&e.scheme_id_uri, &e.value)).collect::<Vec<_>>();
+    schemes.sort();
+    schemes.dedup();
+    for (scheme_id_uri, value) in schemes {
+        writeln!(mpd, r#"      <InbandEventStream schemeIdUri="{}" value="{}"/>"#, xml_escape(scheme_id_uri), xml_escape(value)).unwrap();
+    }
+    writeln!(mpd, r#"      <Representation id="{}" codecs="{}" width="{}" height="{}" frameRate="{}/{}" bandwidth="{}">"#,
+             track.id, codecs, track.width, track.height, frame_rate.num, frame_rate.den, bandwidth).unwrap();
+    writeln!(mpd, r#"        <BaseURL>{}</BaseURL>"#, xml_escape(uri)).unwrap();
+    writeln!(mpd, r#"        <SegmentBase timescale="{}" indexRange="{}-{}">"#, index.timescale, index.index.offset, index.index.last()).unwrap();
+    writeln!(mpd, r#"          <Initialization range="{}-{}"/>"#, index.init.offset, index.init.last()).unwrap();
+    writeln!(mpd, r#"        </SegmentBase>"#).unwrap();
+    writeln!(mpd, r#"      </Representation>"#).unwrap();
+    writeln!(mpd, r#"    </AdaptationSet>"#).unwrap();
+    writeln!(mpd, r#"  </Period>"#).unwrap();
+    writeln!(mpd, r#"</MPD>"#).unwrap();
+    mpd
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// byte range and duration of each run of fragments starting with a key frame
+fn group_by_key_frame(segments: &[SegmentRange]) -> Vec<(ByteRange, u64)> {
+    let mut groups: Vec<(ByteRange, u64)> = vec![];
+    for s in segments {
+        match groups.last_mut() {
+            Some((range, duration)) if !s.key => {
+                range.length = s.range.offset + s.range.length - range.offset;
+                *duration += u64::from(s.duration);
+            }
+            _ => groups.push((s.range, u64::from(s.duration))),
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fmp4::Fmp4;
+
+    fn index(keys: &[bool]) -> FileIndex {
+        FileIndex {
+            init: ByteRange { offset: 0, length: 100 },
+            index: ByteRange { offset: 100, length: 40 + 12 * keys.len() as u64 },
+            segments: keys.iter().enumerate().map(|(i, &key)| SegmentRange {
+                range: ByteRange { offset: 200 + 50 * i as u64, length: 50 },
+                duration: 3000,
+                key,
+            }).collect(),
+            timescale: 90000,
+        }
+    }
+
+    #[test]
+    fn mpd_escapes_the_base_url() {
+        let tracks = Fmp4::new(30, 64, 48).tracks();
+        let mpd = dash_mpd(&index(&[true, false]), "a&b <1>.mp4", &tracks, 30.into(), &[]);
+        assert!(mpd.contains("<BaseURL>a&amp;b &lt;1&gt;.mp4</BaseURL>"), "{}", mpd);
+    }
+
+    #[test]
+    fn mpd_announces_sap_only_when_segments_start_with_key_frames() {
+        let tracks = Fmp4::new(30, 64, 48).tracks();
+        assert!(dash_mpd(&index(&[true, false, true]), "a.mp4", &tracks, 30.into(), &[]).contains(r#"startWithSAP="1""#));
+        assert!(!dash_mpd(&index(&[false, true]), "a.mp4", &tracks, 30.into(), &[]).contains("startWithSAP"));
+    }
+
+    #[test]
+    fn hls_segments_start_at_key_frames() {
+        let m3u8 = hls_playlist(&index(&[true, false, false, true]), "a.mp4");
+        let ranges = m3u8.lines().filter(|l| l.starts_with("#EXT-X-BYTERANGE")).collect::<Vec<_>>();
+        assert_eq!(ranges, ["#EXT-X-BYTERANGE:150@200", "#EXT-X-BYTERANGE:50@350"]);
+    }
+}
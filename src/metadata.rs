@@ -0,0 +1,267 @@
+//!
+//! Descriptive metadata of a recording, written into the `moov` of the init segment.
+//!
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+
+use crate::bmff::{BoxRef, BoxWriter};
+
+/// seconds from 1904-01-01, the MP4 epoch, to 1970-01-01
+const MP4_EPOCH_OFFSET: u64 = 2_082_844_800;
+
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    /// creation and modification time of the movie and its tracks, 0 (unknown) if `None`
+    pub creation_time: Option<SystemTime>,
+    /// `©nam`
+    pub title: Option<String>,
+    /// `©ART`
+    pub author: Option<String>,
+    /// `©cmt`
+    pub comment: Option<String>,
+    /// `©too`
+    pub encoder: Option<String>,
+    /// free-form entries with reverse-DNS keys, e.g. `com.example.recording-id`,
+    /// written as a `mdta` `meta` box with a `keys` list
+    pub custom: Vec<(String, String)>,
+}
+
+impl Metadata {
+    /// Creation time now, encoder `img2vp9 <version>`.
+    pub fn now() -> Self {
+        Self {
+            creation_time: Some(SystemTime::now()),
+            encoder: Some(concat!("img2vp9 ", env!("CARGO_PKG_VERSION")).to_string()),
+            ..Self::default()
+        }
+    }
+
+    /// `creation_time` in seconds since 1904-01-01
+    pub fn mp4_time(&self) -> u64 {
+        self.creation_time
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs() + MP4_EPOCH_OFFSET)
+    }
+}
+
+/// Converts seconds since 1904-01-01, as found in `mvhd`, to a `SystemTime`.
+pub fn from_mp4_time(seconds: u64) -> Option<SystemTime> {
+    seconds.checked_sub(MP4_EPOCH_OFFSET).map(|s| UNIX_EPOCH + Duration::from_secs(s))
+}
+
+/// `udta` with an iTunes-style `meta`, and a `meta` with the custom entries, in `moov`.
+pub fn write(w: &mut BoxWriter, metadata: &Metadata) {
+    let items = [
+        (b"\xa9nam", &metadata.title),
+        (b"\xa9ART", &metadata.author),
+        (b"\xa9cmt", &metadata.comment),
+        (b"\xa9too", &metadata.encoder),
+    ];
+    let items = items.iter()
+        .filter_map(|(key, value)| value.as_ref().map(|v| (*key, v)))
+        .collect::<Vec<_>>();
+    if !items.is_empty() {
+        w.boxed(b"udta", |w| {
+            meta(w, b"mdir", |w| {
+                w.boxed(b"ilst", |w| {
+                    for (key, value) in items {
+                        w.boxed(key, |w| data(w, value));
+                    }
+                });
+            });
+        });
+    }
+
+    if !metadata.custom.is_empty() {
+        meta(w, b"mdta", |w| {
+            w.full_box(b"keys", 0, 0, |w| {
+                w.u32(metadata.custom.len() as u32); // entry_count
+                for (key, _) in &metadata.custom {
+                    w.u32(8 + key.len() as u32) // key_size
+                        .fourcc(b"mdta") // key_namespace
+                        .bytes(key.as_bytes());
+                }
+            });
+            w.boxed(b"ilst", |w| {
+                // items are named by their 1-based index in keys
+                for (i, (_, value)) in metadata.custom.iter().enumerate() {
+                    w.boxed(&(i as u32 + 1).to_be_bytes(), |w| data(w, value));
+                }
+            });
+        });
+    }
+}
+
+/// The entries `write` puts into `moov`, other items are skipped. The creation time is left
+/// to `mvhd`.
+pub fn read(moov: &BoxRef) -> anyhow::Result<Metadata> {
+    let mut metadata = Metadata::default();
+    for b in moov.reader().boxes()? {
+        match &b.box_type {
+            b"udta" => {
+                for meta in b.reader().boxes()?.iter().filter(|b| &b.box_type == b"meta") {
+                    for (key, value) in items(meta)? {
+                        let field = match &key {
+                            b"\xa9nam" => &mut metadata.title,
+                            b"\xa9ART" => &mut metadata.author,
+                            b"\xa9cmt" => &mut metadata.comment,
+                            b"\xa9too" => &mut metadata.encoder,
+                            _ => continue,
+                        };
+                        *field = Some(value);
+                    }
+                }
+            }
+            b"meta" => {
+                let keys = meta_boxes(&b)?.into_iter().find(|b| &b.box_type == b"keys");
+                let mut names = vec![];
+                if let Some(keys) = keys {
+                    let mut r = keys.reader();
+                    r.full_box_header()?;
+                    for _ in 0..r.u32()? {
+                        let size = r.u32()? as usize;
+                        r.fourcc()?; // key_namespace
+                        let name = r.bytes(size.checked_sub(8).context("invalid key size")?)?;
+                        names.push(String::from_utf8_lossy(name).into_owned());
+                    }
+                }
+                for (index, value) in items(&b)? {
+                    // items are named by their 1-based index in keys
+                    let index = u32::from_be_bytes(index) as usize;
+                    if let Some(name) = index.checked_sub(1).and_then(|i| names.get(i)) {
+                        metadata.custom.push((name.clone(), value));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(metadata)
+}
+
+/// children of a `meta` box, a full box
+fn meta_boxes<'a>(meta: &BoxRef<'a>) -> anyhow::Result<Vec<BoxRef<'a>>> {
+    let mut r = meta.reader();
+    r.full_box_header()?;
+    r.boxes()
+}
+
+/// name and UTF-8 `data` of the items in the `ilst` of a `meta`
+fn items(meta: &BoxRef) -> anyhow::Result<Vec<([u8; 4], String)>> {
+    let mut items = vec![];
+    for ilst in meta_boxes(meta)?.iter().filter(|b| &b.box_type == b"ilst") {
+        for item in ilst.reader().boxes()? {
+            let data = item.reader().boxes()?.into_iter().find(|b| &b.box_type == b"data");
+            if let Some(data) = data {
+                let mut r = data.reader();
+                let (type_indicator, _locale) = (r.u32()?, r.u32()?);
+                if type_indicator == 1 {
+                    items.push((item.box_type, String::from_utf8_lossy(r.bytes(r.remaining())?).into_owned()));
+                }
+            }
+        }
+    }
+    Ok(items)
+}
+
+fn meta<F: FnOnce(&mut BoxWriter)>(w: &mut BoxWriter, handler_type: &[u8; 4], f: F) {
+    w.full_box(b"meta", 0, 0, |w| {
+        w.full_box(b"hdlr", 0, 0, |w| {
+            w.u32(0) // pre_defined
+                .fourcc(handler_type)
+                .zeros(12) // reserved
+                .u8(0); // empty name
+        });
+        f(w);
+    });
+}
+
+/// value of a metadata item
+fn data(w: &mut BoxWriter, value: &str) {
+    w.boxed(b"data", |w| {
+        w.u32(1) // type indicator, UTF-8
+            .u32(0) // locale, default
+            .bytes(value.as_bytes());
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmff::BoxReader;
+
+    /// `moov` holding what `f` writes.
+    fn moov<F: FnOnce(&mut BoxWriter)>(f: F) -> Vec<u8> {
+        let mut w = BoxWriter::new();
+        w.boxed(b"moov", f);
+        w.into_bytes()
+    }
+
+    fn read_moov(data: &[u8]) -> anyhow::Result<Metadata> {
+        read(&BoxReader::new(data).next_box()?.unwrap())
+    }
+
+    #[test]
+    fn items_round_trip() {
+        let metadata = Metadata {
+            creation_time: Some(SystemTime::now()),
+            title: Some("Title".to_string()),
+            author: Some("Ünïcödé".to_string()),
+            comment: Some(String::new()),
+            encoder: Some("img2vp9 test".to_string()),
+            custom: vec![
+                ("com.example.recording-id".to_string(), "42".to_string()),
+                ("com.example.camera".to_string(), "front door".to_string()),
+            ],
+        };
+        let data = moov(|w| write(w, &metadata));
+        let boxes = BoxReader::new(&data).next_box().unwrap().unwrap().reader().boxes().unwrap();
+        assert_eq!(boxes.iter().map(|b| b.type_str()).collect::<Vec<_>>(), ["udta", "meta"]);
+
+        let read = read_moov(&data).unwrap();
+        // the creation time goes into mvhd
+        assert_eq!(read.creation_time, None);
+        assert_eq!((read.title, read.author, read.comment, read.encoder), (metadata.title, metadata.author, metadata.comment, metadata.encoder));
+        assert_eq!(read.custom, metadata.custom);
+
+        // nothing to write
+        assert_eq!(moov(|w| write(w, &Metadata::default())), moov(|_| {}));
+    }
+
+    #[test]
+    fn malformed_keys_are_errors() {
+        for key_size in [0, 7, 8 + 4 + 1, u32::MAX] {
+            let data = moov(|w| {
+                meta(w, b"mdta", |w| {
+                    w.full_box(b"keys", 0, 0, |w| {
+                        w.u32(1).u32(key_size).fourcc(b"mdta").bytes(b"name");
+                    });
+                });
+            });
+            assert!(read_moov(&data).is_err(), "key size {}", key_size);
+        }
+        // an item without a key is skipped
+        let data = moov(|w| {
+            meta(w, b"mdta", |w| {
+                w.full_box(b"keys", 0, 0, |w| {
+                    w.u32(0);
+                });
+                w.boxed(b"ilst", |w| {
+                    w.boxed(&1u32.to_be_bytes(), |w| data(w, "value"));
+                });
+            });
+        });
+        assert!(read_moov(&data).unwrap().custom.is_empty());
+    }
+
+    #[test]
+    fn converts_mp4_times() {
+        let metadata = Metadata { creation_time: Some(UNIX_EPOCH + Duration::from_secs(1_000_000_000)), ..Metadata::default() };
+        assert_eq!(metadata.mp4_time(), 1_000_000_000 + MP4_EPOCH_OFFSET);
+        assert_eq!(from_mp4_time(metadata.mp4_time()), metadata.creation_time);
+        assert_eq!(Metadata::default().mp4_time(), 0);
+        // before 1970
+        assert_eq!(from_mp4_time(MP4_EPOCH_OFFSET - 1), None);
+    }
+}
@@ -0,0 +1,343 @@
+//!
+//! Pre-encoded Opus packets from an Ogg Opus file (RFC 7845), to be muxed as an audio track.
+//!
+use std::collections::VecDeque;
+use std::io::Read;
+
+use anyhow::{bail, Context};
+
+/// Opus always runs at 48 kHz, whatever the input sample rate was.
+pub const SAMPLE_RATE: u32 = 48000;
+
+/// Identification header, carried into the `dOps` box.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OpusHead {
+    pub channels: u8,
+    /// samples (at 48 kHz) to discard from the decoder output at the start
+    pub pre_skip: u16,
+    /// sample rate of the original input, informational only
+    pub input_sample_rate: u32,
+    /// Q7.8 dB
+    pub output_gain: i16,
+    pub channel_mapping_family: u8,
+    pub stream_count: u8,
+    pub coupled_count: u8,
+    pub channel_mapping: Vec<u8>,
+}
+
+impl OpusHead {
+    fn parse(packet: &[u8]) -> anyhow::Result<Self> {
+        if packet.len() < 19 || &packet[0..8] != b"OpusHead" {
+            bail!("missing OpusHead");
+        }
+        if packet[8] >> 4 != 0 {
+            bail!("unsupported OpusHead version {}", packet[8]);
+        }
+        let channels = packet[9];
+        let channel_mapping_family = packet[18];
+        let (stream_count, coupled_count, channel_mapping) = if channel_mapping_family == 0 {
+            (1, channels.saturating_sub(1), vec![])
+        } else {
+            let table = packet.get(19..21 + channels as usize).context("truncated channel mapping table")?;
+            (table[0], table[1], table[2..].to_vec())
+        };
+        Ok(Self {
+            channels,
+            pre_skip: u16::from_le_bytes([packet[10], packet[11]]),
+            input_sample_rate: u32::from_le_bytes([packet[12], packet[13], packet[14], packet[15]]),
+            output_gain: i16::from_le_bytes([packet[16], packet[17]]),
+            channel_mapping_family,
+            stream_count,
+            coupled_count,
+            channel_mapping,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct OpusPacket {
+    pub data: Vec<u8>,
+    /// in samples at 48 kHz, cut short on the last packet by the end trim of the final granule position
+    pub duration: u32,
+}
+
+/// Reads Opus packets page by page from an Ogg stream with a single logical bitstream.
+pub struct OggOpusReader<R: Read> {
+    inner: R,
+    head: OpusHead,
+    /// packets completed by the last page
+    packets: VecDeque<Vec<u8>>,
+    /// packet continued on the next page
+    partial: Vec<u8>,
+    /// samples (at 48 kHz) in the packets returned so far, pre-skip included like granule positions
+    position: u64,
+    /// granule position of the last page, the end of its last packet
+    granule: u64,
+    /// the last page had the end of stream flag
+    end_of_stream: bool,
+}
+
+impl<R: Read> OggOpusReader<R> {
+    /// Reads the OpusHead and OpusTags headers.
+    pub fn new(inner: R) -> anyhow::Result<Self> {
+        let mut reader = Self {
+            inner,
+            head: OpusHead::default(),
+            packets: VecDeque::new(),
+            partial: vec![],
+            position: 0,
+            granule: 0,
+            end_of_stream: false,
+        };
+        let head = reader.next_raw_packet()?.context("empty Ogg stream")?;
+        reader.head = OpusHead::parse(&head)?;
+        let tags = reader.next_raw_packet()?.context("missing OpusTags")?;
+        if !tags.starts_with(b"OpusTags") {
+            bail!("missing OpusTags");
+        }
+        Ok(reader)
+    }
+
+    pub fn head(&self) -> &OpusHead {
+        &self.head
+    }
+
+    /// Returns `None` at the end of the stream.
+    pub fn next_packet(&mut self) -> anyhow::Result<Option<OpusPacket>> {
+        match self.next_raw_packet()? {
+            Some(data) => {
+                let mut duration = packet_duration(&data)?;
+                self.position += u64::from(duration);
+                // the final granule position ends the stream before the end of its last packet,
+                // a position beyond the packets is a stream starting at a later time
+                if self.packets.is_empty() && self.end_of_stream && self.granule < self.position {
+                    let end_trim = self.position - self.granule;
+                    if end_trim > u64::from(duration) {
+                        bail!("end trim of {} samples exceeds the last packet of {} samples", end_trim, duration);
+                    }
+                    duration -= end_trim as u32;
+                }
+                Ok(Some(OpusPacket { data, duration }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_raw_packet(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        while self.packets.is_empty() {
+            if !self.read_page()? {
+                return Ok(None);
+            }
+        }
+        Ok(self.packets.pop_front())
+    }
+
+    /// Returns false at the end of the stream.
+    fn read_page(&mut self) -> anyhow::Result<bool> {
+        let mut header = [0u8; 27];
+        match self.inner.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+        if &header[0..4] != b"OggS" {
+            bail!("lost Ogg page sync");
+        }
+        self.end_of_stream = header[5] & 0x04 != 0;
+        // -1 when no packet ends on the page
+        let mut granule = [0; 8];
+        granule.copy_from_slice(&header[6..14]);
+        if granule != [0xFF; 8] {
+            self.granule = u64::from_le_bytes(granule);
+        }
+        let mut lacing = vec![0u8; header[26] as usize];
+        self.inner.read_exact(&mut lacing).context("truncated Ogg page")?;
+        let mut body = vec![0u8; lacing.iter().map(|x| *x as usize).sum()];
+        self.inner.read_exact(&mut body).context("truncated Ogg page")?;
+
+        // a lacing value below 255 terminates a packet
+        let mut offset = 0;
+        for l in lacing {
+            self.partial.extend_from_slice(&body[offset..offset + l as usize]);
+            offset += l as usize;
+            if l < 255 {
+                self.packets.push_back(std::mem::take(&mut self.partial));
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<R: Read> Iterator for OggOpusReader<R> {
+    type Item = anyhow::Result<OpusPacket>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_packet().transpose()
+    }
+}
+
+/// Duration of a packet in samples at 48 kHz, from its TOC byte (RFC 6716, 3.1).
+pub fn packet_duration(packet: &[u8]) -> anyhow::Result<u32> {
+    let toc = *packet.first().context("empty Opus packet")?;
+    let config = toc >> 3;
+    // frame size in samples at 48 kHz
+    let frame_size = match config {
+        0..=11 => [480, 960, 1920, 2880][config as usize % 4], // SILK
+        12..=15 => [480, 960][config as usize % 2], // hybrid
+        _ => [120, 240, 480, 960][config as usize % 4], // CELT
+    };
+    let frames = match toc & 0x03 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => u32::from(*packet.get(1).context("truncated Opus packet")? & 0x3f),
+    };
+    Ok(frame_size * frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmff::BoxReader;
+    use crate::fmp4::Fmp4;
+    use crate::fmp4_reader::Fmp4Reader;
+
+    /// 20 ms CELT frame, one per packet
+    const TOC: u8 = 31 << 3;
+
+    /// An Ogg page of `packets`, the last one continued on the next page unless `complete`.
+    fn page(header_type: u8, granule: u64, packets: &[&[u8]], complete: bool) -> Vec<u8> {
+        let mut lacing = vec![];
+        for (i, packet) in packets.iter().enumerate() {
+            lacing.resize(lacing.len() + packet.len() / 255, 255);
+            if complete || i + 1 < packets.len() {
+                lacing.push((packet.len() % 255) as u8);
+            }
+        }
+        // version, header type, granule position, serial number, sequence number and CRC
+        let mut page = [&b"OggS\0"[..], &[header_type], &granule.to_le_bytes(), &[0; 12]].concat();
+        page.push(lacing.len() as u8);
+        page.extend_from_slice(&lacing);
+        packets.iter().for_each(|p| page.extend_from_slice(p));
+        page
+    }
+
+    /// Stereo, 312 samples of pre-skip, 44.1 kHz input and -1 dB of gain.
+    fn opus_head(channel_mapping_family: u8, table: &[u8]) -> Vec<u8> {
+        [&b"OpusHead\x01\x02"[..], &312u16.to_le_bytes(), &44100u32.to_le_bytes(), &(-256i16).to_le_bytes(),
+         &[channel_mapping_family], table].concat()
+    }
+
+    /// Header pages followed by `pages`.
+    fn stream(pages: &[Vec<u8>]) -> Vec<u8> {
+        let headers = [page(0x02, 0, &[&opus_head(0, &[])], true), page(0, 0, &[b"OpusTags\0\0\0\0\0\0\0\0"], true)];
+        headers.iter().chain(pages).flatten().copied().collect()
+    }
+
+    fn read_packets(data: &[u8]) -> anyhow::Result<Vec<OpusPacket>> {
+        OggOpusReader::new(data)?.collect()
+    }
+
+    #[test]
+    fn reassembles_packets_across_pages() {
+        // a packet of exactly two lacing values, terminated by a 0 on the next page
+        let long = [&[TOC][..], &[1; 509]].concat();
+        let data = stream(&[
+            page(0, u64::MAX, &[&long[..255]], false),
+            page(0, 960 * 3, &[&long[255..], &[TOC, 2], &[TOC, 3]], true),
+        ]);
+        let packets = read_packets(&data).unwrap();
+        let data = packets.iter().map(|p| (p.data.as_slice(), p.duration)).collect::<Vec<_>>();
+        assert_eq!(data, [(&long[..], 960), (&[TOC, 2][..], 960), (&[TOC, 3][..], 960)]);
+
+        let mut truncated = stream(&[page(0, 960, &[&[TOC, 2]], true)]);
+        truncated.pop();
+        assert!(read_packets(&truncated).is_err());
+        let mut out_of_sync = page(0, 960, &[&[TOC, 2]], true);
+        out_of_sync[3] = b'T';
+        let lost_sync = stream(&[page(0, 960, &[&[TOC, 2]], true), out_of_sync]);
+        assert!(read_packets(&lost_sync).is_err());
+    }
+
+    #[test]
+    fn end_trim_shortens_the_last_packet() {
+        // 312 samples of pre-skip and 1000 of audio in two packets, without and with the end of stream flag
+        for (header_type, durations) in [(0, [960, 960]), (0x04, [960, 352])] {
+            let data = stream(&[page(0, 960, &[&[TOC, 1]], true), page(header_type, 1312, &[&[TOC, 2]], true)]);
+            let packets = read_packets(&data).unwrap();
+            assert_eq!(packets.iter().map(|p| p.duration).collect::<Vec<_>>(), durations);
+        }
+        // no trim of a stream starting later
+        let data = stream(&[page(0x04, 5000, &[&[TOC, 1]], true)]);
+        assert_eq!(read_packets(&data).unwrap()[0].duration, 960);
+        // more than the last packet
+        let data = stream(&[page(0, 960, &[&[TOC, 1]], true), page(0x04, 900, &[&[TOC, 2]], true)]);
+        assert!(read_packets(&data).is_err());
+    }
+
+    #[test]
+    fn parses_opus_head() {
+        let head = OpusHead::parse(&opus_head(0, &[])).unwrap();
+        assert_eq!(head, OpusHead {
+            channels: 2,
+            pre_skip: 312,
+            input_sample_rate: 44100,
+            output_gain: -256,
+            channel_mapping_family: 0,
+            stream_count: 1,
+            coupled_count: 1,
+            channel_mapping: vec![],
+        });
+
+        let surround = OpusHead::parse(&opus_head(1, &[1, 1, 0, 1])).unwrap();
+        assert_eq!((surround.stream_count, surround.coupled_count, surround.channel_mapping), (1, 1, vec![0, 1]));
+
+        for invalid in [
+            opus_head(1, &[1, 1, 0]),
+            opus_head(0, &[])[..18].to_vec(),
+            [&b"OpusHeaD"[..], &opus_head(0, &[])[8..]].concat(),
+            [&opus_head(0, &[])[..8], &[0x10], &opus_head(0, &[])[9..]].concat(),
+        ] {
+            assert!(OpusHead::parse(&invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn packet_durations() {
+        for (packet, duration) in [
+            // SILK 10 and 60 ms, hybrid 10 and 20 ms, CELT 2.5 and 20 ms
+            (&[0 << 3][..], 480),
+            (&[3 << 3], 2880),
+            (&[12 << 3], 480),
+            (&[13 << 3], 960),
+            (&[16 << 3], 120),
+            (&[31 << 3], 960),
+            // two frames of the same or different sizes, then a count of frames
+            (&[31 << 3 | 1], 1920),
+            (&[31 << 3 | 2], 1920),
+            (&[16 << 3 | 3, 0x85], 600),
+        ] {
+            assert_eq!(packet_duration(packet).unwrap(), duration, "{:?}", packet);
+        }
+        assert!(packet_duration(&[]).is_err());
+        assert!(packet_duration(&[31 << 3 | 3]).is_err());
+    }
+
+    #[test]
+    fn writes_dops() {
+        for (family, table, dops) in [
+            (0, &[][..], &[][..]),
+            (1, &[1, 1, 0, 1], &[1, 1, 0, 1]),
+        ] {
+            let mut fmp4 = Fmp4::new(30, 64, 48);
+            let id = fmp4.add_opus_track(OpusHead::parse(&opus_head(family, table)).unwrap());
+            let init = fmp4.init_segment();
+            let reader = Fmp4Reader::new(&init).unwrap();
+            let entry = &reader.track(id).unwrap().sample_entry;
+            assert_eq!(&entry[4..8], b"Opus");
+            // after the 28 bytes of the AudioSampleEntry
+            let b = BoxReader::new(&entry[8 + 28..]).next_box().unwrap().unwrap();
+            assert_eq!(&b.box_type, b"dOps");
+            let expected = [&[0, 2][..], &312u16.to_be_bytes(), &44100u32.to_be_bytes(), &(-256i16).to_be_bytes(), &[family], dops].concat();
+            assert_eq!(b.payload, expected);
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//!
+//! Destinations for the init segment and the fragments produced by `Fmp4`.
+//!
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+
+use crate::fmp4::Fmp4;
+
+pub trait SegmentSink {
+    fn write_init(&mut self, data: &[u8]) -> anyhow::Result<()>;
+    fn write_segment(&mut self, data: &[u8], key: bool) -> anyhow::Result<()>;
+}
+
+/// One file for the init segment and one per fragment, `<dir>/header.m4s` and `<dir>/body_<n>.m4s`.
+pub struct SegmentFiles {
+    dir: PathBuf,
+    sn: u32,
+    /// append chunks to the current file until the next key frame
+    group_chunks: bool,
+}
+
+impl SegmentFiles {
+    /// Removes any previous output in `dir`.
+    pub fn create(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir, sn: 0, group_chunks: false })
+    }
+
+    /// Writes one file per segment instead of one per fragment, for chunked CMAF output.
+    pub fn set_group_chunks(&mut self, enabled: bool) {
+        self.group_chunks = enabled;
+    }
+}
+
+impl SegmentSink for SegmentFiles {
+    fn write_init(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        fs::write(self.dir.join("header.m4s"), data)?;
+        Ok(())
+    }
+
+    fn write_segment(&mut self, data: &[u8], key: bool) -> anyhow::Result<()> {
+        if self.group_chunks && key && self.dir.join(format!("body_{}.m4s", self.sn)).exists() {
+            self.sn += 1;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(format!("body_{}.m4s", self.sn)))?;
+        file.write_all(data)?;
+        if !self.group_chunks {
+            self.sn += 1;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl ByteRange {
+    /// Inclusive last byte, as used by HTTP and DASH ranges.
+    pub fn last(&self) -> u64 {
+        self.offset + self.length - 1
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SegmentRange {
+    pub range: ByteRange,
+    pub duration: u32,
+    pub key: bool,
+}
+
+/// Byte ranges of a file written by `SingleFile`.
+#[derive(Clone, Debug)]
+pub struct FileIndex {
+    pub init: ByteRange,
+    /// the top-level `sidx`
+    pub index: ByteRange,
+    pub segments: Vec<SegmentRange>,
+    pub timescale: u32,
+}
+
+/// Init segment and all fragments appended into one playable `.mp4`.
+///
+/// `finish` puts a top-level `sidx` behind the init segment and appends an `mfra`,
+/// so the file can be served with DASH `SegmentBase` or HLS `#EXT-X-BYTERANGE`.
+/// The `Fmp4` must have `set_random_access_index` enabled.
+pub struct SingleFile {
+    path: PathBuf,
+    file: File,
+    init_size: u64,
+    /// size of the `free` box written behind the init segment, where the sidx goes
+    reserved: u64,
+}
+
+impl SingleFile {
+    /// Leaves room for a sidx of `segments` segments behind the init segment. With more
+    /// segments, `finish` has to rewrite the whole file to make room.
+    pub fn create(path: impl AsRef<Path>, segments: usize) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(Self { path, file, init_size: 0, reserved: Fmp4::sidx_size(segments) as u64 })
+    }
+
+    pub fn finish(mut self, fmp4: &Fmp4) -> anyhow::Result<FileIndex> {
+        let fragments = fmp4.fragments();
+        let fragments_size = self.file.stream_position()? - self.init_size - self.reserved;
+        let recorded_size = fragments.last().map_or(0, |f| f.offset + u64::from(f.size));
+        if recorded_size != fragments_size {
+            bail!("fragments written ({} bytes) do not match the recorded index ({} bytes), \
+                   is the random access index enabled?", fragments_size, recorded_size);
+        }
+
+        let init_size = self.init_size;
+        // the sidx replaces the reserved box, the rest of which stays free
+        let sidx_size = fmp4.sidx(0).len() as u64;
+        let (sidx, first_fragment_offset) = match self.reserved.checked_sub(sidx_size) {
+            Some(free) if free == 0 || free >= 8 => {
+                let sidx = fmp4.sidx(free);
+                self.file.seek(SeekFrom::Start(self.init_size))?;
+                self.file.write_all(&sidx)?;
+                if free > 0 {
+                    self.file.write_all(&free_box_header(free))?;
+                }
+                self.file.seek(SeekFrom::End(0))?;
+                let first_fragment_offset = self.init_size + self.reserved;
+                self.file.write_all(&fmp4.mfra(first_fragment_offset))?;
+                self.file.sync_all()?;
+                (sidx, first_fragment_offset)
+            }
+            _ => {
+                let sidx = fmp4.sidx(0);
+                let first_fragment_offset = self.init_size + sidx_size;
+                self.rewrite(&sidx, &fmp4.mfra(first_fragment_offset))?;
+                (sidx, first_fragment_offset)
+            }
+        };
+
+        Ok(FileIndex {
+            init: ByteRange { offset: 0, length: init_size },
+            index: ByteRange { offset: init_size, length: sidx.len() as u64 },
+            segments: fragments.iter().map(|f| SegmentRange {
+                range: ByteRange { offset: first_fragment_offset + f.offset, length: u64::from(f.size) },
+                duration: f.duration,
+                key: f.key,
+            }).collect(),
+            timescale: fmp4.track().timescale,
+        })
+    }
+
+    /// Copies the file with `sidx` in place of the reserved box and `mfra` at the end, for
+    /// when the sidx doesn't fit.
+    fn rewrite(mut self, sidx: &[u8], mfra: &[u8]) -> anyhow::Result<()> {
+        let (tmp_path, mut tmp) = create_temporary(&self.path)?;
+        let result = (|| -> anyhow::Result<()> {
+            self.file.seek(SeekFrom::Start(0))?;
+            io::copy(&mut (&mut self.file).take(self.init_size), &mut tmp)?;
+            tmp.write_all(sidx)?;
+            self.file.seek(SeekFrom::Current(self.reserved as i64))?;
+            io::copy(&mut self.file, &mut tmp)?;
+            tmp.write_all(mfra)?;
+            tmp.sync_all()?;
+            Ok(())
+        })();
+        drop(tmp);
+        if let Err(e) = result {
+            fs::remove_file(&tmp_path).ok();
+            return Err(e);
+        }
+        drop(self.file);
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// A new file next to `path`, never one that already exists.
+fn create_temporary(path: &Path) -> anyhow::Result<(PathBuf, File)> {
+    let name = path.file_name().map(|x| x.to_string_lossy()).unwrap_or_default();
+    for i in 0.. {
+        let tmp_path = path.with_file_name(format!(".{}.{}.{}.tmp", name, std::process::id(), i));
+        match OpenOptions::new().write(true).create_new(true).open(&tmp_path) {
+            Ok(file) => return Ok((tmp_path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    unreachable!()
+}
+
+/// Header of a `free` box of `size` bytes, its content being whatever follows.
+fn free_box_header(size: u64) -> [u8; 8] {
+    let mut header = [0; 8];
+    header[..4].copy_from_slice(&(size as u32).to_be_bytes());
+    header[4..].copy_from_slice(b"free");
+    header
+}
+
+impl SegmentSink for SingleFile {
+    fn write_init(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.file.write_all(data)?;
+        self.init_size = data.len() as u64;
+        // zeros, so the box is free whether or not the sidx is written into it
+        let mut reserved = vec![0; self.reserved as usize];
+        reserved[..8].copy_from_slice(&free_box_header(self.reserved));
+        self.file.write_all(&reserved)?;
+        Ok(())
+    }
+
+    fn write_segment(&mut self, data: &[u8], _key: bool) -> anyhow::Result<()> {
+        self.file.write_all(data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmff::BoxReader;
+    use crate::fmp4_reader::Fmp4Reader;
+
+    /// An empty directory of its own under the system temporary directory.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("img2vp9-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// 7 frames with a key frame every 3, written through a `SingleFile` reserving `segments`.
+    fn record(dir: &Path, segments: usize) -> (Vec<u8>, FileIndex) {
+        let path = dir.join("out.mp4");
+        let mut fmp4 = Fmp4::new(30, 64, 48);
+        fmp4.set_random_access_index(true);
+        let mut sink = SingleFile::create(&path, segments).unwrap();
+        sink.write_init(&fmp4.init_segment()).unwrap();
+        for i in 0..7 {
+            let key = i % 3 == 0;
+            for chunk in fmp4.push_frame(&[if key { 0x82 } else { 0x86 }, 0, 0], key).unwrap() {
+                sink.write_segment(&chunk.data, chunk.independent).unwrap();
+            }
+        }
+        let index = sink.finish(&fmp4).unwrap();
+        (fs::read(path).unwrap(), index)
+    }
+
+    fn check(file: &[u8], index: &FileIndex) {
+        let boxes = BoxReader::new(file).boxes().unwrap();
+        let types = boxes.iter().map(|b| b.type_str()).collect::<Vec<_>>();
+        assert_eq!(&types[..3], ["ftyp", "moov", "sidx"]);
+        assert_eq!(types.last().map(String::as_str), Some("mfra"));
+        assert_eq!((index.index.offset as usize, index.index.length as usize), (boxes[2].offset, boxes[2].size));
+
+        // one subsegment per key frame, starting at the first moof past the first_offset
+        let mut r = boxes[2].reader();
+        r.skip(4 + 4 + 4 + 8).unwrap();
+        let first_offset = r.u64().unwrap() as usize;
+        r.skip(2).unwrap();
+        assert_eq!(r.u16().unwrap(), 3);
+        let mut offset = boxes[2].offset + boxes[2].size + first_offset;
+        for frames in [3, 3, 1] {
+            assert_eq!(&file[offset + 4..offset + 8], b"moof");
+            let size = r.u32().unwrap() as usize;
+            assert_eq!((r.u32().unwrap(), r.u32().unwrap()), (frames * 3000, 0x9000_0000));
+            offset += size;
+        }
+        assert_eq!(offset, boxes.last().unwrap().offset);
+
+        for s in &index.segments {
+            assert_eq!(&file[s.range.offset as usize + 4..][..4], b"moof");
+        }
+        let samples = Fmp4Reader::new(file).unwrap().samples().unwrap();
+        assert_eq!(samples.iter().filter(|s| s.key).count(), 3);
+        assert_eq!(samples.len(), 7);
+    }
+
+    #[test]
+    fn single_file_sidx_goes_into_the_reserved_space() {
+        let dir = test_dir("reserved");
+        let (file, index) = record(&dir, 5);
+        check(&file, &index);
+        // what is left of the reservation stays a free box
+        let boxes = BoxReader::new(&file).boxes().unwrap();
+        assert_eq!(&boxes[3].box_type, b"free");
+        assert_eq!(boxes[3].size, 2 * 12);
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn single_file_is_rewritten_without_room_for_the_sidx() {
+        let dir = test_dir("rewritten");
+        // a temporary file of the old naming scheme is left alone
+        fs::write(dir.join("out.tmp"), b"keep").unwrap();
+        for segments in [0, 2] {
+            let (file, index) = record(&dir, segments);
+            check(&file, &index);
+            assert_eq!(&BoxReader::new(&file).boxes().unwrap()[3].box_type, b"moof");
+        }
+        let mut names = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["out.mp4", "out.tmp"]);
+        assert_eq!(fs::read(dir.join("out.tmp")).unwrap(), b"keep");
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,190 @@
+//!
+//! Cuts the head and tail off a fragmented MP4 without re-encoding.
+//!
+use std::time::Duration;
+
+use anyhow::bail;
+
+use crate::fmp4::{self, Edit, Media, Sample, Track, TrackFragment};
+use crate::fmp4_reader::{Fmp4Reader, Mp4Sample};
+use crate::metadata::{self, Metadata};
+use crate::video::VideoProperties;
+
+/// Keeps the presentation between `start` and `end`, or the end of the input if `None`.
+///
+/// Every track is cut at its last sync sample at or before `start`, since the samples after
+/// it cannot be decoded without it, and an edit list hides the lead-in. Fragments are rebuilt,
+/// one per key frame of the video track.
+pub fn trim(input: &[u8], start: Duration, end: Option<Duration>) -> anyhow::Result<Vec<u8>> {
+    if end.is_some_and(|end| end <= start) {
+        bail!("trim end {:?} is not after start {:?}", end, start);
+    }
+    let reader = Fmp4Reader::new(input)?;
+    let samples = reader.samples()?;
+    let movie_timescale = reader.movie_timescale.max(1);
+    if reader.tracks.iter().any(|t| &t.codec == b"encv" || &t.codec == b"enca") {
+        bail!("trimming encrypted tracks is not supported");
+    }
+
+    let mut tracks = vec![];
+    // kept samples per track, rebased to start at 0
+    let mut kept: Vec<Vec<Mp4Sample>> = vec![];
+    for info in &reader.tracks {
+        let track_samples = samples.iter().filter(|s| s.track_id == info.id).collect::<Vec<_>>();
+        // media time presented at time 0 of the input
+        let offset = info.edits.iter().find(|e| e.media_time >= 0).map_or(0, |e| e.media_time as u64);
+        let start_ticks = offset + ticks(start, info.timescale);
+        let end_ticks = end.map(|end| offset + ticks(end, info.timescale));
+
+        let cut = track_samples.iter()
+            .filter(|s| s.key && s.dts <= start_ticks)
+            .map(|s| s.dts)
+            .max()
+            .or_else(|| track_samples.iter().find(|s| s.key).map(|s| s.dts))
+            .unwrap_or(0);
+        let track_samples = track_samples.into_iter()
+            .filter(|s| s.dts >= cut && end_ticks.is_none_or(|end| s.dts < end))
+            .map(|s| Mp4Sample { dts: s.dts - cut, pts: s.pts - cut as i64, ..s.clone() })
+            .collect::<Vec<_>>();
+
+        let media_end = cut + track_samples.last().map_or(0, |s| s.dts + u64::from(s.duration));
+        let presentation_end = end_ticks.map_or(media_end, |end| end.min(media_end));
+        let presentation_start = start_ticks.max(cut);
+        let mut edits = vec![];
+        if cut > start_ticks {
+            // the first sync sample comes after `start`
+            edits.push(Edit::empty(rescale(cut - start_ticks, info.timescale, movie_timescale)));
+        }
+        edits.push(Edit {
+            segment_duration: rescale(presentation_end.saturating_sub(presentation_start), info.timescale, movie_timescale),
+            media_time: (presentation_start - cut) as i64,
+        });
+
+        let mut track = Track::new(info.timescale, 0, info.width, info.height);
+        track.id = info.id;
+        track.media = Media::SampleEntry { handler: info.handler, entry: info.sample_entry.clone() };
+        track.volume = if &info.handler == b"soun" { 0x0100 } else { 0 };
+        track.edits = edits;
+        // pixel aspect ratio and colour come along with the sample entry, the tkhd size follows them
+        track.video.pixel_aspect_ratio = info.pixel_aspect_ratio;
+        if let Some((rotation, flip)) = VideoProperties::orientation(&info.matrix) {
+            track.video = VideoProperties { rotation, flip, ..track.video };
+        }
+        tracks.push(track);
+        kept.push(track_samples);
+    }
+
+    // fragment boundaries at the key frames of the video track, or of the first track
+    let primary = tracks.iter().position(|t| &t.handler() == b"vide").unwrap_or(0);
+    let primary_timescale = u128::from(tracks.get(primary).map_or(1, |t| t.timescale));
+    let boundaries = kept.get(primary).map_or(vec![], |s| s.iter().filter(|s| s.key).map(|s| u128::from(s.dts)).collect());
+    let fragment_count = boundaries.len().max(1);
+    // index of the fragment a sample falls into
+    let fragment_of = |track: &Track, dts: u64| {
+        let time = u128::from(dts) * primary_timescale;
+        let timescale = u128::from(track.timescale);
+        boundaries.partition_point(|b| b * timescale <= time).saturating_sub(1)
+    };
+
+    // a trimmed recording keeps the title, creation time etc. of the input
+    let metadata = Metadata { creation_time: metadata::from_mp4_time(reader.creation_time), ..reader.metadata.clone() };
+    let mut output = fmp4::init_segment(&tracks, movie_timescale, &reader.major_brand == b"cmf2", &metadata);
+    let mut sn = 1;
+    // samples of each track are in decode order, so each fragment continues where the last one ended
+    let mut cursors = vec![0; kept.len()];
+    for index in 0..fragment_count {
+        let mut fragment_tracks = vec![];
+        let mut fragment_samples = vec![];
+        for ((track, samples), cursor) in tracks.iter().zip(&kept).zip(&mut cursors) {
+            let start = *cursor;
+            while samples.get(*cursor).is_some_and(|s| fragment_of(track, s.dts) <= index) {
+                *cursor += 1;
+            }
+            let samples = samples[start..*cursor].iter().collect::<Vec<_>>();
+            if let Some(first) = samples.first() {
+                fragment_tracks.push(Track { dts: first.dts, ..track.clone() });
+                fragment_samples.push(samples);
+            }
+        }
+        if fragment_tracks.is_empty() {
+            continue;
+        }
+        let trafs = fragment_tracks.iter().zip(&fragment_samples).map(|(track, samples)| TrackFragment {
+            track,
+            samples: samples.iter()
+                .map(|s| Sample::new(s.data.len() as u32, s.duration, (s.pts - s.dts as i64) as u32, s.key))
+                .collect(),
+            encryption: vec![],
+        }).collect::<Vec<_>>();
+        let data = fragment_samples.iter().flatten().map(|s| s.data);
+        output.extend(fmp4::fragment(sn, &trafs, data));
+        sn += 1;
+    }
+    Ok(output)
+}
+
+fn ticks(time: Duration, timescale: u32) -> u64 {
+    (time.as_nanos() * u128::from(timescale) / 1_000_000_000) as u64
+}
+
+fn rescale(value: u64, from: u32, to: u32) -> u64 {
+    (u128::from(value) * u128::from(to) / u128::from(from.max(1))) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmff::BoxReader;
+    use crate::fmp4::Fmp4;
+    use crate::video::PixelAspectRatio;
+
+    /// 10 frames at 30 fps with a key frame every 4, 4:3 pixels and a title.
+    fn input() -> Vec<u8> {
+        let mut fmp4 = Fmp4::new(30, 64, 48);
+        fmp4.set_video_properties(VideoProperties {
+            pixel_aspect_ratio: PixelAspectRatio { h_spacing: 4, v_spacing: 3 },
+            ..VideoProperties::default()
+        });
+        fmp4.set_metadata(Metadata {
+            title: Some("title".to_string()),
+            custom: vec![("com.example.id".to_string(), "42".to_string())],
+            ..Metadata::now()
+        });
+        let mut file = fmp4.init_segment();
+        for i in 0..10u8 {
+            file.extend(fmp4.wrap_frame(&[i; 8], i % 4 == 0).unwrap());
+        }
+        file
+    }
+
+    #[test]
+    fn cuts_at_the_key_frame_before_the_start() {
+        let output = trim(&input(), Duration::from_millis(200), None).unwrap();
+        let reader = Fmp4Reader::new(&output).unwrap();
+        let samples = reader.samples().unwrap();
+        let frames = samples.iter().map(|s| (s.dts, s.key, s.data[0])).collect::<Vec<_>>();
+        assert_eq!(frames, (4..10).map(|i| (u64::from(i - 4) * 3000, i % 4 == 0, i)).collect::<Vec<_>>());
+        // frames 4 and 5 are decoded but not shown
+        assert_eq!(reader.tracks[0].edits, [Edit { segment_duration: 12000, media_time: 6000 }]);
+        // one fragment per key frame
+        let moofs = BoxReader::new(&output).boxes().unwrap().iter().filter(|b| &b.box_type == b"moof").count();
+        assert_eq!(moofs, 2);
+    }
+
+    #[test]
+    fn keeps_metadata_and_display_size() {
+        let output = trim(&input(), Duration::from_millis(100), Some(Duration::from_millis(250))).unwrap();
+        let reader = Fmp4Reader::new(&output).unwrap();
+        assert_eq!(reader.metadata.title.as_deref(), Some("title"));
+        assert_eq!(reader.metadata.custom, [("com.example.id".to_string(), "42".to_string())]);
+        assert_eq!(reader.tracks[0].pixel_aspect_ratio, PixelAspectRatio { h_spacing: 4, v_spacing: 3 });
+        assert_eq!(reader.samples().unwrap().len(), 8);
+
+        let moov = BoxReader::new(&output).boxes().unwrap().into_iter().find(|b| &b.box_type == b"moov").unwrap();
+        let trak = moov.reader().boxes().unwrap().into_iter().find(|b| &b.box_type == b"trak").unwrap();
+        let tkhd = trak.reader().next_box().unwrap().unwrap();
+        let mut r = tkhd.reader();
+        r.skip(tkhd.payload.len() - 8).unwrap();
+        assert_eq!((r.u32().unwrap(), r.u32().unwrap()), (85 << 16, 48 << 16));
+    }
+}
@@ -0,0 +1,336 @@
+//!
+//! How the decoded pictures of a video track are presented: orientation, pixel aspect ratio,
+//! clean aperture, colour description and sample format. Set from the command line and written
+//! into the `tkhd` matrix and the `pasp`, `clap`, `colr` and `vpcC` boxes of the sample entry.
+//!
+use anyhow::{bail, Context};
+
+use crate::yuv_util::{ColorMatrix, Range, Transfer};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VideoProperties {
+    pub rotation: Rotation,
+    /// mirrored horizontally, before the rotation
+    pub flip: bool,
+    /// `pasp`, written when not square
+    pub pixel_aspect_ratio: PixelAspectRatio,
+    /// `clap`
+    pub clean_aperture: Option<CleanAperture>,
+    /// `nclx` `colr`, also used in `vpcC`
+    pub color: Option<ColorInfo>,
+    /// bit depth and chroma subsampling, in `vpcC` and the codecs parameter
+    pub sample_format: SampleFormat,
+}
+
+/// Clockwise display rotation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rotation {
+    #[default]
+    None,
+    Clockwise90,
+    Clockwise180,
+    Clockwise270,
+}
+
+impl std::str::FromStr for Rotation {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "0" => Ok(Rotation::None),
+            "90" => Ok(Rotation::Clockwise90),
+            "180" => Ok(Rotation::Clockwise180),
+            "270" => Ok(Rotation::Clockwise270),
+            _ => bail!("unsupported rotation {}, expected 0, 90, 180 or 270", s),
+        }
+    }
+}
+
+/// Width to height of a pixel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PixelAspectRatio {
+    pub h_spacing: u32,
+    pub v_spacing: u32,
+}
+
+impl PixelAspectRatio {
+    pub fn is_square(&self) -> bool {
+        self.h_spacing == self.v_spacing
+    }
+}
+
+impl Default for PixelAspectRatio {
+    fn default() -> Self {
+        Self { h_spacing: 1, v_spacing: 1 }
+    }
+}
+
+/// `<h>:<v>`, e.g. `4:3`
+impl std::str::FromStr for PixelAspectRatio {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (h, v) = s.split_once(':').with_context(|| format!("expected <h>:<v>, got {}", s))?;
+        let (h_spacing, v_spacing) = (h.parse()?, v.parse()?);
+        if h_spacing == 0 || v_spacing == 0 {
+            bail!("invalid pixel aspect ratio {}", s);
+        }
+        Ok(Self { h_spacing, v_spacing })
+    }
+}
+
+/// The part of the picture meant to be shown, in pixels from the top left corner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CleanAperture {
+    pub width: u32,
+    pub height: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl CleanAperture {
+    /// Offset of the aperture centre from the picture centre, as a fraction with denominator 2.
+    pub fn center_offset(&self, picture_width: u16, picture_height: u16) -> (i32, i32) {
+        (
+            (2 * self.x + self.width) as i32 - i32::from(picture_width),
+            (2 * self.y + self.height) as i32 - i32::from(picture_height),
+        )
+    }
+}
+
+/// `<width>x<height>+<x>+<y>`, e.g. `1920x800+0+140`
+impl std::str::FromStr for CleanAperture {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let invalid = || format!("expected <width>x<height>+<x>+<y>, got {}", s);
+        let mut fields = s.split(['x', '+']);
+        let mut next = || -> anyhow::Result<u32> { Ok(fields.next().with_context(invalid)?.parse()?) };
+        let aperture = Self { width: next()?, height: next()?, x: next()?, y: next()? };
+        if fields.next().is_some() {
+            bail!(invalid());
+        }
+        Ok(aperture)
+    }
+}
+
+/// Colour description with the code points of ISO/IEC 23091-2, 2 being unspecified.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ColorInfo {
+    pub primaries: u8,
+    pub transfer: u8,
+    pub matrix: u8,
+    pub full_range: bool,
+}
+
+/// Coded bit depth and chroma subsampling, which make the VP9 profile.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SampleFormat {
+    /// 8, 10 or 12
+    pub bit_depth: u8,
+    pub chroma: ChromaSubsampling,
+}
+
+impl Default for SampleFormat {
+    fn default() -> Self {
+        Self { bit_depth: 8, chroma: ChromaSubsampling::Yuv420 }
+    }
+}
+
+impl SampleFormat {
+    /// 0 for 8-bit 4:2:0, 1 with more chroma, 2 with more bits and 3 with both
+    pub fn profile(&self) -> u8 {
+        u8::from(self.chroma != ChromaSubsampling::Yuv420) + 2 * u8::from(self.bit_depth > 8)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+    /// sited left, as VP9 expects
+    #[default]
+    Yuv420,
+    Yuv422,
+    Yuv444,
+}
+
+impl ChromaSubsampling {
+    /// `chromaSubsampling` of `vpcC`
+    pub fn vpcc_value(&self) -> u8 {
+        match self {
+            ChromaSubsampling::Yuv420 => 0,
+            ChromaSubsampling::Yuv422 => 2,
+            ChromaSubsampling::Yuv444 => 3,
+        }
+    }
+}
+
+impl std::str::FromStr for ChromaSubsampling {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "420" => Ok(ChromaSubsampling::Yuv420),
+            "422" => Ok(ChromaSubsampling::Yuv422),
+            "444" => Ok(ChromaSubsampling::Yuv444),
+            _ => bail!("unsupported chroma subsampling {}, expected 420, 422 or 444", s),
+        }
+    }
+}
+
+impl Default for ColorInfo {
+    fn default() -> Self {
+        Self { primaries: 2, transfer: 2, matrix: 2, full_range: false }
+    }
+}
+
+impl ColorInfo {
+    /// Description of YUV converted with `matrix` from input encoded with `transfer`.
+    pub fn from_yuv(matrix: ColorMatrix, range: Range, transfer: Transfer) -> Self {
+        let (primaries, sdr_transfer, matrix) = match matrix {
+            // SMPTE 170M, the 525-line flavour of BT.601
+            ColorMatrix::Bt601 => (6, 6, 6),
+            ColorMatrix::Bt709 => (1, 1, 1),
+            ColorMatrix::Bt2020Ncl => (9, 14, 9),
+        };
+        let transfer = match transfer {
+            Transfer::Sdr => sdr_transfer,
+            Transfer::Pq => 16,
+            Transfer::Hlg => 18,
+        };
+        Self { primaries, transfer, matrix, full_range: range == Range::Full }
+    }
+}
+
+/// `<primaries>,<transfer>,<matrix>[,full]`, e.g. `1,1,1` for BT.709 limited range
+impl std::str::FromStr for ColorInfo {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let fields = s.split(',').collect::<Vec<_>>();
+        if fields.len() < 3 || fields.len() > 4 {
+            bail!("expected <primaries>,<transfer>,<matrix>[,full], got {}", s);
+        }
+        let full_range = match fields.get(3) {
+            None => false,
+            Some(&"full") => true,
+            Some(&"limited") => false,
+            Some(range) => bail!("unknown colour range {}, expected full or limited", range),
+        };
+        Ok(Self { primaries: fields[0].parse()?, transfer: fields[1].parse()?, matrix: fields[2].parse()?, full_range })
+    }
+}
+
+impl VideoProperties {
+    /// Rotation and flip of a `tkhd` matrix, `None` if it is not one of the 8 orientations.
+    pub fn orientation(matrix: &[u32; 9]) -> Option<(Rotation, bool)> {
+        let rotations = [Rotation::None, Rotation::Clockwise90, Rotation::Clockwise180, Rotation::Clockwise270];
+        rotations.iter()
+            .flat_map(|rotation| [(*rotation, false), (*rotation, true)])
+            .find(|(rotation, flip)| {
+                let m = Self { rotation: *rotation, flip: *flip, ..Self::default() }.matrix(0, 0);
+                // translation aside
+                m[..2] == matrix[..2] && m[3..5] == matrix[3..5]
+            })
+    }
+
+    /// Width and height to present a `width`x`height` picture at, before rotation.
+    pub fn display_size(&self, width: u16, height: u16) -> (u32, u32) {
+        let par = self.pixel_aspect_ratio;
+        (u32::from(width) * par.h_spacing / par.v_spacing, u32::from(height))
+    }
+
+    /// `tkhd` transformation matrix for a `width`x`height` presentation.
+    pub fn matrix(&self, width: u32, height: u32) -> [u32; 9] {
+        // x' = a x + c y + tx, y' = b x + d y + ty
+        let (mut a, mut b, mut c, mut d) = (1i32, 0i32, 0i32, 1i32);
+        if self.flip {
+            a = -1;
+        }
+        for _ in 0..self.rotation.quarter_turns() {
+            // (x, y) -> (-y, x), a quarter turn clockwise with y pointing down
+            (a, b, c, d) = (-b, a, -d, c);
+        }
+        // move the rotated picture back to the origin
+        let (width, height) = (i64::from(width), i64::from(height));
+        let tx = i64::from((-a).max(0)) * width + i64::from((-c).max(0)) * height;
+        let ty = i64::from((-b).max(0)) * width + i64::from((-d).max(0)) * height;
+        let fixed = |v: i32| (v << 16) as u32;
+        [
+            fixed(a), fixed(b), 0,
+            fixed(c), fixed(d), 0,
+            (tx << 16) as u32, (ty << 16) as u32, 0x4000_0000,
+        ]
+    }
+}
+
+impl Rotation {
+    fn quarter_turns(&self) -> u32 {
+        match self {
+            Rotation::None => 0,
+            Rotation::Clockwise90 => 1,
+            Rotation::Clockwise180 => 2,
+            Rotation::Clockwise270 => 3,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 16.16 fixed-point
+    fn fixed(v: i32) -> u32 {
+        (v << 16) as u32
+    }
+
+    #[test]
+    fn orientation_matrices() {
+        // {a, b, c, d} and the translation of a 64x48 presentation
+        for (rotation, flip, [a, b, c, d], (tx, ty)) in [
+            (Rotation::None, false, [1, 0, 0, 1], (0, 0)),
+            (Rotation::Clockwise90, false, [0, 1, -1, 0], (48, 0)),
+            (Rotation::Clockwise180, false, [-1, 0, 0, -1], (64, 48)),
+            (Rotation::Clockwise270, false, [0, -1, 1, 0], (0, 64)),
+            (Rotation::None, true, [-1, 0, 0, 1], (64, 0)),
+            (Rotation::Clockwise90, true, [0, -1, -1, 0], (48, 64)),
+            (Rotation::Clockwise180, true, [1, 0, 0, -1], (0, 48)),
+            (Rotation::Clockwise270, true, [0, 1, 1, 0], (0, 0)),
+        ] {
+            let properties = VideoProperties { rotation, flip, ..VideoProperties::default() };
+            let matrix = properties.matrix(64, 48);
+            assert_eq!(matrix, [fixed(a), fixed(b), 0, fixed(c), fixed(d), 0, fixed(tx), fixed(ty), 0x4000_0000],
+                       "{:?} flipped {}", rotation, flip);
+            assert_eq!(VideoProperties::orientation(&matrix), Some((rotation, flip)));
+        }
+
+        // scaled, or sheared
+        let mut scaled = VideoProperties::default().matrix(64, 48);
+        scaled[0] = fixed(2);
+        assert_eq!(VideoProperties::orientation(&scaled), None);
+        let mut sheared = VideoProperties::default().matrix(64, 48);
+        sheared[1] = fixed(1);
+        assert_eq!(VideoProperties::orientation(&sheared), None);
+    }
+
+    #[test]
+    fn parses_clean_apertures() {
+        assert_eq!("1920x800+0+140".parse::<CleanAperture>().unwrap(), CleanAperture { width: 1920, height: 800, x: 0, y: 140 });
+        for invalid in ["", "1920x800", "1920x800+0", "1920x800+0+140+1", "1920x800-0+140", "1920x800+0+-140", "ax800+0+140"] {
+            assert!(invalid.parse::<CleanAperture>().is_err(), "{}", invalid);
+        }
+        let aperture = CleanAperture { width: 1920, height: 800, x: 0, y: 140 };
+        assert_eq!(aperture.center_offset(1920, 1080), (0, 0));
+        assert_eq!(CleanAperture { y: 0, ..aperture }.center_offset(1920, 1080), (0, -280));
+    }
+
+    #[test]
+    fn parses_pixel_aspect_ratios() {
+        assert_eq!("4:3".parse::<PixelAspectRatio>().unwrap(), PixelAspectRatio { h_spacing: 4, v_spacing: 3 });
+        for invalid in ["", "4", "4/3", "0:1", "1:0", "a:3", "4:3:2", "-4:3"] {
+            assert!(invalid.parse::<PixelAspectRatio>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_rotations() {
+        assert_eq!("270".parse::<Rotation>().unwrap(), Rotation::Clockwise270);
+        for invalid in ["", "45", "-90", "360"] {
+            assert!(invalid.parse::<Rotation>().is_err(), "{}", invalid);
+        }
+    }
+}
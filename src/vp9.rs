@@ -0,0 +1,388 @@
+//!
+//! Just enough of the VP9 bitstream (VP9 Bitstream Specification v0.6) to find frames in a
+//! superframe and the size of their uncompressed headers, e.g. for subsample encryption.
+//!
+use anyhow::{bail, Context};
+
+/// Sizes of the frames in a superframe and of its trailing index.
+///
+/// A frame without superframe index is a superframe of one frame and no index.
+pub fn superframe(data: &[u8]) -> (Vec<usize>, usize) {
+    let marker = match data.last() {
+        Some(b) if b & 0xe0 == 0xc0 => *b,
+        _ => return (vec![data.len()], 0),
+    };
+    let frames = usize::from(marker & 0x07) + 1;
+    let size_bytes = usize::from((marker >> 3) & 0x03) + 1;
+    let index_size = 2 + size_bytes * frames;
+    // the index starts with the same marker byte
+    if data.len() < index_size || data[data.len() - index_size] != marker {
+        return (vec![data.len()], 0);
+    }
+    let index = &data[data.len() - index_size + 1..];
+    let sizes = index.chunks(size_bytes)
+        .take(frames)
+        .map(|b| b.iter().rev().fold(0, |size, byte| size << 8 | usize::from(*byte)))
+        .collect::<Vec<_>>();
+    if sizes.iter().sum::<usize>() + index_size != data.len() {
+        return (vec![data.len()], 0);
+    }
+    (sizes, index_size)
+}
+
+/// Parses uncompressed headers, keeping the reference frame sizes that later inter frames
+/// refer to, so frames have to be passed in decode order.
+#[derive(Default)]
+pub struct HeaderParser {
+    /// frame width and height in each of the 8 reference slots
+    ref_frame_sizes: [(u32, u32); 8],
+}
+
+impl HeaderParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Size in bytes of the uncompressed header of a frame (not a superframe).
+    pub fn uncompressed_header_size(&mut self, frame: &[u8]) -> anyhow::Result<usize> {
+        let mut r = BitReader::new(frame);
+        let (profile, show_existing_frame) = header_start(&mut r)?;
+        if show_existing_frame {
+            // the frame is nothing but a header
+            r.bits(3)?; // frame_to_show_map_idx
+            return Ok(r.byte_position());
+        }
+        let key_frame = r.bit()? == 0;
+        let show_frame = r.bit()? == 1;
+        let error_resilient_mode = r.bit()? == 1;
+
+        let refresh_frame_flags;
+        let size;
+        if key_frame {
+            frame_sync_code(&mut r)?;
+            color_config(&mut r, profile)?;
+            size = frame_size(&mut r)?;
+            render_size(&mut r)?;
+            refresh_frame_flags = 0xff;
+        } else {
+            let intra_only = !show_frame && r.bit()? == 1;
+            if !error_resilient_mode {
+                r.bits(2)?; // reset_frame_context
+            }
+            if intra_only {
+                frame_sync_code(&mut r)?;
+                if profile > 0 {
+                    color_config(&mut r, profile)?;
+                }
+                refresh_frame_flags = r.bits(8)?;
+                size = frame_size(&mut r)?;
+                render_size(&mut r)?;
+            } else {
+                refresh_frame_flags = r.bits(8)?;
+                let mut ref_frame_idx = [0; 3];
+                for idx in ref_frame_idx.iter_mut() {
+                    *idx = r.bits(3)? as usize;
+                    r.bit()?; // ref_frame_sign_bias
+                }
+                // frame_size_with_refs
+                let mut found = None;
+                for idx in &ref_frame_idx {
+                    if r.bit()? == 1 {
+                        found = Some(self.ref_frame_sizes[*idx]);
+                        break;
+                    }
+                }
+                size = match found {
+                    Some(size) => size,
+                    None => frame_size(&mut r)?,
+                };
+                render_size(&mut r)?;
+                r.bit()?; // allow_high_precision_mv
+                // read_interpolation_filter
+                if r.bit()? == 0 {
+                    r.bits(2)?; // raw_interpolation_filter
+                }
+            }
+        }
+
+        if !error_resilient_mode {
+            r.bit()?; // refresh_frame_context
+            r.bit()?; // frame_parallel_decoding_mode
+        }
+        r.bits(2)?; // frame_context_idx
+        loop_filter_params(&mut r)?;
+        quantization_params(&mut r)?;
+        segmentation_params(&mut r)?;
+        tile_info(&mut r, size.0)?;
+        r.bits(16)?; // header_size_in_bytes
+
+        for (i, slot) in self.ref_frame_sizes.iter_mut().enumerate() {
+            if refresh_frame_flags >> i & 1 == 1 {
+                *slot = size;
+            }
+        }
+        Ok(r.byte_position())
+    }
+}
+
+/// Whether a frame, or the first frame of a superframe, is a key frame.
+pub fn is_key_frame(data: &[u8]) -> bool {
+    let (frames, _) = superframe(data);
+    let mut r = BitReader::new(&data[..frames[0]]);
+    let key_frame = |r: &mut BitReader| -> anyhow::Result<bool> {
+        let (_, show_existing_frame) = header_start(r)?;
+        Ok(!show_existing_frame && r.bit()? == 0)
+    };
+    key_frame(&mut r).unwrap_or(false)
+}
+
+/// frame_marker, profile and show_existing_frame, returns the profile and the latter.
+fn header_start(r: &mut BitReader) -> anyhow::Result<(u32, bool)> {
+    if r.bits(2)? != 2 {
+        bail!("invalid VP9 frame marker");
+    }
+    let profile_low = r.bit()?;
+    let profile = r.bit()? << 1 | profile_low;
+    if profile == 3 {
+        r.bit()?; // reserved_zero
+    }
+    Ok((profile, r.bit()? == 1))
+}
+
+fn frame_sync_code(r: &mut BitReader) -> anyhow::Result<()> {
+    if r.bits(24)? != 0x49_83_42 {
+        bail!("invalid VP9 frame sync code");
+    }
+    Ok(())
+}
+
+fn color_config(r: &mut BitReader, profile: u32) -> anyhow::Result<()> {
+    if profile >= 2 {
+        r.bit()?; // ten_or_twelve_bit
+    }
+    // CS_RGB
+    if r.bits(3)? != 7 {
+        r.bit()?; // color_range
+        if profile == 1 || profile == 3 {
+            r.bits(3)?; // subsampling_x, subsampling_y, reserved_zero
+        }
+    } else if profile == 1 || profile == 3 {
+        r.bit()?; // reserved_zero
+    }
+    Ok(())
+}
+
+fn frame_size(r: &mut BitReader) -> anyhow::Result<(u32, u32)> {
+    Ok((r.bits(16)? + 1, r.bits(16)? + 1))
+}
+
+fn render_size(r: &mut BitReader) -> anyhow::Result<()> {
+    if r.bit()? == 1 {
+        r.bits(32)?; // render_width_minus_1, render_height_minus_1
+    }
+    Ok(())
+}
+
+fn loop_filter_params(r: &mut BitReader) -> anyhow::Result<()> {
+    r.bits(6 + 3)?; // loop_filter_level, loop_filter_sharpness
+    // loop_filter_delta_enabled, loop_filter_delta_update
+    if r.bit()? == 1 && r.bit()? == 1 {
+        // 4 ref deltas and 2 mode deltas, each an update flag and su(6)
+        for _ in 0..4 + 2 {
+            if r.bit()? == 1 {
+                r.bits(7)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn quantization_params(r: &mut BitReader) -> anyhow::Result<()> {
+    r.bits(8)?; // base_q_idx
+    // delta_q_y_dc, delta_q_uv_dc, delta_q_uv_ac
+    for _ in 0..3 {
+        if r.bit()? == 1 {
+            r.bits(5)?; // su(4)
+        }
+    }
+    Ok(())
+}
+
+fn segmentation_params(r: &mut BitReader) -> anyhow::Result<()> {
+    // segmentation_enabled
+    if r.bit()? == 0 {
+        return Ok(());
+    }
+    let read_prob = |r: &mut BitReader| -> anyhow::Result<()> {
+        if r.bit()? == 1 {
+            r.bits(8)?;
+        }
+        Ok(())
+    };
+    // segmentation_update_map
+    if r.bit()? == 1 {
+        for _ in 0..7 {
+            read_prob(r)?; // segmentation_tree_probs
+        }
+        // segmentation_temporal_update
+        if r.bit()? == 1 {
+            for _ in 0..3 {
+                read_prob(r)?; // segmentation_pred_prob
+            }
+        }
+    }
+    // segmentation_update_data
+    if r.bit()? == 1 {
+        r.bit()?; // segmentation_abs_or_delta_update
+        const FEATURE_BITS: [u32; 4] = [8, 6, 2, 0];
+        const FEATURE_SIGNED: [bool; 4] = [true, true, false, false];
+        for _ in 0..8 {
+            for j in 0..4 {
+                // feature_enabled
+                if r.bit()? == 1 {
+                    r.bits(FEATURE_BITS[j])?; // feature_value
+                    if FEATURE_SIGNED[j] {
+                        r.bit()?; // feature_sign
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn tile_info(r: &mut BitReader, width: u32) -> anyhow::Result<()> {
+    let sb64_cols = ((width + 7) >> 3).div_ceil(8);
+    let mut min_log2 = 0;
+    while (64 << min_log2) < sb64_cols {
+        min_log2 += 1;
+    }
+    let mut max_log2 = 1;
+    while (sb64_cols >> max_log2) >= 4 {
+        max_log2 += 1;
+    }
+    max_log2 -= 1;
+
+    let mut tile_cols_log2 = min_log2;
+    // increment_tile_cols_log2
+    while tile_cols_log2 < max_log2 && r.bit()? == 1 {
+        tile_cols_log2 += 1;
+    }
+    // tile_rows_log2, increment_tile_rows_log2
+    if r.bit()? == 1 {
+        r.bit()?;
+    }
+    Ok(())
+}
+
+/// MSB-first bit reader
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn bit(&mut self) -> anyhow::Result<u32> {
+        let byte = self.data.get(self.position / 8).context("truncated VP9 header")?;
+        let bit = byte >> (7 - self.position % 8) & 1;
+        self.position += 1;
+        Ok(u32::from(bit))
+    }
+
+    fn bits(&mut self, n: u32) -> anyhow::Result<u32> {
+        (0..n).try_fold(0, |v, _| Ok(v << 1 | self.bit()?))
+    }
+
+    /// bytes read so far, including the trailing bits of a partial byte
+    fn byte_position(&self) -> usize {
+        self.position.div_ceil(8)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// MSB-first bit writer, the counterpart of `BitReader`
+    #[derive(Default)]
+    struct BitWriter {
+        data: Vec<u8>,
+        position: usize,
+    }
+
+    impl BitWriter {
+        fn bits(&mut self, n: u32, v: u32) -> &mut Self {
+            for i in (0..n).rev() {
+                if self.position.is_multiple_of(8) {
+                    self.data.push(0);
+                }
+                self.data[self.position / 8] |= ((v >> i & 1) as u8) << (7 - self.position % 8);
+                self.position += 1;
+            }
+            self
+        }
+    }
+
+    /// A profile 0 frame with a minimal uncompressed header followed by `payload`, inter frames
+    /// taking their size from reference slot 0.
+    pub fn frame(key_frame: bool, width: u16, height: u16, payload: &[u8]) -> Vec<u8> {
+        let mut w = BitWriter::default();
+        // frame_marker, profile, show_existing_frame, frame_type, show_frame, error_resilient_mode
+        w.bits(2, 2).bits(2, 0).bits(1, 0).bits(1, u32::from(!key_frame)).bits(1, 1).bits(1, 0);
+        if key_frame {
+            w.bits(24, 0x49_83_42).bits(3, 1).bits(1, 0); // sync code, BT.601, studio range
+            w.bits(16, u32::from(width) - 1).bits(16, u32::from(height) - 1).bits(1, 0);
+        } else {
+            w.bits(2, 0).bits(8, 0); // reset_frame_context, refresh_frame_flags
+            w.bits(12, 0).bits(1, 1).bits(1, 0); // ref_frame_idx and sign bias, found_ref, render_size
+            w.bits(1, 0).bits(1, 1); // allow_high_precision_mv, switchable interpolation filter
+        }
+        w.bits(2, 0).bits(2, 0); // refresh_frame_context, frame_parallel_decoding_mode, frame_context_idx
+        w.bits(9, 0).bits(1, 0).bits(8, 60).bits(3, 0).bits(1, 0); // loop filter, quantizer, segmentation
+        // no tile column increments below 512 pixels, tile_rows_log2, header_size_in_bytes
+        w.bits(1, 0).bits(16, payload.len() as u32);
+        let mut frame = w.data;
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn finds_uncompressed_header_sizes() {
+        let mut parser = HeaderParser::new();
+        assert_eq!(parser.uncompressed_header_size(&frame(true, 64, 48, &[0; 20])).unwrap(), 14);
+        assert_eq!(parser.uncompressed_header_size(&frame(false, 64, 48, &[0; 20])).unwrap(), 10);
+        assert!(parser.uncompressed_header_size(&frame(true, 64, 48, &[])[..10]).is_err());
+    }
+
+    #[test]
+    fn splits_superframes() {
+        let (a, b) = (frame(false, 64, 48, &[1; 30]), frame(false, 64, 48, &[2; 5]));
+        let mut data = [&a[..], &b[..]].concat();
+        // 2 frames, 1-byte sizes
+        data.extend_from_slice(&[0xc1, a.len() as u8, b.len() as u8, 0xc1]);
+        assert_eq!(superframe(&data), (vec![a.len(), b.len()], 4));
+        assert_eq!(superframe(&a), (vec![a.len()], 0));
+    }
+
+    #[test]
+    fn finds_key_frames() {
+        let (key, inter) = (frame(true, 64, 48, &[0; 4]), frame(false, 64, 48, &[0; 4]));
+        assert!(is_key_frame(&key));
+        assert!(!is_key_frame(&inter));
+        // profile 3 has a reserved bit before show_existing_frame
+        assert!(is_key_frame(&[0b1011_0000]));
+        assert!(!is_key_frame(&[0b1011_0010]));
+        // show_existing_frame
+        assert!(!is_key_frame(&[0b1000_1000]));
+        assert!(!is_key_frame(&[]));
+        assert!(!is_key_frame(&[0x02]));
+        // a superframe starting with a key frame
+        let mut data = [&key[..], &inter[..]].concat();
+        data.extend_from_slice(&[0xc1, key.len() as u8, inter.len() as u8, 0xc1]);
+        assert!(is_key_frame(&data));
+    }
+}
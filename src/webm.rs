@@ -0,0 +1,462 @@
+//!
+//! WebM (Matroska) output of a VP9 stream, optionally with its alpha channel.
+//!
+//! The alpha channel is a second VP9 stream whose luma is the alpha, carried in the
+//! `BlockAdditional` of each block with `BlockAddID` 1, as decoded by browsers.
+//!
+//! The segment has an unknown size, as in live streams, so the file is written in one pass
+//! without cues. Clusters are kept in memory until the next key frame and written whole.
+//!
+use std::io::Write;
+
+use crate::video::ColorInfo;
+
+/// Element IDs, with their length marker bits
+mod id {
+    pub const EBML: u32 = 0x1A45_DFA3;
+    pub const EBML_VERSION: u32 = 0x4286;
+    pub const EBML_READ_VERSION: u32 = 0x42F7;
+    pub const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+    pub const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+    pub const DOC_TYPE: u32 = 0x4282;
+    pub const DOC_TYPE_VERSION: u32 = 0x4287;
+    pub const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+    pub const SEGMENT: u32 = 0x1853_8067;
+    pub const INFO: u32 = 0x1549_A966;
+    pub const TIMECODE_SCALE: u32 = 0x2A_D7B1;
+    pub const MUXING_APP: u32 = 0x4D80;
+    pub const WRITING_APP: u32 = 0x5741;
+    pub const TRACKS: u32 = 0x1654_AE6B;
+    pub const TRACK_ENTRY: u32 = 0xAE;
+    pub const TRACK_NUMBER: u32 = 0xD7;
+    pub const TRACK_UID: u32 = 0x73C5;
+    pub const TRACK_TYPE: u32 = 0x83;
+    pub const FLAG_LACING: u32 = 0x9C;
+    pub const CODEC_ID: u32 = 0x86;
+    pub const MAX_BLOCK_ADDITION_ID: u32 = 0x55EE;
+    pub const VIDEO: u32 = 0xE0;
+    pub const PIXEL_WIDTH: u32 = 0xB0;
+    pub const PIXEL_HEIGHT: u32 = 0xBA;
+    pub const ALPHA_MODE: u32 = 0x53C0;
+    pub const COLOUR: u32 = 0x55B0;
+    pub const MATRIX_COEFFICIENTS: u32 = 0x55B1;
+    pub const RANGE: u32 = 0x55B9;
+    pub const TRANSFER_CHARACTERISTICS: u32 = 0x55BA;
+    pub const PRIMARIES: u32 = 0x55BB;
+    pub const CLUSTER: u32 = 0x1F43_B675;
+    pub const TIMECODE: u32 = 0xE7;
+    pub const SIMPLE_BLOCK: u32 = 0xA3;
+    pub const BLOCK_GROUP: u32 = 0xA0;
+    pub const BLOCK: u32 = 0xA1;
+    pub const REFERENCE_BLOCK: u32 = 0xFB;
+    pub const BLOCK_ADDITIONS: u32 = 0x75A1;
+    pub const BLOCK_MORE: u32 = 0xA6;
+    pub const BLOCK_ADD_ID: u32 = 0xEE;
+    pub const BLOCK_ADDITIONAL: u32 = 0xA5;
+}
+
+/// size of an element running to the end of the file
+const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+/// nanoseconds per timecode tick, timecodes are in milliseconds
+const TIMECODE_SCALE: u64 = 1_000_000;
+/// `BlockAddID` of the alpha channel
+const ALPHA_ADD_ID: u64 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WebmHeader {
+    pub width: u16,
+    pub height: u16,
+    /// numerator and denominator (in seconds) of the frame pts, as in `IvfHeader`
+    pub timebase: [u32; 2],
+    /// blocks carry the alpha stream, `AlphaMode` 1
+    pub alpha: bool,
+    /// `Colour` of the track
+    pub color: Option<ColorInfo>,
+}
+
+impl WebmHeader {
+    pub fn new(width: u16, height: u16, timebase: [u32; 2]) -> Self {
+        Self { width, height, timebase, alpha: false, color: None }
+    }
+
+    /// EBML header, the start of the segment, `Info` and `Tracks`
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut w = EbmlWriter::default();
+        w.element(id::EBML, |w| {
+            w.uint(id::EBML_VERSION, 1)
+                .uint(id::EBML_READ_VERSION, 1)
+                .uint(id::EBML_MAX_ID_LENGTH, 4)
+                .uint(id::EBML_MAX_SIZE_LENGTH, 8)
+                .string(id::DOC_TYPE, "webm")
+                // BlockAdditions need version 4
+                .uint(id::DOC_TYPE_VERSION, 4)
+                .uint(id::DOC_TYPE_READ_VERSION, 2);
+        });
+        w.id(id::SEGMENT).bytes(&UNKNOWN_SIZE);
+        w.element(id::INFO, |w| {
+            w.uint(id::TIMECODE_SCALE, TIMECODE_SCALE)
+                .string(id::MUXING_APP, "img2vp9")
+                .string(id::WRITING_APP, "img2vp9");
+        });
+        w.element(id::TRACKS, |w| {
+            w.element(id::TRACK_ENTRY, |w| {
+                w.uint(id::TRACK_NUMBER, 1)
+                    .uint(id::TRACK_UID, 1)
+                    .uint(id::TRACK_TYPE, 1) // video
+                    .uint(id::FLAG_LACING, 0)
+                    .string(id::CODEC_ID, "V_VP9");
+                if self.alpha {
+                    w.uint(id::MAX_BLOCK_ADDITION_ID, ALPHA_ADD_ID);
+                }
+                w.element(id::VIDEO, |w| {
+                    w.uint(id::PIXEL_WIDTH, u64::from(self.width))
+                        .uint(id::PIXEL_HEIGHT, u64::from(self.height));
+                    if self.alpha {
+                        w.uint(id::ALPHA_MODE, 1);
+                    }
+                    if let Some(color) = &self.color {
+                        w.element(id::COLOUR, |w| {
+                            w.uint(id::MATRIX_COEFFICIENTS, u64::from(color.matrix))
+                                // 1 broadcast, 2 full
+                                .uint(id::RANGE, if color.full_range { 2 } else { 1 })
+                                .uint(id::TRANSFER_CHARACTERISTICS, u64::from(color.transfer))
+                                .uint(id::PRIMARIES, u64::from(color.primaries));
+                        });
+                    }
+                });
+            });
+        });
+        w.into_bytes()
+    }
+}
+
+/// Blocks of a cluster not written yet.
+struct Cluster {
+    /// absolute, in milliseconds
+    timecode: i64,
+    /// timecode of the last block, relative to the cluster
+    last_block: i16,
+    blocks: EbmlWriter,
+}
+
+/// Writes encoded frames into a WebM stream.
+pub struct WebmWriter<W: Write> {
+    inner: W,
+    header: WebmHeader,
+    cluster: Option<Cluster>,
+}
+
+impl<W: Write> WebmWriter<W> {
+    pub fn new(mut inner: W, header: WebmHeader) -> anyhow::Result<Self> {
+        inner.write_all(&header.to_bytes())?;
+        Ok(Self { inner, header, cluster: None })
+    }
+
+    /// Adds a frame and, if the header has `alpha`, the frame of the alpha stream encoded
+    /// from the same picture.
+    ///
+    /// Frames must come in decode order, a key frame starts a new cluster.
+    pub fn write_frame(&mut self, data: &[u8], alpha: Option<&[u8]>, pts: i64, key: bool) -> anyhow::Result<()> {
+        let [num, den] = self.header.timebase;
+        let timecode = (i128::from(pts) * i128::from(num) * 1000 / i128::from(den.max(1))) as i64;
+        let relative = self.cluster.as_ref().map(|c| timecode - c.timecode);
+        // block timecodes are 16-bit, relative to the cluster
+        if key || !relative.is_some_and(|t| (0..=i64::from(i16::MAX)).contains(&t)) {
+            self.flush()?;
+            self.cluster = Some(Cluster { timecode, last_block: 0, blocks: EbmlWriter::default() });
+        }
+
+        let with_alpha = self.header.alpha;
+        let cluster = self.cluster.as_mut().unwrap();
+        let relative = (timecode - cluster.timecode) as i16;
+        // track number 1, timecode and flags, key frames are only flagged in simple blocks
+        let block_header = |flags: u8| {
+            let [high, low] = relative.to_be_bytes();
+            [0x81, high, low, flags]
+        };
+        match alpha.filter(|_| with_alpha) {
+            None => {
+                cluster.blocks.id(id::SIMPLE_BLOCK)
+                    .size(4 + data.len())
+                    .bytes(&block_header(if key { 0x80 } else { 0 }))
+                    .bytes(data);
+            }
+            Some(alpha) => {
+                let last_block = cluster.last_block;
+                cluster.blocks.element(id::BLOCK_GROUP, |w| {
+                    w.id(id::BLOCK)
+                        .size(4 + data.len())
+                        .bytes(&block_header(0))
+                        .bytes(data);
+                    // blocks without a reference are key frames
+                    if !key {
+                        w.int(id::REFERENCE_BLOCK, i64::from(last_block) - i64::from(relative));
+                    }
+                    w.element(id::BLOCK_ADDITIONS, |w| {
+                        w.element(id::BLOCK_MORE, |w| {
+                            w.uint(id::BLOCK_ADD_ID, ALPHA_ADD_ID)
+                                .binary(id::BLOCK_ADDITIONAL, alpha);
+                        });
+                    });
+                });
+            }
+        }
+        cluster.last_block = relative;
+        Ok(())
+    }
+
+    /// Writes the pending cluster and returns the inner writer.
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        self.flush()?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if let Some(cluster) = self.cluster.take() {
+            let mut w = EbmlWriter::default();
+            w.element(id::CLUSTER, |w| {
+                w.uint(id::TIMECODE, cluster.timecode.max(0) as u64)
+                    .bytes(&cluster.blocks.into_bytes());
+            });
+            self.inner.write_all(&w.into_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Builds EBML elements, the counterpart of `bmff::BoxWriter`. All integers are big-endian.
+#[derive(Default)]
+struct EbmlWriter {
+    buffer: Vec<u8>,
+}
+
+impl EbmlWriter {
+    /// Writes a master element whose children are produced by `f`.
+    fn element<F: FnOnce(&mut Self)>(&mut self, id: u32, f: F) -> &mut Self {
+        let mut children = Self::default();
+        f(&mut children);
+        self.id(id).size(children.buffer.len()).bytes(&children.buffer)
+    }
+
+    /// Unsigned integer element, in as few bytes as possible.
+    fn uint(&mut self, id: u32, v: u64) -> &mut Self {
+        let bytes = v.to_be_bytes();
+        let skip = (v.leading_zeros() / 8).min(7) as usize;
+        self.binary(id, &bytes[skip..])
+    }
+
+    /// Signed integer element, in as few bytes as possible.
+    fn int(&mut self, id: u32, v: i64) -> &mut Self {
+        let bytes = v.to_be_bytes();
+        // bytes repeating the sign bit of the next one can go
+        let redundant = bytes.windows(2).take_while(|b| (b[0] == 0 && b[1] < 0x80) || (b[0] == 0xFF && b[1] >= 0x80)).count();
+        self.binary(id, &bytes[redundant..])
+    }
+
+    fn string(&mut self, id: u32, v: &str) -> &mut Self {
+        self.binary(id, v.as_bytes())
+    }
+
+    fn binary(&mut self, id: u32, v: &[u8]) -> &mut Self {
+        self.id(id).size(v.len()).bytes(v)
+    }
+
+    /// IDs carry their own length marker, leading zero bytes are left out.
+    fn id(&mut self, id: u32) -> &mut Self {
+        let bytes = id.to_be_bytes();
+        self.bytes(&bytes[(id.leading_zeros() / 8) as usize..])
+    }
+
+    /// Variable-length size, the all-ones values of each length being reserved for unknown sizes.
+    fn size(&mut self, size: usize) -> &mut Self {
+        let size = size as u64;
+        let length = (1..=8usize).find(|n| size < (1 << (7 * n)) - 1).expect("element too large");
+        let bytes = (size | 1 << (7 * length)).to_be_bytes();
+        self.bytes(&bytes[8 - length..])
+    }
+
+    fn bytes(&mut self, v: &[u8]) -> &mut Self {
+        self.buffer.extend_from_slice(v);
+        self
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An element read back, its ID with the length marker bits.
+    struct Element<'a> {
+        id: u32,
+        data: &'a [u8],
+    }
+
+    /// Length and value of the variable-length integer at the start of `bytes`, the marker bit
+    /// kept when `marker`.
+    fn vint(bytes: &[u8], marker: bool) -> (usize, u64) {
+        let length = bytes[0].leading_zeros() as usize + 1;
+        let value = bytes[..length].iter().fold(0, |v, b| v << 8 | u64::from(*b));
+        match marker {
+            true => (length, value),
+            false => (length, value & !(1 << (7 * length))),
+        }
+    }
+
+    /// The elements one after the other in `data`, an unknown size running to its end.
+    fn elements(mut data: &[u8]) -> Vec<Element<'_>> {
+        let mut elements = vec![];
+        while !data.is_empty() {
+            let (id_length, id) = vint(data, true);
+            let (size_length, size) = vint(&data[id_length..], false);
+            let start = id_length + size_length;
+            let end = match size == (1 << (7 * size_length)) - 1 {
+                true => data.len(),
+                false => start + size as usize,
+            };
+            elements.push(Element { id: id as u32, data: &data[start..end] });
+            data = &data[end..];
+        }
+        elements
+    }
+
+    /// The first element at the end of `path`, each ID looked up within the previous element.
+    fn find<'a>(data: &'a [u8], path: &[u32]) -> Option<&'a [u8]> {
+        path.iter().try_fold(data, |data, id| elements(data).into_iter().find(|e| e.id == *id).map(|e| e.data))
+    }
+
+    fn uint(data: &[u8]) -> u64 {
+        data.iter().fold(0, |v, b| v << 8 | u64::from(*b))
+    }
+
+    fn int(data: &[u8]) -> i64 {
+        let sign = if data[0] >= 0x80 { -1 } else { 0 };
+        data.iter().fold(sign, |v, b| v << 8 | i64::from(*b))
+    }
+
+    /// Relative timecode and frame of a block.
+    type Block = (i16, Vec<u8>);
+
+    /// Timecode and the blocks of each cluster.
+    fn clusters(file: &[u8]) -> Vec<(u64, Vec<Block>)> {
+        let segment = find(file, &[id::SEGMENT]).unwrap();
+        elements(segment).into_iter().filter(|e| e.id == id::CLUSTER).map(|cluster| {
+            let children = elements(cluster.data);
+            let blocks = children.iter().filter_map(|e| match e.id {
+                id::SIMPLE_BLOCK => Some(e.data),
+                id::BLOCK_GROUP => find(e.data, &[id::BLOCK]),
+                _ => None,
+            });
+            let blocks = blocks.map(|b| (i16::from_be_bytes([b[1], b[2]]), b[4..].to_vec())).collect();
+            (uint(find(cluster.data, &[id::TIMECODE]).unwrap()), blocks)
+        }).collect()
+    }
+
+    fn writer(alpha: bool) -> WebmWriter<Vec<u8>> {
+        // pts in milliseconds
+        WebmWriter::new(vec![], WebmHeader { alpha, ..WebmHeader::new(64, 48, [1, 1000]) }).unwrap()
+    }
+
+    #[test]
+    fn sizes_avoid_the_all_ones_values() {
+        for (size, encoded) in [
+            (0, &[0x80][..]),
+            (126, &[0xFE]),
+            // 0xFF is an unknown size
+            (127, &[0x40, 0x7F]),
+            (16382, &[0x7F, 0xFE]),
+            (16383, &[0x20, 0x3F, 0xFF]),
+        ] {
+            let mut w = EbmlWriter::default();
+            w.binary(id::BLOCK_ADDITIONAL, &vec![7; size]);
+            let bytes = w.into_bytes();
+            assert_eq!(bytes[1..][..encoded.len()], *encoded, "{}", size);
+            let read = elements(&bytes);
+            assert_eq!((read.len(), read[0].id, read[0].data.len()), (1, id::BLOCK_ADDITIONAL, size));
+        }
+
+        // simple blocks of 127 and 16383 bytes, with their 4-byte header
+        let mut webm = writer(false);
+        let frames = [vec![1; 122], vec![2; 123], vec![3; 16378], vec![4; 16379]];
+        for (i, frame) in frames.iter().enumerate() {
+            webm.write_frame(frame, None, i as i64, i == 0).unwrap();
+        }
+        let blocks = clusters(&webm.finish().unwrap()).remove(0).1;
+        assert_eq!(blocks.into_iter().map(|(_, frame)| frame).collect::<Vec<_>>(), frames);
+    }
+
+    #[test]
+    fn integers_take_as_few_bytes_as_their_sign_allows() {
+        for (value, encoded) in [
+            (0, &[0x00][..]),
+            (127, &[0x7F]),
+            (128, &[0x00, 0x80]),
+            (-1, &[0xFF]),
+            (-128, &[0x80]),
+            (-129, &[0xFF, 0x7F]),
+            (-32768, &[0x80, 0x00]),
+            (-32769, &[0xFF, 0x7F, 0xFF]),
+        ] {
+            let mut w = EbmlWriter::default();
+            w.int(id::REFERENCE_BLOCK, value);
+            let bytes = w.into_bytes();
+            assert_eq!(bytes[2..], *encoded, "{}", value);
+            assert_eq!(int(elements(&bytes)[0].data), value);
+        }
+
+        // each block refers back to the previous one
+        let mut webm = writer(true);
+        for (pts, key) in [(0, true), (33, false), (300, false)] {
+            webm.write_frame(&[pts as u8], Some(&[0xA]), pts, key).unwrap();
+        }
+        let file = webm.finish().unwrap();
+        let cluster = find(&file, &[id::SEGMENT, id::CLUSTER]).unwrap();
+        let references = elements(cluster).iter().filter(|e| e.id == id::BLOCK_GROUP)
+            .map(|group| find(group.data, &[id::REFERENCE_BLOCK]).map(|r| (r.len(), int(r))))
+            .collect::<Vec<_>>();
+        assert_eq!(references, [None, Some((1, -33)), Some((2, -267))]);
+    }
+
+    #[test]
+    fn clusters_split_when_block_timecodes_overflow() {
+        let mut webm = writer(false);
+        for (pts, key) in [(0, true), (32767, false), (32768, false), (40000, false), (41000, true)] {
+            webm.write_frame(&[pts as u8], None, pts, key).unwrap();
+        }
+        let clusters = clusters(&webm.finish().unwrap());
+        let timecodes = clusters.iter().map(|(timecode, blocks)| (*timecode, blocks.iter().map(|b| b.0).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        // the largest relative timecode fits, the next one starts a cluster as does a key frame
+        assert_eq!(timecodes, [(0, vec![0, 32767]), (32768, vec![0, 7232]), (41000, vec![0])]);
+    }
+
+    #[test]
+    fn alpha_is_signalled_in_the_track() {
+        for alpha in [false, true] {
+            let file = writer(alpha).finish().unwrap();
+            let ebml = find(&file, &[id::EBML]).unwrap();
+            assert_eq!(find(ebml, &[id::DOC_TYPE]), Some(&b"webm"[..]));
+            assert_eq!(find(ebml, &[id::DOC_TYPE_VERSION]).map(uint), Some(4));
+
+            let track = find(&file, &[id::SEGMENT, id::TRACKS, id::TRACK_ENTRY]).unwrap();
+            assert_eq!(find(track, &[id::CODEC_ID]), Some(&b"V_VP9"[..]));
+            assert_eq!(find(track, &[id::VIDEO, id::PIXEL_WIDTH]).map(uint), Some(64));
+            assert_eq!(find(track, &[id::VIDEO, id::PIXEL_HEIGHT]).map(uint), Some(48));
+            let expected = alpha.then_some(ALPHA_ADD_ID);
+            assert_eq!(find(track, &[id::MAX_BLOCK_ADDITION_ID]).map(uint), expected);
+            assert_eq!(find(track, &[id::VIDEO, id::ALPHA_MODE]).map(uint), alpha.then_some(1));
+        }
+
+        // the alpha frame is the BlockAdditional of its block
+        let mut webm = writer(true);
+        webm.write_frame(&[1, 2], Some(&[3, 4, 5]), 0, true).unwrap();
+        let file = webm.finish().unwrap();
+        let more = find(&file, &[id::SEGMENT, id::CLUSTER, id::BLOCK_GROUP, id::BLOCK_ADDITIONS, id::BLOCK_MORE]).unwrap();
+        assert_eq!(find(more, &[id::BLOCK_ADD_ID]).map(uint), Some(ALPHA_ADD_ID));
+        assert_eq!(find(more, &[id::BLOCK_ADDITIONAL]), Some(&[3, 4, 5][..]));
+    }
+}
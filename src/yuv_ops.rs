@@ -0,0 +1,561 @@
+//!
+//! Cropping, scaling and blending of `YuvFrame`s, done on the planes so thumbnails, overlays
+//! and pictures of another size don't need a round trip through RGB.
+//!
+use crate::yuv_util::{clamp, YuvFormat, YuvFrame, SHIFT};
+
+/// Extra fraction bits of 8-bit samples kept between the horizontal and the vertical pass of
+/// `scale`, fewer for larger samples
+const INTERMEDIATE_BITS: u32 = 6;
+
+/// The `width`x`height` part of `frame` at `x`, `y`, which must be even for subsampled chroma.
+pub fn crop(frame: &YuvFrame, x: u32, y: u32, width: u32, height: u32) -> YuvFrame {
+    let inside = |start: u32, size: u32, limit: u32| start.checked_add(size).is_some_and(|end| end <= limit);
+    assert!(inside(x, width, frame.width()) && inside(y, height, frame.height()),
+            "{}x{}+{}+{} is outside of {}x{}", width, height, x, y, frame.width(), frame.height());
+    let (x_shift, y_shift) = frame.format().chroma_shift();
+    assert!(x.is_multiple_of(1 << x_shift) && y.is_multiple_of(1 << y_shift), "cropping at {},{} splits chroma samples", x, y);
+
+    let mut cropped = YuvFrame::with_bit_depth(frame.format(), width, height, frame.bit_depth());
+    let dimensions = [0, 1, 2].map(|plane| cropped.plane_dimensions(plane));
+    let strides = (frame.strides(), cropped.strides());
+    let bytes_per_sample = frame.bytes_per_sample();
+    let (sources, targets) = (frame.planes(), cropped.planes_mut());
+    for plane in 0..3 {
+        let (samples, rows, channels) = dimensions[plane];
+        let (left, top) = if plane == 0 { (x, y) } else { (x >> x_shift, y >> y_shift) };
+        let (left, top) = (left as usize * channels * bytes_per_sample, top as usize);
+        let row_bytes = samples * channels * bytes_per_sample;
+        for row in 0..rows {
+            let source = &sources[plane][(top + row) * strides.0[plane] + left..][..row_bytes];
+            targets[plane][row * strides.1[plane]..][..row_bytes].copy_from_slice(source);
+        }
+    }
+    cropped
+}
+
+/// `frame` resampled to `width`x`height` with `filter`.
+pub fn scale(frame: &YuvFrame, width: u32, height: u32, filter: ScaleFilter) -> YuvFrame {
+    let mut scaled = YuvFrame::with_bit_depth(frame.format(), width, height, frame.bit_depth());
+    scale_into(frame, &mut scaled, filter);
+    scaled
+}
+
+/// Resamples `frame` to the size of `scaled`, which has the same format and bit depth.
+pub fn scale_into(frame: &YuvFrame, scaled: &mut YuvFrame, filter: ScaleFilter) {
+    let (source, target) = (Rect::of(frame), Rect::of(scaled));
+    resample(frame, source, scaled, target, filter);
+}
+
+/// Resamples `frame` into `target`, of any size, keeping the aspect ratio as `options.mode` says.
+///
+/// `padding` holds the Y, U and V samples of the bars of `ResizeMode::Fit`, see
+/// `yuv_util::convert_color`. Bars and crops start on even pixels, so no chroma sample is split
+/// and the alpha of a picture lines up with its colour whatever the formats.
+pub fn resize_into(frame: &YuvFrame, target: &mut YuvFrame, options: &ResizeOptions, padding: [u16; 3]) {
+    let (source, full) = (Rect::of(frame), Rect::of(target));
+    // the nearest even size within `1..=limit`
+    let fit = |size: f64, limit: u32| (((size / 2.0).round() as u32) * 2).clamp(2, limit.max(2)).min(limit);
+    let centered = |free: u32| free / 4 * 2;
+    let x_scale = f64::from(full.width) / f64::from(source.width);
+    let y_scale = f64::from(full.height) / f64::from(source.height);
+    match options.mode {
+        ResizeMode::Stretch => scale_into(frame, target, options.filter),
+        ResizeMode::Fit => {
+            let scale = x_scale.min(y_scale);
+            let (width, height) = (fit(f64::from(source.width) * scale, full.width), fit(f64::from(source.height) * scale, full.height));
+            let (x, y) = (centered(full.width - width), centered(full.height - height));
+            fill(target, padding);
+            resample(frame, source, target, Rect { x, y, width, height }, options.filter);
+        }
+        ResizeMode::Fill => {
+            let scale = x_scale.max(y_scale);
+            let (width, height) = (fit(f64::from(full.width) / scale, source.width), fit(f64::from(full.height) / scale, source.height));
+            let (x, y) = (centered(source.width - width), centered(source.height - height));
+            resample(frame, Rect { x, y, width, height }, target, full, options.filter);
+        }
+    }
+}
+
+/// Sets every sample of `frame` to `yuv`.
+pub fn fill(frame: &mut YuvFrame, yuv: [u16; 3]) {
+    let (format, bytes_per_sample) = (frame.format(), frame.bytes_per_sample());
+    let [_, u, v] = yuv;
+    let mut targets = frame.planes_mut();
+    for (plane, target) in targets.iter_mut().enumerate() {
+        // the second plane of NV12 interleaves U and V
+        let samples = match (format, plane) {
+            (YuvFormat::Nv12, 1) => vec![u, v],
+            _ => vec![yuv[plane]],
+        };
+        let pattern = samples.iter()
+            .flat_map(|&sample| if bytes_per_sample == 2 { sample.to_le_bytes().to_vec() } else { vec![sample as u8] })
+            .collect::<Vec<_>>();
+        target.chunks_exact_mut(pattern.len()).for_each(|t| t.copy_from_slice(&pattern));
+    }
+}
+
+/// Filter of `scale` and `resize_into`, widened to take in every covered pixel when shrinking.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScaleFilter {
+    /// triangle, 2x2 pixels when enlarging
+    Bilinear,
+    /// Catmull-Rom, 4x4 pixels when enlarging
+    #[default]
+    Bicubic,
+    /// 3 lobes, 6x6 pixels when enlarging, the sharpest
+    Lanczos,
+}
+
+impl ScaleFilter {
+    /// radius of the kernel, in input pixels when enlarging
+    fn support(&self) -> f64 {
+        match self {
+            ScaleFilter::Bilinear => 1.0,
+            ScaleFilter::Bicubic => 2.0,
+            ScaleFilter::Lanczos => 3.0,
+        }
+    }
+
+    fn kernel(&self, x: f64) -> f64 {
+        let x = x.abs();
+        match self {
+            ScaleFilter::Bilinear => (1.0 - x).max(0.0),
+            ScaleFilter::Bicubic if x < 1.0 => (1.5 * x - 2.5) * x * x + 1.0,
+            ScaleFilter::Bicubic if x < 2.0 => ((-0.5 * x + 2.5) * x - 4.0) * x + 2.0,
+            ScaleFilter::Bicubic => 0.0,
+            ScaleFilter::Lanczos if x < 1e-9 => 1.0,
+            ScaleFilter::Lanczos if x < 3.0 => {
+                let pi_x = std::f64::consts::PI * x;
+                3.0 * pi_x.sin() * (pi_x / 3.0).sin() / (pi_x * pi_x)
+            }
+            ScaleFilter::Lanczos => 0.0,
+        }
+    }
+}
+
+impl std::str::FromStr for ScaleFilter {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "bilinear" => Ok(ScaleFilter::Bilinear),
+            "bicubic" => Ok(ScaleFilter::Bicubic),
+            "lanczos" => Ok(ScaleFilter::Lanczos),
+            _ => anyhow::bail!("unknown filter {}, expected bilinear, bicubic or lanczos", s),
+        }
+    }
+}
+
+/// How a picture of another aspect ratio goes into the frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResizeMode {
+    /// to the size of the frame, distorted
+    #[default]
+    Stretch,
+    /// as large as fits, with bars of the padding colour, i.e. letterboxed or pillarboxed
+    Fit,
+    /// as small as covers the frame, the overflow cut off
+    Fill,
+}
+
+impl std::str::FromStr for ResizeMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "stretch" => Ok(ResizeMode::Stretch),
+            "fit" | "letterbox" => Ok(ResizeMode::Fit),
+            "fill" => Ok(ResizeMode::Fill),
+            _ => anyhow::bail!("unknown resize mode {}, expected stretch, fit, letterbox or fill", s),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResizeOptions {
+    pub mode: ResizeMode,
+    pub filter: ScaleFilter,
+    /// RGB of the bars of `ResizeMode::Fit`
+    pub padding: [u8; 3],
+}
+
+/// A part of a frame, in luma samples, `<width>x<height>+<x>+<y>` on the command line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl std::str::FromStr for Rect {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let invalid = || anyhow::anyhow!("expected <width>x<height>+<x>+<y>, got {}", s);
+        let fields = s.split(['x', '+']).map(|f| f.parse::<u32>().map_err(|_| invalid())).collect::<anyhow::Result<Vec<_>>>()?;
+        match fields[..] {
+            [width, height, x, y] if width > 0 && height > 0 => Ok(Self { x, y, width, height }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Rect {
+    fn of(frame: &YuvFrame) -> Self {
+        Self { x: 0, y: 0, width: frame.width(), height: frame.height() }
+    }
+
+    /// The samples of `plane` covering the rectangle, as x, y, width and height.
+    fn plane(&self, format: YuvFormat, plane: usize) -> (usize, usize, usize, usize) {
+        let (x_shift, y_shift) = if plane == 0 { (0, 0) } else { format.chroma_shift() };
+        let (left, top) = (self.x >> x_shift, self.y >> y_shift);
+        let right = (self.x + self.width + (1 << x_shift) - 1) >> x_shift;
+        let bottom = (self.y + self.height + (1 << y_shift) - 1) >> y_shift;
+        (left as usize, top as usize, (right - left) as usize, (bottom - top) as usize)
+    }
+}
+
+/// Resamples `source` of `frame` into `target` of `scaled`, plane by plane: a horizontal pass
+/// into rows of `INTERMEDIATE_BITS` more precision, then a vertical pass. Both are plain loops
+/// over contiguous samples, which the compiler vectorizes.
+fn resample(frame: &YuvFrame, source: Rect, scaled: &mut YuvFrame, target: Rect, filter: ScaleFilter) {
+    assert_eq!((frame.format(), frame.bit_depth()), (scaled.format(), scaled.bit_depth()));
+    assert!(source.x + source.width <= frame.width() && source.y + source.height <= frame.height());
+    assert!(target.x + target.width <= scaled.width() && target.y + target.height <= scaled.height());
+    let (format, bit_depth, bytes_per_sample) = (frame.format(), frame.bit_depth(), frame.bytes_per_sample());
+    let dimensions = [0, 1, 2].map(|plane| frame.plane_dimensions(plane));
+    let strides = (frame.strides(), scaled.strides());
+    // the same headroom at every bit depth
+    let intermediate_bits = INTERMEDIATE_BITS + 8 - bit_depth;
+    let max = (1 << bit_depth) - 1;
+    let (sources, targets) = (frame.planes(), scaled.planes_mut());
+    for plane in 0..3 {
+        let (x, y, width, height) = source.plane(format, plane);
+        let (scaled_x, scaled_y, scaled_width, scaled_height) = target.plane(format, plane);
+        let (plane_width, _, channels) = dimensions[plane];
+        if plane_width == 0 || scaled_width == 0 || scaled_height == 0 {
+            continue;
+        }
+        assert!(width > 0 && height > 0, "cannot scale an empty frame");
+        let (horizontal, vertical) = (Weights::new(width, scaled_width, filter), Weights::new(height, scaled_height, filter));
+
+        // horizontal pass
+        let row_len = scaled_width * channels;
+        let mut rows = vec![0i32; height * row_len];
+        let mut line = vec![0i32; width * channels];
+        let shift = SHIFT - intermediate_bits;
+        for (r, row) in rows.chunks_exact_mut(row_len).enumerate() {
+            let source = &sources[plane][(y + r) * strides.0[plane] + x * channels * bytes_per_sample..][..line.len() * bytes_per_sample];
+            match bytes_per_sample {
+                1 => line.iter_mut().zip(source).for_each(|(l, s)| *l = i32::from(*s)),
+                _ => line.iter_mut().zip(source.chunks_exact(2)).for_each(|(l, s)| *l = i32::from(u16::from_le_bytes([s[0], s[1]]))),
+            }
+            for (i, samples) in row.chunks_exact_mut(channels).enumerate() {
+                let (start, weights) = horizontal.get(i);
+                for (channel, sample) in samples.iter_mut().enumerate() {
+                    let taps = line[start * channels + channel..].iter().step_by(channels);
+                    let sum = weights.iter().zip(taps).map(|(w, s)| w * s).sum::<i32>();
+                    *sample = (sum + (1 << (shift - 1))) >> shift;
+                }
+            }
+        }
+
+        // vertical pass, a row at a time
+        let shift = SHIFT + intermediate_bits;
+        let mut sums = vec![0i32; row_len];
+        let offset = scaled_x * channels * bytes_per_sample;
+        for (i, target) in targets[plane].chunks_mut(strides.1[plane]).skip(scaled_y).take(scaled_height).enumerate() {
+            let (start, weights) = vertical.get(i);
+            sums.iter_mut().for_each(|sum| *sum = 1 << (shift - 1));
+            for (k, weight) in weights.iter().enumerate() {
+                let row = &rows[(start + k) * row_len..][..row_len];
+                sums.iter_mut().zip(row).for_each(|(sum, sample)| *sum += weight * sample);
+            }
+            let target = &mut target[offset..][..row_len * bytes_per_sample];
+            match bytes_per_sample {
+                1 => target.iter_mut().zip(&sums).for_each(|(t, sum)| *t = clamp(sum >> shift)),
+                _ => target.chunks_exact_mut(2).zip(&sums).for_each(|(t, sum)| {
+                    t.copy_from_slice(&((sum >> shift).clamp(0, max) as u16).to_le_bytes());
+                }),
+            }
+        }
+    }
+}
+
+/// Puts `overlay` over `frame` at `x`, `y`, which must be even for subsampled chroma, with
+/// `opacity` out of 255. The parts of `overlay` past the edges of `frame` are left out.
+pub fn blend(frame: &mut YuvFrame, overlay: &YuvFrame, x: u32, y: u32, opacity: u8) {
+    assert_eq!((frame.format(), frame.bit_depth()), (overlay.format(), overlay.bit_depth()));
+    let bytes_per_sample = frame.bytes_per_sample();
+    let (x_shift, y_shift) = frame.format().chroma_shift();
+    assert!(x.is_multiple_of(1 << x_shift) && y.is_multiple_of(1 << y_shift), "blending at {},{} splits chroma samples", x, y);
+
+    let dimensions = [0, 1, 2].map(|plane| (frame.plane_dimensions(plane), overlay.plane_dimensions(plane)));
+    let strides = (frame.strides(), overlay.strides());
+    let alpha = u32::from(opacity);
+    let mix = |s: u32, t: u32| (s * alpha + t * (255 - alpha) + 127) / 255;
+    let (targets, sources) = (frame.planes_mut(), overlay.planes());
+    for plane in 0..3 {
+        let ((width, height, channels), (overlay_width, overlay_height, _)) = dimensions[plane];
+        let (left, top) = if plane == 0 { (x, y) } else { (x >> x_shift, y >> y_shift) };
+        let (left, top) = (left as usize, top as usize);
+        let row_bytes = overlay_width.min(width.saturating_sub(left)) * channels * bytes_per_sample;
+        if row_bytes == 0 {
+            continue;
+        }
+        for row in 0..overlay_height.min(height.saturating_sub(top)) {
+            let source = &sources[plane][row * strides.1[plane]..][..row_bytes];
+            let target = &mut targets[plane][(top + row) * strides.0[plane] + left * channels * bytes_per_sample..][..row_bytes];
+            match bytes_per_sample {
+                1 => target.iter_mut().zip(source).for_each(|(t, s)| *t = mix(u32::from(*s), u32::from(*t)) as u8),
+                _ => target.chunks_exact_mut(2).zip(source.chunks_exact(2)).for_each(|(t, s)| {
+                    let sample = mix(u32::from(u16::from_le_bytes([s[0], s[1]])), u32::from(u16::from_le_bytes([t[0], t[1]])));
+                    t.copy_from_slice(&(sample as u16).to_le_bytes());
+                }),
+            }
+        }
+    }
+}
+
+/// Input samples of each output sample along one axis, with `SHIFT` fixed-point weights adding
+/// up to one, negative ones included for the sharper filters.
+struct Weights {
+    taps: usize,
+    starts: Vec<usize>,
+    weights: Vec<i32>,
+}
+
+impl Weights {
+    fn new(size: usize, scaled_size: usize, filter: ScaleFilter) -> Self {
+        let ratio = size as f64 / scaled_size as f64;
+        // stretched when shrinking, so no input pixel falls between the taps
+        let stretch = ratio.max(1.0);
+        let support = filter.support() * stretch;
+        let taps = ((2.0 * support).ceil() as usize + 1).min(size);
+        let mut starts = Vec::with_capacity(scaled_size);
+        let mut weights = Vec::with_capacity(scaled_size * taps);
+        let mut row = vec![0.0; taps];
+        for i in 0..scaled_size {
+            let center = (i as f64 + 0.5) * ratio;
+            let start = ((center - support).floor().max(0.0) as usize).min(size - taps);
+            for (k, weight) in row.iter_mut().enumerate() {
+                *weight = filter.kernel(((start + k) as f64 + 0.5 - center) / stretch);
+            }
+            let total = row.iter().sum::<f64>();
+            let first = weights.len();
+            weights.extend(row.iter().map(|w| (w / total * f64::from(1 << SHIFT)).round() as i32));
+            // the rounding error goes to the largest weight, so flat areas stay flat
+            let error = (1 << SHIFT) - weights[first..].iter().sum::<i32>();
+            let largest = (first..weights.len()).max_by_key(|&k| weights[k]).unwrap();
+            weights[largest] += error;
+            starts.push(start);
+        }
+        Self { taps, starts, weights }
+    }
+
+    /// First input sample and weights of output sample `i`
+    fn get(&self, i: usize) -> (usize, &[i32]) {
+        (self.starts[i], &self.weights[i * self.taps..][..self.taps])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [(YuvFormat, u32); 5] =
+        [(YuvFormat::I420, 8), (YuvFormat::Nv12, 8), (YuvFormat::I422, 8), (YuvFormat::I444, 8), (YuvFormat::I420, 10)];
+    const FILTERS: [ScaleFilter; 3] = [ScaleFilter::Bilinear, ScaleFilter::Bicubic, ScaleFilter::Lanczos];
+
+    /// Sample `channel` of `plane` at `x`, `y`, in samples of that plane.
+    fn sample(frame: &YuvFrame, plane: usize, x: usize, y: usize, channel: usize) -> u16 {
+        let (_, _, channels) = frame.plane_dimensions(plane);
+        let bytes_per_sample = frame.bytes_per_sample();
+        let at = &frame.planes()[plane][y * frame.strides()[plane] + (x * channels + channel) * bytes_per_sample..];
+        match bytes_per_sample {
+            1 => u16::from(at[0]),
+            _ => u16::from_le_bytes([at[0], at[1]]),
+        }
+    }
+
+    /// Every sample of `frame` as `(plane, x, y, channel, sample)`.
+    fn samples(frame: &YuvFrame) -> Vec<(usize, usize, usize, usize, u16)> {
+        let mut samples = vec![];
+        for plane in 0..3 {
+            let (width, height, channels) = frame.plane_dimensions(plane);
+            for (y, x, channel) in (0..height).flat_map(|y| (0..width).flat_map(move |x| (0..channels).map(move |c| (y, x, c)))) {
+                samples.push((plane, x, y, channel, sample(frame, plane, x, y, channel)));
+            }
+        }
+        samples
+    }
+
+    /// A `width`x`height` frame with the samples of `f(plane, x, y, channel)`.
+    fn frame(format: YuvFormat, bit_depth: u32, width: u32, height: u32, f: impl Fn(usize, usize, usize, usize) -> u16) -> YuvFrame {
+        let mut frame = YuvFrame::with_bit_depth(format, width, height, bit_depth);
+        let dimensions = [0, 1, 2].map(|plane| frame.plane_dimensions(plane));
+        let (strides, bytes_per_sample) = (frame.strides(), frame.bytes_per_sample());
+        for (plane, target) in frame.planes_mut().iter_mut().enumerate() {
+            let (width, height, channels) = dimensions[plane];
+            for (y, x, channel) in (0..height).flat_map(|y| (0..width).flat_map(move |x| (0..channels).map(move |c| (y, x, c)))) {
+                let value = f(plane, x, y, channel);
+                let at = &mut target[y * strides[plane] + (x * channels + channel) * bytes_per_sample..];
+                match bytes_per_sample {
+                    1 => at[0] = value as u8,
+                    _ => at[..2].copy_from_slice(&value.to_le_bytes()),
+                }
+            }
+        }
+        frame
+    }
+
+    /// Samples different in every position, within `bit_depth`.
+    fn pattern(bit_depth: u32) -> impl Fn(usize, usize, usize, usize) -> u16 {
+        move |plane, x, y, channel| ((x * 7 + y * 13 + plane * 31 + channel * 3) % (1 << bit_depth)) as u16
+    }
+
+    #[test]
+    fn crops_every_plane() {
+        for (format, bit_depth) in FORMATS {
+            let (x_shift, y_shift) = format.chroma_shift();
+            let source = frame(format, bit_depth, 37, 23, pattern(bit_depth));
+            // an odd size inside, and up to the odd edges of the frame
+            for (x, y, width, height) in [(4, 6, 13, 9), (4, 2, 33, 21), (0, 0, 37, 23)] {
+                let cropped = crop(&source, x, y, width, height);
+                assert_eq!((cropped.width(), cropped.height(), cropped.format(), cropped.bit_depth()), (width, height, format, bit_depth));
+                for (plane, sx, sy, channel, value) in samples(&cropped) {
+                    let (left, top) = if plane == 0 { (x, y) } else { (x >> x_shift, y >> y_shift) };
+                    let expected = sample(&source, plane, sx + left as usize, sy + top as usize, channel);
+                    assert_eq!(value, expected, "{:?} {} bits, {}x{}+{}+{}, plane {} at {},{}", format, bit_depth, width, height, x, y, plane, sx, sy);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "is outside of")]
+    fn crops_past_the_edge_panic() {
+        let source = frame(YuvFormat::I420, 8, 16, 16, pattern(8));
+        // x + width overflows
+        crop(&source, u32::MAX - 1, 0, 4, 4);
+    }
+
+    #[test]
+    fn scales_to_the_same_size_exactly() {
+        for filter in FILTERS {
+            for (format, bit_depth) in FORMATS {
+                let source = frame(format, bit_depth, 37, 23, pattern(bit_depth));
+                let scaled = scale(&source, 37, 23, filter);
+                assert_eq!(samples(&scaled), samples(&source), "{:?} {:?} {} bits", filter, format, bit_depth);
+            }
+        }
+    }
+
+    #[test]
+    fn scales_flat_frames_to_flat_frames() {
+        for filter in FILTERS {
+            for (format, bit_depth) in FORMATS {
+                let yuv = [0, 1, 2].map(|plane| ((100 + 40 * plane) << (bit_depth - 8)) as u16);
+                let source = frame(format, bit_depth, 37, 23, |plane, _, _, channel| yuv[plane + channel]);
+                for (width, height) in [(19, 41), (5, 3), (74, 11)] {
+                    let scaled = scale(&source, width, height, filter);
+                    assert_eq!((scaled.width(), scaled.height()), (width, height));
+                    for (plane, x, y, channel, value) in samples(&scaled) {
+                        assert_eq!(value, yuv[plane + channel], "{:?} {:?} {} bits to {}x{}, plane {} at {},{}",
+                                   filter, format, bit_depth, width, height, plane, x, y);
+                    }
+                }
+            }
+        }
+    }
+
+    fn resize(source: &YuvFrame, width: u32, height: u32, mode: ResizeMode, filter: ScaleFilter, padding: [u16; 3]) -> YuvFrame {
+        let mut target = YuvFrame::with_bit_depth(source.format(), width, height, source.bit_depth());
+        let options = ResizeOptions { mode, filter, ..ResizeOptions::default() };
+        resize_into(source, &mut target, &options, padding);
+        target
+    }
+
+    #[test]
+    fn resizes_flat_frames_to_flat_frames() {
+        for filter in FILTERS {
+            for mode in [ResizeMode::Stretch, ResizeMode::Fit, ResizeMode::Fill] {
+                for (format, bit_depth) in FORMATS {
+                    let yuv = [0, 1, 2].map(|plane| ((100 + 40 * plane) << (bit_depth - 8)) as u16);
+                    let source = frame(format, bit_depth, 37, 23, |plane, _, _, channel| yuv[plane + channel]);
+                    for (width, height) in [(21, 31), (9, 5), (75, 23)] {
+                        // the padding is the same colour, so the bars of fit don't show
+                        let resized = resize(&source, width, height, mode, filter, [yuv[0], yuv[1], yuv[2]]);
+                        for (plane, x, y, channel, value) in samples(&resized) {
+                            assert_eq!(value, yuv[plane + channel], "{:?} {:?} {:?} {} bits to {}x{}, plane {} at {},{}",
+                                       filter, mode, format, bit_depth, width, height, plane, x, y);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fit_adds_bars_of_the_padding_colour() {
+        for filter in FILTERS {
+            let picture = [800, 300, 600];
+            let padding = [64, 512, 512];
+            let source = frame(YuvFormat::I420, 10, 40, 20, |plane, _, _, _| picture[plane]);
+            // 2:1 into 31x31: 31x16 at 0,6, bars of 6 rows above and 9 below
+            let resized = resize(&source, 31, 31, ResizeMode::Fit, filter, padding);
+            for (plane, x, y, _, value) in samples(&resized) {
+                let rows = if plane == 0 { 6..22 } else { 3..11 };
+                let expected = if rows.contains(&y) { picture[plane] } else { padding[plane] };
+                assert_eq!(value, expected, "{:?}, plane {} at {},{}", filter, plane, x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn fill_crops_the_centre() {
+        for filter in FILTERS {
+            for (format, bit_depth) in [(YuvFormat::I420, 10), (YuvFormat::Nv12, 8)] {
+                let (inside, outside) = ((700 >> (10 - bit_depth)) as u16, (100 >> (10 - bit_depth)) as u16);
+                // 61x21 into 21x21 takes the 22 columns from 18, keep them away from the edges
+                let source = frame(format, bit_depth, 61, 21, |plane, x, _, _| {
+                    let columns = if plane == 0 { 16..45 } else { 8..23 };
+                    if columns.contains(&x) { inside } else { outside }
+                });
+                let resized = resize(&source, 21, 21, ResizeMode::Fill, filter, [0; 3]);
+                for (plane, x, y, _, value) in samples(&resized) {
+                    assert_eq!(value, inside, "{:?} {:?} {} bits, plane {} at {},{}", filter, format, bit_depth, plane, x, y);
+                }
+
+                // at the same height, the middle of the picture comes out untouched
+                let source = frame(format, bit_depth, 40, 20, pattern(bit_depth));
+                let resized = resize(&source, 20, 20, ResizeMode::Fill, filter, [0; 3]);
+                assert_eq!(samples(&resized), samples(&crop(&source, 10, 0, 20, 20)), "{:?} {:?} {} bits", filter, format, bit_depth);
+            }
+        }
+    }
+
+    #[test]
+    fn blends_inside_the_frame() {
+        for (format, bit_depth) in FORMATS {
+            let (x_shift, y_shift) = format.chroma_shift();
+            let max = (1u32 << bit_depth) - 1;
+            let background = frame(format, bit_depth, 11, 9, |_, _, _, _| (max / 4) as u16);
+            let overlay = frame(format, bit_depth, 5, 3, |_, _, _, _| max as u16);
+            // inside, and hanging over the bottom right corner
+            for (x, y) in [(4, 2), (8, 6)] {
+                for opacity in [0, 128, 255] {
+                    let mut blended = background.clone();
+                    blend(&mut blended, &overlay, x, y, opacity);
+                    let mixed = ((max * u32::from(opacity) + max / 4 * (255 - u32::from(opacity)) + 127) / 255) as u16;
+                    for (plane, sx, sy, _, value) in samples(&blended) {
+                        let (left, top) = if plane == 0 { (x, y) } else { (x >> x_shift, y >> y_shift) };
+                        let (width, height, _) = overlay.plane_dimensions(plane);
+                        let covered = (left as usize..left as usize + width).contains(&sx) && (top as usize..top as usize + height).contains(&sy);
+                        let expected = if covered { mixed } else { (max / 4) as u16 };
+                        assert_eq!(value, expected, "{:?} {} bits at {},{} with {}, plane {} at {},{}", format, bit_depth, x, y, opacity, plane, sx, sy);
+                    }
+                }
+            }
+        }
+    }
+}
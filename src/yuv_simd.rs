@@ -0,0 +1,361 @@
+//!
+//! SIMD versions of the inner loops of `yuv_util`, picked at runtime by CPU features.
+//!
+//! Each function converts as much of a row as fits its vectors and returns how far it got,
+//! `yuv_util` does the rest with the scalar code. Both do the same integer arithmetic, so the
+//! output is bit-exact whichever path runs.
+//!
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::yuv_util::{ChromaSiting, Coefficients, SHIFT};
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Turns the SIMD code off or back on, e.g. to compare against the scalar reference.
+#[allow(unused)]
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Converts the start of a row of R, G and B planes to luma, returns the number of pixels done.
+#[allow(unused_variables)]
+pub fn luma_row(c: &Coefficients, rgb: [&[i16]; 3], luma: &mut [u8]) -> usize {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return 0;
+    }
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            return unsafe { x86::luma_row_avx2(c, rgb, luma) };
+        }
+        if is_x86_feature_detected!("sse2") {
+            return unsafe { x86::luma_row_sse2(c, rgb, luma) };
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            return unsafe { neon::luma_row(c, rgb, luma) };
+        }
+    }
+    0
+}
+
+/// Converts chroma samples from the sums of two rows of R, G and B planes, returns the range
+/// of samples done. Samples whose filter reaches past the edges of the row are left out.
+#[allow(unused_variables)]
+pub fn chroma_row(c: &Coefficients, siting: ChromaSiting, sums: [&[i16]; 3], u: &mut [u8], v: &mut [u8]) -> (usize, usize) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return (0, 0);
+    }
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            return unsafe { x86::chroma_row_avx2(c, siting, sums, u, v) };
+        }
+        if is_x86_feature_detected!("sse2") {
+            return unsafe { x86::chroma_row_sse2(c, siting, sums, u, v) };
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            return unsafe { neon::chroma_row(c, siting, sums, u, v) };
+        }
+    }
+    (0, 0)
+}
+
+/// First chroma sample with all its pixels inside the row, and the number of samples whose
+/// pixel pair is, so `[2i - 1, 2i + 1]` for `Left` siting stays in bounds.
+#[allow(unused)]
+fn chroma_bounds(siting: ChromaSiting, width: usize) -> (usize, usize) {
+    match siting {
+        ChromaSiting::Center => (0, width / 2),
+        ChromaSiting::Left => (1, width / 2),
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    use super::*;
+
+    /// `(k0, k1)` as the 16-bit pair multiplied by `_mm_madd_epi16`
+    fn pair(k0: i32, k1: i32) -> i32 {
+        (k1 << 16) | (k0 & 0xffff)
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn load(plane: &[i16], x: usize) -> __m128i {
+        _mm_loadu_si128(plane[x..x + 8].as_ptr() as *const __m128i)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn load256(plane: &[i16], x: usize) -> __m256i {
+        _mm256_loadu_si256(plane[x..x + 16].as_ptr() as *const __m256i)
+    }
+
+    /// `((k · rgb + round) >> shift) + offset` of 4 pixels, `rg` and `b0` interleaved as pairs
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn dot4(rg: __m128i, b0: __m128i, k: [i32; 3], shift: u32, offset: i32) -> __m128i {
+        let sum = _mm_add_epi32(
+            _mm_madd_epi16(rg, _mm_set1_epi32(pair(k[0], k[1]))),
+            _mm_madd_epi16(b0, _mm_set1_epi32(pair(k[2], 0))),
+        );
+        let sum = _mm_add_epi32(sum, _mm_set1_epi32(1 << (shift - 1)));
+        _mm_add_epi32(_mm_sra_epi32(sum, _mm_cvtsi32_si128(shift as i32)), _mm_set1_epi32(offset))
+    }
+
+    /// 8 pixels of 16-bit R, G and B to bytes, saturated like `clamp`, in the low half
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn convert8(r: __m128i, g: __m128i, b: __m128i, k: [i32; 3], shift: u32, offset: i32) -> __m128i {
+        let zero = _mm_setzero_si128();
+        let lo = dot4(_mm_unpacklo_epi16(r, g), _mm_unpacklo_epi16(b, zero), k, shift, offset);
+        let hi = dot4(_mm_unpackhi_epi16(r, g), _mm_unpackhi_epi16(b, zero), k, shift, offset);
+        let words = _mm_packs_epi32(lo, hi);
+        _mm_packus_epi16(words, words)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn dot8(rg: __m256i, b0: __m256i, k: [i32; 3], shift: u32, offset: i32) -> __m256i {
+        let sum = _mm256_add_epi32(
+            _mm256_madd_epi16(rg, _mm256_set1_epi32(pair(k[0], k[1]))),
+            _mm256_madd_epi16(b0, _mm256_set1_epi32(pair(k[2], 0))),
+        );
+        let sum = _mm256_add_epi32(sum, _mm256_set1_epi32(1 << (shift - 1)));
+        _mm256_add_epi32(_mm256_sra_epi32(sum, _mm_cvtsi32_si128(shift as i32)), _mm256_set1_epi32(offset))
+    }
+
+    /// 16 pixels, see `convert8`
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn convert16(r: __m256i, g: __m256i, b: __m256i, k: [i32; 3], shift: u32, offset: i32) -> __m128i {
+        let zero = _mm256_setzero_si256();
+        // unpack and pack both work within 128-bit lanes, so the order comes out right
+        let lo = dot8(_mm256_unpacklo_epi16(r, g), _mm256_unpacklo_epi16(b, zero), k, shift, offset);
+        let hi = dot8(_mm256_unpackhi_epi16(r, g), _mm256_unpackhi_epi16(b, zero), k, shift, offset);
+        let words = _mm256_packs_epi32(lo, hi);
+        let bytes = _mm256_packus_epi16(words, words);
+        // the low 8 bytes of each lane
+        _mm256_castsi256_si128(_mm256_permute4x64_epi64(bytes, 0b10_00_10_00))
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn luma_row_sse2(c: &Coefficients, [r, g, b]: [&[i16]; 3], luma: &mut [u8]) -> usize {
+        let done = luma.len() / 8 * 8;
+        for x in (0..done).step_by(8) {
+            let y = convert8(load(r, x), load(g, x), load(b, x), c.y, SHIFT, c.y_offset);
+            _mm_storel_epi64(luma[x..x + 8].as_mut_ptr() as *mut __m128i, y);
+        }
+        done
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn luma_row_avx2(c: &Coefficients, [r, g, b]: [&[i16]; 3], luma: &mut [u8]) -> usize {
+        let done = luma.len() / 16 * 16;
+        for x in (0..done).step_by(16) {
+            let y = convert16(load256(r, x), load256(g, x), load256(b, x), c.y, SHIFT, c.y_offset);
+            _mm_storeu_si128(luma[x..x + 16].as_mut_ptr() as *mut __m128i, y);
+        }
+        done
+    }
+
+    /// Sums of the pixel pairs `(x, x + 1)` of 8 chroma samples starting at pixel `x`.
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn pairs8(plane: &[i16], x: usize) -> __m128i {
+        let ones = _mm_set1_epi16(1);
+        // at most 4 * 510, no saturation
+        _mm_packs_epi32(_mm_madd_epi16(load(plane, x), ones), _mm_madd_epi16(load(plane, x + 8), ones))
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn pairs16(plane: &[i16], x: usize) -> __m256i {
+        let ones = _mm256_set1_epi16(1);
+        let words = _mm256_packs_epi32(_mm256_madd_epi16(load256(plane, x), ones),
+                                       _mm256_madd_epi16(load256(plane, x + 16), ones));
+        // packing interleaves the 64-bit halves of both inputs
+        _mm256_permute4x64_epi64(words, 0b11_01_10_00)
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn chroma_row_sse2(c: &Coefficients, siting: ChromaSiting, sums: [&[i16]; 3], u: &mut [u8],
+                                  v: &mut [u8]) -> (usize, usize) {
+        let (_, weight_log2) = siting.taps();
+        let (first, count) = chroma_bounds(siting, sums[0].len());
+        let mut i = first;
+        while i + 8 <= count {
+            let mut planes = [_mm_setzero_si128(); 3];
+            for (plane, sum) in planes.iter_mut().zip(&sums) {
+                *plane = pairs8(sum, 2 * i);
+                if siting == ChromaSiting::Left {
+                    // [1 2 1] is the pair sums at 2i - 1 and 2i added up
+                    *plane = _mm_add_epi16(*plane, pairs8(sum, 2 * i - 1));
+                }
+            }
+            let [r, g, b] = planes;
+            let shift = SHIFT + weight_log2;
+            _mm_storel_epi64(u[i..i + 8].as_mut_ptr() as *mut __m128i, convert8(r, g, b, c.u, shift, 128));
+            _mm_storel_epi64(v[i..i + 8].as_mut_ptr() as *mut __m128i, convert8(r, g, b, c.v, shift, 128));
+            i += 8;
+        }
+        (first, i)
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn chroma_row_avx2(c: &Coefficients, siting: ChromaSiting, sums: [&[i16]; 3], u: &mut [u8],
+                                  v: &mut [u8]) -> (usize, usize) {
+        let (_, weight_log2) = siting.taps();
+        let (first, count) = chroma_bounds(siting, sums[0].len());
+        let mut i = first;
+        while i + 16 <= count {
+            let mut planes = [_mm256_setzero_si256(); 3];
+            for (plane, sum) in planes.iter_mut().zip(&sums) {
+                *plane = pairs16(sum, 2 * i);
+                if siting == ChromaSiting::Left {
+                    *plane = _mm256_add_epi16(*plane, pairs16(sum, 2 * i - 1));
+                }
+            }
+            let [r, g, b] = planes;
+            let shift = SHIFT + weight_log2;
+            _mm_storeu_si128(u[i..i + 16].as_mut_ptr() as *mut __m128i, convert16(r, g, b, c.u, shift, 128));
+            _mm_storeu_si128(v[i..i + 16].as_mut_ptr() as *mut __m128i, convert16(r, g, b, c.v, shift, 128));
+            i += 16;
+        }
+        (first, i)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    use super::*;
+
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn load(plane: &[i16], x: usize) -> int16x8_t {
+        vld1q_s16(plane[x..x + 8].as_ptr())
+    }
+
+    /// 8 pixels of 16-bit R, G and B to bytes, saturated like `clamp`
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn convert8(r: int16x8_t, g: int16x8_t, b: int16x8_t, k: [i32; 3], shift: u32, offset: i32) -> uint8x8_t {
+        let (k0, k1, k2) = (k[0] as i16, k[1] as i16, k[2] as i16);
+        let lo = vmlal_n_s16(vmlal_n_s16(vmull_n_s16(vget_low_s16(r), k0), vget_low_s16(g), k1), vget_low_s16(b), k2);
+        let hi = vmlal_high_n_s16(vmlal_high_n_s16(vmull_high_n_s16(r, k0), g, k1), b, k2);
+        let round = vdupq_n_s32(1 << (shift - 1));
+        // a negative count shifts right, arithmetic and truncating like `>>`
+        let count = vdupq_n_s32(-(shift as i32));
+        let offset = vdupq_n_s32(offset);
+        let lo = vaddq_s32(vshlq_s32(vaddq_s32(lo, round), count), offset);
+        let hi = vaddq_s32(vshlq_s32(vaddq_s32(hi, round), count), offset);
+        vqmovun_s16(vcombine_s16(vqmovn_s32(lo), vqmovn_s32(hi)))
+    }
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn luma_row(c: &Coefficients, [r, g, b]: [&[i16]; 3], luma: &mut [u8]) -> usize {
+        let done = luma.len() / 8 * 8;
+        for x in (0..done).step_by(8) {
+            let y = convert8(load(r, x), load(g, x), load(b, x), c.y, SHIFT, c.y_offset);
+            vst1_u8(luma[x..x + 8].as_mut_ptr(), y);
+        }
+        done
+    }
+
+    /// Sums of the pixel pairs `(x, x + 1)` of 8 chroma samples starting at pixel `x`.
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn pairs8(plane: &[i16], x: usize) -> int16x8_t {
+        vcombine_s16(vqmovn_s32(vpaddlq_s16(load(plane, x))), vqmovn_s32(vpaddlq_s16(load(plane, x + 8))))
+    }
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn chroma_row(c: &Coefficients, siting: ChromaSiting, sums: [&[i16]; 3], u: &mut [u8],
+                             v: &mut [u8]) -> (usize, usize) {
+        let (_, weight_log2) = siting.taps();
+        let (first, count) = chroma_bounds(siting, sums[0].len());
+        let mut i = first;
+        while i + 8 <= count {
+            let mut planes = [vdupq_n_s16(0); 3];
+            for (plane, sum) in planes.iter_mut().zip(&sums) {
+                *plane = pairs8(sum, 2 * i);
+                if siting == ChromaSiting::Left {
+                    *plane = vaddq_s16(*plane, pairs8(sum, 2 * i - 1));
+                }
+            }
+            let [r, g, b] = planes;
+            let shift = SHIFT + weight_log2;
+            vst1_u8(u[i..i + 8].as_mut_ptr(), convert8(r, g, b, c.u, shift, 128));
+            vst1_u8(v[i..i + 8].as_mut_ptr(), convert8(r, g, b, c.v, shift, 128));
+            i += 8;
+        }
+        (first, i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::yuv_util::{convert_rgb_to_yuv420p, ColorMatrix, ConvertOptions, PixelLayout, Range};
+
+    /// xorshift64, reproducible from the seed printed on failure
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    #[test]
+    fn simd_matches_scalar() {
+        let mut random = Random(0x9E37_79B9_7F4A_7C15);
+        // every width up to two AVX2 vectors of chroma, then random ones including odd widths
+        let widths = (1..=66).chain((0..100).map(|_| 1 + random.below(300) as u32)).collect::<Vec<_>>();
+        for (case, width) in widths.into_iter().enumerate() {
+            let height = 1 + random.below(5) as u32;
+            let img = (0..width * height * 3).map(|_| random.next() as u8).collect::<Vec<_>>();
+            for siting in [ChromaSiting::Center, ChromaSiting::Left] {
+                let options = ConvertOptions {
+                    matrix: [ColorMatrix::Bt601, ColorMatrix::Bt709, ColorMatrix::Bt2020Ncl][random.below(3) as usize],
+                    range: if random.below(2) == 0 { Range::Limited } else { Range::Full },
+                    siting,
+                    ..ConvertOptions::default()
+                };
+                set_enabled(false);
+                let scalar = convert_rgb_to_yuv420p(&img, width, height, PixelLayout::Rgb, &options);
+                set_enabled(true);
+                let simd = convert_rgb_to_yuv420p(&img, width, height, PixelLayout::Rgb, &options);
+
+                let luma = (width * height) as usize;
+                let chroma = (scalar.len() - luma) / 2;
+                for (plane, range) in ["Y", "U", "V"].iter().zip([0..luma, luma..luma + chroma, luma + chroma..scalar.len()]) {
+                    assert!(
+                        scalar[range.clone()] == simd[range],
+                        "{} plane differs in case {}: {}x{}, {:?}", plane, case, width, height, options,
+                    );
+                }
+            }
+        }
+    }
+}
@@ -1,99 +1,1434 @@
+use std::sync::OnceLock;
+
+use crate::yuv_simd;
+
+/// Fraction bits of the fixed-point conversion coefficients
+pub(crate) const SHIFT: u32 = 14;
+
+/// YCbCr matrix coefficients of the conversion.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorMatrix {
+    /// SD, ITU-R BT.601
+    Bt601,
+    /// HD, ITU-R BT.709
+    Bt709,
+    /// UHD, ITU-R BT.2020 non-constant luminance
+    Bt2020Ncl,
+}
+
+impl ColorMatrix {
+    /// `(Kr, Kb)`, the luma weights of red and blue
+    fn kr_kb(&self) -> (f64, f64) {
+        match self {
+            ColorMatrix::Bt601 => (0.299, 0.114),
+            ColorMatrix::Bt709 => (0.2126, 0.0722),
+            ColorMatrix::Bt2020Ncl => (0.2627, 0.0593),
+        }
+    }
+}
+
+impl std::str::FromStr for ColorMatrix {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "bt601" => Ok(ColorMatrix::Bt601),
+            "bt709" => Ok(ColorMatrix::Bt709),
+            "bt2020" => Ok(ColorMatrix::Bt2020Ncl),
+            _ => anyhow::bail!("unknown colour matrix {}, expected bt601, bt709 or bt2020", s),
+        }
+    }
+}
+
+/// Range of the YUV values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Range {
+    /// 0-255
+    Full,
+    /// "studio swing", Y 16-235 and U, V 16-240
+    Limited,
+}
+
+impl std::str::FromStr for Range {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "full" => Ok(Range::Full),
+            "limited" => Ok(Range::Limited),
+            _ => anyhow::bail!("unknown colour range {}, expected full or limited", s),
+        }
+    }
+}
+
+/// Transfer function the RGB is encoded with, for high-bit-depth output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transfer {
+    /// BT.709, sRGB and the like
+    #[default]
+    Sdr,
+    /// SMPTE ST 2084 perceptual quantizer, linear 1.0 being 10000 cd/m²
+    Pq,
+    /// ARIB STD-B67 hybrid log-gamma
+    Hlg,
+}
+
+impl Transfer {
+    /// Encodes linear light in 0-1.
+    fn encode(&self, linear: f64) -> f64 {
+        match self {
+            Transfer::Sdr if linear < 0.018 => 4.5 * linear,
+            Transfer::Sdr => 1.099 * linear.powf(0.45) - 0.099,
+            Transfer::Pq => {
+                let (m1, m2) = (2610.0 / 16384.0, 2523.0 / 4096.0 * 128.0);
+                let (c1, c2, c3) = (3424.0 / 4096.0, 2413.0 / 4096.0 * 32.0, 2392.0 / 4096.0 * 32.0);
+                let y = linear.powf(m1);
+                ((c1 + c2 * y) / (1.0 + c3 * y)).powf(m2)
+            }
+            Transfer::Hlg if linear <= 1.0 / 12.0 => (3.0 * linear).sqrt(),
+            Transfer::Hlg => {
+                let (a, b) = (0.17883277, 0.28466892);
+                a * (12.0 * linear - b).ln() + 0.55991073
+            }
+        }
+    }
+
+    /// 16-bit linear light to 16-bit encoded values, made on first use.
+    fn table(&self) -> &'static [u16] {
+        static TABLES: [OnceLock<Vec<u16>>; 3] = [OnceLock::new(), OnceLock::new(), OnceLock::new()];
+        TABLES[*self as usize].get_or_init(|| {
+            (0..=u16::MAX).map(|v| (self.encode(f64::from(v) / 65535.0) * 65535.0).round() as u16).collect()
+        })
+    }
+}
+
+impl std::str::FromStr for Transfer {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "sdr" => Ok(Transfer::Sdr),
+            "pq" => Ok(Transfer::Pq),
+            "hlg" => Ok(Transfer::Hlg),
+            _ => anyhow::bail!("unknown transfer {}, expected sdr, pq or hlg", s),
+        }
+    }
+}
+
+/// RGB to YUV rows of the matrix in `SHIFT` fixed point, and the offset of Y.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Coefficients {
+    pub y: [i32; 3],
+    pub u: [i32; 3],
+    pub v: [i32; 3],
+    pub y_offset: i32,
+}
+
+impl Coefficients {
+    fn new(matrix: ColorMatrix, range: Range) -> Self {
+        let (kr, kb) = matrix.kr_kb();
+        let kg = 1.0 - kr - kb;
+        let (y_scale, c_scale, y_offset) = match range {
+            Range::Full => (1.0, 1.0, 0),
+            Range::Limited => (219.0 / 255.0, 224.0 / 255.0, 16),
+        };
+        let fixed = |v: f64| (v * f64::from(1 << SHIFT)).round() as i32;
+        // rows sum up exactly, so white and gray stay neutral
+        let (yr, yb) = (fixed(kr * y_scale), fixed(kb * y_scale));
+        let yg = fixed(y_scale) - yr - yb;
+        // Cb = (B - Y) / (2 (1 - Kb))
+        let (ur, ug) = (fixed(-kr / (2.0 * (1.0 - kb)) * c_scale), fixed(-kg / (2.0 * (1.0 - kb)) * c_scale));
+        // Cr = (R - Y) / (2 (1 - Kr))
+        let (vg, vb) = (fixed(-kg / (2.0 * (1.0 - kr)) * c_scale), fixed(-kb / (2.0 * (1.0 - kr)) * c_scale));
+        Self {
+            y: [yr, yg, yb],
+            u: [ur, ug, -ur - ug],
+            v: [-vg - vb, vg, vb],
+            y_offset,
+        }
+    }
+
+    fn y(&self, [r, g, b]: [i32; 3]) -> u8 {
+        clamp(((self.y[0] * r + self.y[1] * g + self.y[2] * b + (1 << (SHIFT - 1))) >> SHIFT) + self.y_offset)
+    }
+
+    /// U of the weighted sum of `2^weight_log2` pixels
+    fn u(&self, [r, g, b]: [i32; 3], weight_log2: u32) -> u8 {
+        let shift = SHIFT + weight_log2;
+        clamp(((self.u[0] * r + self.u[1] * g + self.u[2] * b + (1 << (shift - 1))) >> shift) + 128)
+    }
+
+    /// V of the weighted sum of `2^weight_log2` pixels
+    fn v(&self, [r, g, b]: [i32; 3], weight_log2: u32) -> u8 {
+        let shift = SHIFT + weight_log2;
+        clamp(((self.v[0] * r + self.v[1] * g + self.v[2] * b + (1 << (shift - 1))) >> shift) + 128)
+    }
+}
+
+/// Order and size of the channels of the input pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelLayout {
+    Rgb,
+    Bgr,
+    Rgba,
+    /// Windows and macOS screen captures
+    Bgra,
+    /// Core Video `k32ARGB` buffers, which the image decoders never produce
+    #[allow(dead_code)]
+    Argb,
+    /// 16 bits per channel in native byte order, as decoded by `image`
+    Rgb48,
+    /// `Rgb48` with alpha
+    Rgba64,
+    Gray8,
+    /// native byte order
+    Gray16,
+}
+
+impl PixelLayout {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelLayout::Gray8 => 1,
+            PixelLayout::Gray16 => 2,
+            PixelLayout::Rgb | PixelLayout::Bgr => 3,
+            PixelLayout::Rgba | PixelLayout::Bgra | PixelLayout::Argb => 4,
+            PixelLayout::Rgb48 => 6,
+            PixelLayout::Rgba64 => 8,
+        }
+    }
+
+    fn is_gray(&self) -> bool {
+        matches!(self, PixelLayout::Gray8 | PixelLayout::Gray16)
+    }
+
+    fn is_16_bit(&self) -> bool {
+        matches!(self, PixelLayout::Rgb48 | PixelLayout::Rgba64 | PixelLayout::Gray16)
+    }
+
+    /// Sample index of R, G, B and alpha in a pixel.
+    fn channels(&self) -> ([usize; 3], Option<usize>) {
+        match self {
+            PixelLayout::Rgb | PixelLayout::Rgb48 => ([0, 1, 2], None),
+            PixelLayout::Bgr => ([2, 1, 0], None),
+            PixelLayout::Rgba | PixelLayout::Rgba64 => ([0, 1, 2], Some(3)),
+            PixelLayout::Bgra => ([2, 1, 0], Some(3)),
+            PixelLayout::Argb => ([1, 2, 3], Some(0)),
+            PixelLayout::Gray8 | PixelLayout::Gray16 => ([0, 0, 0], None),
+        }
+    }
+}
+
+/// What to do with the alpha channel of the input, which YUV 4:2:0 cannot carry.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AlphaPolicy {
+    /// ignored, transparent pixels show whatever colour they hold
+    #[default]
+    Drop,
+    /// pixels are premultiplied by their alpha and put over this RGB colour
+    Background([u8; 3]),
+}
+
+/// `drop` or the background as `rrggbb`
+impl std::str::FromStr for AlphaPolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s == "drop" {
+            return Ok(AlphaPolicy::Drop);
+        }
+        let color = parse_color(s).map_err(|_| anyhow::anyhow!("expected drop or a background colour rrggbb, got {}", s))?;
+        Ok(AlphaPolicy::Background(color))
+    }
+}
+
+/// An RGB colour as `rrggbb`.
+pub fn parse_color(s: &str) -> anyhow::Result<[u8; 3]> {
+    if s.len() != 6 || !s.bytes().all(|c| c.is_ascii_hexdigit()) {
+        anyhow::bail!("expected a colour rrggbb, got {}", s);
+    }
+    let [_, r, g, b] = u32::from_str_radix(s, 16)?.to_be_bytes();
+    Ok([r, g, b])
+}
+
+/// 16-bit RGB to `bit_depth` YUV, in `WIDE_SHIFT` fixed point.
+#[derive(Clone, Copy, Debug)]
+struct WideCoefficients {
+    y: [i64; 3],
+    u: [i64; 3],
+    v: [i64; 3],
+    y_offset: i64,
+    /// the largest sample
+    max: i64,
+}
+
+/// Fraction bits of `WideCoefficients`
+const WIDE_SHIFT: u32 = 24;
+
+impl WideCoefficients {
+    fn new(matrix: ColorMatrix, range: Range, bit_depth: u32) -> Self {
+        let (kr, kb) = matrix.kr_kb();
+        let kg = 1.0 - kr - kb;
+        let max = (1 << bit_depth) - 1;
+        // 16 to 235 and 240 at 8 bits, shifted up
+        let step = f64::from(1 << (bit_depth - 8));
+        let (y_scale, c_scale, y_offset) = match range {
+            Range::Full => (f64::from(max), f64::from(max), 0),
+            Range::Limited => (219.0 * step, 224.0 * step, 16 << (bit_depth - 8)),
+        };
+        let fixed = |v: f64| (v / 65535.0 * (1u64 << WIDE_SHIFT) as f64).round() as i64;
+        let (yr, yb) = (fixed(kr * y_scale), fixed(kb * y_scale));
+        let (ur, ug) = (fixed(-kr / (2.0 * (1.0 - kb)) * c_scale), fixed(-kg / (2.0 * (1.0 - kb)) * c_scale));
+        let (vg, vb) = (fixed(-kg / (2.0 * (1.0 - kr)) * c_scale), fixed(-kb / (2.0 * (1.0 - kr)) * c_scale));
+        Self {
+            y: [yr, fixed(y_scale) - yr - yb, yb],
+            u: [ur, ug, -ur - ug],
+            v: [-vg - vb, vg, vb],
+            y_offset: i64::from(y_offset),
+            max: i64::from(max),
+        }
+    }
+
+    fn y(&self, rgb: [i64; 3]) -> u16 {
+        let sum = self.y.iter().zip(rgb).map(|(c, v)| c * v).sum::<i64>();
+        (((sum + (1 << (WIDE_SHIFT - 1))) >> WIDE_SHIFT) + self.y_offset).clamp(0, self.max) as u16
+    }
+
+    /// U and V of the weighted sum of `2^weight_log2` pixels
+    fn uv(&self, sum: [i64; 3], weight_log2: u32) -> (u16, u16) {
+        let shift = WIDE_SHIFT + weight_log2;
+        let chroma = |row: &[i64; 3]| {
+            let value = row.iter().zip(sum).map(|(c, v)| c * v).sum::<i64>();
+            (((value + (1 << (shift - 1))) >> shift) + (self.max + 1) / 2).clamp(0, self.max) as u16
+        };
+        (chroma(&self.u), chroma(&self.v))
+    }
+}
+
+/// YUV to RGB in `SHIFT` fixed point, the inverse of `Coefficients`.
+#[derive(Clone, Copy, Debug)]
+struct InverseCoefficients {
+    y: i32,
+    y_offset: i32,
+    /// V to R, U and V to G, U to B
+    rv: i32,
+    gu: i32,
+    gv: i32,
+    bu: i32,
+}
+
+impl InverseCoefficients {
+    fn new(matrix: ColorMatrix, range: Range) -> Self {
+        let (kr, kb) = matrix.kr_kb();
+        let kg = 1.0 - kr - kb;
+        let (y_scale, c_scale, y_offset) = match range {
+            Range::Full => (1.0, 1.0, 0),
+            Range::Limited => (255.0 / 219.0, 255.0 / 224.0, 16),
+        };
+        let fixed = |v: f64| (v * f64::from(1 << SHIFT)).round() as i32;
+        Self {
+            y: fixed(y_scale),
+            y_offset,
+            rv: fixed(2.0 * (1.0 - kr) * c_scale),
+            gu: fixed(-2.0 * kb * (1.0 - kb) / kg * c_scale),
+            gv: fixed(-2.0 * kr * (1.0 - kr) / kg * c_scale),
+            bu: fixed(2.0 * (1.0 - kb) * c_scale),
+        }
+    }
+
+    fn rgb(&self, y: u8, u: u8, v: u8) -> [u8; 3] {
+        let y = self.y * (i32::from(y) - self.y_offset) + (1 << (SHIFT - 1));
+        let (u, v) = (i32::from(u) - 128, i32::from(v) - 128);
+        [clamp((y + self.rv * v) >> SHIFT), clamp((y + self.gu * u + self.gv * v) >> SHIFT), clamp((y + self.bu * u) >> SHIFT)]
+    }
+}
+
+/// Position of the chroma samples relative to the 2x2 luma block they cover.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChromaSiting {
+    /// In the middle of the block, the plain average of its 4 pixels (JPEG, MPEG-1).
+    Center,
+    /// Vertically centred on the left column (MPEG-2, H.264 and VP9 default), filtered
+    /// horizontally with `[1 2 1]`.
+    Left,
+}
+
+impl ChromaSiting {
+    /// horizontal filter, `(column offset, weight)`, and log2 of the total weight of both rows
+    pub(crate) fn taps(&self) -> (&'static [(isize, i32)], u32) {
+        match self {
+            ChromaSiting::Center => (&[(0, 1), (1, 1)], 2),
+            ChromaSiting::Left => (&[(-1, 1), (0, 2), (1, 1)], 3),
+        }
+    }
+}
+
+impl std::str::FromStr for ChromaSiting {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "center" => Ok(ChromaSiting::Center),
+            "left" => Ok(ChromaSiting::Left),
+            _ => anyhow::bail!("unknown chroma siting {}, expected center or left", s),
+        }
+    }
+}
+
+/// Size of a 4:2:0 frame, odd dimensions have their last chroma column or row half covered.
+#[allow(unused)]
+pub fn yuv420_size(width: u32, height: u32) -> usize {
+    let (width, height) = (width as usize, height as usize);
+    width * height + 2 * width.div_ceil(2) * height.div_ceil(2)
+}
+
+/// How RGB is converted to YUV.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConvertOptions {
+    /// the YUV colour space, to be signalled to the encoder and the player
+    pub matrix: ColorMatrix,
+    pub range: Range,
+    /// where the chroma samples are taken
+    pub siting: ChromaSiting,
+    /// for input layouts with alpha
+    pub alpha: AlphaPolicy,
+    /// of the input, high-bit-depth conversion only
+    pub transfer: Transfer,
+    /// the input is linear light and gets encoded with `transfer`, high-bit-depth conversion only
+    pub linear_input: bool,
+    /// rows are converted on this many threads with the `parallel` feature
+    pub threads: usize,
+}
+
+impl Default for ConvertOptions {
+    /// HD on the calling thread
+    fn default() -> Self {
+        Self {
+            matrix: ColorMatrix::Bt709,
+            range: Range::Limited,
+            siting: ChromaSiting::Left,
+            alpha: AlphaPolicy::Drop,
+            transfer: Transfer::Sdr,
+            linear_input: false,
+            threads: 1,
+        }
+    }
+}
+
+/// Layout of the planes of a `YuvFrame`.
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum YuvFormat {
+    /// Y, U and V planes
+    I420,
+    /// Y plane and interleaved UV plane
+    Nv12,
+    /// Y, U and V planes with half the chroma columns
+    I422,
+    /// Y, U and V planes without chroma subsampling
+    I444,
+}
+
+impl YuvFormat {
+    /// log2 of the horizontal and vertical chroma subsampling
+    pub fn chroma_shift(&self) -> (u32, u32) {
+        match self {
+            YuvFormat::I420 | YuvFormat::Nv12 => (1, 1),
+            YuvFormat::I422 => (1, 0),
+            YuvFormat::I444 => (0, 0),
+        }
+    }
+
+    /// Width and height in samples of `plane` in a `width`x`height` frame, and the number of
+    /// channels interleaved in it.
+    pub fn plane_dimensions(&self, plane: usize, width: u32, height: u32) -> (usize, usize, usize) {
+        let (x_shift, y_shift) = self.chroma_shift();
+        let chroma = ((width as usize).div_ceil(1 << x_shift), (height as usize).div_ceil(1 << y_shift));
+        match (self, plane) {
+            (_, 0) => (width as usize, height as usize, 1),
+            (YuvFormat::Nv12, 1) => (chroma.0, chroma.1, 2),
+            (YuvFormat::Nv12, _) => (0, 0, 2),
+            _ => (chroma.0, chroma.1, 1),
+        }
+    }
+}
+
+/// Rows of `YuvFrame::new` start at multiples of this many bytes, for the SIMD loads of the encoder.
+const ROW_ALIGNMENT: usize = 32;
+
+/// A YUV frame owning its planes, kept in one buffer with the rows `strides` bytes apart.
+///
+/// Made once and converted into again for each picture, see `convert_rgb_to_yuv420p_into` and
+/// `convert_rgb_to_yuv_high_bit_depth_into`, and back to RGB with `convert_yuv_to_rgb`.
+#[derive(Clone, Debug)]
+pub struct YuvFrame {
+    format: YuvFormat,
+    width: u32,
+    height: u32,
+    /// 8, or 10 and 12 with 16-bit little-endian samples
+    bit_depth: u32,
+    /// Y, U and V, or Y and UV and 0 for NV12
+    strides: [usize; 3],
+    data: Vec<u8>,
+}
+
+#[allow(unused)]
+impl YuvFrame {
+    /// 8 bits, rows padded to 32 bytes.
+    pub fn new(format: YuvFormat, width: u32, height: u32) -> Self {
+        Self::with_alignment(format, width, height, 8, ROW_ALIGNMENT)
+    }
+
+    /// 8 bits, planes back to back without padding, `yuv420_size` bytes for 4:2:0.
+    pub fn packed(format: YuvFormat, width: u32, height: u32) -> Self {
+        Self::with_alignment(format, width, height, 8, 1)
+    }
+
+    /// Rows padded to 32 bytes, with 16-bit samples above 8 bits.
+    pub fn with_bit_depth(format: YuvFormat, width: u32, height: u32, bit_depth: u32) -> Self {
+        assert!(matches!(bit_depth, 8 | 10 | 12), "unsupported bit depth {}", bit_depth);
+        Self::with_alignment(format, width, height, bit_depth, ROW_ALIGNMENT)
+    }
+
+    fn with_alignment(format: YuvFormat, width: u32, height: u32, bit_depth: u32, alignment: usize) -> Self {
+        let bytes_per_sample = if bit_depth > 8 { 2 } else { 1 };
+        let strides = [0, 1, 2].map(|plane| {
+            let (samples, _, channels) = format.plane_dimensions(plane, width, height);
+            (samples * channels * bytes_per_sample).next_multiple_of(alignment)
+        });
+        let mut frame = Self { format, width, height, bit_depth, strides, data: vec![] };
+        frame.data = vec![0; frame.plane_sizes().iter().sum()];
+        frame
+    }
+
+    pub fn format(&self) -> YuvFormat {
+        self.format
+    }
+
+    pub fn bit_depth(&self) -> u32 {
+        self.bit_depth
+    }
+
+    pub fn bytes_per_sample(&self) -> usize {
+        if self.bit_depth > 8 { 2 } else { 1 }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Bytes from one row to the next in each plane.
+    pub fn strides(&self) -> [usize; 3] {
+        self.strides
+    }
+
+    /// Width, height and channels of `plane`, see `YuvFormat::plane_dimensions`.
+    pub fn plane_dimensions(&self, plane: usize) -> (usize, usize, usize) {
+        self.format.plane_dimensions(plane, self.width, self.height)
+    }
+
+    /// Y, U and V, or Y, UV and an empty plane for NV12.
+    pub fn planes(&self) -> [&[u8]; 3] {
+        let [y_size, u_size, _] = self.plane_sizes();
+        let (y, chroma) = self.data.split_at(y_size);
+        let (u, v) = chroma.split_at(u_size);
+        [y, u, v]
+    }
+
+    pub fn planes_mut(&mut self) -> [&mut [u8]; 3] {
+        let [y_size, u_size, _] = self.plane_sizes();
+        let (y, chroma) = self.data.split_at_mut(y_size);
+        let (u, v) = chroma.split_at_mut(u_size);
+        [y, u, v]
+    }
+
+    /// The planes back to back, rows padded to the strides.
+    pub fn into_vec(self) -> Vec<u8> {
+        self.data
+    }
+
+    fn plane_sizes(&self) -> [usize; 3] {
+        [0, 1, 2].map(|plane| self.strides[plane] * self.plane_dimensions(plane).1)
+    }
+}
+
+/// Frames of one format, size and bit depth, handed out again once the encoder is done with them.
+pub struct FramePool {
+    format: YuvFormat,
+    width: u32,
+    height: u32,
+    bit_depth: u32,
+    free: Vec<YuvFrame>,
+}
+
+impl FramePool {
+    pub fn new(format: YuvFormat, width: u32, height: u32, bit_depth: u32) -> Self {
+        Self { format, width, height, bit_depth, free: vec![] }
+    }
+
+    /// A returned frame, still holding its last picture, or a new one if none is free.
+    pub fn take(&mut self) -> YuvFrame {
+        self.free.pop().unwrap_or_else(|| YuvFrame::with_bit_depth(self.format, self.width, self.height, self.bit_depth))
+    }
+
+    /// Keeps `frame` for the next `take`, other frames are dropped.
+    pub fn put(&mut self, frame: YuvFrame) {
+        let kind = |f: &YuvFrame| (f.format, f.width, f.height, f.bit_depth);
+        if kind(&frame) == (self.format, self.width, self.height, self.bit_depth) {
+            self.free.push(frame);
+        }
+    }
+}
+
 /// Converts an RGB image to YUV420p (planar/3 planes)
 ///
 /// # Arguments
 ///
-/// * `img` - should contain the pixel data in the following format:
-/// `[r, g, b, ... , r, g, b, ... , r, g, b, ...]`
+/// * `img` - `width * height` pixels in `layout`, row after row without padding
 ///
-/// * `bytes_per_pixel` - should contain the number of bytes used by one pixel
-/// (eg.: RGB is 3 bytes and RGBA is 4 bytes)
+/// * `layout` - order and size of the channels of one pixel (eg.: `Rgb` is 3 bytes and `Bgra`
+///   is 4 bytes)
+///
+/// * `options` - colour space, chroma siting, alpha policy and threads
 ///
 /// # Return
 ///
-/// `[y, y, y, ... , u, u, u, ... , v, v, v, ...]`
+/// `[y, y, y, ... , u, u, u, ... , v, v, v, ...]`, `yuv420_size` bytes
 ///
 /// # Examples
 ///
 /// ```
+/// use crate::yuv_util::{convert_rgb_to_yuv420p, ConvertOptions, PixelLayout};
+///
 /// let rgb = vec![0u8; 12];
-/// let yuv = rgb2yuv420::convert_rgb_to_yuv420p(&rgb, 2, 2, 3);
+/// let yuv = convert_rgb_to_yuv420p(&rgb, 2, 2, PixelLayout::Rgb, &ConvertOptions::default());
 /// assert_eq!(yuv.len(), rgb.len() / 2);
 /// ```
 #[allow(unused)]
-pub fn convert_rgb_to_yuv420p(img: &[u8], width: u32, height: u32, bytes_per_pixel: usize) -> Vec<u8> {
-    convert_rgb_to_yuv420(img, width, height, bytes_per_pixel, |yuv, uv_index, chroma_size, u, v| {
-        yuv[*uv_index] = u;
-        yuv[*uv_index + chroma_size] = v;
-        *uv_index += 1;
-    })
+pub fn convert_rgb_to_yuv420p(img: &[u8], width: u32, height: u32, layout: PixelLayout, options: &ConvertOptions) -> Vec<u8> {
+    let mut frame = YuvFrame::packed(YuvFormat::I420, width, height);
+    convert_rgb_to_yuv420p_into(img, layout, options, &mut frame);
+    frame.into_vec()
+}
+
+/// Converts an RGB image of the size of `frame` into its planes, without allocating.
+///
+/// `frame` must be `YuvFormat::I420`, see `convert_rgb_to_yuv420p` for the other arguments.
+pub fn convert_rgb_to_yuv420p_into(img: &[u8], layout: PixelLayout, options: &ConvertOptions, frame: &mut YuvFrame) {
+    assert_eq!((frame.format, frame.bit_depth), (YuvFormat::I420, 8));
+    convert_rgb_to_yuv420(img, layout, options, frame)
 }
 
 /// Converts an RGB image to YUV420sp NV12 (semi-planar/2 planes)
 ///
 /// # Arguments
 ///
-/// * `img` - should contain the pixel data in the following format:
-/// [r, g, b, ... , r, g, b, ... , r, g, b, ...]
+/// * `img` - `width * height` pixels in `layout`, row after row without padding
 ///
-/// * `bytes_per_pixel` - should contain the number of bytes used by one pixel
-/// (eg.: RGB is 3 bytes and RGBA is 4 bytes)
+/// * `layout` - order and size of the channels of one pixel (eg.: `Rgb` is 3 bytes and `Bgra`
+///   is 4 bytes)
+///
+/// * `options` - colour space, chroma siting, alpha policy and threads
 ///
 /// # Return
 ///
-/// `[y, y, y, ... , u, v, u, v, ...]`
+/// `[y, y, y, ... , u, v, u, v, ...]`, `yuv420_size` bytes
 ///
 /// # Examples
 ///
 /// ```
+/// use crate::yuv_util::{convert_rgb_to_yuv420sp_nv12, ConvertOptions, PixelLayout};
+///
 /// let rgb = vec![0u8; 12];
-/// let yuv = rgb2yuv420::convert_rgb_to_yuv420sp_nv12(&rgb, 2, 2, 3);
+/// let yuv = convert_rgb_to_yuv420sp_nv12(&rgb, 2, 2, PixelLayout::Rgb, &ConvertOptions::default());
 /// assert_eq!(yuv.len(), rgb.len() / 2);
 /// ```
 #[allow(unused)]
-pub fn convert_rgb_to_yuv420sp_nv12(img: &[u8], width: u32, height: u32, bytes_per_pixel: usize) -> Vec<u8> {
-    convert_rgb_to_yuv420(img, width, height, bytes_per_pixel, |yuv, uv_index, _cs, u, v| {
-        yuv[*uv_index] = u;
-        *uv_index += 1;
-        yuv[*uv_index] = v;
-        *uv_index += 1;
-    })
-}
-
-fn convert_rgb_to_yuv420<T>(img: &[u8], width: u32, height: u32, bytes_per_pixel: usize, store_uv: T) -> Vec<u8>
-    where T: Fn(&mut Vec<u8>, &mut usize, usize, u8, u8) -> () {
-    let frame_size: usize = (width * height) as usize;
-    let chroma_size: usize = frame_size / 4;
-    // println!("frame_size={}, chroma_size={}", frame_size, chroma_size);
-
-    let mut y_index: usize = 0;
-    let mut uv_index = frame_size;
-    let mut yuv = vec![0; (width * height * 3 / 2) as usize];
-    let mut index: usize = 0;
-
-    for j in 0..height {
-        for _ in 0..width {
-            let r = i32::from(img[index * bytes_per_pixel]);
-            let g = i32::from(img[index * bytes_per_pixel + 1]);
-            let b = i32::from(img[index * bytes_per_pixel + 2]);
-            index += 1;
-            yuv[y_index] = clamp((77 * r + 150 * g + 29 * b + 128) >> 8);
-            y_index += 1;
-            if j % 2 == 0 && index % 2 == 0 {
-                store_uv(&mut yuv,
-                         &mut uv_index,
-                         chroma_size,
-                         clamp(((-43 * r - 84 * g + 127 * b + 128) >> 8) + 128),
-                         clamp(((127 * r - 106 * g - 21 * b + 128) >> 8) + 128));
-            }
-        }
-    }
-    yuv
-}
-
-fn clamp(val: i32) -> u8 {
+pub fn convert_rgb_to_yuv420sp_nv12(img: &[u8], width: u32, height: u32, layout: PixelLayout, options: &ConvertOptions) -> Vec<u8> {
+    let mut frame = YuvFrame::packed(YuvFormat::Nv12, width, height);
+    convert_rgb_to_yuv420sp_nv12_into(img, layout, options, &mut frame);
+    frame.into_vec()
+}
+
+/// Converts an RGB image of the size of `frame` into its planes, without allocating.
+///
+/// `frame` must be `YuvFormat::Nv12`, see `convert_rgb_to_yuv420sp_nv12` for the other arguments.
+#[allow(unused)]
+pub fn convert_rgb_to_yuv420sp_nv12_into(img: &[u8], layout: PixelLayout, options: &ConvertOptions, frame: &mut YuvFrame) {
+    assert_eq!((frame.format, frame.bit_depth), (YuvFormat::Nv12, 8));
+    convert_rgb_to_yuv420(img, layout, options, frame)
+}
+
+/// Y, U and V of a single colour at `bit_depth`, e.g. to fill the bars around a picture. The
+/// colour is taken like the pixels, linear with `options.linear_input`.
+pub fn convert_color(rgb: [u8; 3], options: &ConvertOptions, bit_depth: u32) -> [u16; 3] {
+    let c = WideCoefficients::new(options.matrix, options.range, bit_depth);
+    let table = options.linear_input.then(|| options.transfer.table());
+    let rgb = rgb.map(|v| {
+        let v = u16::from(v) * 257;
+        i64::from(table.map_or(v, |table| table[usize::from(v)]))
+    });
+    let (u, v) = c.uv(rgb, 0);
+    [c.y(rgb), u, v]
+}
+
+/// Copies the alpha channel of an RGB image into the luma plane of `frame`, as is, for an alpha
+/// stream encoded next to the colour. Chroma is neutral, layouts without alpha are opaque.
+///
+/// `frame` must be 8-bit `YuvFormat::I420`, see `convert_rgb_to_yuv420p` for the other arguments.
+pub fn convert_alpha_to_yuv420p_into(img: &[u8], layout: PixelLayout, frame: &mut YuvFrame) {
+    assert_eq!((frame.format, frame.bit_depth), (YuvFormat::I420, 8));
+    let (width, height) = (frame.width as usize, frame.height as usize);
+    let bytes_per_pixel = layout.bytes_per_pixel();
+    assert!(img.len() >= width * height * bytes_per_pixel, "image smaller than {}x{}", width, height);
+    if width == 0 || height == 0 {
+        return;
+    }
+
+    let (stride, alpha) = (frame.strides[0], layout.channels().1);
+    let [luma, u, v] = frame.planes_mut();
+    for (row, pixels) in luma.chunks_mut(stride).zip(img.chunks_exact(width * bytes_per_pixel)).take(height) {
+        let pixels = row.iter_mut().zip(pixels.chunks_exact(bytes_per_pixel));
+        match alpha {
+            Some(ai) if layout.is_16_bit() => pixels.for_each(|(a, pixel)| *a = to_8_bit([pixel[2 * ai], pixel[2 * ai + 1]])),
+            Some(ai) => pixels.for_each(|(a, pixel)| *a = pixel[ai]),
+            None => row[..width].fill(255),
+        }
+    }
+    u.fill(128);
+    v.fill(128);
+}
+
+/// Converts a YUV frame back to RGB, for previews and to check what was encoded.
+///
+/// # Arguments
+///
+/// * `frame` - any 8-bit `YuvFormat`, each chroma sample is used for all the pixels it covers
+///
+/// * `layout` - a colour layout, alpha is opaque
+///
+/// * `options` - the colour space the frame was converted with
+///
+/// # Return
+///
+/// `width * height` pixels in `layout`
+pub fn convert_yuv_to_rgb(frame: &YuvFrame, layout: PixelLayout, options: &ConvertOptions) -> Vec<u8> {
+    let mut rgb = vec![0; frame.width as usize * frame.height as usize * layout.bytes_per_pixel()];
+    convert_yuv_to_rgb_into(frame, layout, options, &mut rgb);
+    rgb
+}
+
+/// Converts a YUV frame into `rgb`, see `convert_yuv_to_rgb`.
+pub fn convert_yuv_to_rgb_into(frame: &YuvFrame, layout: PixelLayout, options: &ConvertOptions, rgb: &mut [u8]) {
+    assert!(!layout.is_gray(), "{:?} has no colour", layout);
+    assert_eq!(frame.bit_depth, 8, "8-bit frames only");
+    let (width, height) = (frame.width as usize, frame.height as usize);
+    let bytes_per_pixel = layout.bytes_per_pixel();
+    assert!(rgb.len() >= width * height * bytes_per_pixel, "buffer smaller than {}x{}", width, height);
+    if width == 0 || height == 0 {
+        return;
+    }
+
+    let c = InverseCoefficients::new(options.matrix, options.range);
+    let (x_shift, y_shift) = frame.format.chroma_shift();
+    let ([ri, gi, bi], alpha) = layout.channels();
+    let [luma, u, v] = frame.planes();
+    // U and V of NV12 are the even and odd bytes of the second plane
+    let (u, v, v_stride, chroma_step) = match frame.format {
+        YuvFormat::Nv12 => (u, &u[1..], frame.strides[1], 2),
+        _ => (u, v, frame.strides[2], 1),
+    };
+    for (y, out) in rgb.chunks_exact_mut(width * bytes_per_pixel).take(height).enumerate() {
+        let luma = &luma[y * frame.strides[0]..][..width];
+        let (u, v) = (&u[(y >> y_shift) * frame.strides[1]..], &v[(y >> y_shift) * v_stride..]);
+        for (x, (pixel, luma)) in out.chunks_exact_mut(bytes_per_pixel).zip(luma).enumerate() {
+            let i = (x >> x_shift) * chroma_step;
+            let [r, g, b] = c.rgb(*luma, u[i], v[i]);
+            if layout.is_16_bit() {
+                for (channel, value) in [(ri, r), (gi, g), (bi, b)] {
+                    pixel[2 * channel..][..2].copy_from_slice(&(u16::from(value) * 257).to_ne_bytes());
+                }
+            } else {
+                (pixel[ri], pixel[gi], pixel[bi]) = (r, g, b);
+            }
+            match alpha {
+                Some(alpha) if layout.is_16_bit() => pixel[2 * alpha..][..2].fill(255),
+                Some(alpha) => pixel[alpha] = 255,
+                None => {}
+            }
+        }
+    }
+}
+
+/// Converts an RGB image of the size of `frame` to 10 or 12 bits, for VP9 profiles 2 and 3.
+///
+/// # Arguments
+///
+/// * `img` - pixels in `layout`, 16-bit layouts keep their full precision and are in native
+///   byte order, as `image` decodes them
+///
+/// * `layout` - order and size of the channels of one pixel
+///
+/// * `options` - colour space, chroma siting, alpha policy, and the transfer of linear input
+///
+/// * `frame` - a planar `YuvFormat` of 10 or 12 bits, filled with little-endian 16-bit samples
+pub fn convert_rgb_to_yuv_high_bit_depth_into(img: &[u8], layout: PixelLayout, options: &ConvertOptions, frame: &mut YuvFrame) {
+    assert!(frame.bit_depth > 8, "use convert_rgb_to_yuv420p_into for 8-bit frames");
+    assert_ne!(frame.format, YuvFormat::Nv12, "high-bit-depth frames are planar");
+    let (width, height) = (frame.width as usize, frame.height as usize);
+    let stride = width * layout.bytes_per_pixel();
+    assert!(img.len() >= stride * height, "image smaller than {}x{}", width, height);
+    if width == 0 || height == 0 {
+        return;
+    }
+
+    let c = WideCoefficients::new(options.matrix, options.range, frame.bit_depth);
+    let table = options.linear_input.then(|| options.transfer.table());
+    let (x_shift, y_shift) = frame.format.chroma_shift();
+    // `taps` weigh two rows, halved when only the columns are subsampled
+    let (taps, weight_log2): (&[(isize, i32)], u32) = match x_shift {
+        0 => (&[(0, 1)], 0),
+        _ => {
+            let (taps, weight_log2) = options.siting.taps();
+            (taps, weight_log2 - 1)
+        }
+    };
+    let (chroma_width, chroma_height, _) = frame.plane_dimensions(1);
+    let strides = frame.strides;
+    let [luma, u, v] = frame.planes_mut();
+    let mut rows = [vec![[0u16; 3]; width], vec![[0u16; 3]; width]];
+    let mut sums = vec![[0i64; 3]; width];
+    for chroma_y in 0..chroma_height {
+        sums.iter_mut().for_each(|sum| *sum = [0; 3]);
+        for (k, row) in rows.iter_mut().enumerate().take(1 << y_shift) {
+            // an odd last row is paired with itself
+            let y = ((chroma_y << y_shift) + k).min(height - 1);
+            unpack_wide_row(&img[y * stride..][..stride], layout, options.alpha, table, row);
+            let out = luma[y * strides[0]..][..2 * width].chunks_exact_mut(2);
+            for ((out, rgb), sum) in out.zip(row.iter()).zip(&mut sums) {
+                let rgb = rgb.map(i64::from);
+                out.copy_from_slice(&c.y(rgb).to_le_bytes());
+                sum.iter_mut().zip(rgb).for_each(|(s, v)| *s += v);
+            }
+        }
+        let u = u[chroma_y * strides[1]..][..2 * chroma_width].chunks_exact_mut(2);
+        let v = v[chroma_y * strides[2]..][..2 * chroma_width].chunks_exact_mut(2);
+        for (i, (u, v)) in u.zip(v).enumerate() {
+            let mut sum = [0; 3];
+            for (dx, weight) in taps {
+                let column = (((i << x_shift) as isize) + dx).clamp(0, width as isize - 1) as usize;
+                sum.iter_mut().zip(sums[column]).for_each(|(s, v)| *s += i64::from(*weight) * v);
+            }
+            let (u_sample, v_sample) = c.uv(sum, weight_log2 + y_shift);
+            u.copy_from_slice(&u_sample.to_le_bytes());
+            v.copy_from_slice(&v_sample.to_le_bytes());
+        }
+    }
+}
+
+/// Output of a pair of input rows: two rows of luma, a stride apart, and one of chroma.
+struct RowPair<'a> {
+    index: usize,
+    luma: &'a mut [u8],
+    chroma: ChromaRow<'a>,
+}
+
+enum ChromaRow<'a> {
+    /// U and V plane
+    Planar(&'a mut [u8], &'a mut [u8]),
+    /// NV12 UV plane
+    Interleaved(&'a mut [u8]),
+}
+
+/// Rows unpacked into 16-bit planes, reused from one row pair to the next.
+#[derive(Default)]
+struct Scratch {
+    rows: [[Vec<i16>; 3]; 2],
+    /// both rows added up
+    sums: [Vec<i16>; 3],
+    u: Vec<u8>,
+    v: Vec<u8>,
+}
+
+thread_local! {
+    /// Kept for the next frames, on the calling thread or the workers of the `parallel` pool.
+    static SCRATCH: std::cell::RefCell<Scratch> = Default::default();
+}
+
+impl Scratch {
+    fn resize(&mut self, width: usize) {
+        for plane in self.rows.iter_mut().flatten().chain(&mut self.sums) {
+            plane.resize(width, 0);
+        }
+        self.u.resize(width.div_ceil(2), 0);
+        self.v.resize(width.div_ceil(2), 0);
+    }
+
+    /// Runs `f` with the scratch rows of this thread, sized for `width`.
+    fn with<R>(width: usize, f: impl FnOnce(&mut Scratch) -> R) -> R {
+        SCRATCH.with(|scratch| {
+            let mut scratch = scratch.borrow_mut();
+            scratch.resize(width);
+            f(&mut scratch)
+        })
+    }
+}
+
+/// Works on pairs of rows, unpacked into 16-bit planes so the arithmetic can use SIMD, and
+/// spread over threads with the `parallel` feature.
+fn convert_rgb_to_yuv420(img: &[u8], layout: PixelLayout, options: &ConvertOptions, frame: &mut YuvFrame) {
+    let (width, height) = (frame.width as usize, frame.height as usize);
+    let chroma_width = width.div_ceil(2);
+    assert!(img.len() >= width * height * layout.bytes_per_pixel(), "image smaller than {}x{}", width, height);
+    if width == 0 || height == 0 {
+        return;
+    }
+
+    let (format, strides) = (frame.format, frame.strides);
+    let [luma, u, v] = frame.planes_mut();
+    // one of the two is used, chained to get a single iterator type
+    let (planar, interleaved) = match format {
+        YuvFormat::I420 => {
+            let rows = u.chunks_mut(strides[1]).zip(v.chunks_mut(strides[2]));
+            (Some(rows.map(|(u, v)| ChromaRow::Planar(&mut u[..chroma_width], &mut v[..chroma_width]))), None)
+        }
+        YuvFormat::Nv12 => (None, Some(u.chunks_mut(strides[1]).map(|uv| ChromaRow::Interleaved(&mut uv[..2 * chroma_width])))),
+        YuvFormat::I422 | YuvFormat::I444 => unreachable!("8-bit RGB is converted to 4:2:0 only"),
+    };
+    let chroma_rows = planar.into_iter().flatten().chain(interleaved.into_iter().flatten());
+    let pairs = luma.chunks_mut(2 * strides[0])
+        .zip(chroma_rows)
+        .enumerate()
+        .map(|(index, (luma, chroma))| RowPair { index, luma, chroma });
+
+    let c = Coefficients::new(options.matrix, options.range);
+    // gray only needs the luma of each level
+    let gray_luma = layout.is_gray().then(|| std::array::from_fn::<u8, 256, _>(|level| c.y([level as i32; 3])));
+    let stride = width * layout.bytes_per_pixel();
+    let convert = |pair: RowPair| {
+        let rows = &img[2 * pair.index * stride..][..pair.luma.len().div_ceil(strides[0]) * stride];
+        match &gray_luma {
+            Some(table) => convert_gray_row_pair(table, layout, rows, strides[0], width, pair),
+            None => Scratch::with(width, |scratch| {
+                convert_row_pair(&c, options, rows, layout, strides[0], scratch, pair)
+            }),
+        }
+    };
+
+    #[cfg(feature = "parallel")]
+    {
+        if options.threads > 1 {
+            use rayon::prelude::*;
+            let pairs = pairs.collect::<Vec<_>>();
+            pool(options.threads).install(|| pairs.into_par_iter().for_each(convert));
+            return;
+        }
+    }
+    pairs.for_each(convert);
+}
+
+/// Converts one or, at the bottom of odd heights, two rows of pixels.
+fn convert_row_pair(c: &Coefficients, options: &ConvertOptions, rows: &[u8], layout: PixelLayout, luma_stride: usize,
+                    scratch: &mut Scratch, pair: RowPair) {
+    let width = scratch.sums[0].len();
+    let siting = options.siting;
+    let rows = rows.chunks(width * layout.bytes_per_pixel()).zip(pair.luma.chunks_mut(luma_stride));
+    for (planes, (row, luma_row)) in scratch.rows.iter_mut().zip(rows) {
+        unpack_row(row, layout, options.alpha, planes);
+        convert_luma_row(c, planes, &mut luma_row[..width]);
+    }
+    let [top, bottom] = &scratch.rows;
+    // a missing last row repeats the one above
+    let bottom = if pair.luma.len() > luma_stride { bottom } else { top };
+    for (sum, (top, bottom)) in scratch.sums.iter_mut().zip(top.iter().zip(bottom)) {
+        sum.iter_mut().zip(top.iter().zip(bottom)).for_each(|(s, (t, b))| *s = t + b);
+    }
+
+    match pair.chroma {
+        ChromaRow::Planar(u, v) => convert_chroma_row(c, siting, &scratch.sums, u, v),
+        ChromaRow::Interleaved(uv) => {
+            convert_chroma_row(c, siting, &scratch.sums, &mut scratch.u, &mut scratch.v);
+            for (uv, (u, v)) in uv.chunks_exact_mut(2).zip(scratch.u.iter().zip(&scratch.v)) {
+                uv[0] = *u;
+                uv[1] = *v;
+            }
+        }
+    }
+}
+
+/// Pool with `threads` threads, kept for the next frames.
+#[cfg(feature = "parallel")]
+fn pool(threads: usize) -> std::sync::Arc<rayon::ThreadPool> {
+    use std::sync::{Arc, Mutex};
+    static POOL: Mutex<Option<Arc<rayon::ThreadPool>>> = Mutex::new(None);
+    let mut pool = POOL.lock().unwrap();
+    match &*pool {
+        Some(pool) if pool.current_num_threads() == threads => pool.clone(),
+        _ => {
+            let new = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(threads).build().expect("cannot start conversion threads"));
+            *pool = Some(new.clone());
+            new
+        }
+    }
+}
+
+/// Converts one or two rows of gray, whose chroma is neutral.
+fn convert_gray_row_pair(luma_table: &[u8; 256], layout: PixelLayout, rows: &[u8], luma_stride: usize, width: usize,
+                         pair: RowPair) {
+    let rows = rows.chunks(width * layout.bytes_per_pixel()).zip(pair.luma.chunks_mut(luma_stride));
+    for (row, luma_row) in rows {
+        let luma_row = &mut luma_row[..width];
+        if layout.is_16_bit() {
+            for (luma, level) in luma_row.iter_mut().zip(row.chunks_exact(2)) {
+                *luma = luma_table[usize::from(to_8_bit([level[0], level[1]]))];
+            }
+        } else {
+            luma_row.iter_mut().zip(row).for_each(|(luma, level)| *luma = luma_table[usize::from(*level)]);
+        }
+    }
+    match pair.chroma {
+        ChromaRow::Planar(u, v) => {
+            u.fill(128);
+            v.fill(128);
+        }
+        ChromaRow::Interleaved(uv) => uv.fill(128),
+    }
+}
+
+/// Splits a row of pixels into R, G and B planes of 8-bit values.
+fn unpack_row(row: &[u8], layout: PixelLayout, alpha: AlphaPolicy, planes: &mut [Vec<i16>; 3]) {
+    let [r, g, b] = planes;
+    let planes = r.iter_mut().zip(g.iter_mut().zip(b.iter_mut()));
+    let pixels = row.chunks_exact(layout.bytes_per_pixel());
+    let ([ri, gi, bi], alpha_index) = layout.channels();
+    match (alpha_index, alpha) {
+        (Some(ai), AlphaPolicy::Background([background_r, background_g, background_b])) => {
+            for (pixel, (r, (g, b))) in pixels.zip(planes) {
+                let sample = |i: usize| match layout.is_16_bit() {
+                    true => to_8_bit([pixel[2 * i], pixel[2 * i + 1]]),
+                    false => pixel[i],
+                };
+                let alpha = u32::from(sample(ai));
+                let blend = |value: u8, background: u8| {
+                    let sum = u32::from(value) * alpha + u32::from(background) * (255 - alpha);
+                    ((sum + 127) / 255) as i16
+                };
+                (*r, *g, *b) = (blend(sample(ri), background_r), blend(sample(gi), background_g), blend(sample(bi), background_b));
+            }
+        }
+        _ if layout.is_16_bit() => {
+            for (pixel, (r, (g, b))) in pixels.zip(planes) {
+                let sample = |i: usize| i16::from(to_8_bit([pixel[2 * i], pixel[2 * i + 1]]));
+                (*r, *g, *b) = (sample(ri), sample(gi), sample(bi));
+            }
+        }
+        _ => {
+            for (pixel, (r, (g, b))) in pixels.zip(planes) {
+                (*r, *g, *b) = (i16::from(pixel[ri]), i16::from(pixel[gi]), i16::from(pixel[bi]));
+            }
+        }
+    }
+}
+
+/// Splits a row of pixels into 16-bit RGB, encoded with the transfer `table` if it is linear.
+fn unpack_wide_row(row: &[u8], layout: PixelLayout, alpha: AlphaPolicy, table: Option<&[u16]>, out: &mut [[u16; 3]]) {
+    let ([ri, gi, bi], alpha_index) = layout.channels();
+    for (pixel, rgb) in row.chunks_exact(layout.bytes_per_pixel()).zip(out) {
+        let sample = |i: usize| match layout.is_16_bit() {
+            true => u16::from_ne_bytes([pixel[2 * i], pixel[2 * i + 1]]),
+            false => u16::from(pixel[i]) * 257,
+        };
+        *rgb = [sample(ri), sample(gi), sample(bi)];
+        if let (Some(ai), AlphaPolicy::Background(background)) = (alpha_index, alpha) {
+            let alpha = u32::from(sample(ai));
+            for (value, background) in rgb.iter_mut().zip(background) {
+                let sum = u32::from(*value) * alpha + u32::from(background) * 257 * (65535 - alpha);
+                *value = ((sum + 32767) / 65535) as u16;
+            }
+        }
+        if let Some(table) = table {
+            rgb.iter_mut().for_each(|value| *value = table[usize::from(*value)]);
+        }
+    }
+}
+
+/// Rounds a native-endian 16-bit sample to 8 bits.
+fn to_8_bit(sample: [u8; 2]) -> u8 {
+    ((u32::from(u16::from_ne_bytes(sample)) + 128) / 257) as u8
+}
+
+fn convert_luma_row(c: &Coefficients, [r, g, b]: &[Vec<i16>; 3], luma: &mut [u8]) {
+    let done = yuv_simd::luma_row(c, [r, g, b], luma);
+    for x in done..luma.len() {
+        luma[x] = c.y([i32::from(r[x]), i32::from(g[x]), i32::from(b[x])]);
+    }
+}
+
+/// `sums` are the pixels of two rows added up.
+fn convert_chroma_row(c: &Coefficients, siting: ChromaSiting, sums: &[Vec<i16>; 3], u: &mut [u8], v: &mut [u8]) {
+    let [r, g, b] = sums;
+    let (start, end) = yuv_simd::chroma_row(c, siting, [r, g, b], u, v);
+    for i in (0..start).chain(end..u.len()) {
+        (u[i], v[i]) = chroma_sample(c, siting, sums, i);
+    }
+}
+
+/// Reference for the SIMD code, missing pixels past the right edge repeat the last column.
+fn chroma_sample(c: &Coefficients, siting: ChromaSiting, sums: &[Vec<i16>; 3], i: usize) -> (u8, u8) {
+    let (taps, weight_log2) = siting.taps();
+    let width = sums[0].len();
+    // the weighted sum of RGB, converted at once, gives the average without rounding twice
+    let mut sum = [0; 3];
+    for (dx, weight) in taps {
+        let column = (2 * i as isize + dx).clamp(0, width as isize - 1) as usize;
+        sum.iter_mut().zip(sums).for_each(|(s, plane)| *s += weight * i32::from(plane[column]));
+    }
+    (c.u(sum, weight_log2), c.v(sum, weight_log2))
+}
+
+pub(crate) fn clamp(val: i32) -> u8 {
     match val {
         ref v if *v < 0 => 0,
         ref v if *v > 255 => 255,
         v => v as u8,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: [u8; 3] = [0, 0, 0];
+    const WHITE: [u8; 3] = [255, 255, 255];
+    const RED: [u8; 3] = [255, 0, 0];
+    const GREEN: [u8; 3] = [0, 255, 0];
+    const BLUE: [u8; 3] = [0, 0, 255];
+
+    fn options(matrix: ColorMatrix, range: Range) -> ConvertOptions {
+        ConvertOptions { matrix, range, ..ConvertOptions::default() }
+    }
+
+    /// A `width`x`height` image of `rgb` pixels.
+    fn flat(rgb: [u8; 3], width: usize, height: usize) -> Vec<u8> {
+        rgb.repeat(width * height)
+    }
+
+    #[test]
+    fn converts_primaries_with_each_matrix_and_range() {
+        // Y, Cb and Cr of black, white, red, green and blue, rounded from the definitions of the standards
+        for (matrix, range, expected) in [
+            (ColorMatrix::Bt601, Range::Full, [[0, 128, 128], [255, 128, 128], [76, 85, 255], [150, 44, 21], [29, 255, 107]]),
+            (ColorMatrix::Bt601, Range::Limited, [[16, 128, 128], [235, 128, 128], [81, 90, 240], [145, 54, 34], [41, 240, 110]]),
+            (ColorMatrix::Bt709, Range::Full, [[0, 128, 128], [255, 128, 128], [54, 99, 255], [182, 30, 12], [18, 255, 116]]),
+            (ColorMatrix::Bt709, Range::Limited, [[16, 128, 128], [235, 128, 128], [63, 102, 240], [173, 42, 26], [32, 240, 118]]),
+            (ColorMatrix::Bt2020Ncl, Range::Full, [[0, 128, 128], [255, 128, 128], [67, 92, 255], [173, 36, 11], [15, 255, 118]]),
+            (ColorMatrix::Bt2020Ncl, Range::Limited, [[16, 128, 128], [235, 128, 128], [74, 97, 240], [164, 47, 25], [29, 240, 119]]),
+        ] {
+            let c = Coefficients::new(matrix, range);
+            for (&rgb, &yuv) in [BLACK, WHITE, RED, GREEN, BLUE].iter().zip(&expected) {
+                let rgb32 = rgb.map(i32::from);
+                assert_eq!([c.y(rgb32), c.u(rgb32, 0), c.v(rgb32, 0)], yuv, "{:?} {:?} {:?}", matrix, range, rgb);
+
+                // the whole frame through the options
+                let frame = convert_rgb_to_yuv420p(&flat(rgb, 2, 2), 2, 2, PixelLayout::Rgb, &options(matrix, range));
+                assert_eq!(frame, [yuv[0], yuv[0], yuv[0], yuv[0], yuv[1], yuv[2]], "{:?} {:?} {:?}", matrix, range, rgb);
+            }
+        }
+    }
+
+    /// Full range BT.601, so red is Y 76, U 128 - 0.1687 R and V 128 + 0.5 R.
+    fn sited(siting: ChromaSiting) -> ConvertOptions {
+        ConvertOptions { siting, ..options(ColorMatrix::Bt601, Range::Full) }
+    }
+
+    /// `img` with the pixel at `x`, `y` red.
+    fn paint_red(img: &mut [u8], width: usize, x: usize, y: usize) {
+        img[(y * width + x) * 3..][..3].copy_from_slice(&RED);
+    }
+
+    #[test]
+    fn chroma_averages_each_2x2_block() {
+        // a quarter of each block is red, R 63.75: U 117.2 and V 159.9
+        let mut img = flat(BLACK, 4, 2);
+        paint_red(&mut img, 4, 0, 0);
+        paint_red(&mut img, 4, 3, 1);
+        let yuv = convert_rgb_to_yuv420p(&img, 4, 2, PixelLayout::Rgb, &sited(ChromaSiting::Center));
+        assert_eq!(yuv, [76, 0, 0, 0, 0, 0, 0, 76, 117, 117, 160, 160]);
+    }
+
+    #[test]
+    fn left_siting_filters_with_1_2_1() {
+        // column 1 red: half of the first block, a quarter of the [1 2 1] taps around columns 0 and 2
+        let mut img = flat(BLACK, 4, 2);
+        paint_red(&mut img, 4, 1, 0);
+        paint_red(&mut img, 4, 1, 1);
+        let yuv = convert_rgb_to_yuv420p(&img, 4, 2, PixelLayout::Rgb, &sited(ChromaSiting::Center));
+        // R 127.5: U 106.5 and V 191.75
+        assert_eq!(yuv[8..], [106, 128, 192, 128]);
+        let yuv = convert_rgb_to_yuv420p(&img, 4, 2, PixelLayout::Rgb, &sited(ChromaSiting::Left));
+        assert_eq!(yuv[8..], [117, 117, 160, 160]);
+
+        // the column left of the first one repeats it, so column 0 weighs 3/4
+        let mut img = flat(BLACK, 4, 2);
+        paint_red(&mut img, 4, 0, 0);
+        paint_red(&mut img, 4, 0, 1);
+        let yuv = convert_rgb_to_yuv420p(&img, 4, 2, PixelLayout::Rgb, &sited(ChromaSiting::Left));
+        // R 191.25: U 95.7 and V 223.6
+        assert_eq!(yuv[8..], [96, 128, 224, 128]);
+    }
+
+    #[test]
+    fn odd_sizes_repeat_the_last_row_and_column() {
+        // the bottom right chroma sample covers the last pixel only
+        let mut img = flat(BLACK, 3, 3);
+        paint_red(&mut img, 3, 2, 2);
+        let luma = [0, 0, 0, 0, 0, 0, 0, 0, 76];
+        let yuv = convert_rgb_to_yuv420p(&img, 3, 3, PixelLayout::Rgb, &sited(ChromaSiting::Center));
+        assert_eq!(yuv[..9], luma);
+        // R 255: U 85 and V 255.5, clamped
+        assert_eq!(yuv[9..], [128, 128, 128, 85, 128, 128, 128, 255]);
+        // columns 1, 2 and 2 again weigh it 3/4
+        let yuv = convert_rgb_to_yuv420p(&img, 3, 3, PixelLayout::Rgb, &sited(ChromaSiting::Left));
+        assert_eq!(yuv[9..], [128, 128, 128, 96, 128, 128, 128, 224]);
+        assert_eq!(yuv.len(), yuv420_size(3, 3));
+
+        for siting in [ChromaSiting::Center, ChromaSiting::Left] {
+            assert_eq!(convert_rgb_to_yuv420p(&RED, 1, 1, PixelLayout::Rgb, &sited(siting)), [76, 85, 255]);
+        }
+    }
+
+    #[test]
+    fn nv12_interleaves_u_and_v() {
+        let mut img = flat(BLACK, 3, 3);
+        paint_red(&mut img, 3, 2, 2);
+        paint_red(&mut img, 3, 0, 0);
+        let yuv = convert_rgb_to_yuv420sp_nv12(&img, 3, 3, PixelLayout::Rgb, &sited(ChromaSiting::Center));
+        assert_eq!(yuv[..9], [76, 0, 0, 0, 0, 0, 0, 0, 76]);
+        // a quarter of red in the first block, all of it in the last one
+        assert_eq!(yuv[9..], [117, 160, 128, 128, 128, 128, 85, 255]);
+    }
+
+    /// 5x3 pixels of distinct colours, an odd size so the edges are covered.
+    fn colorful() -> Vec<u8> {
+        (0..5 * 3 * 3).map(|i| (i * 37 % 256) as u8).collect()
+    }
+
+    /// `rgb` rearranged into `layout`, with `alpha` where it has one.
+    fn to_layout(rgb: &[u8], layout: PixelLayout, alpha: u8) -> Vec<u8> {
+        rgb.chunks_exact(3).flat_map(|p| {
+            let wide = |v: u8| (u16::from(v) * 257).to_ne_bytes();
+            match layout {
+                PixelLayout::Rgb => p.to_vec(),
+                PixelLayout::Bgr => vec![p[2], p[1], p[0]],
+                PixelLayout::Rgba => vec![p[0], p[1], p[2], alpha],
+                PixelLayout::Bgra => vec![p[2], p[1], p[0], alpha],
+                PixelLayout::Argb => vec![alpha, p[0], p[1], p[2]],
+                PixelLayout::Rgb48 => [wide(p[0]), wide(p[1]), wide(p[2])].concat(),
+                PixelLayout::Rgba64 => [wide(p[0]), wide(p[1]), wide(p[2]), wide(alpha)].concat(),
+                PixelLayout::Gray8 => vec![p[0]],
+                PixelLayout::Gray16 => wide(p[0]).to_vec(),
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn layouts_convert_like_rgb() {
+        let rgb = colorful();
+        let options = ConvertOptions::default();
+        let expected = convert_rgb_to_yuv420p(&rgb, 5, 3, PixelLayout::Rgb, &options);
+        for layout in [PixelLayout::Bgr, PixelLayout::Rgba, PixelLayout::Bgra, PixelLayout::Argb, PixelLayout::Rgb48, PixelLayout::Rgba64] {
+            // alpha is dropped by default
+            let img = to_layout(&rgb, layout, 7);
+            assert_eq!(img.len(), 5 * 3 * layout.bytes_per_pixel());
+            assert_eq!(convert_rgb_to_yuv420p(&img, 5, 3, layout, &options), expected, "{:?}", layout);
+        }
+
+        // gray is R = G = B
+        let gray = rgb.chunks_exact(3).flat_map(|p| [p[0]; 3]).collect::<Vec<_>>();
+        let expected = convert_rgb_to_yuv420p(&gray, 5, 3, PixelLayout::Rgb, &options);
+        for layout in [PixelLayout::Gray8, PixelLayout::Gray16] {
+            let img = to_layout(&gray, layout, 0);
+            assert_eq!(convert_rgb_to_yuv420p(&img, 5, 3, layout, &options), expected, "{:?}", layout);
+        }
+    }
+
+    #[test]
+    fn background_blends_by_alpha() {
+        // opaque red, transparent red, half transparent white and a quarter of blue over green
+        let pixels = [(RED, 255), (RED, 0), (WHITE, 128), (BLUE, 64)];
+        let blended = [RED, GREEN, [128, 255, 128], [0, 191, 64]].concat();
+        let options = ConvertOptions { alpha: AlphaPolicy::Background(GREEN), ..ConvertOptions::default() };
+        let expected = convert_rgb_to_yuv420p(&blended, 2, 2, PixelLayout::Rgb, &options);
+        for layout in [PixelLayout::Rgba, PixelLayout::Bgra, PixelLayout::Argb, PixelLayout::Rgba64] {
+            let img = pixels.iter().flat_map(|(rgb, alpha)| to_layout(rgb, layout, *alpha)).collect::<Vec<_>>();
+            assert_eq!(convert_rgb_to_yuv420p(&img, 2, 2, layout, &options), expected, "{:?}", layout);
+        }
+
+        // and in 16 bits above 8, where the blend of these is exact as well
+        let wide = |img: &[u8], layout: PixelLayout| {
+            let mut frame = YuvFrame::with_bit_depth(YuvFormat::I444, 2, 2, 10);
+            convert_rgb_to_yuv_high_bit_depth_into(img, layout, &options, &mut frame);
+            wide_samples(&frame)
+        };
+        let expected = wide(&to_layout(&blended, PixelLayout::Rgb48, 0), PixelLayout::Rgb48);
+        for layout in [PixelLayout::Rgba, PixelLayout::Rgba64] {
+            let img = pixels.iter().flat_map(|(rgb, alpha)| to_layout(rgb, layout, *alpha)).collect::<Vec<_>>();
+            assert_eq!(wide(&img, layout), expected, "{:?}", layout);
+        }
+
+        // layouts without alpha are opaque
+        let rgb = pixels.iter().flat_map(|(rgb, _)| *rgb).collect::<Vec<_>>();
+        assert_eq!(convert_rgb_to_yuv420p(&rgb, 2, 2, PixelLayout::Rgb, &options),
+                   convert_rgb_to_yuv420p(&rgb, 2, 2, PixelLayout::Rgb, &ConvertOptions::default()));
+    }
+
+    /// Largest difference of any channel.
+    fn max_error(a: &[u8], b: &[u8]) -> u8 {
+        a.iter().zip(b).map(|(a, b)| a.abs_diff(*b)).max().unwrap()
+    }
+
+    #[test]
+    fn yuv_converts_back_to_rgb() {
+        // 8x6 pixels, uniform in each 2x2 block so that 4:2:0 loses no colour
+        let blocks = (0..4 * 3 * 3).map(|i| (i * 71 % 256) as u8).collect::<Vec<_>>();
+        let rgb = (0..8 * 6).flat_map(|i| {
+            let block = (i / 8 / 2) * 4 + i % 8 / 2;
+            blocks[block * 3..][..3].to_vec()
+        }).collect::<Vec<_>>();
+        for matrix in [ColorMatrix::Bt601, ColorMatrix::Bt709, ColorMatrix::Bt2020Ncl] {
+            // limited range has fewer levels to round to
+            for (range, bound) in [(Range::Full, 1), (Range::Limited, 2)] {
+                let options = ConvertOptions { siting: ChromaSiting::Center, ..options(matrix, range) };
+                let mut i420 = YuvFrame::new(YuvFormat::I420, 8, 6);
+                convert_rgb_to_yuv420p_into(&rgb, PixelLayout::Rgb, &options, &mut i420);
+                let mut nv12 = YuvFrame::new(YuvFormat::Nv12, 8, 6);
+                convert_rgb_to_yuv420sp_nv12_into(&rgb, PixelLayout::Rgb, &options, &mut nv12);
+                // the 8-bit conversion makes 4:2:0 only, 4:4:4 takes the samples of each pixel
+                let mut i444 = YuvFrame::new(YuvFormat::I444, 8, 6);
+                let c = Coefficients::new(matrix, range);
+                let strides = i444.strides();
+                for (i, pixel) in rgb.chunks_exact(3).enumerate() {
+                    let pixel = [pixel[0], pixel[1], pixel[2]].map(i32::from);
+                    let [y, u, v] = i444.planes_mut();
+                    let offset = i / 8 * strides[0] + i % 8;
+                    (y[offset], u[offset], v[offset]) = (c.y(pixel), c.u(pixel, 0), c.v(pixel, 0));
+                }
+
+                for frame in [&i420, &nv12, &i444] {
+                    let back = convert_yuv_to_rgb(frame, PixelLayout::Rgb, &options);
+                    let error = max_error(&back, &rgb);
+                    assert!(error <= bound, "{:?} {:?} {:?} off by {}", frame.format(), matrix, range, error);
+
+                    // other layouts hold the same values, with opaque alpha
+                    let bgra = convert_yuv_to_rgb(frame, PixelLayout::Bgra, &options);
+                    assert_eq!(bgra, to_layout(&back, PixelLayout::Bgra, 255));
+                    let rgb48 = convert_yuv_to_rgb(frame, PixelLayout::Rgb48, &options);
+                    assert_eq!(rgb48, to_layout(&back, PixelLayout::Rgb48, 0));
+                }
+            }
+        }
+    }
+
+    /// The samples of each plane of a high-bit-depth frame, without the padding.
+    fn wide_samples(frame: &YuvFrame) -> [Vec<u16>; 3] {
+        let strides = frame.strides();
+        [0, 1, 2].map(|plane| {
+            let (width, height, _) = frame.plane_dimensions(plane);
+            let rows = frame.planes()[plane].chunks(strides[plane]).take(height);
+            rows.flat_map(|row| row[..2 * width].chunks_exact(2).map(|s| u16::from_le_bytes([s[0], s[1]]))).collect()
+        })
+    }
+
+    /// `rgb` as a 16-bit `PixelLayout::Rgb48` image of `width`x`height`.
+    fn flat_wide(rgb: [u16; 3], width: usize, height: usize) -> Vec<u8> {
+        rgb.iter().flat_map(|v| v.to_ne_bytes()).collect::<Vec<_>>().repeat(width * height)
+    }
+
+    #[test]
+    fn converts_primaries_at_10_and_12_bits() {
+        // Y, Cb and Cr of black, white, red, green and blue, rounded from the definitions of the standards
+        for (bit_depth, matrix, range, expected) in [
+            (10, ColorMatrix::Bt709, Range::Full, [[0, 512, 512], [1023, 512, 512], [217, 395, 1023], [732, 118, 47], [74, 1023, 465]]),
+            (10, ColorMatrix::Bt709, Range::Limited, [[64, 512, 512], [940, 512, 512], [250, 409, 960], [691, 167, 105], [127, 960, 471]]),
+            (10, ColorMatrix::Bt2020Ncl, Range::Full, [[0, 512, 512], [1023, 512, 512], [269, 369, 1023], [694, 143, 42], [61, 1023, 471]]),
+            (10, ColorMatrix::Bt2020Ncl, Range::Limited, [[64, 512, 512], [940, 512, 512], [294, 387, 960], [658, 189, 100], [116, 960, 476]]),
+            (12, ColorMatrix::Bt709, Range::Full, [[0, 2048, 2048], [4095, 2048, 2048], [871, 1579, 4095], [2929, 470, 188], [296, 4095, 1860]]),
+            (12, ColorMatrix::Bt709, Range::Limited, [[256, 2048, 2048], [3760, 2048, 2048], [1001, 1637, 3840], [2762, 667, 420], [509, 3840, 1884]]),
+            (12, ColorMatrix::Bt2020Ncl, Range::Full, [[0, 2048, 2048], [4095, 2048, 2048], [1076, 1476, 4095], [2776, 572, 165], [243, 4095, 1883]]),
+            (12, ColorMatrix::Bt2020Ncl, Range::Limited, [[256, 2048, 2048], [3760, 2048, 2048], [1177, 1548, 3840], [2632, 756, 400], [464, 3840, 1904]]),
+        ] {
+            for (&rgb, &[y, u, v]) in [BLACK, WHITE, RED, GREEN, BLUE].iter().zip(&expected) {
+                // 8-bit and 16-bit input alike
+                for (img, layout) in [(flat(rgb, 2, 2), PixelLayout::Rgb), (to_layout(&flat(rgb, 2, 2), PixelLayout::Rgb48, 0), PixelLayout::Rgb48)] {
+                    let mut frame = YuvFrame::with_bit_depth(YuvFormat::I420, 2, 2, bit_depth);
+                    convert_rgb_to_yuv_high_bit_depth_into(&img, layout, &options(matrix, range), &mut frame);
+                    assert_eq!(wide_samples(&frame), [vec![y; 4], vec![u], vec![v]], "{} {:?} {:?} {:?}", bit_depth, matrix, range, rgb);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn i422_and_i444_take_the_chroma_of_each_row() {
+        // column 1 of the first row red, full range BT.601 at 10 bits: U 512 - 172.6 R and V 512 + 511.5 R
+        let mut img = flat(BLACK, 4, 2);
+        paint_red(&mut img, 4, 1, 0);
+        let convert = |format: YuvFormat, siting: ChromaSiting| {
+            let mut frame = YuvFrame::with_bit_depth(format, 4, 2, 10);
+            convert_rgb_to_yuv_high_bit_depth_into(&img, PixelLayout::Rgb, &sited(siting), &mut frame);
+            wide_samples(&frame)
+        };
+        let [y, u, v] = convert(YuvFormat::I444, ChromaSiting::Left);
+        assert_eq!(y, [0, 306, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(u, [512, 339, 512, 512, 512, 512, 512, 512]);
+        assert_eq!(v, [512, 1023, 512, 512, 512, 512, 512, 512]);
+        // half of the first pair of columns
+        let [_, u, v] = convert(YuvFormat::I422, ChromaSiting::Center);
+        assert_eq!((u, v), (vec![426, 512, 512, 512], vec![768, 512, 512, 512]));
+        // a quarter of the [1 2 1] taps around columns 0 and 2
+        let [_, u, v] = convert(YuvFormat::I422, ChromaSiting::Left);
+        assert_eq!((u, v), (vec![469, 469, 512, 512], vec![640, 640, 512, 512]));
+        // 4:2:0 halves that again with the second row
+        let [_, u, v] = convert(YuvFormat::I420, ChromaSiting::Left);
+        assert_eq!((u, v), (vec![490, 490], vec![576, 576]));
+    }
+
+    #[test]
+    fn linear_input_is_encoded_with_the_transfer() {
+        // limited range luma of gray linear light, 1.0 being 10000 cd/m² with PQ
+        for (transfer, linear, expected) in [
+            (Transfer::Pq, 0.0, [64, 256]),
+            // 100 and 1000 cd/m²
+            (Transfer::Pq, 0.01, [509, 2036]),
+            (Transfer::Pq, 0.1, [723, 2890]),
+            (Transfer::Pq, 1.0, [940, 3760]),
+            (Transfer::Hlg, 0.0, [64, 256]),
+            // the end of the square root segment, signal 0.5
+            (Transfer::Hlg, 1.0 / 12.0, [502, 2008]),
+            (Transfer::Hlg, 0.5, [828, 3310]),
+            (Transfer::Hlg, 1.0, [940, 3760]),
+        ] {
+            let value = (linear * 65535.0_f64).round() as u16;
+            let img = flat_wide([value; 3], 2, 2);
+            let options = ConvertOptions { transfer, linear_input: true, ..options(ColorMatrix::Bt2020Ncl, Range::Limited) };
+            for (bit_depth, y) in [10, 12].iter().zip(expected) {
+                let mut frame = YuvFrame::with_bit_depth(YuvFormat::I420, 2, 2, *bit_depth);
+                convert_rgb_to_yuv_high_bit_depth_into(&img, PixelLayout::Rgb48, &options, &mut frame);
+                let neutral = 1 << (bit_depth - 1);
+                assert_eq!(wide_samples(&frame), [vec![y; 4], vec![neutral], vec![neutral]], "{:?} {} {}", transfer, linear, bit_depth);
+            }
+        }
+
+        // encoded input passes through
+        let img = flat_wide([32768; 3], 2, 2);
+        let options = ConvertOptions { transfer: Transfer::Pq, ..options(ColorMatrix::Bt2020Ncl, Range::Limited) };
+        let mut frame = YuvFrame::with_bit_depth(YuvFormat::I420, 2, 2, 10);
+        convert_rgb_to_yuv_high_bit_depth_into(&img, PixelLayout::Rgb48, &options, &mut frame);
+        assert_eq!(wide_samples(&frame)[0], [502; 4]);
+    }
+
+    #[test]
+    fn default_options_are_hd() {
+        let options = ConvertOptions::default();
+        assert_eq!((options.matrix, options.range, options.siting), (ColorMatrix::Bt709, Range::Limited, ChromaSiting::Left));
+        assert_eq!(convert_rgb_to_yuv420p(&flat(RED, 2, 2), 2, 2, PixelLayout::Rgb, &options), [63, 63, 63, 63, 102, 240]);
+    }
+}
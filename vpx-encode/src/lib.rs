@@ -54,10 +54,58 @@ impl Default for VideoCodecId {
     }
 }
 
+/// Colour space signalled in the VP9 bitstream, as `vpx_color_space_t`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ColorSpace {
+    #[default]
+    Unknown = 0,
+    Bt601 = 1,
+    Bt709 = 2,
+    Smpte170 = 3,
+    Smpte240 = 4,
+    Bt2020 = 5,
+    Srgb = 7,
+}
+
+/// Chroma subsampling of the input pictures, 4:2:2 and 4:4:4 need VP9 (profile 1 or 3).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum PixelFormat {
+    #[default]
+    I420,
+    I422,
+    I444,
+}
+
+impl PixelFormat {
+    fn img_fmt(&self, high_bit_depth: bool) -> vpx_img_fmt {
+        match (self, high_bit_depth) {
+            (PixelFormat::I420, false) => vpx_img_fmt::VPX_IMG_FMT_I420,
+            (PixelFormat::I422, false) => vpx_img_fmt::VPX_IMG_FMT_I422,
+            (PixelFormat::I444, false) => vpx_img_fmt::VPX_IMG_FMT_I444,
+            (PixelFormat::I420, true) => vpx_img_fmt::VPX_IMG_FMT_I42016,
+            (PixelFormat::I422, true) => vpx_img_fmt::VPX_IMG_FMT_I42216,
+            (PixelFormat::I444, true) => vpx_img_fmt::VPX_IMG_FMT_I44416,
+        }
+    }
+
+    /// log2 of the horizontal and vertical chroma subsampling
+    fn chroma_shift(&self) -> (usize, usize) {
+        match self {
+            PixelFormat::I420 => (1, 1),
+            PixelFormat::I422 => (1, 0),
+            PixelFormat::I444 => (0, 0),
+        }
+    }
+}
+
 pub struct Encoder {
     ctx: vpx_codec_ctx_t,
     width: usize,
     height: usize,
+    pixel_format: PixelFormat,
+    bit_depth: u32,
+    /// make the next frame a key frame
+    force_key_frame: bool,
 }
 
 #[derive(Debug)]
@@ -109,6 +157,8 @@ impl Encoder {
 
         assert!(config.width % 2 == 0);
         assert!(config.height % 2 == 0);
+        assert!(matches!(config.bit_depth, 8 | 10 | 12));
+        let high_bit_depth = config.bit_depth > 8;
 
         let c = MaybeUninit::zeroed();
         let mut c = unsafe { c.assume_init() };
@@ -123,6 +173,8 @@ impl Encoder {
         // g_pass: realtime mode
         c.g_pass = vpx_enc_pass::VPX_RC_FIRST_PASS;
         c.g_lag_in_frames = 0;
+        // never drop a frame, so every picture comes out as one packet right away
+        c.rc_dropframe_thresh = 0;
 
         // [0-63]
         c.rc_min_quantizer = config.quantizer.0 as _;
@@ -131,11 +183,21 @@ impl Encoder {
         c.g_threads = config.threads;
         c.g_error_resilient = VPX_ERROR_RESILIENT_DEFAULT;
 
+        // profile 1 and 3 for 4:2:2 and 4:4:4, 2 and 3 for 10 and 12 bits
+        c.g_profile = u32::from(config.pixel_format != PixelFormat::I420) + 2 * u32::from(high_bit_depth);
+        c.g_bit_depth = match config.bit_depth {
+            10 => vpx_bit_depth::VPX_BITS_10,
+            12 => vpx_bit_depth::VPX_BITS_12,
+            _ => vpx_bit_depth::VPX_BITS_8,
+        };
+        c.g_input_bit_depth = config.bit_depth;
+
         let ctx = MaybeUninit::zeroed();
         let mut ctx = unsafe { ctx.assume_init() };
 
         match config.codec {
             VideoCodecId::VP8 => {
+                assert!(config.pixel_format == PixelFormat::I420 && !high_bit_depth, "VP8 is 8-bit 4:2:0 only");
                 call_vpx!(vpx_codec_enc_init_ver(
                     &mut ctx,
                     i,
@@ -146,11 +208,13 @@ impl Encoder {
             }
             #[cfg(feature = "vp9")]
             VideoCodecId::VP9 => {
+                // 16-bit input, needs libvpx built with --enable-vp9-highbitdepth
+                let flags = if high_bit_depth { VPX_CODEC_USE_HIGHBITDEPTH } else { 0 };
                 call_vpx!(vpx_codec_enc_init_ver(
                     &mut ctx,
                     i,
                     &c,
-                    0,
+                    flags as _,
                     vpx_sys::VPX_ENCODER_ABI_VERSION as i32
                 ));
                 // set encoder internal speed settings
@@ -165,6 +229,17 @@ impl Encoder {
                     VP9E_SET_ROW_MT as _,
                     1 as c_int
                 ));
+                // colour space and range of the input, signalled in the bitstream
+                call_vpx!(vpx_codec_control_(
+                    &mut ctx,
+                    VP9E_SET_COLOR_SPACE as _,
+                    config.color_space as c_int
+                ));
+                call_vpx!(vpx_codec_control_(
+                    &mut ctx,
+                    VP9E_SET_COLOR_RANGE as _,
+                    config.full_range as c_int
+                ));
             }
         };
 
@@ -172,10 +247,25 @@ impl Encoder {
             ctx,
             width: config.width as usize,
             height: config.height as usize,
+            pixel_format: config.pixel_format,
+            bit_depth: config.bit_depth,
+            force_key_frame: false,
         })
     }
 
+    /// Encodes the next frame as a key frame, e.g. to keep a second stream in step with this one.
+    pub fn force_key_frame(&mut self) {
+        self.force_key_frame = true;
+    }
+
+    fn take_flags(&mut self) -> vpx_enc_frame_flags_t {
+        let flags = if self.force_key_frame { VPX_EFLAG_FORCE_KF } else { 0 };
+        self.force_key_frame = false;
+        flags as _
+    }
+
     pub fn encode(&mut self, pts: i64, data: &[u8]) -> Result<Packets> {
+        assert!(self.pixel_format == PixelFormat::I420 && self.bit_depth == 8, "use encode_planes");
         assert!(2 * data.len() >= 3 * self.width * self.height);
 
         let image = MaybeUninit::zeroed();
@@ -190,12 +280,68 @@ impl Encoder {
             data.as_ptr() as _,
         ));
 
+        let flags = self.take_flags();
+        call_vpx!(vpx_codec_encode(
+            &mut self.ctx,
+            &image,
+            pts,
+            1, // Duration
+            flags,
+            vpx_sys::VPX_DL_REALTIME as c_ulong,
+        ));
+
+        Ok(Packets {
+            ctx: &mut self.ctx,
+            iter: ptr::null(),
+        })
+    }
+
+    /// Encodes a picture in the configured pixel format with separate Y, U and V planes, rows
+    /// `strides` bytes apart. Samples are 16-bit little-endian above 8 bits.
+    pub fn encode_planes(&mut self, pts: i64, planes: [&[u8]; 3], strides: [usize; 3]) -> Result<Packets> {
+        let (x_shift, y_shift) = self.pixel_format.chroma_shift();
+        let chroma_width = (self.width + (1 << x_shift) - 1) >> x_shift;
+        let chroma_height = (self.height + (1 << y_shift) - 1) >> y_shift;
+        let bytes_per_sample = if self.bit_depth > 8 { 2 } else { 1 };
+        for (i, (plane, stride)) in planes.iter().zip(&strides).enumerate() {
+            let (width, height) = if i == 0 { (self.width, self.height) } else { (chroma_width, chroma_height) };
+            let row = width * bytes_per_sample;
+            assert!(*stride >= row && plane.len() >= stride * (height - 1) + row);
+        }
+        // libvpx reads 16-bit samples in native byte order
+        let swapped;
+        let planes = if bytes_per_sample == 2 && cfg!(target_endian = "big") {
+            swapped = planes.map(|plane| plane.chunks(2).flat_map(|s| s.iter().rev().copied()).collect::<Vec<_>>());
+            [&swapped[0][..], &swapped[1][..], &swapped[2][..]]
+        } else {
+            planes
+        };
+
+        let image = MaybeUninit::zeroed();
+        let mut image = unsafe { image.assume_init() };
+
+        call_vpx_ptr!(vpx_img_wrap(
+            &mut image,
+            self.pixel_format.img_fmt(self.bit_depth > 8),
+            self.width as _,
+            self.height as _,
+            1,
+            planes[0].as_ptr() as _,
+        ));
+        for i in 0..3 {
+            image.planes[i] = planes[i].as_ptr() as _;
+            image.stride[i] = strides[i] as _;
+        }
+        // the wrapped 16-bit formats default to 16 significant bits
+        image.bit_depth = self.bit_depth;
+
+        let flags = self.take_flags();
         call_vpx!(vpx_codec_encode(
             &mut self.ctx,
             &image,
             pts,
             1, // Duration
-            0, // Flags
+            flags,
             vpx_sys::VPX_DL_REALTIME as c_ulong,
         ));
 
@@ -261,6 +407,14 @@ pub struct Config {
     pub quantizer: (u8,u8),
     /// threads
     pub threads: u32,
+    /// colour space of the input (VP9 only)
+    pub color_space: ColorSpace,
+    /// full instead of limited ("studio") range (VP9 only)
+    pub full_range: bool,
+    /// chroma subsampling of the input, anything but I420 is VP9 only
+    pub pixel_format: PixelFormat,
+    /// 8, or 10 and 12 with 16-bit samples (VP9 only)
+    pub bit_depth: u32,
 }
 
 pub struct Packets<'a> {
@@ -326,3 +480,203 @@ impl Finish {
         }
     }
 }
+
+#[cfg(all(test, feature = "vp9"))]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 64;
+    const HEIGHT: usize = 48;
+    /// bytes after every row, to check that strides are honoured
+    const PADDING: usize = 8;
+
+    fn config(pixel_format: PixelFormat, bit_depth: u32) -> Config {
+        Config {
+            width: WIDTH as _,
+            height: HEIGHT as _,
+            timebase: [1, 30],
+            bitrate: 5000,
+            codec: VideoCodecId::VP9,
+            kf_max_dist: 1000,
+            quantizer: (0, 8),
+            threads: 1,
+            color_space: ColorSpace::Bt709,
+            full_range: false,
+            pixel_format,
+            bit_depth,
+        }
+    }
+
+    /// Samples of a picture, one `Vec` per plane with rows back to back.
+    #[derive(Debug)]
+    struct Picture {
+        fmt: vpx_img_fmt_t,
+        bit_depth: u32,
+        planes: Vec<Vec<u16>>,
+    }
+
+    fn plane_size(pixel_format: PixelFormat, plane: usize) -> (usize, usize) {
+        let (x_shift, y_shift) = if plane == 0 { (0, 0) } else { pixel_format.chroma_shift() };
+        ((WIDTH + (1 << x_shift) - 1) >> x_shift, (HEIGHT + (1 << y_shift) - 1) >> y_shift)
+    }
+
+    /// Smooth gradients in all three planes, easy to compress with little loss.
+    fn gradient(pixel_format: PixelFormat, bit_depth: u32, frame: usize) -> Vec<Vec<u16>> {
+        (0..3)
+            .map(|plane| {
+                let (width, height) = plane_size(pixel_format, plane);
+                (0..height)
+                    .flat_map(|y| (0..width).map(move |x| (64 + 2 * x + y + 4 * frame + 32 * plane) as u16))
+                    .map(|sample| sample << (bit_depth - 8))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// `planes` as bytes with `PADDING` after every row, 16-bit little-endian above 8 bits.
+    fn strided(pixel_format: PixelFormat, bit_depth: u32, planes: &[Vec<u16>]) -> (Vec<Vec<u8>>, [usize; 3]) {
+        let bytes_per_sample = if bit_depth > 8 { 2 } else { 1 };
+        let mut strides = [0; 3];
+        let data = planes.iter().enumerate()
+            .map(|(i, samples)| {
+                let (width, _) = plane_size(pixel_format, i);
+                strides[i] = width * bytes_per_sample + PADDING;
+                samples.chunks(width)
+                    .flat_map(|row| {
+                        let mut bytes = row.iter()
+                            .flat_map(|&s| if bit_depth > 8 { s.to_le_bytes().to_vec() } else { vec![s as u8] })
+                            .collect::<Vec<_>>();
+                        bytes.resize(strides[i], 0xAA);
+                        bytes
+                    })
+                    .collect()
+            })
+            .collect();
+        (data, strides)
+    }
+
+    struct Decoder {
+        ctx: vpx_codec_ctx_t,
+    }
+
+    impl Decoder {
+        fn new() -> Self {
+            let mut ctx = unsafe { MaybeUninit::zeroed().assume_init() };
+            let result = unsafe {
+                vpx_codec_dec_init_ver(&mut ctx, vpx_codec_vp9_dx(), ptr::null(), 0, VPX_DECODER_ABI_VERSION as i32)
+            };
+            assert_eq!(result, VPX_CODEC_OK);
+            Self { ctx }
+        }
+
+        fn decode(&mut self, data: &[u8]) -> Picture {
+            let result = unsafe { vpx_codec_decode(&mut self.ctx, data.as_ptr(), data.len() as _, ptr::null_mut(), 0) };
+            assert_eq!(result, VPX_CODEC_OK);
+            let mut iter = ptr::null();
+            let image = unsafe { vpx_codec_get_frame(&mut self.ctx, &mut iter).as_ref() }.expect("no decoded frame");
+            assert_eq!((image.d_w as usize, image.d_h as usize), (WIDTH, HEIGHT));
+            let high_bit_depth = image.fmt as u32 & VPX_IMG_FMT_HIGHBITDEPTH != 0;
+            let planes = (0..3)
+                .map(|i| {
+                    let width = ((WIDTH as u32 + (1 << image.x_chroma_shift) - 1) >> image.x_chroma_shift) as usize;
+                    let height = ((HEIGHT as u32 + (1 << image.y_chroma_shift) - 1) >> image.y_chroma_shift) as usize;
+                    let (width, height) = if i == 0 { (WIDTH, HEIGHT) } else { (width, height) };
+                    (0..height)
+                        .flat_map(|y| {
+                            let row = unsafe { image.planes[i].offset(y as isize * image.stride[i] as isize) };
+                            (0..width).map(move |x| unsafe {
+                                if high_bit_depth {
+                                    (row as *const u16).add(x).read_unaligned()
+                                } else {
+                                    u16::from(*row.add(x))
+                                }
+                            })
+                        })
+                        .collect()
+                })
+                .collect();
+            Picture { fmt: image.fmt, bit_depth: image.bit_depth, planes }
+        }
+    }
+
+    impl Drop for Decoder {
+        fn drop(&mut self) {
+            unsafe { vpx_codec_destroy(&mut self.ctx) };
+        }
+    }
+
+    /// Mean absolute difference between the samples of two planes.
+    fn mean_error(a: &[u16], b: &[u16]) -> f64 {
+        assert_eq!(a.len(), b.len());
+        a.iter().zip(b).map(|(&a, &b)| f64::from((i32::from(a) - i32::from(b)).abs())).sum::<f64>() / a.len() as f64
+    }
+
+    /// Encodes `frames` gradients with `encode_planes` and decodes them again, checking that
+    /// they come back close to the input in the same format.
+    fn round_trip(pixel_format: PixelFormat, bit_depth: u32, frames: usize) -> Vec<bool> {
+        // 10 and 12 bits need libvpx built with --enable-vp9-highbitdepth
+        let mut encoder = Encoder::new(config(pixel_format, bit_depth)).unwrap();
+        let mut decoder = Decoder::new();
+        let mut keys = vec![];
+        for i in 0..frames {
+            let input = gradient(pixel_format, bit_depth, i);
+            let (data, strides) = strided(pixel_format, bit_depth, &input);
+            if i == 2 {
+                encoder.force_key_frame();
+            }
+            let packets = encoder.encode_planes(i as i64, [&data[0], &data[1], &data[2]], strides).unwrap()
+                .map(|f| (f.data.to_vec(), f.key, f.pts))
+                .collect::<Vec<_>>();
+            // no lag, every frame comes out right away
+            assert_eq!(packets.len(), 1, "{:?} {} bits, frame {}", pixel_format, bit_depth, i);
+            let (packet, key, pts) = &packets[0];
+            assert_eq!(*pts, i as i64);
+            keys.push(*key);
+
+            let picture = decoder.decode(packet);
+            assert_eq!(picture.fmt, pixel_format.img_fmt(bit_depth > 8));
+            assert_eq!(picture.bit_depth, bit_depth);
+            for (plane, (decoded, input)) in picture.planes.iter().zip(&input).enumerate() {
+                let error = mean_error(decoded, input);
+                assert!(
+                    error <= f64::from(2 << (bit_depth - 8)),
+                    "{:?} {} bits, frame {}, plane {}: mean error {}", pixel_format, bit_depth, i, plane, error,
+                );
+            }
+        }
+        assert!(encoder.finish().unwrap().next().unwrap().is_none());
+        keys
+    }
+
+    #[test]
+    fn encodes_every_pixel_format_and_bit_depth() {
+        for &pixel_format in &[PixelFormat::I420, PixelFormat::I422, PixelFormat::I444] {
+            for &bit_depth in &[8, 10, 12] {
+                round_trip(pixel_format, bit_depth, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn forces_key_frames() {
+        for &bit_depth in &[8, 10] {
+            assert_eq!(round_trip(PixelFormat::I420, bit_depth, 4), [true, false, true, false]);
+        }
+    }
+
+    #[test]
+    fn encodes_packed_i420() {
+        let mut encoder = Encoder::new(config(PixelFormat::I420, 8)).unwrap();
+        let mut decoder = Decoder::new();
+        let input = gradient(PixelFormat::I420, 8, 0);
+        let data = input.iter().flatten().map(|&s| s as u8).collect::<Vec<_>>();
+        let packets = encoder.encode(0, &data).unwrap().map(|f| (f.data.to_vec(), f.key)).collect::<Vec<_>>();
+        assert_eq!(packets.len(), 1);
+        assert!(packets[0].1);
+        let picture = decoder.decode(&packets[0].0);
+        assert_eq!((picture.fmt, picture.bit_depth), (vpx_img_fmt::VPX_IMG_FMT_I420, 8));
+        for (decoded, input) in picture.planes.iter().zip(&input) {
+            assert!(mean_error(decoded, input) <= 2.0);
+        }
+    }
+}